@@ -0,0 +1,4 @@
+CREATE TABLE users (
+  id VARCHAR(200) PRIMARY KEY,
+  name VARCHAR(200)
+);
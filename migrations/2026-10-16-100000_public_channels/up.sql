@@ -0,0 +1 @@
+ALTER TABLE channels ADD COLUMN is_public BOOLEAN NOT NULL DEFAULT 0;
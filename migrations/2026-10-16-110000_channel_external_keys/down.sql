@@ -0,0 +1,2 @@
+DROP INDEX channels_external_key ON channels;
+ALTER TABLE channels DROP COLUMN external_key;
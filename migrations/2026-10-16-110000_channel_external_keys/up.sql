@@ -0,0 +1,2 @@
+ALTER TABLE channels ADD COLUMN external_key VARCHAR(255) NULL;
+CREATE UNIQUE INDEX channels_external_key ON channels (external_key);
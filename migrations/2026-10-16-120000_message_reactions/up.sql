@@ -0,0 +1,9 @@
+CREATE TABLE message_reactions (
+  id INT AUTO_INCREMENT PRIMARY KEY,
+  message_id INT NOT NULL,
+  FOREIGN KEY (message_id) REFERENCES messages (id),
+  user VARCHAR(200) NOT NULL,
+  emoji VARCHAR(32) NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+CREATE UNIQUE INDEX message_reactions_once ON message_reactions (message_id, user, emoji);
@@ -1,13 +1,101 @@
 use biscuit::errors::{Error as JwtErr, ValidationError};
 use biscuit::jwa::*;
 use biscuit::*;
-use chrono::{Duration as CDuration, Utc};
+use chrono::{DateTime, Duration as CDuration, Utc};
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 
-// JWTs will expire in 10 days
-const TIME_TO_EXPIRATION: Duration = Duration::from_secs(60 * 60 * 24 * 10);
+/// How tokens are signed and checked.
+/// Keys are read when this is made, so build it once and share it.
+#[derive(Clone)]
+pub struct JwtConfig {
+  /// What tokens are signed with. None when only the public key is known.
+  signing_secret: Option<Arc<jws::Secret>>,
+  /// What tokens are verified with
+  verifying_secret: Arc<jws::Secret>,
+  /// Tokens signed with any other algorithm are rejected
+  pub algorithm: SignatureAlgorithm,
+  /// How long a token is valid after it is issued
+  pub ttl: Duration,
+  /// How long after expiring a token can still be swapped for a new one
+  pub refresh_grace: Duration,
+  /// Who issues tokens. Tokens from anyone else are rejected.
+  pub issuer: String,
+  /// When set, tokens have to be meant for this audience
+  pub audience: Option<String>,
+}
+
+impl JwtConfig {
+  /// HS256 tokens that expire in 10 days, with the issuer tokens have always had
+  pub fn hmac(secret: &str) -> Self {
+    let secret = Arc::new(jws::Secret::Bytes(secret.as_bytes().to_owned()));
+    JwtConfig {
+      signing_secret: Some(secret.clone()),
+      verifying_secret: secret,
+      algorithm: SignatureAlgorithm::HS256,
+      ttl: Duration::from_secs(60 * 60 * 24 * 10),
+      refresh_grace: Duration::from_secs(60 * 60 * 24),
+      issuer: "MY URL".to_owned(),
+      audience: None,
+    }
+  }
+
+  /// Like `hmac`, but with RSA keys read from DER encoded files.
+  /// Without a private key, tokens can be checked but not issued.
+  pub fn rsa(
+    algorithm: SignatureAlgorithm,
+    private_key_path: Option<&str>,
+    public_key_path: &str,
+  ) -> Result<Self, JwtErr> {
+    let signing_secret = match private_key_path {
+      Some(path) => Some(Arc::new(jws::Secret::rsa_keypair_from_file(path)?)),
+      None => None,
+    };
+    Ok(JwtConfig {
+      signing_secret,
+      verifying_secret: Arc::new(jws::Secret::public_key_from_file(public_key_path)?),
+      algorithm,
+      ..JwtConfig::hmac("")
+    })
+  }
+
+  fn signing_secret(&self) -> Result<&jws::Secret, JwtErr> {
+    match &self.signing_secret {
+      Some(secret) => Ok(&**secret),
+      None => Err(JwtErr::GenericError(
+        "No private key to sign tokens with".to_owned(),
+      )),
+    }
+  }
+}
+
+/// Whether the algorithm signs with a shared secret, rather than a key pair
+pub fn is_hmac(algorithm: SignatureAlgorithm) -> bool {
+  match algorithm {
+    SignatureAlgorithm::HS256 | SignatureAlgorithm::HS384 | SignatureAlgorithm::HS512 => true,
+    _ => false,
+  }
+}
+
+/// Reads an algorithm name as it appears in a token header, like `HS256`.
+/// Only HMAC and RSA algorithms are accepted, since those are the ones tokens can be signed with.
+pub fn parse_algorithm(name: &str) -> Option<SignatureAlgorithm> {
+  match name {
+    "HS256" => Some(SignatureAlgorithm::HS256),
+    "HS384" => Some(SignatureAlgorithm::HS384),
+    "HS512" => Some(SignatureAlgorithm::HS512),
+    "RS256" => Some(SignatureAlgorithm::RS256),
+    "RS384" => Some(SignatureAlgorithm::RS384),
+    "RS512" => Some(SignatureAlgorithm::RS512),
+    "PS256" => Some(SignatureAlgorithm::PS256),
+    "PS384" => Some(SignatureAlgorithm::PS384),
+    "PS512" => Some(SignatureAlgorithm::PS512),
+    _ => None,
+  }
+}
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 struct JWTClaims {
@@ -20,21 +108,89 @@ pub struct UserClaims {
   pub id: String,
 }
 
-pub fn encode_jwt(user_id: &str, user_name: &str, secret: &str) -> String {
-  let cur_time: Timestamp = From::from(Utc::now());
+/// Why a token was turned down
+#[derive(Clone, Debug, PartialEq)]
+pub enum AuthError {
+  /// The token was fine, but it has expired, so the client should get a new one
+  Expired,
+  /// The token wasn't signed with the configured algorithm and key
+  BadSignature,
+  /// The token couldn't be read at all
+  Malformed,
+  /// The token doesn't say which user it is for
+  MissingSubject,
+  /// The token's claims don't fit, like one from another issuer or not valid yet
+  InvalidClaims(String),
+}
+
+impl fmt::Display for AuthError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      AuthError::Expired => write!(f, "The token has expired"),
+      AuthError::BadSignature => write!(f, "The token's signature is invalid"),
+      AuthError::Malformed => write!(f, "The token is malformed"),
+      AuthError::MissingSubject => write!(f, "The token has no subject"),
+      AuthError::InvalidClaims(reason) => write!(f, "{}", reason),
+    }
+  }
+}
+
+impl From<ValidationError> for AuthError {
+  fn from(err: ValidationError) -> Self {
+    match err {
+      ValidationError::Expired(_) => AuthError::Expired,
+      ValidationError::InvalidSignature | ValidationError::WrongAlgorithmHeader => {
+        AuthError::BadSignature
+      }
+      other => AuthError::InvalidClaims(other.to_string()),
+    }
+  }
+}
+
+impl From<JwtErr> for AuthError {
+  fn from(err: JwtErr) -> Self {
+    match err {
+      JwtErr::ValidationError(err) => err.into(),
+      _ => AuthError::Malformed,
+    }
+  }
+}
+
+/// An issuer or audience the way it is read back out of a token,
+/// where anything with a `:` is a URI
+fn string_or_uri(value: &str) -> StringOrUri {
+  StringOrUri::from_str(value).unwrap_or_else(|_| StringOrUri::String(value.to_owned()))
+}
+
+pub fn encode_jwt(user_id: &str, user_name: &str, config: &JwtConfig) -> Result<String, JwtErr> {
+  encode_jwt_at(user_id, user_name, config, Utc::now())
+}
+
+/// Makes a token as if it was issued at `issued`
+fn encode_jwt_at(
+  user_id: &str,
+  user_name: &str,
+  config: &JwtConfig,
+  issued: DateTime<Utc>,
+) -> Result<String, JwtErr> {
+  let cur_time: Timestamp = From::from(issued);
   let exp_time: Timestamp = From::from(
     cur_time
-      .checked_add_signed(CDuration::from_std(TIME_TO_EXPIRATION).unwrap())
+      .checked_add_signed(CDuration::from_std(config.ttl).unwrap())
       .unwrap(),
   );
-  let signing_secret = jws::Secret::Bytes(secret.as_bytes().to_owned());
+  let signing_secret = config.signing_secret()?;
   let header = jws::RegisteredHeader {
-    algorithm: SignatureAlgorithm::HS256,
+    algorithm: config.algorithm,
     ..Default::default()
   };
   let claims = ClaimsSet::<JWTClaims> {
     registered: RegisteredClaims {
-      issuer: Some(FromStr::from_str("MY URL").unwrap()),
+      issuer: Some(string_or_uri(&config.issuer)),
+      audience: config
+        .audience
+        .as_ref()
+        .map(|aud| SingleOrMultiple::Single(string_or_uri(aud))),
       subject: Some(FromStr::from_str(user_id).unwrap()),
       not_before: Some(cur_time),
       expiry: Some(exp_time),
@@ -46,32 +202,66 @@ pub fn encode_jwt(user_id: &str, user_name: &str, secret: &str) -> String {
   };
 
   let jwt = JWT::new_decoded(From::from(header), claims);
-  jwt
-    .encode(&signing_secret)
-    .unwrap()
-    .unwrap_encoded()
-    .to_string()
+  Ok(jwt.encode(signing_secret)?.unwrap_encoded().to_string())
 }
 
-pub fn decode_jwt(jwt: &str, secret: &str) -> Result<UserClaims, JwtErr> {
-  let signing_secret = jws::Secret::Bytes(secret.as_bytes().to_owned());
+/// Checks the token was signed with the configured algorithm and key,
+/// comes from the configured issuer for the configured audience, and is still valid
+pub fn decode_jwt(jwt: &str, config: &JwtConfig) -> Result<UserClaims, AuthError> {
+  decode_claims(jwt, config, None)
+}
+
+/// A new token for the same user as `jwt`, which can have expired up to `refresh_grace` ago
+pub fn refresh_jwt(jwt: &str, config: &JwtConfig) -> Result<String, AuthError> {
+  let claims = decode_claims(jwt, config, Some(config.refresh_grace))?;
+  Ok(encode_jwt(&claims.id, &claims.name, config)?)
+}
+
+/// Like `decode_jwt`, but with `expiry_grace` an expired token is still accepted
+/// until that much time has passed since it expired
+fn decode_claims(
+  jwt: &str,
+  config: &JwtConfig,
+  expiry_grace: Option<Duration>,
+) -> Result<UserClaims, AuthError> {
   let token: JWT<JWTClaims, Empty> = JWT::new_encoded(jwt);
+  // fails when the header names a different algorithm
   let jwt_data = token
-    .into_decoded(&signing_secret, SignatureAlgorithm::HS256)?
+    .into_decoded(&config.verifying_secret, config.algorithm)?
     .payload()?
     .to_owned();
   jwt_data.registered.validate(ValidationOptions {
+    claim_presence_options: ClaimPresenceOptions {
+      issuer: Presence::Required,
+      audience: match config.audience {
+        Some(_) => Presence::Required,
+        None => Presence::Optional,
+      },
+      ..Default::default()
+    },
+    issuer: Validation::Validate(string_or_uri(&config.issuer)),
+    audience: match &config.audience {
+      Some(aud) => Validation::Validate(string_or_uri(aud)),
+      None => Validation::Ignored,
+    },
+    expiry: match expiry_grace {
+      Some(_) => Validation::Ignored,
+      None => Validation::Validate(()),
+    },
     ..Default::default()
   })?;
+  if let (Some(grace), Some(expiry)) = (expiry_grace, &jwt_data.registered.expiry) {
+    let grace = CDuration::from_std(grace).map_err(|_| AuthError::Expired)?;
+    if **expiry + grace < Utc::now() {
+      return Err(AuthError::Expired);
+    }
+  }
 
-  let sub = match jwt_data.registered.subject.ok_or(JwtErr::ValidationError(
-    ValidationError::MissingRequiredClaims(vec!["subject".to_owned()]),
-  ))? {
-    StringOrUri::String(s) => Ok(s),
-    _ => Err(JwtErr::GenericError(
-      "Could not decode the token subject".to_owned(),
-    )),
-  }?;
+  let sub = match jwt_data.registered.subject {
+    Some(StringOrUri::String(s)) => s,
+    Some(_) => return Err(AuthError::Malformed),
+    None => return Err(AuthError::MissingSubject),
+  };
   let name = jwt_data.private.name;
   Ok(UserClaims { name, id: sub })
 }
@@ -82,17 +272,125 @@ mod tests {
 
   #[test]
   fn jwt_ser_and_deser() {
-    let token = encode_jwt("1", "joe", "123456");
-    let invalid_token = encode_jwt("1", "joe", "BAD SECRET");
+    let config = JwtConfig::hmac("123456");
+    let token = encode_jwt("1", "joe", &config).unwrap();
+    let invalid_token = encode_jwt("1", "joe", &JwtConfig::hmac("BAD SECRET")).unwrap();
     let garbage_token = "asdfasdfasdfasdf".to_owned();
-    assert!(decode_jwt(&invalid_token, "123456").is_err());
-    assert!(decode_jwt(&garbage_token, "123456").is_err());
+    assert!(decode_jwt(&invalid_token, &config).is_err());
+    assert!(decode_jwt(&garbage_token, &config).is_err());
     assert_eq!(
-      decode_jwt(&token, "123456").unwrap(),
+      decode_jwt(&token, &config).unwrap(),
       UserClaims {
         name: "joe".to_owned(),
         id: "1".to_owned()
       }
     );
   }
+
+  #[test]
+  fn other_algorithms_are_rejected() {
+    let hs256 = JwtConfig::hmac("123456");
+    let hs384 = JwtConfig {
+      algorithm: SignatureAlgorithm::HS384,
+      ..hs256.clone()
+    };
+    let token = encode_jwt("1", "joe", &hs384).unwrap();
+    assert_eq!(decode_jwt(&token, &hs384).unwrap().id, "1");
+    // same secret, but not the configured algorithm
+    assert!(decode_jwt(&token, &hs256).is_err());
+  }
+
+  #[test]
+  fn issuer_and_audience() {
+    let config = JwtConfig::hmac("123456");
+    let other_issuer = JwtConfig {
+      issuer: "another service".to_owned(),
+      ..config.clone()
+    };
+    let token = encode_jwt("1", "joe", &other_issuer).unwrap();
+    assert!(decode_jwt(&token, &config).is_err());
+    let by_url = JwtConfig {
+      issuer: "https://chat.example.com".to_owned(),
+      ..config.clone()
+    };
+    let token = encode_jwt("1", "joe", &by_url).unwrap();
+    assert_eq!(decode_jwt(&token, &by_url).unwrap().id, "1");
+
+    let for_chat = JwtConfig {
+      audience: Some("chat".to_owned()),
+      ..config.clone()
+    };
+    let for_billing = JwtConfig {
+      audience: Some("billing".to_owned()),
+      ..config.clone()
+    };
+    let token = encode_jwt("1", "joe", &for_chat).unwrap();
+    assert_eq!(decode_jwt(&token, &for_chat).unwrap().id, "1");
+    assert!(decode_jwt(&token, &for_billing).is_err());
+    // a token without an audience isn't accepted once one is expected
+    let token = encode_jwt("1", "joe", &config).unwrap();
+    assert!(decode_jwt(&token, &for_chat).is_err());
+  }
+
+  #[test]
+  fn decode_errors() {
+    let config = JwtConfig {
+      ttl: Duration::from_secs(60 * 60),
+      ..JwtConfig::hmac("123456")
+    };
+    let yesterday = Utc::now() - CDuration::days(1);
+    let expired = encode_jwt_at("1", "joe", &config, yesterday).unwrap();
+    assert_eq!(decode_jwt(&expired, &config), Err(AuthError::Expired));
+    assert_eq!(
+      decode_jwt("asdfasdfasdfasdf", &config),
+      Err(AuthError::Malformed)
+    );
+    let other_secret = encode_jwt("1", "joe", &JwtConfig::hmac("BAD SECRET")).unwrap();
+    assert_eq!(
+      decode_jwt(&other_secret, &config),
+      Err(AuthError::BadSignature)
+    );
+  }
+
+  #[test]
+  fn refresh() {
+    let config = JwtConfig {
+      ttl: Duration::from_secs(60 * 60),
+      refresh_grace: Duration::from_secs(60 * 60 * 24),
+      ..JwtConfig::hmac("123456")
+    };
+    let valid = encode_jwt("1", "joe", &config).unwrap();
+    let refreshed = refresh_jwt(&valid, &config).unwrap();
+    assert_eq!(
+      decode_jwt(&refreshed, &config).unwrap(),
+      UserClaims {
+        name: "joe".to_owned(),
+        id: "1".to_owned()
+      }
+    );
+
+    // expired an hour ago, which is within the grace window
+    let two_hours_ago = Utc::now() - CDuration::hours(2);
+    let recently_expired = encode_jwt_at("1", "joe", &config, two_hours_ago).unwrap();
+    assert_eq!(
+      decode_jwt(&recently_expired, &config),
+      Err(AuthError::Expired)
+    );
+    let refreshed = refresh_jwt(&recently_expired, &config).unwrap();
+    assert_eq!(decode_jwt(&refreshed, &config).unwrap().id, "1");
+
+    let last_week = Utc::now() - CDuration::days(7);
+    let long_expired = encode_jwt_at("1", "joe", &config, last_week).unwrap();
+    assert_eq!(refresh_jwt(&long_expired, &config), Err(AuthError::Expired));
+    let forged = encode_jwt("1", "joe", &JwtConfig::hmac("BAD SECRET")).unwrap();
+    assert_eq!(refresh_jwt(&forged, &config), Err(AuthError::BadSignature));
+  }
+
+  #[test]
+  fn algorithm_names() {
+    assert_eq!(parse_algorithm("RS256"), Some(SignatureAlgorithm::RS256));
+    assert_eq!(parse_algorithm("none"), None);
+    assert!(is_hmac(SignatureAlgorithm::HS512));
+    assert!(!is_hmac(SignatureAlgorithm::PS256));
+  }
 }
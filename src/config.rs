@@ -1,21 +1,128 @@
+use actix_cors::{Cors, CorsFactory};
+use biscuit::errors::Error as JwtErr;
+use biscuit::jwa::SignatureAlgorithm;
 use log::{error, warn};
+use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod};
 use std::env;
+use std::time::Duration;
+
+use crate::auth::{self, JwtConfig};
+use crate::gqln::MissingResolverPolicy;
+
+/// How log lines are written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+  /// env_logger's human readable lines
+  Text,
+  /// One JSON object per line, for log aggregation
+  Json,
+}
+
+impl LogFormat {
+  /// Reads `LOG_FORMAT`. This is separate from `AppConfig::new`,
+  /// since the logger has to be running before the rest of the config is read.
+  pub fn from_env() -> Self {
+    match env::var("LOG_FORMAT") {
+      Ok(ref format) if format == "json" => LogFormat::Json,
+      _ => LogFormat::Text,
+    }
+  }
+}
 
 #[derive(Debug, Clone)]
 pub struct AppConfig {
+  /// The shared secret for HMAC algorithms
   pub jwt_secret: Option<String>,
+  /// What tokens are signed with. Tokens signed any other way are rejected.
+  pub jwt_algorithm: SignatureAlgorithm,
+  /// Key pair RSA algorithms sign tokens with. Without it tokens can only be checked.
+  pub jwt_private_key_path: Option<String>,
+  /// Public key RSA algorithms check tokens with
+  pub jwt_public_key_path: Option<String>,
+  /// How long tokens are valid for
+  pub jwt_ttl_secs: u64,
+  /// How long after expiring tokens can still be refreshed
+  pub jwt_refresh_grace_secs: u64,
+  /// Tokens have to be issued by this
+  pub jwt_issuer: String,
+  /// Tokens have to be meant for this audience, if it is set
+  pub jwt_audience: Option<String>,
   pub db_url: Option<String>,
   pub graphql_port: u32,
   pub management_port: u32,
+  pub missing_resolver_policy: MissingResolverPolicy,
+  pub statement_timeout_ms: Option<u64>,
+  pub tls_cert_path: Option<String>,
+  pub tls_key_path: Option<String>,
+  /// Users allowed to use admin routes on the management API
+  pub admin_users: Vec<String>,
+  pub max_expanded_fields: Option<usize>,
+  /// Answer failed GraphQL requests with a matching HTTP status instead of 200
+  pub http_error_status: bool,
+  /// How often websocket clients are sent keep-alive messages
+  pub ws_keep_alive_ms: Option<u64>,
+  /// Send server details with `connection_ack`
+  pub ws_ack_payload: bool,
+  /// Origins allowed to call the management API from a browser.
+  /// Empty means no CORS headers are sent, so only same-origin requests work.
+  pub management_cors_origins: Vec<String>,
+  /// Run each GraphQL request over HTTP in a single database transaction
+  pub request_transactions: bool,
+  /// Start the root fields of a query at once, instead of one after the other.
+  /// Requests run in a transaction always resolve one field at a time.
+  pub parallel_root_fields: bool,
+  /// Longer messages are broadcast to subscribers as a preview of this many bytes
+  pub max_broadcast_content: Option<usize>,
+  /// Refuse to create messages with nothing but whitespace in them
+  pub reject_blank_messages: bool,
+  /// Fields that can't be queried, as `Type.field`. Can be changed later through the management API.
+  pub denied_fields: Vec<String>,
+  /// Queries estimated to cost more than this are rejected before running
+  pub max_query_complexity: Option<usize>,
+  /// How many items a list is assumed to have when estimating a query's cost
+  pub list_complexity_factor: usize,
+  /// How many subscriptions a single websocket may have running at once
+  pub max_subscriptions_per_connection: Option<usize>,
+  /// How long a websocket client has to send an authenticated `connection_init`
+  pub ws_init_timeout_ms: Option<u64>,
+  /// How many persisted queries are kept before the least recently used are dropped
+  pub max_persisted_queries: usize,
 }
 
 impl Default for AppConfig {
   fn default() -> Self {
     AppConfig {
       jwt_secret: None,
+      jwt_algorithm: SignatureAlgorithm::HS256,
+      jwt_private_key_path: None,
+      jwt_public_key_path: None,
+      jwt_ttl_secs: 60 * 60 * 24 * 10,
+      jwt_refresh_grace_secs: 60 * 60 * 24,
+      jwt_issuer: "MY URL".to_owned(),
+      jwt_audience: None,
       db_url: None,
       graphql_port: 8000,
       management_port: 7999,
+      missing_resolver_policy: MissingResolverPolicy::Error,
+      statement_timeout_ms: None,
+      tls_cert_path: None,
+      tls_key_path: None,
+      admin_users: Vec::new(),
+      max_expanded_fields: None,
+      http_error_status: false,
+      ws_keep_alive_ms: None,
+      ws_ack_payload: false,
+      management_cors_origins: Vec::new(),
+      request_transactions: false,
+      parallel_root_fields: false,
+      max_broadcast_content: None,
+      reject_blank_messages: false,
+      denied_fields: Vec::new(),
+      max_query_complexity: None,
+      list_complexity_factor: 10,
+      max_subscriptions_per_connection: None,
+      ws_init_timeout_ms: None,
+      max_persisted_queries: 1000,
     }
   }
 }
@@ -29,14 +136,194 @@ impl AppConfig {
     if let Ok(secret) = env::var("JWT_SECRET") {
       self.jwt_secret = Some(secret.to_owned());
     }
+    if let Ok(algorithm) = env::var("JWT_ALGORITHM") {
+      match auth::parse_algorithm(&algorithm) {
+        Some(algorithm) => self.jwt_algorithm = algorithm,
+        None => warn!("Unsupported JWT_ALGORITHM {}", algorithm),
+      }
+    }
+    self.jwt_private_key_path = env::var("JWT_PRIVATE_KEY_PATH").ok();
+    self.jwt_public_key_path = env::var("JWT_PUBLIC_KEY_PATH").ok();
+    if let Ok(ttl) = env::var("JWT_TTL_SECS") {
+      match ttl.parse() {
+        Ok(secs) => self.jwt_ttl_secs = secs,
+        Err(_) => warn!("Could not parse JWT_TTL_SECS as seconds"),
+      }
+    }
+    if let Ok(grace) = env::var("JWT_REFRESH_GRACE_SECS") {
+      match grace.parse() {
+        Ok(secs) => self.jwt_refresh_grace_secs = secs,
+        Err(_) => warn!("Could not parse JWT_REFRESH_GRACE_SECS as seconds"),
+      }
+    }
+    if let Ok(issuer) = env::var("JWT_ISSUER") {
+      self.jwt_issuer = issuer;
+    }
+    self.jwt_audience = env::var("JWT_AUDIENCE").ok();
+    if let Ok(tolerant) = env::var("NULL_MISSING_RESOLVERS") {
+      if tolerant == "true" || tolerant == "1" {
+        self.missing_resolver_policy = MissingResolverPolicy::Null;
+      }
+    }
+    if let Ok(timeout) = env::var("STATEMENT_TIMEOUT_MS") {
+      match timeout.parse() {
+        Ok(ms) => self.statement_timeout_ms = Some(ms),
+        Err(_) => warn!("Could not parse STATEMENT_TIMEOUT_MS as milliseconds"),
+      }
+    }
+    self.tls_cert_path = env::var("TLS_CERT_PATH").ok();
+    self.tls_key_path = env::var("TLS_KEY_PATH").ok();
+    if let Ok(admins) = env::var("ADMIN_USERS") {
+      self.admin_users = admins
+        .split(',')
+        .map(|a| a.trim().to_owned())
+        .filter(|a| !a.is_empty())
+        .collect();
+    }
+    if let Ok(max) = env::var("MAX_EXPANDED_FIELDS") {
+      match max.parse() {
+        Ok(max) => self.max_expanded_fields = Some(max),
+        Err(_) => warn!("Could not parse MAX_EXPANDED_FIELDS as a number"),
+      }
+    }
+    if let Ok(status) = env::var("HTTP_ERROR_STATUS") {
+      self.http_error_status = status == "true" || status == "1";
+    }
+    if let Ok(interval) = env::var("WS_KEEP_ALIVE_MS") {
+      match interval.parse() {
+        Ok(ms) => self.ws_keep_alive_ms = Some(ms),
+        Err(_) => warn!("Could not parse WS_KEEP_ALIVE_MS as milliseconds"),
+      }
+    }
+    if let Ok(ack) = env::var("WS_ACK_PAYLOAD") {
+      self.ws_ack_payload = ack == "true" || ack == "1";
+    }
+    if let Ok(origins) = env::var("MANAGEMENT_CORS_ORIGINS") {
+      self.management_cors_origins = origins
+        .split(',')
+        .map(|o| o.trim().to_owned())
+        .filter(|o| !o.is_empty())
+        .collect();
+    }
+    if let Ok(transactions) = env::var("REQUEST_TRANSACTIONS") {
+      self.request_transactions = transactions == "true" || transactions == "1";
+    }
+    if let Ok(parallel) = env::var("PARALLEL_ROOT_FIELDS") {
+      self.parallel_root_fields = parallel == "true" || parallel == "1";
+    }
+    if let Ok(max) = env::var("MAX_BROADCAST_CONTENT_BYTES") {
+      match max.parse() {
+        Ok(max) => self.max_broadcast_content = Some(max),
+        Err(_) => warn!("Could not parse MAX_BROADCAST_CONTENT_BYTES as a number"),
+      }
+    }
+    if let Ok(reject) = env::var("REJECT_BLANK_MESSAGES") {
+      self.reject_blank_messages = reject == "true" || reject == "1";
+    }
+    if let Ok(fields) = env::var("DENIED_FIELDS") {
+      self.denied_fields = fields
+        .split(',')
+        .map(|f| f.trim().to_owned())
+        .filter(|f| !f.is_empty())
+        .collect();
+    }
+    if let Ok(max) = env::var("MAX_QUERY_COMPLEXITY") {
+      match max.parse() {
+        Ok(max) => self.max_query_complexity = Some(max),
+        Err(_) => warn!("Could not parse MAX_QUERY_COMPLEXITY as a number"),
+      }
+    }
+    if let Ok(factor) = env::var("LIST_COMPLEXITY_FACTOR") {
+      match factor.parse() {
+        Ok(factor) => self.list_complexity_factor = factor,
+        Err(_) => warn!("Could not parse LIST_COMPLEXITY_FACTOR as a number"),
+      }
+    }
+    if let Ok(max) = env::var("MAX_SUBSCRIPTIONS_PER_CONNECTION") {
+      match max.parse() {
+        Ok(max) => self.max_subscriptions_per_connection = Some(max),
+        Err(_) => warn!("Could not parse MAX_SUBSCRIPTIONS_PER_CONNECTION as a number"),
+      }
+    }
+    if let Ok(timeout) = env::var("WS_INIT_TIMEOUT_MS") {
+      match timeout.parse() {
+        Ok(ms) => self.ws_init_timeout_ms = Some(ms),
+        Err(_) => warn!("Could not parse WS_INIT_TIMEOUT_MS as milliseconds"),
+      }
+    }
+    if let Ok(max) = env::var("MAX_PERSISTED_QUERIES") {
+      match max.parse() {
+        Ok(max) => self.max_persisted_queries = max,
+        Err(_) => warn!("Could not parse MAX_PERSISTED_QUERIES as a number"),
+      }
+    }
   }
   fn verify(&self) {
     if self.db_url.is_none() {
       panic!("Missing database url. Set it with the DATABASE_URL variable");
     }
-    if self.jwt_secret.is_none() {
+    if auth::is_hmac(self.jwt_algorithm) && self.jwt_secret.is_none() {
       panic!("No JWT verification secrets found. Set one with the `JWT_SECRET` variable.");
     }
+    if !auth::is_hmac(self.jwt_algorithm) && self.jwt_public_key_path.is_none() {
+      panic!("No JWT verification key found. RSA algorithms need `JWT_PUBLIC_KEY_PATH`.");
+    }
+    if self.tls_cert_path.is_some() != self.tls_key_path.is_some() {
+      panic!("TLS needs both a certificate and a key. Set `TLS_CERT_PATH` and `TLS_KEY_PATH`.");
+    }
+  }
+
+  /// How tokens are signed and checked. RSA keys are read from disk,
+  /// so this is called once at startup and the result is shared.
+  pub fn jwt_config(&self) -> Result<JwtConfig, JwtErr> {
+    let mut jwt = if auth::is_hmac(self.jwt_algorithm) {
+      JwtConfig::hmac(self.jwt_secret.as_ref().map_or("", String::as_str))
+    } else {
+      JwtConfig::rsa(
+        self.jwt_algorithm,
+        self.jwt_private_key_path.as_ref().map(String::as_str),
+        self.jwt_public_key_path.as_ref().map_or("", String::as_str),
+      )?
+    };
+    jwt.algorithm = self.jwt_algorithm;
+    jwt.ttl = Duration::from_secs(self.jwt_ttl_secs);
+    jwt.refresh_grace = Duration::from_secs(self.jwt_refresh_grace_secs);
+    jwt.issuer = self.jwt_issuer.clone();
+    jwt.audience = self.jwt_audience.clone();
+    Ok(jwt)
+  }
+
+  /// Loads the TLS certificate chain and private key, if TLS is configured.
+  /// Servers bound with this config also support HTTP/2.
+  pub fn tls_config(&self) -> Option<SslAcceptorBuilder> {
+    let (cert_path, key_path) = match (&self.tls_cert_path, &self.tls_key_path) {
+      (Some(cert), Some(key)) => (cert, key),
+      _ => return None,
+    };
+    let mut builder =
+      SslAcceptor::mozilla_intermediate(SslMethod::tls()).expect("Could not set up TLS");
+    builder
+      .set_private_key_file(key_path, SslFiletype::PEM)
+      .expect("Could not load TLS key");
+    builder
+      .set_certificate_chain_file(cert_path)
+      .expect("Could not load TLS certificate");
+    builder
+      .check_private_key()
+      .expect("Invalid TLS certificate or key");
+    Some(builder)
+  }
+
+  /// CORS middleware for the management API, allowing only the configured origins.
+  /// Only wrap the app with this when `management_cors_origins` isn't empty,
+  /// since a `Cors` without origins allows every origin.
+  pub fn management_cors(&self) -> CorsFactory {
+    self
+      .management_cors_origins
+      .iter()
+      .fold(Cors::new(), |cors, origin| cors.allowed_origin(origin))
+      .allowed_methods(vec!["GET", "POST", "PUT", "DELETE"])
+      .finish()
   }
 
   pub fn new() -> Self {
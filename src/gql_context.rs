@@ -1,14 +1,72 @@
-use crate::gqln::GqlSchema;
-use crate::models::DbPool;
+use crate::gqln::{GqlSchema, ResolutionErr};
+use crate::loader::{BatchLoader, Deferred};
+use crate::models::{
+  get_member_roles, get_messages, get_reactions, get_read_messages, get_user_role, get_users,
+  DbMessage, DbPool, DbReaction, DbUser,
+};
 use crate::ws_actors::ConnectionTracker;
 
-use actix::Addr;
+use actix::dev::ToEnvelope;
+use actix::{Addr, Context, Handler, Message};
+use actix_web::{error::BlockingError, web};
+use diesel::mysql::MysqlConnection;
+use diesel::r2d2::{ConnectionManager, PooledConnection};
+use futures::{future, Future};
+use std::cell::RefCell;
+use std::ops::Deref;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Instant;
+
+type PooledConn = PooledConnection<ConnectionManager<MysqlConnection>>;
+
+/// A message for the tracker, held back until the transaction it came from commits
+pub type DeferredNotice = Box<dyn FnOnce(&Addr<ConnectionTracker>)>;
+
+/// Runs on message content before it is stored and broadcast.
+/// Returns the content to store, or why the message was rejected.
+pub type ContentFilter = Arc<dyn Fn(&str) -> Result<String, String> + Send + Sync>;
 
 #[derive(Clone)]
 pub struct GqlContext {
   pub cur_user: String,
   pub db: DbPool,
   pub ws_addr: Addr<ConnectionTracker>,
+  pub content_filter: Option<ContentFilter>,
+  /// When the server started, for reporting uptime
+  pub server_started: Option<Instant>,
+  /// The connection of the transaction the request runs in, if any
+  transaction: Option<Rc<PooledConn>>,
+  /// Messages for the tracker sent during the transaction
+  notices: Rc<RefCell<Vec<DeferredNotice>>>,
+  /// Messages asked for while resolving the request, loaded together
+  messages: BatchLoader<i32, DbMessage>,
+  /// Whether the current user has read the messages asked about, loaded together
+  reads: BatchLoader<i32, bool>,
+  /// Reactions to the messages asked about, loaded together
+  reactions: BatchLoader<i32, Vec<DbReaction>>,
+  /// Senders of the messages asked about, loaded together
+  users: BatchLoader<String, DbUser>,
+  /// Each sender's role in the channel of their message, keyed by (channel, user)
+  roles: BatchLoader<(i32, String), String>,
+}
+
+/// A database connection for a resolver, either fresh from the pool
+/// or shared with the rest of the request's transaction
+pub enum ContextConn {
+  Pooled(PooledConn),
+  Shared(Rc<PooledConn>),
+}
+
+impl Deref for ContextConn {
+  type Target = MysqlConnection;
+
+  fn deref(&self) -> &MysqlConnection {
+    match self {
+      ContextConn::Pooled(conn) => &**conn,
+      ContextConn::Shared(conn) => &***conn,
+    }
+  }
 }
 
 impl GqlContext {
@@ -17,8 +75,182 @@ impl GqlContext {
       cur_user,
       db,
       ws_addr,
+      content_filter: None,
+      server_started: None,
+      transaction: None,
+      notices: Rc::new(RefCell::new(Vec::new())),
+      messages: BatchLoader::new(),
+      reads: BatchLoader::new(),
+      reactions: BatchLoader::new(),
+      users: BatchLoader::new(),
+      roles: BatchLoader::new(),
+    }
+  }
+
+  pub fn server_started(mut self, started: Instant) -> Self {
+    self.server_started = Some(started);
+    self
+  }
+
+  /// Has every resolver use `conn`, which the caller has started a transaction on
+  pub fn transaction(mut self, conn: Rc<PooledConn>) -> Self {
+    self.transaction = Some(conn);
+    self
+  }
+
+  /// Sends `msg` to the tracker. Inside a transaction it is held back until `take_notices`,
+  /// so subscribers never hear about changes that get rolled back.
+  pub fn notify<M>(&self, msg: M)
+  where
+    M: Message + Send + 'static,
+    M::Result: Send,
+    ConnectionTracker: Handler<M>,
+    Context<ConnectionTracker>: ToEnvelope<ConnectionTracker, M>,
+  {
+    match self.transaction {
+      Some(_) => self
+        .notices
+        .borrow_mut()
+        .push(Box::new(move |addr: &Addr<ConnectionTracker>| {
+          addr.do_send(msg)
+        })),
+      None => self.ws_addr.do_send(msg),
+    }
+  }
+
+  /// The messages held back during the transaction, to send once it has committed
+  pub fn take_notices(&self) -> Vec<DeferredNotice> {
+    self.notices.borrow_mut().drain(..).collect()
+  }
+
+  /// The connection resolvers should run their queries on
+  pub fn conn(&self) -> Result<ContextConn, r2d2::Error> {
+    match &self.transaction {
+      Some(conn) => Ok(ContextConn::Shared(conn.clone())),
+      None => self.db.get().map(ContextConn::Pooled),
+    }
+  }
+
+  /// Runs `query` on the blocking thread pool, so root fields that are resolved at the same
+  /// time can wait on the database together. Inside a transaction it runs right away instead,
+  /// on the transaction's connection.
+  pub fn blocking_query<T, Q>(&self, query: Q) -> Box<dyn Future<Item = T, Error = ResolutionErr>>
+  where
+    T: Send + 'static,
+    Q: FnOnce(&MysqlConnection) -> Result<T, ResolutionErr> + Send + 'static,
+  {
+    if self.transaction.is_some() {
+      let found = self
+        .conn()
+        .map_err(ResolutionErr::from)
+        .and_then(|conn| query(&conn));
+      return Box::new(future::result(found));
     }
+    let db = self.db.clone();
+    Box::new(web::block(move || query(&*db.get()?)).map_err(|e| match e {
+      BlockingError::Error(e) => e,
+      BlockingError::Canceled => ResolutionErr::io_err("The database query was canceled"),
+    }))
+  }
+
+  /// Queues a message to be loaded along with every other one queued during the request.
+  /// Pass the result to `get_message` once the message itself is needed.
+  pub fn load_message(&self, id: i32) -> Deferred<i32, DbMessage> {
+    self.messages.load(id)
+  }
+
+  /// Loads a queued message, fetching the whole batch if it hasn't been yet.
+  /// The senders of the batch are queued, so they can be loaded together too.
+  pub fn get_message(
+    &self,
+    msg: Deferred<i32, DbMessage>,
+  ) -> Result<Option<DbMessage>, ResolutionErr> {
+    msg.get(|ids| -> Result<Vec<(i32, DbMessage)>, ResolutionErr> {
+      let conn = self.conn()?;
+      let found = get_messages(&conn, ids)?;
+      for message in &found {
+        self.load_sender(message);
+      }
+      Ok(found.into_iter().map(|m| (m.id, m)).collect())
+    })
+  }
+
+  /// Queues a message's sender, and their role in its channel, to be loaded along with
+  /// every other one queued. The batches are fetched with `fetch_users` and `fetch_roles`.
+  pub fn load_sender(
+    &self,
+    message: &DbMessage,
+  ) -> (Deferred<String, DbUser>, Deferred<(i32, String), String>) {
+    (
+      self.users.load(message.sender.clone()),
+      self
+        .roles
+        .load((message.channel_id, message.sender.clone())),
+    )
+  }
+
+  /// The stored users out of `ids`
+  pub fn fetch_users(&self, ids: &[String]) -> Result<Vec<(String, DbUser)>, ResolutionErr> {
+    let conn = self.conn()?;
+    let found = get_users(&conn, ids)?;
+    Ok(found.into_iter().map(|u| (u.id.clone(), u)).collect())
+  }
+
+  /// The roles of the members out of `members`, as (channel, user) pairs
+  pub fn fetch_roles(
+    &self,
+    members: &[(i32, String)],
+  ) -> Result<Vec<((i32, String), String)>, ResolutionErr> {
+    let conn = self.conn()?;
+    Ok(get_member_roles(&conn, members)?)
+  }
+
+  /// Queues a message to be checked for being read along with every other one queued.
+  /// Pass the result to `is_read` once the answer is needed.
+  pub fn load_read(&self, id: i32) -> Deferred<i32, bool> {
+    self.reads.load(id)
+  }
+
+  /// Whether the current user has read a queued message, fetching the whole batch if needed
+  pub fn is_read(&self, msg: Deferred<i32, bool>) -> Result<bool, ResolutionErr> {
+    let read = msg.get(|ids| -> Result<Vec<(i32, bool)>, ResolutionErr> {
+      let conn = self.conn()?;
+      let found = get_read_messages(&conn, &self.cur_user, ids)?;
+      Ok(found.into_iter().map(|id| (id, true)).collect())
+    })?;
+    Ok(read.unwrap_or(false))
+  }
+
+  /// Queues a message's reactions to be loaded along with every other one queued.
+  /// The batch is fetched with `fetch_reactions` once any of them are needed.
+  pub fn load_reactions(&self, id: i32) -> Deferred<i32, Vec<DbReaction>> {
+    self.reactions.load(id)
+  }
+
+  /// The reactions to each of `ids` that has any
+  pub fn fetch_reactions(&self, ids: &[i32]) -> Result<Vec<(i32, Vec<DbReaction>)>, ResolutionErr> {
+    let conn = self.conn()?;
+    Ok(get_reactions(&conn, ids)?)
+  }
+
+  /// The current user's role in a channel, or `None` if they aren't a member
+  pub fn role_in(&self, channel_id: i32) -> Result<Option<String>, ResolutionErr> {
+    let conn = self.conn()?;
+    Ok(get_user_role(&conn, channel_id, &self.cur_user)?)
+  }
+
+  pub fn content_filter(mut self, filter: Option<ContentFilter>) -> Self {
+    self.content_filter = filter;
+    self
+  }
+}
+
+/// Rejects messages with nothing but whitespace in them
+pub fn reject_blank_content(content: &str) -> Result<String, String> {
+  if content.trim().is_empty() {
+    return Err("Message content may not be blank".to_owned());
   }
+  Ok(content.to_owned())
 }
 
 pub type Schema = GqlSchema<GqlContext>;
@@ -1,4 +1,5 @@
 use actix::Message;
+use futures::Future;
 use graphql_parser::query;
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
@@ -13,9 +14,27 @@ pub type GqlValue = query::Value;
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct GqlRequest {
+  /// May be left out when the request refers to a persisted query
+  #[serde(default)]
   pub query: String,
   pub operation_name: Option<String>,
   pub variables: Option<JsonValue>,
+  #[serde(default)]
+  pub extensions: Option<GqlRequestExtensions>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct GqlRequestExtensions {
+  pub persisted_query: Option<PersistedQuery>,
+}
+
+/// An Apollo style automatic persisted query
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PersistedQuery {
+  pub version: i32,
+  pub sha256_hash: String,
 }
 
 /// An error that is encountered with the schema or resolvers when setting up
@@ -27,6 +46,10 @@ pub enum GqlSchemaErr {
   MissingType(String),
   MissingResolver((String, String)),
   InvalidResolver,
+  /// A resolver was registered for a field its type does not declare
+  UnknownResolverField((String, String)),
+  /// A directive in the schema is used in a way its definition doesn't allow
+  InvalidDirective(String),
 }
 
 pub type SchemaResult<T> = Result<T, GqlSchemaErr>;
@@ -38,6 +61,9 @@ pub enum GqlQueryErr {
   Directive(QueryValidationError),
   Field(QueryValidationError),
   Type(QueryValidationError),
+  PersistedQuery(QueryValidationError),
+  /// The query's estimated cost is over the schema's limit
+  TooComplex(QueryValidationError),
 }
 
 #[derive(Debug, Clone, Default, Serialize)]
@@ -84,6 +110,38 @@ pub enum ResolutionErr {
   QueryParseIssue(String),
   QueryResult(String),
   MissingArgument(MissingArgument),
+  /// The hash of a persisted query that has not been registered yet
+  PersistedQueryNotFound(String),
+  /// The requested object does not exist
+  NotFound(String),
+  /// The current user is not allowed to see or change the field
+  Unauthorized(String),
+  /// The current user is known, but may not do this
+  Forbidden(String),
+  /// The field has been turned off by an operator
+  Disabled(String),
+  /// Another error, along with where it happened in the response and the query
+  Located {
+    error: Box<ResolutionErr>,
+    path: Vec<JsonValue>,
+    locations: Vec<ErrorLocation>,
+  },
+}
+
+/// A position in the query text, as sent to clients
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ErrorLocation {
+  pub line: usize,
+  pub column: usize,
+}
+
+impl From<graphql_parser::Pos> for ErrorLocation {
+  fn from(pos: graphql_parser::Pos) -> Self {
+    ErrorLocation {
+      line: pos.line,
+      column: pos.column,
+    }
+  }
 }
 
 impl ResolutionErr {
@@ -114,6 +172,74 @@ impl ResolutionErr {
       message: msg.to_owned(),
     })
   }
+
+  /// Records where the error happened. An error that already knows keeps
+  /// its own path, since it was raised deeper in the response.
+  pub fn at(self, path: Vec<JsonValue>, location: ErrorLocation) -> Self {
+    match self {
+      located @ Self::Located { .. } => located,
+      error => Self::Located {
+        error: Box::new(error),
+        path,
+        locations: vec![location],
+      },
+    }
+  }
+
+  /// The error itself, without where it happened
+  pub fn cause(&self) -> &ResolutionErr {
+    match self {
+      Self::Located { error, .. } => error.cause(),
+      e => e,
+    }
+  }
+
+  /// Where in the response data the error happened, if known
+  pub fn path(&self) -> &[JsonValue] {
+    match self {
+      Self::Located { path, .. } => path,
+      _ => &[],
+    }
+  }
+
+  /// A stable, machine readable code for the error,
+  /// following the conventions used by Apollo Server.
+  pub fn error_code(&self) -> &'static str {
+    match self {
+      Self::IO(_) => "INTERNAL_SERVER_ERROR",
+      Self::QueryValidation(_) => "GRAPHQL_VALIDATION_FAILED",
+      Self::SchemaIssue(_) => "INTERNAL_SERVER_ERROR",
+      Self::QueryParseIssue(_) => "GRAPHQL_PARSE_FAILED",
+      Self::QueryResult(_) => "INTERNAL_SERVER_ERROR",
+      Self::MissingArgument(_) => "BAD_USER_INPUT",
+      Self::PersistedQueryNotFound(_) => "PERSISTED_QUERY_NOT_FOUND",
+      Self::NotFound(_) => "NOT_FOUND",
+      Self::Unauthorized(_) => "UNAUTHENTICATED",
+      Self::Forbidden(_) => "FORBIDDEN",
+      Self::Disabled(_) => "FORBIDDEN",
+      Self::Located { error, .. } => error.error_code(),
+    }
+  }
+
+  /// The HTTP status that best matches the error, for clients that prefer
+  /// it over the GraphQL convention of always answering with 200.
+  /// Persisted query misses stay 200 so that clients retry with the full query.
+  pub fn http_status(&self) -> u16 {
+    match self {
+      Self::IO(_) => 500,
+      Self::QueryValidation(_) => 400,
+      Self::SchemaIssue(_) => 500,
+      Self::QueryParseIssue(_) => 400,
+      Self::QueryResult(_) => 500,
+      Self::MissingArgument(_) => 400,
+      Self::PersistedQueryNotFound(_) => 200,
+      Self::NotFound(_) => 404,
+      Self::Unauthorized(_) => 401,
+      Self::Forbidden(_) => 403,
+      Self::Disabled(_) => 403,
+      Self::Located { error, .. } => error.http_status(),
+    }
+  }
 }
 
 impl std::convert::From<GqlQueryErr> for ResolutionErr {
@@ -124,17 +250,75 @@ impl std::convert::From<GqlQueryErr> for ResolutionErr {
 
 pub type GqlObj = BTreeMap<String, GqlValue>;
 
+/// An argument or input field, telling apart one that was left out
+/// from one that was explicitly set to null.
+/// By convention, `Absent` means "leave unchanged" and `Null` means "clear".
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MaybeInput<T> {
+  Absent,
+  Null,
+  Value(T),
+}
+
+impl<T> MaybeInput<T> {
+  /// `None` for both absent and null inputs
+  pub fn value(self) -> Option<T> {
+    match self {
+      MaybeInput::Value(v) => Some(v),
+      _ => None,
+    }
+  }
+
+  pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> MaybeInput<U> {
+    match self {
+      MaybeInput::Absent => MaybeInput::Absent,
+      MaybeInput::Null => MaybeInput::Null,
+      MaybeInput::Value(v) => MaybeInput::Value(f(v)),
+    }
+  }
+}
+
+/// Looks up an argument (or a field of an input object)
+pub fn get_input<'a>(obj: &'a GqlObj, name: &str) -> MaybeInput<&'a GqlValue> {
+  match obj.get(name) {
+    None => MaybeInput::Absent,
+    Some(GqlValue::Null) => MaybeInput::Null,
+    Some(v) => MaybeInput::Value(v),
+  }
+}
+
 #[derive(Debug, Clone)]
 pub enum ResolutionReturn {
   Scalar(query::Value),
+  /// A list of scalars, such as `[String]`. Its items are never resolved any further.
+  ScalarList(Vec<query::Value>),
   Type((String, GqlObj)),
   TypeList((String, Vec<GqlObj>)),
+  /// A list of any of these, so a field like `[[Message]]` keeps the depth of its lists
+  List(Vec<ResolutionReturn>),
 }
 
 pub type ResResult = Result<ResolutionReturn, ResolutionErr>;
 pub type GqlRoot = BTreeMap<String, query::Value>;
 pub type GqlArgs = BTreeMap<String, query::Value>;
 
+/// Reads a custom scalar from the string it was sent as
+pub type ScalarParser = fn(&str) -> Result<GqlValue, String>;
+/// Turns a custom scalar returned by a resolver into what the client receives
+pub type ScalarSerializer = fn(&GqlValue) -> JsonValue;
+
+/// How a custom scalar declared in the schema is read from inputs and written to results
+#[derive(Clone, Copy, Debug)]
+pub struct CustomScalar {
+  pub parse: ScalarParser,
+  pub serialize: ScalarSerializer,
+}
+
+/// Finds the current user's role for a field guarded with `@auth`, given the type
+/// the field is on, the field's parent and its arguments. `None` when they have no role.
+pub type RoleLookup<C> =
+  fn(&str, &GqlRoot, &GqlArgs, &mut C) -> Result<Option<String>, ResolutionErr>;
+
 pub type ResolverBoxed<C> = Box<fn(&GqlRoot, GqlArgs, &mut C, &GqlSchema<C>) -> ResResult>;
 
 #[derive(Clone)]
@@ -160,10 +344,94 @@ impl<C> fmt::Debug for Resolver<C> {
   }
 }
 
+/// What an async resolver gives back, to be waited on before the field's value is known
+pub type ResFuture = Box<dyn Future<Item = ResolutionReturn, Error = ResolutionErr>>;
+pub type AsyncResolverBoxed<C> = Box<fn(&GqlRoot, GqlArgs, &mut C, &GqlSchema<C>) -> ResFuture>;
+
+/// A resolver that doesn't have its value right away, like one waiting on the database.
+/// `GqlSchema::resolve_async` waits on it without blocking, other ways of resolving block until it's ready.
+#[derive(Clone)]
+pub struct AsyncResolver<C> {
+  pub resolve: AsyncResolverBoxed<C>,
+  pub field: String,
+  pub on_type: String,
+}
+
+impl<C> AsyncResolver<C> {
+  pub fn new(resolve: AsyncResolverBoxed<C>, on_type: &str, on_field: &str) -> Self {
+    AsyncResolver {
+      resolve,
+      on_type: on_type.to_owned(),
+      field: on_field.to_owned(),
+    }
+  }
+}
+
+impl<C> fmt::Debug for AsyncResolver<C> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "AsyncResolver: {}.field: {}", self.on_type, self.field)
+  }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GqlErrorExtensions {
+  pub code: &'static str,
+}
+
+/// A `ResolutionErr` as it is sent to clients
+#[derive(Debug, Clone, Serialize)]
+pub struct GqlError {
+  #[serde(flatten)]
+  pub error: ResolutionErr,
+  /// Where in the response data the error happened
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub path: Vec<JsonValue>,
+  /// Where in the query the error happened
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub locations: Vec<ErrorLocation>,
+  pub extensions: GqlErrorExtensions,
+}
+
+impl From<ResolutionErr> for GqlError {
+  fn from(error: ResolutionErr) -> Self {
+    let (error, path, locations) = match error {
+      ResolutionErr::Located {
+        error,
+        path,
+        locations,
+      } => (*error, path, locations),
+      error => (error, Vec::new(), Vec::new()),
+    };
+    GqlError {
+      extensions: GqlErrorExtensions {
+        code: error.error_code(),
+      },
+      path,
+      locations,
+      error,
+    }
+  }
+}
+
 #[derive(Serialize, Message)]
 pub struct GqlResponse {
   pub data: Option<JsonValue>,
-  pub errors: Vec<ResolutionErr>,
+  pub errors: Vec<GqlError>,
+}
+
+impl From<Result<(JsonValue, Vec<GqlError>), ResolutionErr>> for GqlResponse {
+  fn from(res_result: Result<(JsonValue, Vec<GqlError>), ResolutionErr>) -> Self {
+    match res_result {
+      Ok((d, errors)) => GqlResponse {
+        data: Some(d),
+        errors,
+      },
+      Err(e) => GqlResponse {
+        data: None,
+        errors: vec![GqlError::from(e)],
+      },
+    }
+  }
 }
 
 impl From<Result<JsonValue, ResolutionErr>> for GqlResponse {
@@ -175,7 +443,7 @@ impl From<Result<JsonValue, ResolutionErr>> for GqlResponse {
       },
       Err(e) => GqlResponse {
         data: None,
-        errors: vec![e],
+        errors: vec![GqlError::from(e)],
       },
     }
   }
@@ -2,10 +2,15 @@
 
 use graphql_parser::query;
 use serde_json::{json, Map as JsonMap, Number as JsonNumber, Value as JsonValue};
+use std::cell::Cell;
 use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
 
 use crate::gqln::base_types::*;
 
+/// Scalars whose values can't be given as strings
+const NON_STRING_SCALARS: [&str; 4] = ["Int", "Integer", "Float", "Boolean"];
+
 // TODO: make this function smart
 pub fn naive_check_var_type(var_type: &query::Type, var_value: &GqlValue) -> bool {
   match (var_type, var_value) {
@@ -14,7 +19,11 @@ pub fn naive_check_var_type(var_type: &query::Type, var_value: &GqlValue) -> boo
     (query::Type::ListType(_), GqlValue::Null) => true,
     (query::Type::NamedType(l), GqlValue::String(_)) if l == "String" => true,
     (query::Type::NamedType(l), GqlValue::Float(_)) if l == "Float" => true,
-    (query::Type::NamedType(l), GqlValue::Int(_)) if l == "Integer" => true,
+    (query::Type::NamedType(l), GqlValue::Int(_)) if l == "Int" || l == "Integer" => true,
+    (query::Type::NamedType(l), GqlValue::String(_)) if l == "ID" => true,
+    // enums and custom scalars are sent as strings in JSON
+    (query::Type::NamedType(l), GqlValue::String(_)) => !NON_STRING_SCALARS.contains(&l.as_str()),
+    (query::Type::NamedType(l), GqlValue::Int(_)) if l == "ID" => true,
     (query::Type::NamedType(l), GqlValue::Boolean(_)) if l == "Boolean" => true,
     // naive
     (query::Type::NamedType(_), GqlValue::Enum(_)) => true,
@@ -33,6 +42,25 @@ pub fn naive_check_var_type(var_type: &query::Type, var_value: &GqlValue) -> boo
   }
 }
 
+/// Follows the input coercion rules in the spec that don't need the schema:
+/// a single value is wrapped into a one element list wherever a list is expected,
+/// and an Int is turned into a Float wherever a Float is expected.
+pub fn coerce_input(value_type: &query::Type, value: GqlValue) -> GqlValue {
+  match (value_type, value) {
+    (query::Type::NonNullType(inner), v) => coerce_input(inner, v),
+    (query::Type::ListType(inner), GqlValue::List(l)) => {
+      GqlValue::List(l.into_iter().map(|v| coerce_input(inner, v)).collect())
+    }
+    (query::Type::ListType(_), GqlValue::Null) => GqlValue::Null,
+    (query::Type::ListType(_), v @ GqlValue::Variable(_)) => v,
+    (query::Type::ListType(inner), v) => GqlValue::List(vec![coerce_input(inner, v)]),
+    (query::Type::NamedType(name), GqlValue::Int(i)) if name == "Float" => {
+      GqlValue::Float(i.as_i64().unwrap() as f64)
+    }
+    (_, v) => v,
+  }
+}
+
 pub struct FieldSelection {
   pub name: Option<String>,
   pub initial_fields: Vec<query::Field>,
@@ -55,19 +83,54 @@ pub struct GqlRunningQuery {
   fragment_fields: HashMap<String, Vec<query::Field>>,
   query_ast: query::Document,
   pub starting_type: String,
+  max_fields: Option<usize>,
+  expanded_fields: Cell<usize>,
+  /// The object types each union or interface can be, for fragment type conditions
+  possible_types: BTreeMap<String, Vec<String>>,
 }
 
 impl GqlRunningQuery {
-  pub fn new(doc: query::Document) -> Self {
+  pub fn new(mut doc: query::Document) -> Self {
+    // a bare `{ ... }` is shorthand for an anonymous query
+    doc.definitions = doc
+      .definitions
+      .into_iter()
+      .map(|d| match d {
+        query::Definition::Operation(query::OperationDefinition::SelectionSet(set)) => {
+          query::Definition::Operation(query::OperationDefinition::Query(query::Query {
+            position: set.span.0,
+            name: None,
+            variable_definitions: Vec::new(),
+            directives: Vec::new(),
+            selection_set: set,
+          }))
+        }
+        d => d,
+      })
+      .collect();
     GqlRunningQuery {
       variables: HashMap::new(),
       fragments: HashMap::new(),
       fragment_fields: HashMap::new(),
       query_ast: doc,
       starting_type: "Query".to_owned(),
+      max_fields: None,
+      expanded_fields: Cell::new(0),
+      possible_types: BTreeMap::new(),
     }
   }
 
+  /// Caps the total number of fields that selection sets may expand to,
+  /// counting every field brought in through fragments.
+  pub fn set_max_fields(&mut self, max_fields: Option<usize>) {
+    self.max_fields = max_fields;
+  }
+
+  /// Lets fragments on a union or interface apply to the object types it can be
+  pub fn set_possible_types(&mut self, possible_types: BTreeMap<String, Vec<String>>) {
+    self.possible_types = possible_types;
+  }
+
   #[inline(always)]
   fn get_queries<'a>(&'a self) -> Vec<&'a query::Query> {
     self
@@ -107,6 +170,111 @@ impl GqlRunningQuery {
       .collect()
   }
 
+  /// Replaces variables in an argument value with the values they were given,
+  /// including variables nested in lists and input objects.
+  pub fn substitute_variables(&self, value: &GqlValue) -> GqlValue {
+    match value {
+      GqlValue::Variable(name) => self.variables.get(name).cloned().unwrap_or(GqlValue::Null),
+      GqlValue::List(items) => {
+        GqlValue::List(items.iter().map(|i| self.substitute_variables(i)).collect())
+      }
+      // object fields set to variables without a value are left out
+      GqlValue::Object(obj) => GqlValue::Object(
+        obj
+          .iter()
+          .filter(|(_, v)| !self.is_absent(v))
+          .map(|(k, v)| (k.clone(), self.substitute_variables(v)))
+          .collect(),
+      ),
+      v => v.clone(),
+    }
+  }
+
+  /// The directives with variables in their arguments replaced by the values they were given
+  pub fn substitute_directive_variables(
+    &self,
+    directives: &[query::Directive],
+  ) -> Vec<query::Directive> {
+    directives
+      .iter()
+      .map(|d| query::Directive {
+        arguments: d
+          .arguments
+          .iter()
+          .map(|(name, value)| (name.clone(), self.substitute_variables(value)))
+          .collect(),
+        ..d.clone()
+      })
+      .collect()
+  }
+
+  /// Whether the value is a variable that was not given a value
+  pub fn is_absent(&self, value: &GqlValue) -> bool {
+    match value {
+      GqlValue::Variable(name) => !self.variables.contains_key(name),
+      _ => false,
+    }
+  }
+
+  /// Keeps only the operation with the given name, so a document with several
+  /// operations can be sent along with the name of the one to run.
+  pub fn select_operation(&mut self, operation_name: Option<&str>) -> GqlExecResult<()> {
+    let operation_count = self
+      .query_ast
+      .definitions
+      .iter()
+      .filter(|d| match d {
+        query::Definition::Operation(_) => true,
+        _ => false,
+      })
+      .count();
+    let name = match operation_name {
+      Some(name) => name,
+      None if operation_count > 1 => {
+        return Err(GqlQueryErr::Field(QueryValidationError::new(
+          "operationName is required when a document has several operations".to_owned(),
+          "operationName".to_owned(),
+        )))
+      }
+      None => return Ok(()),
+    };
+    let is_named = |op: &query::OperationDefinition| -> bool {
+      let op_name = match op {
+        query::OperationDefinition::Query(q) => &q.name,
+        query::OperationDefinition::Mutation(m) => &m.name,
+        query::OperationDefinition::Subscription(s) => &s.name,
+        query::OperationDefinition::SelectionSet(_) => return false,
+      };
+      op_name.as_ref().map(|n| n == name).unwrap_or(false)
+    };
+    let found = self
+      .query_ast
+      .definitions
+      .iter()
+      .filter(|d| match d {
+        query::Definition::Operation(op) => is_named(op),
+        _ => false,
+      })
+      .count();
+    if found == 0 {
+      return Err(GqlQueryErr::Field(QueryValidationError::new(
+        format!("No operation named {}", name),
+        name.to_owned(),
+      )));
+    }
+    if found > 1 {
+      return Err(GqlQueryErr::Field(QueryValidationError::new(
+        format!("More than one operation is named {}", name),
+        name.to_owned(),
+      )));
+    }
+    self.query_ast.definitions.retain(|d| match d {
+      query::Definition::Operation(op) => is_named(op),
+      _ => true,
+    });
+    Ok(())
+  }
+
   pub fn parse_fragments(&mut self) -> GqlExecResult<()> {
     for item in &self.query_ast.definitions {
       match item {
@@ -173,7 +341,6 @@ impl GqlRunningQuery {
     // match it with its definition
     // and then assign it to the internal map
     for (var_name, var_value) in var_value_map.into_iter() {
-      let gql_var_value = json_to_gql(var_value);
       let var_def =
         var_defs
           .get(&var_name)
@@ -181,6 +348,7 @@ impl GqlRunningQuery {
             format!("Unexpected variable {} found", &var_name),
             var_name.clone(),
           )))?;
+      let gql_var_value = coerce_input(&var_def.var_type, json_to_gql(var_value));
       if !naive_check_var_type(&var_def.var_type, &gql_var_value) {
         return Err(GqlQueryErr::Variable(QueryValidationError::new(
           format!(
@@ -194,11 +362,23 @@ impl GqlRunningQuery {
       variables.insert(var_name, gql_var_value);
     }
 
-    // any variables that did not have provided values must have default values
+    // any non-null variables that did not have provided values must have default values.
+    // Nullable ones are left out, so the arguments using them are absent
     for (var_name, var_def) in var_defs.iter() {
       if let Some(default) = &var_def.default_value {
-        variables.insert(var_name.to_owned(), default.to_owned());
-      } else {
+        // defaults, which can be input objects and lists, are coerced like values that were sent
+        let default = coerce_input(&var_def.var_type, default.to_owned());
+        if !naive_check_var_type(&var_def.var_type, &default) {
+          return Err(GqlQueryErr::Variable(QueryValidationError::new(
+            format!(
+              "the default value of variable {} was not of type {:?}",
+              var_name, &var_def.var_type
+            ),
+            var_name.clone(),
+          )));
+        }
+        variables.insert(var_name.to_owned(), default);
+      } else if let query::Type::NonNullType(_) = var_def.var_type {
         return Err(GqlQueryErr::Variable(QueryValidationError::new(
           format!("Variable {} was not provided a value", var_name),
           Default::default(),
@@ -214,6 +394,14 @@ impl GqlRunningQuery {
     selection: query::Selection,
     on_type: &str,
   ) -> GqlExecResult<Vec<query::Field>> {
+    let directives = match &selection {
+      query::Selection::Field(f) => &f.directives,
+      query::Selection::FragmentSpread(spread) => &spread.directives,
+      query::Selection::InlineFragment(inline) => &inline.directives,
+    };
+    if !self.is_included(directives)? {
+      return Ok(Vec::new());
+    }
     match selection {
       query::Selection::Field(f) => Ok(vec![f]),
       query::Selection::FragmentSpread(spread) => {
@@ -225,14 +413,18 @@ impl GqlRunningQuery {
             format!("Fragment {} not found", &spread.fragment_name),
             spread.fragment_name,
           )))?;
+        let query::TypeCondition::On(type_name) = &fragment.type_condition;
+        if !self.condition_applies(type_name, on_type) {
+          return Ok(Vec::new());
+        }
         for s in &fragment.selection_set.items {
           fields.extend(self.get_fields(s.to_owned(), on_type)?);
         }
         Ok(fields)
       }
       query::Selection::InlineFragment(inline) => {
-        if let Some(query::TypeCondition::On(type_name)) = inline.type_condition {
-          if type_name == on_type {
+        if let Some(query::TypeCondition::On(type_name)) = &inline.type_condition {
+          if !self.condition_applies(type_name, on_type) {
             return Ok(Vec::new());
           }
         }
@@ -245,26 +437,88 @@ impl GqlRunningQuery {
     }
   }
 
+  /// Whether a fragment on `type_name` applies to an object of type `on_type`:
+  /// either the same type, or a union or interface `on_type` is one of.
+  fn condition_applies(&self, type_name: &str, on_type: &str) -> bool {
+    type_name == on_type
+      || self
+        .possible_types
+        .get(type_name)
+        .map_or(false, |types| types.iter().any(|t| t == on_type))
+  }
+
+  /// Applies `@skip` and `@include`, whose `if` can be a literal or a variable.
+  /// Selections that are left out are never resolved.
+  fn is_included(&self, directives: &[query::Directive]) -> GqlExecResult<bool> {
+    for directive in directives {
+      let skip_when = match directive.name.as_str() {
+        "skip" => true,
+        "include" => false,
+        _ => continue,
+      };
+      let condition = directive
+        .arguments
+        .iter()
+        .find(|(name, _)| name == "if")
+        .map(
+          |(_, value)| match (value, self.substitute_variables(value)) {
+            // a nullable variable that is null or was not given counts as false
+            (GqlValue::Variable(_), GqlValue::Null) => GqlValue::Boolean(false),
+            (_, condition) => condition,
+          },
+        );
+      match condition {
+        Some(GqlValue::Boolean(b)) if b == skip_when => return Ok(false),
+        Some(GqlValue::Boolean(_)) => {}
+        _ => {
+          return Err(GqlQueryErr::Directive(QueryValidationError::new(
+            format!("@{} needs a Boolean `if` argument", directive.name),
+            directive.name.clone(),
+          )))
+        }
+      }
+    }
+    Ok(true)
+  }
+
   pub fn fields_from_selectionset(
     &self,
     set: &query::SelectionSet,
     on_type: &str,
   ) -> GqlExecResult<Vec<query::Field>> {
-    Ok(
-      set
-        .items
-        .iter()
-        .map(|e| self.get_fields(e.clone(), on_type))
-        .collect::<GqlExecResult<Vec<Vec<query::Field>>>>()?
-        .into_iter()
-        .flatten()
-        .collect(),
-    )
+    let fields: Vec<query::Field> = set
+      .items
+      .iter()
+      .map(|e| self.get_fields(e.clone(), on_type))
+      .collect::<GqlExecResult<Vec<Vec<query::Field>>>>()?
+      .into_iter()
+      .flatten()
+      .collect();
+    let expanded = self.expanded_fields.get() + fields.len();
+    self.expanded_fields.set(expanded);
+    match self.max_fields {
+      Some(max) if expanded > max => Err(GqlQueryErr::Field(QueryValidationError::new(
+        format!("Query expands to more than {} fields", max),
+        on_type.to_owned(),
+      ))),
+      _ => Ok(fields),
+    }
   }
 
   //fn parse_fields_selection
 
   pub fn get_initial_items(&mut self) -> GqlExecResult<Vec<FieldSelection>> {
+    // e.g. a document of only fragments, which has nothing to run
+    let has_operation = self.query_ast.definitions.iter().any(|d| match d {
+      query::Definition::Operation(_) => true,
+      _ => false,
+    });
+    if !has_operation {
+      return Err(GqlQueryErr::Field(QueryValidationError::new(
+        "No executable operation found".to_owned(),
+        "Operation".to_owned(),
+      )));
+    }
     let queries = self.get_queries();
     let mutations = self.get_mutations();
     let subscriptions = self.get_subscriptions();
@@ -336,11 +590,14 @@ pub fn json_to_gql(value: JsonValue) -> GqlValue {
     JsonValue::Null => GqlValue::Null,
     JsonValue::Bool(b) => GqlValue::Boolean(b),
     JsonValue::Number(n) => {
-      if n.is_u64() || n.is_i64() {
-        let f = n.as_i64().unwrap();
-        GqlValue::Int(query::Number::from(f as i32))
-      } else {
-        GqlValue::Float(n.as_f64().unwrap())
+      if n.is_f64() {
+        return GqlValue::Float(n.as_f64().unwrap());
+      }
+      // graphql Ints are 32 bit. Larger integers are kept exactly as strings,
+      // which ID inputs accept and Int inputs reject, instead of wrapping around
+      match n.as_i64().map(i32::try_from) {
+        Some(Ok(i)) => GqlValue::Int(query::Number::from(i)),
+        _ => GqlValue::String(n.to_string()),
       }
     }
     JsonValue::String(s) => GqlValue::String(s),
@@ -432,6 +689,72 @@ mod tests {
     assert!(var_parse_result.is_err());
   }
 
+  #[test]
+  fn test_list_variable_coercion() {
+    let query = "query Test($ids: [String!]) { messages(ids: $ids) { id } }";
+    for ids in vec![json!("5"), json!(["5"])] {
+      let mut exec = GqlRunningQuery::new(parse_query(query).unwrap());
+      exec.parse_variables(Some(json!({ "ids": ids }))).unwrap();
+      assert_eq!(
+        exec.variables.get("ids"),
+        Some(&GqlValue::List(vec![GqlValue::String("5".to_owned())]))
+      );
+    }
+  }
+
+  #[test]
+  fn shorthand_query() {
+    let exec = GqlRunningQuery::new(parse_query("{ me { id } }").unwrap());
+    assert_eq!(exec.get_queries().len(), 1);
+    assert!(exec.get_queries()[0].name.is_none());
+  }
+
+  #[test]
+  fn fragment_only_document() {
+    let query = "fragment userFields on User { id name }";
+    let mut exec = GqlRunningQuery::new(parse_query(query).unwrap());
+    exec.parse_fragments().unwrap();
+    match exec.get_initial_items() {
+      Err(e) => assert!(format!("{:?}", e).contains("No executable operation found")),
+      Ok(_) => panic!("A document without operations should be rejected"),
+    }
+  }
+
+  #[test]
+  fn integers_keep_their_value() {
+    let max = json!(i32::max_value());
+    assert_eq!(
+      json_to_gql(max.clone()),
+      GqlValue::Int(query::Number::from(i32::max_value()))
+    );
+    assert_eq!(gql_to_json(json_to_gql(max.clone())).unwrap(), max);
+
+    let past_max = i64::from(i32::max_value()) + 1;
+    assert_eq!(
+      json_to_gql(json!(past_max)),
+      GqlValue::String(past_max.to_string())
+    );
+    assert_eq!(
+      json_to_gql(json!(u64::max_value())),
+      GqlValue::String(u64::max_value().to_string())
+    );
+
+    let id_query = "query Test($id: ID) { message(id: $id) { id } }";
+    let mut exec = GqlRunningQuery::new(parse_query(id_query).unwrap());
+    exec
+      .parse_variables(Some(json!({ "id": past_max })))
+      .unwrap();
+    assert_eq!(
+      exec.variables.get("id"),
+      Some(&GqlValue::String(past_max.to_string()))
+    );
+    let count_query = "query Test($count: Int) { messages(count: $count) { id } }";
+    let mut exec = GqlRunningQuery::new(parse_query(count_query).unwrap());
+    assert!(exec
+      .parse_variables(Some(json!({ "count": past_max })))
+      .is_err());
+  }
+
   #[test]
   fn parse_subscription() {
     let mut exec = GqlRunningQuery::new(
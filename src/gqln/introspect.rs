@@ -14,6 +14,24 @@ pub fn r_type_desc<C>(
         "Scalar type".to_owned(),
       )));
     }
+    if let Some(scalar_def) = schema.external_types.scalars.get(parent) {
+      return match &scalar_def.description {
+        Some(desc) => Ok(ResolutionReturn::Scalar(query::Value::String(desc.clone()))),
+        None => Ok(ResolutionReturn::Scalar(query::Value::Null)),
+      };
+    }
+    if let Some(union_def) = schema.external_types.unions.get(parent) {
+      return match &union_def.description {
+        Some(desc) => Ok(ResolutionReturn::Scalar(query::Value::String(desc.clone()))),
+        None => Ok(ResolutionReturn::Scalar(query::Value::Null)),
+      };
+    }
+    if let Some(interface_def) = schema.external_types.interfaces.get(parent) {
+      return match &interface_def.description {
+        Some(desc) => Ok(ResolutionReturn::Scalar(query::Value::String(desc.clone()))),
+        None => Ok(ResolutionReturn::Scalar(query::Value::Null)),
+      };
+    }
     if let Some(enum_def) = schema.external_types.enums.get(parent) {
       return match &enum_def.description {
         Some(desc) => Ok(ResolutionReturn::Scalar(query::Value::String(desc.clone()))),
@@ -51,12 +69,16 @@ pub fn r_type_ofkind<C>(
       "LIST" | "NON_NULL" => {
         let mut bmap = BTreeMap::new();
         bmap.insert("name".to_owned(), GqlValue::String(name.clone()));
-        if BUILTIN_SCALARS.contains(&name.as_str()) {
+        if schema.is_scalar(name) {
           bmap.insert("kind".to_owned(), GqlValue::Enum("SCALAR".to_owned()));
         } else if schema.external_types.enums.contains_key(name) {
           bmap.insert("kind".to_owned(), GqlValue::Enum("ENUM".to_owned()));
         } else if schema.external_types.objects.contains_key(name) {
           bmap.insert("kind".to_owned(), GqlValue::Enum("OBJECT".to_owned()));
+        } else if schema.external_types.unions.contains_key(name) {
+          bmap.insert("kind".to_owned(), GqlValue::Enum("UNION".to_owned()));
+        } else if schema.external_types.interfaces.contains_key(name) {
+          bmap.insert("kind".to_owned(), GqlValue::Enum("INTERFACE".to_owned()));
         }
 
         return Ok(ResolutionReturn::Type(("__Type".to_owned(), bmap)));
@@ -70,14 +92,46 @@ pub fn r_type_ofkind<C>(
 }
 
 pub fn r_type_possibletypes<C>(
-  _root: &BTreeMap<String, query::Value>,
+  root: &BTreeMap<String, query::Value>,
   _args: GqlArgs,
   _ctx: &mut C,
-  _schema: &GqlSchema<C>,
+  schema: &GqlSchema<C>,
 ) -> ResResult {
+  if let Some(GqlValue::String(name)) = root.get("name") {
+    if let Some(possible_types) = schema.possible_types(name) {
+      return Ok(ResolutionReturn::TypeList((
+        "__Type".to_owned(),
+        possible_types
+          .iter()
+          .map(|member| {
+            let mut bmap = BTreeMap::new();
+            bmap.insert("name".to_owned(), GqlValue::String(member.clone()));
+            bmap.insert("kind".to_owned(), GqlValue::Enum("OBJECT".to_owned()));
+            bmap
+          })
+          .collect(),
+      )));
+    }
+  }
   Ok(ResolutionReturn::Scalar(GqlValue::Null))
 }
 
+/// `isDeprecated` and `deprecationReason` for a field or enum value, from its `@deprecated` directive
+fn deprecation(directives: &[schema::Directive]) -> (GqlValue, GqlValue) {
+  match directives.iter().find(|d| d.name == "deprecated") {
+    Some(directive) => {
+      let reason = directive
+        .arguments
+        .iter()
+        .find(|(name, _)| name == "reason")
+        .map(|(_, reason)| reason.clone())
+        .unwrap_or_else(|| GqlValue::String("No longer supported".to_owned()));
+      (GqlValue::Boolean(true), reason)
+    }
+    None => (GqlValue::Boolean(false), GqlValue::Null),
+  }
+}
+
 pub fn r_type_enumvals<C>(
   root: &BTreeMap<String, query::Value>,
   _args: GqlArgs,
@@ -106,8 +160,9 @@ pub fn r_type_enumvals<C>(
           } else {
             bmap.insert("description".to_owned(), GqlValue::Null);
           }
-          bmap.insert("isDeprecated".to_owned(), GqlValue::Boolean(false));
-          bmap.insert("deprecationReason".to_owned(), GqlValue::Null);
+          let (is_deprecated, reason) = deprecation(&value.directives);
+          bmap.insert("isDeprecated".to_owned(), is_deprecated);
+          bmap.insert("deprecationReason".to_owned(), reason);
           res.push(bmap);
         }
 
@@ -125,12 +180,29 @@ pub fn r_type_interfaces<C>(
   root: &BTreeMap<String, query::Value>,
   _args: GqlArgs,
   _ctx: &mut C,
-  _schema: &GqlSchema<C>,
+  schema: &GqlSchema<C>,
 ) -> ResResult {
   // TODO: do we always need a name?
   match (root.get("kind"), root.get("name")) {
     (Some(GqlValue::Enum(ref k)), Some(GqlValue::String(name))) if k == "OBJECT" => {
-      Ok(ResolutionReturn::TypeList(("__Type".to_owned(), vec![])))
+      let object_def = schema
+        .external_types
+        .objects
+        .get(name)
+        .ok_or(ResolutionErr::new_missing_type(name))?;
+      Ok(ResolutionReturn::TypeList((
+        "__Type".to_owned(),
+        object_def
+          .implements_interfaces
+          .iter()
+          .map(|interface| {
+            let mut bmap = BTreeMap::new();
+            bmap.insert("name".to_owned(), GqlValue::String(interface.clone()));
+            bmap.insert("kind".to_owned(), GqlValue::Enum("INTERFACE".to_owned()));
+            bmap
+          })
+          .collect(),
+      )))
     }
     (Some(GqlValue::Enum(_)), Some(_)) => Ok(ResolutionReturn::Scalar(GqlValue::Null)),
     (_, _) => Err(ResolutionErr::new_invalid_field("__Type", "name | kind")),
@@ -180,10 +252,8 @@ pub fn r_type_inputfields<C>(
 
 fn convert_field_type<C>(schema: &GqlSchema<C>, field_type: query::Type) -> GqlObj {
   let mut result = BTreeMap::new();
-  result.insert(
-    "name".to_owned(),
-    GqlValue::String("generic_type".to_owned()),
-  );
+  // only named types have a name, LIST and NON_NULL wrappers don't
+  result.insert("name".to_owned(), GqlValue::Null);
   match field_type {
     query::Type::ListType(inner_type) => {
       let of_kind = convert_field_type(schema, *inner_type);
@@ -197,13 +267,17 @@ fn convert_field_type<C>(schema: &GqlSchema<C>, field_type: query::Type) -> GqlO
     }
     query::Type::NamedType(type_name) => {
       result.insert("name".to_owned(), GqlValue::String(type_name.clone()));
-      if BUILTIN_SCALARS.contains(&type_name.as_str()) {
+      if schema.is_scalar(&type_name) {
         result.insert("kind".to_owned(), GqlValue::Enum("SCALAR".to_owned()));
       }
       if schema.external_types.objects.contains_key(&type_name) {
         result.insert("kind".to_owned(), GqlValue::Enum("OBJECT".to_owned()));
       } else if schema.external_types.enums.contains_key(&type_name) {
         result.insert("kind".to_owned(), GqlValue::Enum("ENUM".to_owned()));
+      } else if schema.external_types.unions.contains_key(&type_name) {
+        result.insert("kind".to_owned(), GqlValue::Enum("UNION".to_owned()));
+      } else if schema.external_types.interfaces.contains_key(&type_name) {
+        result.insert("kind".to_owned(), GqlValue::Enum("INTERFACE".to_owned()));
       }
     }
   }
@@ -222,6 +296,30 @@ fn value_to_string(val: &GqlValue) -> String {
   }
 }
 
+/// An argument definition as an `__InputValue`
+fn input_value<C>(arg: &schema::InputValue, schema: &GqlSchema<C>) -> GqlObj {
+  let mut tmap = BTreeMap::new();
+  tmap.insert("name".to_owned(), GqlValue::String(arg.name.to_owned()));
+  if let Some(desc) = &arg.description {
+    tmap.insert("description".to_owned(), GqlValue::String(desc.clone()));
+  } else {
+    tmap.insert("description".to_owned(), GqlValue::Null);
+  }
+  if let Some(default_val) = &arg.default_value {
+    tmap.insert(
+      "defaultValue".to_owned(),
+      GqlValue::String(value_to_string(default_val)),
+    );
+  } else {
+    tmap.insert("defaultValue".to_owned(), GqlValue::Null);
+  }
+  tmap.insert(
+    "type".to_owned(),
+    full_input_type_resolver(&arg.value_type, schema),
+  );
+  tmap
+}
+
 pub fn r_type_fields<C>(
   root: &BTreeMap<String, query::Value>,
   _args: GqlArgs,
@@ -229,12 +327,25 @@ pub fn r_type_fields<C>(
   schema: &GqlSchema<C>,
 ) -> ResResult {
   match (root.get("kind"), root.get("name")) {
-    (Some(GqlValue::Enum(ref k)), Some(GqlValue::String(name))) if k == "OBJECT" => {
-      if let Some(def) = schema.external_types.objects.get(name.as_str()) {
+    (Some(GqlValue::Enum(ref k)), Some(GqlValue::String(name)))
+      if k == "OBJECT" || k == "INTERFACE" =>
+    {
+      let fields = match k.as_str() {
+        "OBJECT" => schema
+          .external_types
+          .objects
+          .get(name)
+          .map(|def| &def.fields),
+        _ => schema
+          .external_types
+          .interfaces
+          .get(name)
+          .map(|def| &def.fields),
+      };
+      if let Some(fields) = fields {
         return Ok(ResolutionReturn::TypeList((
           "__Field".to_owned(),
-          def
-            .fields
+          fields
             .iter()
             .map(|field| {
               let tmap = convert_field_type(schema, field.field_type.clone());
@@ -247,8 +358,9 @@ pub fn r_type_fields<C>(
                 bmap.insert("description".to_owned(), GqlValue::Null);
               }
               bmap.insert("type".to_owned(), GqlValue::Object(tmap));
-              bmap.insert("isDeprecated".to_owned(), GqlValue::Boolean(false));
-              bmap.insert("deprecationReason".to_owned(), GqlValue::Null);
+              let (is_deprecated, reason) = deprecation(&field.directives);
+              bmap.insert("isDeprecated".to_owned(), is_deprecated);
+              bmap.insert("deprecationReason".to_owned(), reason);
               bmap
             })
             .collect(),
@@ -269,14 +381,21 @@ pub fn r_field_args<C>(
   if let (Some(GqlValue::String(field_name)), Some(GqlValue::String(type_name))) =
     (root.get("name"), root.get("parentTypename"))
   {
-    let obj_def = schema
+    let fields = schema
       .external_types
       .objects
       .get(type_name)
+      .map(|def| &def.fields)
+      .or_else(|| {
+        schema
+          .external_types
+          .interfaces
+          .get(type_name)
+          .map(|def| &def.fields)
+      })
       .ok_or(ResolutionErr::new_missing_type(type_name))?;
 
-    let field = obj_def
-      .fields
+    let field = fields
       .iter()
       .find(|f| f.name == *field_name)
       .ok_or(ResolutionErr::new_invalid_field(type_name, field_name))?;
@@ -286,28 +405,7 @@ pub fn r_field_args<C>(
       field
         .arguments
         .iter()
-        .map(|arg| {
-          let mut tmap = BTreeMap::new();
-          tmap.insert("name".to_owned(), GqlValue::String(arg.name.to_owned()));
-          if let Some(desc) = &arg.description {
-            tmap.insert("description".to_owned(), GqlValue::String(desc.clone()));
-          } else {
-            tmap.insert("description".to_owned(), GqlValue::Null);
-          }
-          if let Some(default_val) = &arg.default_value {
-            tmap.insert(
-              "defaultValue".to_owned(),
-              GqlValue::String(value_to_string(default_val)),
-            );
-          } else {
-            tmap.insert("defaultValue".to_owned(), GqlValue::Null);
-          }
-          tmap.insert(
-            "type".to_owned(),
-            full_input_type_resolver(&arg.value_type, schema),
-          );
-          tmap
-        })
+        .map(|arg| input_value(arg, schema))
         .collect(),
     )));
   }
@@ -358,11 +456,28 @@ pub fn r_schema_directives<C>(
   _root: &BTreeMap<String, query::Value>,
   _args: GqlArgs,
   _ctx: &mut C,
-  _schema: &GqlSchema<C>,
+  schema: &GqlSchema<C>,
 ) -> ResResult {
+  // the built in directives come first, then the ones declared in the schema
+  let directives = schema
+    .internal_types
+    .directives
+    .values()
+    .chain(schema.external_types.directives.values())
+    .map(|def| {
+      let mut bmap = BTreeMap::new();
+      bmap.insert("name".to_owned(), GqlValue::String(def.name.clone()));
+      if let Some(desc) = &def.description {
+        bmap.insert("description".to_owned(), GqlValue::String(desc.clone()));
+      } else {
+        bmap.insert("description".to_owned(), GqlValue::Null);
+      }
+      bmap
+    })
+    .collect();
   Ok(ResolutionReturn::TypeList((
     "__Directive".to_owned(),
-    vec![],
+    directives,
   )))
 }
 
@@ -382,6 +497,55 @@ pub fn r_schema_types<C>(
     bmap.insert("kind".to_owned(), GqlValue::Enum("OBJECT".to_owned()));
     res_items.push(bmap);
   });
+  schema
+    .external_types
+    .interfaces
+    .keys()
+    .for_each(|type_name| {
+      let mut bmap = BTreeMap::new();
+      bmap.insert(
+        "name".to_owned(),
+        query::Value::String(type_name.to_owned()),
+      );
+      bmap.insert("kind".to_owned(), GqlValue::Enum("INTERFACE".to_owned()));
+      res_items.push(bmap);
+    });
+  schema.external_types.unions.keys().for_each(|type_name| {
+    let mut bmap = BTreeMap::new();
+    bmap.insert(
+      "name".to_owned(),
+      query::Value::String(type_name.to_owned()),
+    );
+    bmap.insert("kind".to_owned(), GqlValue::Enum("UNION".to_owned()));
+    res_items.push(bmap);
+  });
+  schema.external_types.enums.keys().for_each(|type_name| {
+    let mut bmap = BTreeMap::new();
+    bmap.insert(
+      "name".to_owned(),
+      query::Value::String(type_name.to_owned()),
+    );
+    bmap.insert("kind".to_owned(), GqlValue::Enum("ENUM".to_owned()));
+    res_items.push(bmap);
+  });
+  BUILTIN_SCALARS.iter().for_each(|type_name| {
+    let mut bmap = BTreeMap::new();
+    bmap.insert(
+      "name".to_owned(),
+      query::Value::String((*type_name).to_owned()),
+    );
+    bmap.insert("kind".to_owned(), GqlValue::Enum("SCALAR".to_owned()));
+    res_items.push(bmap);
+  });
+  schema.custom_scalars.keys().for_each(|type_name| {
+    let mut bmap = BTreeMap::new();
+    bmap.insert(
+      "name".to_owned(),
+      query::Value::String(type_name.to_owned()),
+    );
+    bmap.insert("kind".to_owned(), GqlValue::Enum("SCALAR".to_owned()));
+    res_items.push(bmap);
+  });
   schema
     .external_types
     .input_types
@@ -425,16 +589,12 @@ pub fn r_directive_args<C>(
         .external_types
         .directives
         .get(name)
+        .or_else(|| schema.internal_types.directives.get(name))
         .ok_or(ResolutionErr::new_invalid_field("__Directive", name))?;
       let l = def
         .arguments
-        .clone()
-        .into_iter()
-        .map(|arg| {
-          let mut bmap = BTreeMap::new();
-          bmap.insert("name".to_owned(), GqlValue::String(arg.name));
-          bmap
-        })
+        .iter()
+        .map(|arg| input_value(arg, schema))
         .collect();
       Ok(ResolutionReturn::TypeList(("__InputValue".to_owned(), l)))
     }
@@ -442,14 +602,57 @@ pub fn r_directive_args<C>(
   }
 }
 
+pub fn r_directive_locations<C>(
+  root: &BTreeMap<String, query::Value>,
+  _args: GqlArgs,
+  _ctx: &mut C,
+  schema: &GqlSchema<C>,
+) -> ResResult {
+  match root.get("name") {
+    Some(GqlValue::String(name)) => {
+      let def = schema
+        .external_types
+        .directives
+        .get(name)
+        .or_else(|| schema.internal_types.directives.get(name))
+        .ok_or(ResolutionErr::new_invalid_field("__Directive", name))?;
+      let l = def
+        .locations
+        .iter()
+        .map(|loc| GqlValue::Enum(loc.as_str().to_owned()))
+        .collect();
+      Ok(ResolutionReturn::ScalarList(l))
+    }
+    _ => Err(ResolutionErr::new_invalid_field("__Directive", "name")),
+  }
+}
+
 pub fn r_inputvalue_default<C>(
   root: &BTreeMap<String, query::Value>,
   _args: GqlArgs,
   _ctx: &mut C,
-  _schema: &GqlSchema<C>,
+  schema: &GqlSchema<C>,
 ) -> ResResult {
+  if let (Some(GqlValue::String(my_name)), Some(GqlValue::String(parent_name))) =
+    (root.get("name"), root.get("parentTypename"))
+  {
+    if let Some(input_def) = schema.external_types.input_types.get(parent_name) {
+      let field = input_def
+        .fields
+        .iter()
+        .find(|x| x.name == *my_name)
+        .ok_or(ResolutionErr::new_invalid_field(parent_name, my_name))?;
+      return Ok(ResolutionReturn::Scalar(
+        field
+          .default_value
+          .as_ref()
+          .map(|v| GqlValue::String(value_to_string(v)))
+          .unwrap_or(GqlValue::Null),
+      ));
+    }
+  }
   match root.get("name") {
-    Some(GqlValue::String(name)) => Ok(ResolutionReturn::Scalar(GqlValue::Null)),
+    Some(GqlValue::String(_)) => Ok(ResolutionReturn::Scalar(GqlValue::Null)),
     _ => Err(ResolutionErr::new_invalid_field("__InputValue", "name")),
   }
 }
@@ -480,11 +683,11 @@ pub fn r_inputvalue_type<C>(
 
 fn full_input_type_resolver<C>(value_type: &query::Type, schema: &GqlSchema<C>) -> GqlValue {
   let mut bmap = BTreeMap::new();
-  bmap.insert("name".to_owned(), GqlValue::String(format!("")));
+  bmap.insert("name".to_owned(), GqlValue::Null);
   match &value_type {
     query::Type::NamedType(name) => {
       bmap.insert("name".to_owned(), GqlValue::String(name.clone()));
-      if BUILTIN_SCALARS.contains(&name.as_str()) {
+      if schema.is_scalar(name) {
         bmap.insert("kind".to_owned(), GqlValue::Enum("SCALAR".to_owned()));
       } else {
         // TODO: this should be reliable
@@ -1,6 +1,9 @@
+use futures::future::{self, Future, Loop};
+use futures::{stream, Stream};
 use graphql_parser::{parse_query, query, query::Value as GqlValue, schema};
 use serde_json::{Map as JsonMap, Value as JsonValue};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, RwLock};
 
 mod execution;
 use execution::GqlRunningQuery;
@@ -9,6 +12,8 @@ mod introspect;
 mod resolver_creation;
 mod base_types;
 pub use base_types::*;
+#[cfg(test)]
+mod variable_tests;
 
 #[derive(Clone, Debug, Default)]
 pub struct SchemaTypes {
@@ -16,6 +21,9 @@ pub struct SchemaTypes {
   pub enums: BTreeMap<String, schema::EnumType>,
   pub directives: BTreeMap<String, schema::DirectiveDefinition>,
   pub input_types: BTreeMap<String, schema::InputObjectType>,
+  pub scalars: BTreeMap<String, schema::ScalarType>,
+  pub unions: BTreeMap<String, schema::UnionType>,
+  pub interfaces: BTreeMap<String, schema::InterfaceType>,
 }
 
 impl SchemaTypes {
@@ -24,6 +32,9 @@ impl SchemaTypes {
     let mut enums = BTreeMap::new();
     let mut directives = BTreeMap::new();
     let mut input_types = BTreeMap::new();
+    let mut scalars = BTreeMap::new();
+    let mut unions = BTreeMap::new();
+    let mut interfaces = BTreeMap::new();
     for def in doc.definitions {
       match def {
         schema::Definition::TypeDefinition(t_def) => match t_def {
@@ -36,6 +47,15 @@ impl SchemaTypes {
           schema::TypeDefinition::InputObject(input) => {
             input_types.insert(input.name.clone(), input);
           }
+          schema::TypeDefinition::Scalar(scalar) => {
+            scalars.insert(scalar.name.clone(), scalar);
+          }
+          schema::TypeDefinition::Union(union) => {
+            unions.insert(union.name.clone(), union);
+          }
+          schema::TypeDefinition::Interface(interface) => {
+            interfaces.insert(interface.name.clone(), interface);
+          }
           _ => {}
         },
         schema::Definition::DirectiveDefinition(d) => {
@@ -50,6 +70,9 @@ impl SchemaTypes {
       enums,
       directives,
       input_types,
+      scalars,
+      unions,
+      interfaces,
     }
   }
 
@@ -64,15 +87,96 @@ impl SchemaTypes {
   }
 }
 
+/// What to do when a queried field has no registered resolver
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MissingResolverPolicy {
+  /// Fail the whole query (the default)
+  Error,
+  /// Resolve the field to null and carry on
+  Null,
+}
+
+impl Default for MissingResolverPolicy {
+  fn default() -> Self {
+    MissingResolverPolicy::Error
+  }
+}
+
+/// Fields that operators have turned off, written as `Type.field`.
+/// Clones share the same list, so it can be changed while the server runs.
+#[derive(Clone, Debug, Default)]
+pub struct FieldDenylist(Arc<RwLock<BTreeSet<(String, String)>>>);
+
+impl FieldDenylist {
+  pub fn is_denied(&self, on_type: &str, field: &str) -> bool {
+    self
+      .0
+      .read()
+      .map(|denied| denied.contains(&(on_type.to_owned(), field.to_owned())))
+      .unwrap_or(false)
+  }
+
+  /// The denied fields, as `Type.field`
+  pub fn fields(&self) -> Vec<String> {
+    self
+      .0
+      .read()
+      .map(|denied| {
+        denied
+          .iter()
+          .map(|(on_type, field)| format!("{}.{}", on_type, field))
+          .collect()
+      })
+      .unwrap_or_default()
+  }
+
+  /// Replaces the denied fields. Gives back the first entry that isn't
+  /// written as `Type.field`, leaving the list unchanged.
+  pub fn set(&self, fields: &[String]) -> Result<(), String> {
+    let parsed = fields
+      .iter()
+      .map(|entry| {
+        let mut parts = entry.trim().splitn(2, '.');
+        match (parts.next(), parts.next()) {
+          (Some(on_type), Some(field)) if !on_type.is_empty() && !field.is_empty() => {
+            Ok((on_type.to_owned(), field.to_owned()))
+          }
+          _ => Err(entry.to_owned()),
+        }
+      })
+      .collect::<Result<BTreeSet<(String, String)>, String>>()?;
+    if let Ok(mut denied) = self.0.write() {
+      *denied = parsed;
+    }
+    Ok(())
+  }
+}
+
 #[derive(Default, Clone, Debug)]
 pub struct GqlSchema<C> {
   internal_types: SchemaTypes,
   external_types: SchemaTypes,
   resolvers: BTreeMap<String, BTreeMap<String, Resolver<C>>>,
+  async_resolvers: BTreeMap<String, BTreeMap<String, AsyncResolver<C>>>,
+  missing_resolver_policy: MissingResolverPolicy,
+  max_expanded_fields: Option<usize>,
+  denylist: FieldDenylist,
+  max_complexity: Option<usize>,
+  list_complexity_factor: usize,
+  custom_scalars: BTreeMap<String, CustomScalar>,
+  parallel_root_fields: bool,
+  role_lookup: Option<RoleLookup<C>>,
 }
 
 impl<C> GqlSchema<C> {
   pub fn new(doc: schema::Document) -> SchemaResult<Self> {
+    Self::new_with_policy(doc, MissingResolverPolicy::default())
+  }
+
+  pub fn new_with_policy(
+    doc: schema::Document,
+    missing_resolver_policy: MissingResolverPolicy,
+  ) -> SchemaResult<Self> {
     let external_types = SchemaTypes::new(doc);
     let internal_types = SchemaTypes::new(
       graphql_parser::parse_schema(include_str!("./introspection_defs.graphql")).unwrap(),
@@ -85,7 +189,17 @@ impl<C> GqlSchema<C> {
       internal_types,
       external_types,
       resolvers: BTreeMap::new(),
+      async_resolvers: BTreeMap::new(),
+      missing_resolver_policy,
+      max_expanded_fields: None,
+      denylist: FieldDenylist::default(),
+      max_complexity: None,
+      list_complexity_factor: 1,
+      custom_scalars: BTreeMap::new(),
+      parallel_root_fields: false,
+      role_lookup: None,
     };
+    schema.check_auth_directives()?;
 
     let type_rez: BTreeMap<String, Resolver<C>> = type_resolvers!("__Type", {
       description: introspect::r_type_desc,
@@ -105,8 +219,10 @@ impl<C> GqlSchema<C> {
       directives: introspect::r_schema_directives,
     });
 
-    let directive_rez: BTreeMap<String, Resolver<C>> =
-      type_resolvers!("__Directive", { args: introspect::r_directive_args });
+    let directive_rez: BTreeMap<String, Resolver<C>> = type_resolvers!("__Directive", {
+      args: introspect::r_directive_args,
+      locations: introspect::r_directive_locations,
+    });
 
     let inputv_res: BTreeMap<String, Resolver<C>> = type_resolvers!("__InputValue", {
       defaultValue: introspect::r_inputvalue_default,
@@ -131,25 +247,209 @@ impl<C> GqlSchema<C> {
     schema
       .resolvers
       .insert("__Directive".to_owned(), directive_rez);
+    debug_assert!(
+      schema.check_resolvers().is_ok(),
+      "Introspection resolvers have drifted from introspection_defs.graphql: {:?}",
+      schema.check_resolvers()
+    );
     Ok(schema)
   }
 
-  pub fn add_resolvers(&mut self, resolvers: Vec<Resolver<C>>) -> SchemaResult<()> {
-    for resolver in resolvers {
-      if !self.external_types.objects.contains_key(&resolver.on_type) {
-        return Err(GqlSchemaErr::InvalidResolver);
-      } else {
-        let obj = self.external_types.objects.get(&resolver.on_type).unwrap();
-        let mut found: bool = false;
-        for f in &obj.fields {
-          if f.name == resolver.field {
-            found = true;
-          }
+  /// Checks that every registered resolver is for a field that exists on
+  /// either the internal (introspection) types or the external types.
+  pub fn check_resolvers(&self) -> SchemaResult<()> {
+    for (on_type, fields) in &self.resolvers {
+      let obj = self
+        .external_types
+        .objects
+        .get(on_type)
+        .or_else(|| self.internal_types.objects.get(on_type))
+        .ok_or(GqlSchemaErr::MissingType(on_type.to_owned()))?;
+      for field in fields.keys() {
+        let is_meta = on_type == "Query" && (field == "__schema" || field == "__type");
+        if !is_meta && !obj.fields.iter().any(|f| &f.name == field) {
+          return Err(GqlSchemaErr::UnknownResolverField((
+            on_type.to_owned(),
+            field.to_owned(),
+          )));
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Limits how many fields a single request may expand to once fragments are
+  /// spread, so wide queries are rejected before any resolvers run.
+  pub fn set_max_expanded_fields(&mut self, max_fields: Option<usize>) {
+    self.max_expanded_fields = max_fields;
+  }
+
+  /// Has `resolve_async` start every root field of a query at once, each with its own
+  /// copy of the context, instead of waiting for one to finish before starting the next.
+  pub fn set_parallel_root_fields(&mut self, parallel: bool) {
+    self.parallel_root_fields = parallel;
+  }
+
+  /// Rejects queries whose estimated cost is over `max_cost` before anything is resolved.
+  /// Every field costs 1, and what is selected inside a list costs `list_factor` times more.
+  pub fn with_max_complexity(mut self, max_cost: Option<usize>, list_factor: usize) -> Self {
+    self.max_complexity = max_cost;
+    self.list_complexity_factor = list_factor;
+    self
+  }
+
+  /// Turns off the fields in `denylist`, along with any added to it later.
+  /// Only fields that need resolving are checked, not ones filled in by their parent.
+  pub fn set_denylist(&mut self, denylist: FieldDenylist) {
+    self.denylist = denylist;
+  }
+
+  /// Registers how the current user's role is found for fields guarded with
+  /// `@auth(requires: Role)`. Until one is registered, nobody can resolve those fields.
+  pub fn set_role_lookup(&mut self, lookup: RoleLookup<C>) {
+    self.role_lookup = Some(lookup);
+  }
+
+  /// The enum `@auth` takes as its `requires` argument.
+  /// Its values are the roles, from least to most privileged.
+  fn roles_enum(&self) -> Option<&schema::EnumType> {
+    let directive = self.external_types.directives.get("auth")?;
+    let requires = directive.arguments.iter().find(|a| a.name == "requires")?;
+    let mut value_type = &requires.value_type;
+    while let query::Type::NonNullType(inner) = value_type {
+      value_type = &**inner;
+    }
+    match value_type {
+      query::Type::NamedType(name) => self.external_types.enums.get(name),
+      _ => None,
+    }
+  }
+
+  /// The role a field's `@auth` directive requires, if it has one
+  fn required_role(&self, on_type: &str, field_name: &str) -> Option<&str> {
+    let field = self
+      .external_types
+      .objects
+      .get(on_type)?
+      .fields
+      .iter()
+      .find(|f| f.name == field_name)?;
+    let directive = field.directives.iter().find(|d| d.name == "auth")?;
+    match directive
+      .arguments
+      .iter()
+      .find(|(name, _)| name == "requires")
+    {
+      Some((_, GqlValue::Enum(role))) => Some(role),
+      _ => None,
+    }
+  }
+
+  /// Makes sure every `@auth` directive requires one of the values of the roles enum
+  fn check_auth_directives(&self) -> SchemaResult<()> {
+    for obj in self.external_types.objects.values() {
+      for field in &obj.fields {
+        if !field.directives.iter().any(|d| d.name == "auth") {
+          continue;
         }
-        if !found {
-          return Err(GqlSchemaErr::InvalidResolver);
+        let roles = self.roles_enum().ok_or_else(|| {
+          GqlSchemaErr::InvalidDirective(
+            "@auth has to be declared with a `requires` argument taking an enum of roles"
+              .to_owned(),
+          )
+        })?;
+        let required = self.required_role(&obj.name, &field.name);
+        if !roles
+          .values
+          .iter()
+          .any(|v| Some(v.name.as_str()) == required)
+        {
+          return Err(GqlSchemaErr::InvalidDirective(format!(
+            "@auth on {}.{} has to require one of the {} values",
+            obj.name, field.name, roles.name
+          )));
         }
       }
+    }
+    Ok(())
+  }
+
+  /// Checks the current user has at least the role a field's `@auth` directive requires.
+  /// Roles are compared by where they are in the roles enum, ignoring case.
+  fn authorize(
+    &self,
+    on_type: &str,
+    field: &SimpleField,
+    parent: &GqlRoot,
+    context: &mut C,
+  ) -> Result<(), ResolutionErr> {
+    let (required, roles) = match (self.required_role(on_type, &field.name), self.roles_enum()) {
+      (Some(required), Some(roles)) => (required, roles),
+      _ => return Ok(()),
+    };
+    let rank = |role: &str| {
+      roles
+        .values
+        .iter()
+        .position(|v| v.name.eq_ignore_ascii_case(role))
+    };
+    let role = match self.role_lookup {
+      Some(lookup) => lookup(on_type, parent, &field.arguments, context)?,
+      None => None,
+    };
+    match (role.and_then(|r| rank(&r)), rank(required)) {
+      (Some(have), Some(needed)) if have >= needed => Ok(()),
+      _ => Err(ResolutionErr::Unauthorized(format!(
+        "{}.{} requires the {} role",
+        on_type, field.name, required
+      ))),
+    }
+  }
+
+  /// Registers how a scalar declared with `scalar Name` in the schema is parsed from
+  /// arguments and serialized in results. Arguments have to be sent as strings.
+  pub fn add_scalar(
+    &mut self,
+    name: &str,
+    parse: ScalarParser,
+    serialize: ScalarSerializer,
+  ) -> SchemaResult<()> {
+    if !self.external_types.scalars.contains_key(name) {
+      return Err(GqlSchemaErr::MissingType(name.to_owned()));
+    }
+    self
+      .custom_scalars
+      .insert(name.to_owned(), CustomScalar { parse, serialize });
+    Ok(())
+  }
+
+  /// Whether `value_type` is, or is a list of, a scalar registered with `add_scalar`.
+  /// Those arguments were checked by the scalar's parser, and no longer look like the string sent.
+  fn is_custom_scalar(&self, mut value_type: &query::Type) -> bool {
+    loop {
+      match value_type {
+        query::Type::NamedType(name) => return self.custom_scalars.contains_key(name),
+        query::Type::ListType(inner) | query::Type::NonNullType(inner) => value_type = &**inner,
+      }
+    }
+  }
+
+  /// Whether `type_name` is a built-in scalar or one registered with `add_scalar`
+  fn is_scalar(&self, type_name: &str) -> bool {
+    introspect::BUILTIN_SCALARS.contains(&type_name) || self.custom_scalars.contains_key(type_name)
+  }
+
+  /// Makes sure a resolver is for a field declared in the schema
+  fn check_resolver_field(&self, on_type: &str, field: &str) -> SchemaResult<()> {
+    match self.external_types.objects.get(on_type) {
+      Some(obj) if obj.fields.iter().any(|f| f.name == field) => Ok(()),
+      _ => Err(GqlSchemaErr::InvalidResolver),
+    }
+  }
+
+  pub fn add_resolvers(&mut self, resolvers: Vec<Resolver<C>>) -> SchemaResult<()> {
+    for resolver in resolvers {
+      self.check_resolver_field(&resolver.on_type, &resolver.field)?;
       if let Some(inner) = self.resolvers.get_mut(&resolver.on_type) {
         inner.insert(resolver.field.clone(), resolver);
       } else {
@@ -162,6 +462,24 @@ impl<C> GqlSchema<C> {
     Ok(())
   }
 
+  /// Registers resolvers that give back futures. A field with an async resolver
+  /// uses it instead of any plain resolver registered for it.
+  pub fn add_async_resolvers(&mut self, resolvers: Vec<AsyncResolver<C>>) -> SchemaResult<()> {
+    for resolver in resolvers {
+      self.check_resolver_field(&resolver.on_type, &resolver.field)?;
+      self
+        .async_resolvers
+        .entry(resolver.on_type.clone())
+        .or_insert_with(BTreeMap::new)
+        .insert(resolver.field.clone(), resolver);
+    }
+    Ok(())
+  }
+
+  fn get_async_resolver(&self, on_type: &str, on_field: &str) -> Option<&AsyncResolver<C>> {
+    self.async_resolvers.get(on_type)?.get(on_field)
+  }
+
   fn get_resolvers(&self, on_type: &str, on_field: &str) -> Result<&Resolver<C>, ResolutionErr> {
     Ok(
       self
@@ -184,11 +502,15 @@ impl<C> GqlSchema<C> {
     self.external_types.get_object(on_type)
   }
 
+  /// Checks the arguments of a directive once its variables are substituted.
+  /// A nullable variable that is null or was not given is let through as null,
+  /// even where the directive needs a value.
   fn validate_directive(
     &self,
-    name: &str,
-    args: &Vec<(String, GqlValue)>,
+    given: &query::Directive,
+    exec: &GqlRunningQuery,
   ) -> Result<(), GqlQueryErr> {
+    let name = given.name.as_str();
     let directive: &schema::DirectiveDefinition;
     if let Some(d) = self.internal_types.directives.get(name) {
       directive = d
@@ -203,13 +525,60 @@ impl<C> GqlSchema<C> {
         )))?;
     }
     for arg_def in &directive.arguments {
-      let arg_val = args
+      let value = given
+        .arguments
         .iter()
         .find(|(name, _)| name == &arg_def.name)
-        .unwrap_or(&("".to_owned(), GqlValue::Null))
-        .1
-        .clone();
-      execution::naive_check_var_type(&arg_def.value_type, &arg_val);
+        .map(|(_, value)| value);
+      let arg_val = value.map_or(GqlValue::Null, |v| exec.substitute_variables(v));
+      let arg_type = match (&arg_def.value_type, value) {
+        (query::Type::NonNullType(inner), Some(GqlValue::Variable(_))) => &**inner,
+        (value_type, _) => value_type,
+      };
+      if !execution::naive_check_var_type(arg_type, &arg_val) {
+        return Err(GqlQueryErr::Directive(QueryValidationError::new(
+          format!("@{} has an invalid `{}` argument", name, arg_def.name),
+          name.to_owned(),
+        )));
+      }
+    }
+    Ok(())
+  }
+
+  /// Checks the arguments of a field against its definition before its resolver runs.
+  /// Every non-null argument without a default has to be given, and every argument
+  /// has to roughly fit its type.
+  fn validate_arguments(&self, on_type: &str, field: &SimpleField) -> Result<(), ResolutionErr> {
+    let field_def = match self
+      .get_any_object_type(on_type)
+      .ok()
+      .and_then(|t| t.fields.iter().find(|f| f.name == field.name))
+    {
+      Some(def) => def,
+      None => return Ok(()),
+    };
+    for arg_def in &field_def.arguments {
+      match (field.arguments.get(&arg_def.name), &arg_def.value_type) {
+        (None, query::Type::NonNullType(_)) if arg_def.default_value.is_none() => {
+          return Err(ResolutionErr::new_missing_argument(
+            on_type,
+            &field.name,
+            &arg_def.name,
+          ));
+        }
+        (Some(value), value_type)
+          if !self.is_custom_scalar(value_type)
+            && !execution::naive_check_var_type(value_type, value) =>
+        {
+          return Err(ResolutionErr::QueryValidation(GqlQueryErr::Type(
+            QueryValidationError::new(
+              format!("{} should be a {}", arg_def.name, value_type),
+              arg_def.name.clone(),
+            ),
+          )));
+        }
+        _ => {}
+      }
     }
     Ok(())
   }
@@ -232,71 +601,345 @@ impl<C> GqlSchema<C> {
         on_type.to_owned(),
       )));
     }
-    let resolver = self.get_resolvers(on_type, &field.name)?;
+    if self.denylist.is_denied(on_type, &field.name) {
+      return Err(ResolutionErr::Disabled(format!(
+        "{}.{} has been disabled",
+        on_type, field.name
+      )));
+    }
+    let resolver = match self.get_resolvers(on_type, &field.name) {
+      Ok(r) => r,
+      Err(_) if self.missing_resolver_policy == MissingResolverPolicy::Null => {
+        return Ok(ResolutionReturn::Scalar(GqlValue::Null));
+      }
+      Err(e) => return Err(e),
+    };
     (resolver.resolve)(data, field.arguments.clone(), context, &self)
   }
 
+  /// Makes sure a value returned for an enum typed field is one of the enum's members
+  fn check_enum_value(
+    &self,
+    on_type: &str,
+    field_name: &str,
+    value: &GqlValue,
+  ) -> Result<(), ResolutionErr> {
+    let type_name = match self.field_type_name(on_type, field_name) {
+      Some(name) => name,
+      None => return Ok(()),
+    };
+    let enum_def = match self.get_enum(type_name) {
+      Some(e) => e,
+      None => return Ok(()),
+    };
+    fn check(enum_def: &schema::EnumType, value: &GqlValue) -> Result<(), ResolutionErr> {
+      match value {
+        GqlValue::Enum(member) | GqlValue::String(member) => {
+          if enum_def.values.iter().any(|v| &v.name == member) {
+            Ok(())
+          } else {
+            Err(ResolutionErr::QueryResult(format!(
+              "{} is not a member of enum {}",
+              member, enum_def.name
+            )))
+          }
+        }
+        GqlValue::List(items) => items.iter().map(|i| check(enum_def, i)).collect(),
+        _ => Ok(()),
+      }
+    }
+    check(enum_def, value)
+  }
+
+  fn get_field_type(&self, on_type: &str, field_name: &str) -> Option<&query::Type> {
+    self
+      .get_any_object_type(on_type)
+      .ok()?
+      .fields
+      .iter()
+      .find(|f| f.name == field_name)
+      .map(|f| &f.field_type)
+  }
+
+  /// The name of a field's type, without any list or non-null wrappers
+  fn field_type_name(&self, on_type: &str, field_name: &str) -> Option<&str> {
+    let mut field_type = self.get_field_type(on_type, field_name)?;
+    loop {
+      match field_type {
+        query::Type::NamedType(name) => return Some(name),
+        query::Type::ListType(inner) | query::Type::NonNullType(inner) => field_type = &**inner,
+      }
+    }
+  }
+
+  /// The object types a union or interface can be: a union's members,
+  /// or every object implementing an interface. `None` for any other type.
+  fn possible_types(&self, type_name: &str) -> Option<Vec<String>> {
+    if let Some(union_def) = self.external_types.unions.get(type_name) {
+      return Some(union_def.types.clone());
+    }
+    if self.external_types.interfaces.contains_key(type_name) {
+      return Some(
+        self
+          .external_types
+          .objects
+          .values()
+          .filter(|o| o.implements_interfaces.iter().any(|i| i == type_name))
+          .map(|o| o.name.clone())
+          .collect(),
+      );
+    }
+    None
+  }
+
+  /// `possible_types` for every union and interface
+  fn all_possible_types(&self) -> BTreeMap<String, Vec<String>> {
+    self
+      .external_types
+      .unions
+      .keys()
+      .chain(self.external_types.interfaces.keys())
+      .filter_map(|name| Some((name.clone(), self.possible_types(name)?)))
+      .collect()
+  }
+
+  /// The object type a resolved value has. Values of a union or interface
+  /// type name the object type they are with `__typename`.
+  fn member_type(&self, gql_type: &str, obj: &GqlObj) -> GqlExecResult<String> {
+    let possible_types = match self.possible_types(gql_type) {
+      Some(types) => types,
+      None => return Ok(gql_type.to_owned()),
+    };
+    match obj.get("__typename") {
+      Some(GqlValue::String(member)) if possible_types.contains(member) => Ok(member.clone()),
+      _ => Err(GqlQueryErr::Type(QueryValidationError::new(
+        format!(
+          "A {} should have a __typename naming one of its possible types",
+          gql_type
+        ),
+        gql_type.to_owned(),
+      ))),
+    }
+  }
+
+  /// Estimates the cost of resolving `fields` on `on_type`, for `max_complexity`
+  fn complexity(&self, on_type: &str, fields: &[SimpleField]) -> usize {
+    fields
+      .iter()
+      .map(|field| {
+        let mut multiplier: usize = 1;
+        let mut field_type = self.get_field_type(on_type, &field.name);
+        while let Some(t) = field_type {
+          field_type = match t {
+            query::Type::NamedType(_) => None,
+            query::Type::NonNullType(inner) => Some(&**inner),
+            query::Type::ListType(inner) => {
+              multiplier = multiplier.saturating_mul(self.list_complexity_factor);
+              Some(&**inner)
+            }
+          };
+        }
+        let inner_type = self.field_type_name(on_type, &field.name).unwrap_or("");
+        multiplier
+          .saturating_mul(self.complexity(inner_type, &field.fields))
+          .saturating_add(1)
+      })
+      .fold(0, usize::saturating_add)
+  }
+
+  /// Whether a field may be null, so that it can be nulled when resolving it fails
+  /// without failing its siblings.
+  fn field_nullable(&self, on_type: &str, field_name: &str) -> bool {
+    match self.get_field_type(on_type, field_name) {
+      Some(query::Type::NonNullType(_)) | None => false,
+      Some(_) => true,
+    }
+  }
+
+  /// Resolves as much of a query as it can. Stops at a field with an async resolver,
+  /// handing back its future. Once it's done, its value goes in `state.ready`
+  /// and this carries on from that field.
   fn resolve_loop_next(
     &self,
     context: &mut C,
-    query: &PendingQuery,
-    initial_root: Option<GqlRoot>,
-  ) -> Result<BTreeMap<String, GqlValue>, ResolutionErr> {
-    let mut initial_res = ResolutionContext::new(
-      query.on_type.to_owned(),
-      "".to_owned(),
-      query.fields.clone(),
-    );
-    if let Some(root) = initial_root {
-      initial_res.data = root;
-    }
-    let mut stack = vec![initial_res];
+    state: &mut LoopState,
+    errors: &mut Vec<GqlError>,
+  ) -> Result<LoopOutcome, ResolutionErr> {
+    let stack = &mut state.stack;
 
     'outer: while let Some(mut res_ctx) = stack.pop() {
       while let Some(field) = res_ctx.fields.get(res_ctx.field_res_progress) {
         res_ctx.field_res_progress += 1;
-        // TODO validate args
-        //self.validate_arguments(res_ctx.cur_type.as_str(), field.name.as_str(), field.arguments);
+        // selected for another type the union or interface could have been
+        if let Some(member) = &field.type_condition {
+          if *member != res_ctx.cur_type {
+            continue;
+          }
+        }
 
         // we already have data for that field
-        if res_ctx.data.contains_key(&field.name) {
+        if let Some(value) = res_ctx.data.get(field.response_key()) {
+          // but a prefilled object may still have fields left to resolve.
+          // Introspection objects are always complete, so only schema types are checked.
+          let object_type = self
+            .field_type_name(&res_ctx.cur_type, &field.name)
+            .filter(|name| {
+              self.external_types.objects.contains_key(*name) || self.possible_types(name).is_some()
+            });
+          if let (GqlValue::Object(obj), Some(object_type)) = (value, object_type) {
+            let mut ctx = ResolutionContext::new(
+              self.member_type(object_type, obj)?,
+              field.response_key().to_owned(),
+              field.fields.to_owned(),
+            );
+            ctx.set_data(obj.clone());
+            ctx.nullable = self.field_nullable(&res_ctx.cur_type, &field.name);
+            ctx.path = res_ctx.path.clone();
+            ctx
+              .path
+              .push(JsonValue::String(field.response_key().to_owned()));
+            stack.push(res_ctx);
+            stack.push(ctx);
+            continue 'outer;
+          }
+          continue;
+        }
+        // the parent filled in data under the field's name, and it was asked for under an alias
+        if res_ctx.prefilled.contains(&field.name) {
+          let value = res_ctx.data[&field.name].clone();
+          res_ctx.data.insert(field.response_key().to_owned(), value);
           continue;
         }
-        let value =
-          self.get_resolution_value_next(&res_ctx.cur_type, &field, context, &res_ctx.data)?;
+        self
+          .validate_arguments(&res_ctx.cur_type, field)
+          .map_err(|e| res_ctx.locate(e, field))?;
+        let resolved = match state.ready.take() {
+          Some(ready) => ready,
+          None => match self.authorize(&res_ctx.cur_type, field, &res_ctx.data, context) {
+            Err(e) => Err(e),
+            Ok(()) => match self.get_async_resolver(&res_ctx.cur_type, &field.name) {
+              Some(resolver) if !self.denylist.is_denied(&res_ctx.cur_type, &field.name) => {
+                let pending =
+                  (resolver.resolve)(&res_ctx.data, field.arguments.clone(), context, self);
+                // the field is visited again once its value is ready
+                res_ctx.field_res_progress -= 1;
+                stack.push(res_ctx);
+                return Ok(LoopOutcome::Pending(pending));
+              }
+              _ => {
+                self.get_resolution_value_next(&res_ctx.cur_type, &field, context, &res_ctx.data)
+              }
+            },
+          },
+        };
+        let value = match resolved {
+          Ok(value) => value,
+          // A nullable field that fails becomes null, and its siblings carry on
+          Err(e) if self.field_nullable(&res_ctx.cur_type, &field.name) => {
+            errors.push(GqlError::from(res_ctx.locate(e, field)));
+            res_ctx
+              .data
+              .insert(field.response_key().to_owned(), GqlValue::Null);
+            continue;
+          }
+          // Otherwise the null goes to the nearest parent that can hold it
+          Err(e) => {
+            let error = res_ctx.locate(e, field);
+            propagate_null(res_ctx, stack, error, errors)?;
+            continue 'outer;
+          }
+        };
 
         match value {
-          ResolutionReturn::Scalar(inner_val) => {
-            res_ctx.data.insert(field.name.to_owned(), inner_val);
+          scalar @ ResolutionReturn::Scalar(_) | scalar @ ResolutionReturn::ScalarList(_) => {
+            // a list of scalars is checked and inserted whole, like any other scalar
+            let inner_val = match scalar {
+              ResolutionReturn::ScalarList(values) => GqlValue::List(values),
+              ResolutionReturn::Scalar(value) => value,
+              _ => unreachable!(),
+            };
+            let nullable = self.field_nullable(&res_ctx.cur_type, &field.name);
+            let checked = match inner_val {
+              GqlValue::Null if !nullable => Err(ResolutionErr::QueryResult(format!(
+                "Cannot return null for non-null field {}.{}",
+                res_ctx.cur_type, field.name
+              ))),
+              value => self
+                .check_enum_value(&res_ctx.cur_type, &field.name, &value)
+                .map(
+                  |_| match self.get_field_type(&res_ctx.cur_type, &field.name) {
+                    Some(field_type) => self.serialize_scalar(field_type, value),
+                    None => value,
+                  },
+                ),
+            };
+            match checked {
+              Ok(value) => {
+                res_ctx.data.insert(field.response_key().to_owned(), value);
+              }
+              Err(e) if nullable => {
+                errors.push(GqlError::from(res_ctx.locate(e, field)));
+                res_ctx
+                  .data
+                  .insert(field.response_key().to_owned(), GqlValue::Null);
+              }
+              Err(e) => {
+                let error = res_ctx.locate(e, field);
+                propagate_null(res_ctx, stack, error, errors)?;
+                continue 'outer;
+              }
+            }
           }
           ResolutionReturn::Type((gql_type, initial_field_results)) => {
             let mut ctx = ResolutionContext::new(
-              gql_type.to_owned(),
-              field.name.to_owned(),
+              self.member_type(&gql_type, &initial_field_results)?,
+              field.response_key().to_owned(),
               field.fields.to_owned(),
             );
-            ctx.data = initial_field_results;
+            ctx.set_data(initial_field_results);
+            ctx.nullable = self.field_nullable(&res_ctx.cur_type, &field.name);
+            ctx.path = res_ctx.path.clone();
+            ctx
+              .path
+              .push(JsonValue::String(field.response_key().to_owned()));
             stack.push(res_ctx);
             stack.push(ctx);
             continue 'outer;
           }
-          ResolutionReturn::TypeList((gql_type, initial_values)) => {
+          list @ ResolutionReturn::TypeList(_) | list @ ResolutionReturn::List(_) => {
             // After we push the current resolving type onto the stack,
             // the index of that will be the stack's current length.
             let parent_index = stack.len();
+            let nullable_list = self.field_nullable(&res_ctx.cur_type, &field.name);
+            let mut items = Vec::new();
+            let skeleton = self.list_skeleton(
+              list,
+              self.get_field_type(&res_ctx.cur_type, &field.name),
+              &mut Vec::new(),
+              &mut items,
+            );
             res_ctx
               .data
-              .insert(field.name.clone(), GqlValue::List(vec![]));
+              .insert(field.response_key().to_owned(), skeleton);
             stack.extend(
-              initial_values
+              items
                 .into_iter()
-                .map(|t| -> GqlExecResult<ResolutionContext> {
+                .map(|item| -> GqlExecResult<ResolutionContext> {
                   let mut rctx = ResolutionContext::new(
-                    gql_type.to_owned(),
-                    field.name.clone(),
+                    self.member_type(&item.gql_type, &item.data)?,
+                    field.response_key().to_owned(),
                     field.fields.clone(),
                   );
-                  rctx.set_list(parent_index, t);
+                  rctx.path = res_ctx.path.clone();
+                  rctx
+                    .path
+                    .push(JsonValue::String(field.response_key().to_owned()));
+                  rctx
+                    .path
+                    .extend(item.index.iter().map(|i| JsonValue::from(*i)));
+                  rctx.set_list(parent_index, item.index, item.data);
+                  rctx.nullable = item.nullable;
+                  rctx.nullable_list = nullable_list;
                   Ok(rctx)
                 })
                 .collect::<GqlExecResult<Vec<ResolutionContext>>>()?,
@@ -309,17 +952,20 @@ impl<C> GqlSchema<C> {
         }
       }
       if stack.is_empty() {
-        return Ok(res_ctx.data);
+        return Ok(LoopOutcome::Done(res_ctx.data));
       }
       // We have finished resolving a single type, save it and head back into the stack
       //trim_selection_fields(&res_ctx.fields, &mut res_ctx.data);
       if let Some(parent_index) = res_ctx.in_list {
         let parent_data = &mut stack[parent_index].data;
-        match parent_data.get_mut(&res_ctx.map_key) {
-          Some(GqlValue::List(l)) => {
-            l.push(GqlValue::Object(res_ctx.data));
+        match parent_data
+          .get_mut(&res_ctx.map_key)
+          .and_then(|list| list_slot(list, &res_ctx.list_index))
+        {
+          Some(slot) => {
+            *slot = GqlValue::Object(res_ctx.data);
           }
-          _ => {
+          None => {
             panic!("Found a list that was not a list!");
           }
         }
@@ -330,7 +976,290 @@ impl<C> GqlSchema<C> {
           .insert(res_ctx.map_key, query::Value::Object(res_ctx.data));
       }
     }
-    Ok(BTreeMap::new())
+    Ok(LoopOutcome::Done(BTreeMap::new()))
+  }
+
+  /// Lays out a list result as a list just as deep, with a null wherever an object goes
+  /// until it has been resolved. The objects are collected into `items` with their spot.
+  fn list_skeleton(
+    &self,
+    value: ResolutionReturn,
+    value_type: Option<&query::Type>,
+    index: &mut Vec<usize>,
+    items: &mut Vec<ListItem>,
+  ) -> GqlValue {
+    let (nullable, value_type) = match value_type {
+      Some(query::Type::NonNullType(inner)) => (false, Some(&**inner)),
+      t => (true, t),
+    };
+    match value {
+      ResolutionReturn::Scalar(v) => match value_type {
+        Some(t) => self.serialize_scalar(t, v),
+        None => v,
+      },
+      ResolutionReturn::ScalarList(values) => self.list_skeleton(
+        ResolutionReturn::Scalar(GqlValue::List(values)),
+        value_type,
+        index,
+        items,
+      ),
+      ResolutionReturn::Type((gql_type, data)) => {
+        items.push(ListItem {
+          index: index.clone(),
+          gql_type,
+          data,
+          nullable,
+        });
+        GqlValue::Null
+      }
+      ResolutionReturn::TypeList((gql_type, objects)) => self.list_skeleton(
+        ResolutionReturn::List(
+          objects
+            .into_iter()
+            .map(|obj| ResolutionReturn::Type((gql_type.clone(), obj)))
+            .collect(),
+        ),
+        value_type,
+        index,
+        items,
+      ),
+      ResolutionReturn::List(values) => {
+        let item_type = match value_type {
+          Some(query::Type::ListType(inner)) => Some(&**inner),
+          _ => None,
+        };
+        GqlValue::List(
+          values
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| {
+              index.push(i);
+              let laid_out = self.list_skeleton(v, item_type, index, items);
+              index.pop();
+              laid_out
+            })
+            .collect(),
+        )
+      }
+    }
+  }
+
+  /// Collects the arguments given to a field, coercing them
+  /// to match the argument types declared in the schema.
+  /// Variables are substituted in first, and input objects are checked against their definitions.
+  fn coerce_arguments(
+    &self,
+    on_type: &str,
+    field: &query::Field,
+    exec: &GqlRunningQuery,
+  ) -> Result<BTreeMap<String, GqlValue>, GqlQueryErr> {
+    let arg_defs = self
+      .get_any_object_type(on_type)
+      .ok()
+      .and_then(|t| t.fields.iter().find(|f| f.name == field.name))
+      .map(|f| &f.arguments);
+    field
+      .arguments
+      .iter()
+      // arguments set to variables without a value are left out
+      .filter(|(_, val)| !exec.is_absent(val))
+      .map(|(name, val)| {
+        let val = exec.substitute_variables(val);
+        let coerced = match arg_defs.and_then(|defs| defs.iter().find(|d| d.name == *name)) {
+          Some(def) => {
+            let coerced = self.coerce_enums(
+              &def.value_type,
+              execution::coerce_input(&def.value_type, val),
+            );
+            let coerced = self.parse_scalars(&def.value_type, coerced, name)?;
+            self.validate_input(&def.value_type, &coerced, name)?;
+            coerced
+          }
+          None => val,
+        };
+        Ok((name.clone(), coerced))
+      })
+      .collect()
+  }
+
+  fn get_enum(&self, type_name: &str) -> Option<&schema::EnumType> {
+    self
+      .external_types
+      .enums
+      .get(type_name)
+      .or_else(|| self.internal_types.enums.get(type_name))
+  }
+
+  /// Turns strings into enum values wherever an enum is expected,
+  /// since JSON variables can only send enum values as strings.
+  fn coerce_enums(&self, value_type: &query::Type, value: GqlValue) -> GqlValue {
+    match (value_type, value) {
+      (query::Type::NonNullType(inner), v) => self.coerce_enums(inner, v),
+      (query::Type::ListType(inner), GqlValue::List(l)) => {
+        GqlValue::List(l.into_iter().map(|v| self.coerce_enums(inner, v)).collect())
+      }
+      (query::Type::NamedType(type_name), GqlValue::String(s))
+        if self.get_enum(type_name).is_some() =>
+      {
+        GqlValue::Enum(s)
+      }
+      (query::Type::NamedType(type_name), GqlValue::Object(obj)) => {
+        match self.external_types.input_types.get(type_name) {
+          Some(input_def) => GqlValue::Object(
+            obj
+              .into_iter()
+              .map(|(k, v)| {
+                let v = match input_def.fields.iter().find(|f| f.name == k) {
+                  Some(field_def) => self.coerce_enums(&field_def.value_type, v),
+                  None => v,
+                };
+                (k, v)
+              })
+              .collect(),
+          ),
+          None => GqlValue::Object(obj),
+        }
+      }
+      (_, v) => v,
+    }
+  }
+
+  /// Runs registered scalar parsers over an input value, wherever a custom scalar is expected
+  fn parse_scalars(
+    &self,
+    value_type: &query::Type,
+    value: GqlValue,
+    name: &str,
+  ) -> Result<GqlValue, GqlQueryErr> {
+    match (value_type, value) {
+      (query::Type::NonNullType(inner), v) => self.parse_scalars(inner, v, name),
+      (_, GqlValue::Null) => Ok(GqlValue::Null),
+      (query::Type::ListType(inner), GqlValue::List(l)) => l
+        .into_iter()
+        .map(|v| self.parse_scalars(inner, v, name))
+        .collect::<Result<Vec<GqlValue>, GqlQueryErr>>()
+        .map(GqlValue::List),
+      (query::Type::NamedType(type_name), v) => {
+        if let Some(scalar) = self.custom_scalars.get(type_name) {
+          return match v {
+            GqlValue::String(raw) => (scalar.parse)(&raw).map_err(|e| {
+              GqlQueryErr::Type(QueryValidationError::new(
+                format!("{} should be a {}: {}", name, type_name, e),
+                name.to_owned(),
+              ))
+            }),
+            _ => Err(GqlQueryErr::Type(QueryValidationError::new(
+              format!("{} should be a {} string", name, type_name),
+              name.to_owned(),
+            ))),
+          };
+        }
+        match (self.external_types.input_types.get(type_name), v) {
+          (Some(input_def), GqlValue::Object(obj)) => obj
+            .into_iter()
+            .map(|(k, v)| {
+              let v = match input_def.fields.iter().find(|f| f.name == k) {
+                Some(field_def) => {
+                  self.parse_scalars(&field_def.value_type, v, &format!("{}.{}", name, k))?
+                }
+                None => v,
+              };
+              Ok((k, v))
+            })
+            .collect::<Result<GqlObj, GqlQueryErr>>()
+            .map(GqlValue::Object),
+          (_, v) => Ok(v),
+        }
+      }
+      (_, v) => Ok(v),
+    }
+  }
+
+  /// Runs the registered serializer over a result, when the field's type is a custom scalar
+  fn serialize_scalar(&self, value_type: &query::Type, value: GqlValue) -> GqlValue {
+    match (value_type, value) {
+      (_, GqlValue::Null) => GqlValue::Null,
+      (query::Type::NonNullType(inner), v) => self.serialize_scalar(inner, v),
+      (query::Type::ListType(inner), GqlValue::List(l)) => GqlValue::List(
+        l.into_iter()
+          .map(|v| self.serialize_scalar(inner, v))
+          .collect(),
+      ),
+      (query::Type::NamedType(type_name), v) => match self.custom_scalars.get(type_name) {
+        Some(scalar) => execution::json_to_gql((scalar.serialize)(&v)),
+        None => v,
+      },
+      (_, v) => v,
+    }
+  }
+
+  /// Checks that an input value fits its declared type. Input objects may only
+  /// contain declared fields, and must contain every required one.
+  /// Enum values have to be members of their enum.
+  fn validate_input(
+    &self,
+    value_type: &query::Type,
+    value: &GqlValue,
+    name: &str,
+  ) -> Result<(), GqlQueryErr> {
+    match (value_type, value) {
+      (query::Type::NonNullType(_), GqlValue::Null) => Err(GqlQueryErr::Type(
+        QueryValidationError::new(format!("{} may not be null", name), name.to_owned()),
+      )),
+      (query::Type::NonNullType(inner), v) => self.validate_input(inner, v, name),
+      (_, GqlValue::Null) => Ok(()),
+      (query::Type::ListType(inner), GqlValue::List(items)) => items
+        .iter()
+        .map(|i| self.validate_input(inner, i, name))
+        .collect(),
+      (query::Type::ListType(_), _) => Err(GqlQueryErr::Type(QueryValidationError::new(
+        format!("{} should be a list", name),
+        name.to_owned(),
+      ))),
+      (query::Type::NamedType(type_name), v) => {
+        if let Some(enum_def) = self.get_enum(type_name) {
+          return match v {
+            GqlValue::Enum(member) if enum_def.values.iter().any(|e| &e.name == member) => Ok(()),
+            _ => Err(GqlQueryErr::Type(QueryValidationError::new(
+              format!("{} should be a member of enum {}", name, type_name),
+              name.to_owned(),
+            ))),
+          };
+        }
+        let input_def = match self.external_types.input_types.get(type_name) {
+          Some(def) => def,
+          None => return Ok(()),
+        };
+        let obj = match v {
+          GqlValue::Object(obj) => obj,
+          _ => {
+            return Err(GqlQueryErr::Type(QueryValidationError::new(
+              format!("{} should be a {} object", name, type_name),
+              name.to_owned(),
+            )))
+          }
+        };
+        if let Some(unknown) = obj
+          .keys()
+          .find(|k| !input_def.fields.iter().any(|f| &f.name == *k))
+        {
+          return Err(GqlQueryErr::Type(QueryValidationError::new(
+            format!("{} is not a field of {}", unknown, type_name),
+            unknown.to_owned(),
+          )));
+        }
+        for field_def in &input_def.fields {
+          let field_value = match (obj.get(&field_def.name), &field_def.default_value) {
+            (Some(v), _) => v,
+            (None, Some(default)) => default,
+            (None, None) => &GqlValue::Null,
+          };
+          let field_name = format!("{}.{}", name, field_def.name);
+          self.validate_input(&field_def.value_type, field_value, &field_name)?;
+        }
+        Ok(())
+      }
+    }
   }
 
   fn process_field(
@@ -339,7 +1268,6 @@ impl<C> GqlSchema<C> {
     on_type: &str,
     exec: &GqlRunningQuery,
   ) -> Result<Vec<SimpleField>, GqlQueryErr> {
-    let fields = exec.fields_from_selectionset(&field.selection_set, on_type)?;
     let full_type = self.get_any_object_type(on_type)?;
     let field_type: query::Type;
     if field.name == "__typename" {
@@ -374,37 +1302,172 @@ impl<C> GqlSchema<C> {
         }
       }
     };
-    fields
+    // objects, unions and interfaces need fields picked out of them, and anything else has none
+    let composite =
+      self.get_any_object_type(&final_type).is_ok() || self.possible_types(&final_type).is_some();
+    match (composite, field.selection_set.items.is_empty()) {
+      (true, true) => {
+        return Err(GqlQueryErr::Field(QueryValidationError::new(
+          format!(
+            "Field {} of type {} must have a selection of subfields",
+            field.name, final_type
+          ),
+          "Field".to_owned(),
+        )))
+      }
+      (false, false) => {
+        return Err(GqlQueryErr::Field(QueryValidationError::new(
+          format!(
+            "Field {} of type {} can't have a selection of subfields",
+            field.name, final_type
+          ),
+          "Field".to_owned(),
+        )))
+      }
+      _ => (),
+    }
+    // the selection set belongs to the field's type, which fragment conditions are checked against.
+    // A union or interface's selection set is gathered once for each type it can be,
+    // keeping what each of those types selects.
+    let selections: Vec<(String, Option<String>, query::Field)> =
+      match self.possible_types(&final_type) {
+        Some(possible_types) => possible_types
+          .iter()
+          .map(|member| {
+            Ok(
+              exec
+                .fields_from_selectionset(&field.selection_set, member)?
+                .into_iter()
+                .map(|f| (member.clone(), Some(member.clone()), f))
+                .collect::<Vec<_>>(),
+            )
+          })
+          .collect::<GqlExecResult<Vec<_>>>()?
+          .into_iter()
+          .flatten()
+          .collect(),
+        None => exec
+          .fields_from_selectionset(&field.selection_set, &final_type)?
+          .into_iter()
+          .map(|f| (final_type.clone(), None, f))
+          .collect(),
+      };
+    selections
       .into_iter()
-      .map(|f| {
+      .map(|(on_type, type_condition, f)| {
+        let directives = exec.substitute_directive_variables(&f.directives);
         for d in &f.directives {
-          self.validate_directive(&d.name, &d.arguments)?;
+          self.validate_directive(d, exec)?;
         }
         Ok(SimpleField {
           name: f.name.clone(),
-          directives: f.directives.clone(),
-          arguments: f.arguments.clone().into_iter().fold(
-            BTreeMap::new(),
-            |mut map, (name, val)| {
-              map.insert(name, val);
-              map
-            },
-          ),
-          fields: self.process_field(&f, &final_type, exec)?,
+          alias: f.alias.clone(),
+          position: f.position,
+          directives,
+          arguments: self.coerce_arguments(&on_type, &f, exec)?,
+          fields: self.process_field(&f, &on_type, exec)?,
+          type_condition,
         })
       })
       .collect::<Result<Vec<SimpleField>, GqlQueryErr>>()
+      .map(merge_fields)
   }
 
-  pub fn resolve(
-    &self,
-    context: &mut C,
-    req: GqlRequest,
-    root: Option<GqlRoot>,
+  /// Checks that a subscription request can be resolved, without running it.
+  /// Every selected field has to exist, and top level fields need a resolver.
+  pub fn validate_subscription(&self, req: &GqlRequest) -> Result<(), ResolutionErr> {
+    let query_ast =
+      parse_query(&req.query).map_err(|e| ResolutionErr::QueryParseIssue(format!("{:?}", e)))?;
+    let mut query_info = GqlRunningQuery::new(query_ast);
+    query_info.set_max_fields(self.max_expanded_fields);
+    query_info.set_possible_types(self.all_possible_types());
+    query_info.select_operation(req.operation_name.as_ref().map(String::as_str))?;
+    query_info.parse_fragments()?;
+    query_info.parse_variables(req.variables.clone())?;
+    let selections = query_info.get_initial_items()?;
+    if query_info.starting_type != "Subscription" {
+      return Err(ResolutionErr::QueryValidation(GqlQueryErr::Type(
+        QueryValidationError::new(
+          format!(
+            "Expected a subscription, found a {}",
+            query_info.starting_type
+          ),
+          query_info.starting_type.clone(),
+        ),
+      )));
+    }
+    for selection in selections {
+      for field in &selection.initial_fields {
+        self.process_field(field, "Subscription", &query_info)?;
+        self.get_resolvers("Subscription", &field.name)?;
+      }
+    }
+    Ok(())
+  }
+
+  /// The coerced arguments given to each root field of a request, keyed by field name
+  pub fn root_arguments(
+    &self,
+    req: &GqlRequest,
+  ) -> Result<BTreeMap<String, GqlArgs>, ResolutionErr> {
+    let mut arguments = BTreeMap::new();
+    for query in self.prepare(req.clone())? {
+      for field in query.fields {
+        arguments.insert(field.name, field.arguments);
+      }
+    }
+    Ok(arguments)
+  }
+
+  /// Resolves a request, dropping the errors of any fields or list items that were nulled.
+  /// Use `resolve_partial` to report those errors alongside the data.
+  pub fn resolve(
+    &self,
+    context: &mut C,
+    req: GqlRequest,
+    root: Option<GqlRoot>,
   ) -> Result<JsonValue, ResolutionErr> {
+    self
+      .resolve_partial(context, req, root)
+      .map(|(data, _)| data)
+  }
+
+  /// Resolves a request, isolating failures to the nearest nullable field or list item.
+  /// A nullable field that fails to resolve becomes null. When a non-null field fails
+  /// or resolves to null, the null goes up to the nearest object, list item, or list
+  /// that may be null. Either way the error is returned with the data, and the rest of
+  /// the data still resolves. A null that reaches the root fails the request.
+  pub fn resolve_partial(
+    &self,
+    context: &mut C,
+    req: GqlRequest,
+    root: Option<GqlRoot>,
+  ) -> Result<(JsonValue, Vec<GqlError>), ResolutionErr> {
+    let mut data: JsonMap<String, JsonValue> = JsonMap::new();
+    let mut errors = Vec::new();
+    for pending_query in self.prepare(req)? {
+      let mut state = LoopState::new(&pending_query, root.clone());
+      let res = loop {
+        match self.resolve_loop_next(context, &mut state, &mut errors)? {
+          LoopOutcome::Done(res) => break res,
+          // without an event loop to hand it to, the future is waited on here
+          LoopOutcome::Pending(pending) => state.ready = Some(pending.wait()),
+        }
+      };
+      self.add_query_data(&pending_query, res, &mut data)?;
+    }
+
+    Ok((JsonValue::Object(data), errors))
+  }
+
+  /// Parses and validates a request, giving back the operations it runs
+  fn prepare(&self, req: GqlRequest) -> Result<Vec<PendingQuery>, ResolutionErr> {
     let query_ast =
       parse_query(&req.query).map_err(|e| ResolutionErr::QueryParseIssue(format!("{:?}", e)))?;
     let mut query_info = GqlRunningQuery::new(query_ast);
+    query_info.set_max_fields(self.max_expanded_fields);
+    query_info.set_possible_types(self.all_possible_types());
+    query_info.select_operation(req.operation_name.as_ref().map(String::as_str))?;
     query_info
       .parse_fragments()
       .map_err(|a| ResolutionErr::QueryValidation(a))?;
@@ -415,52 +1478,312 @@ impl<C> GqlSchema<C> {
     // Contains any Queries, Mutations, or Subscriptions in the request
     let queries = query_info.get_initial_items()?;
 
-    let mut data: JsonMap<String, JsonValue> = JsonMap::new();
-    for queree in queries {
-      let pending_query = PendingQuery {
-        on_type: &query_info.starting_type,
-        fields: queree
-          .initial_fields
-          .clone()
-          .into_iter()
-          .map(|f| {
-            Ok(SimpleField {
-              name: f.name.clone(),
-              arguments: f
-                .arguments
-                .iter()
-                .fold(BTreeMap::new(), |mut map, (name, val)| {
-                  map.insert(name.to_owned(), val.to_owned());
-                  map
-                }),
-              directives: f.directives.clone(),
-              fields: self.process_field(&f, &query_info.starting_type, &query_info)?,
+    let pending_queries = queries
+      .into_iter()
+      .map(|queree| {
+        Ok(PendingQuery {
+          on_type: query_info.starting_type.clone(),
+          fields: queree
+            .initial_fields
+            .clone()
+            .into_iter()
+            .map(|f| {
+              let directives = query_info.substitute_directive_variables(&f.directives);
+              for d in &f.directives {
+                self.validate_directive(d, &query_info)?;
+              }
+              Ok(SimpleField {
+                name: f.name.clone(),
+                alias: f.alias.clone(),
+                position: f.position,
+                arguments: self.coerce_arguments(&query_info.starting_type, &f, &query_info)?,
+                directives,
+                fields: self.process_field(&f, &query_info.starting_type, &query_info)?,
+                type_condition: None,
+              })
             })
-          })
-          .collect::<Result<Vec<SimpleField>, GqlQueryErr>>()?,
+            .collect::<Result<Vec<SimpleField>, GqlQueryErr>>()
+            .map(merge_fields)?,
+        })
+      })
+      .collect::<Result<Vec<PendingQuery>, GqlQueryErr>>()?;
+
+    if let Some(max_cost) = self.max_complexity {
+      let cost = pending_queries
+        .iter()
+        .map(|q| self.complexity(&q.on_type, &q.fields))
+        .fold(0, usize::saturating_add);
+      if cost > max_cost {
+        return Err(ResolutionErr::QueryValidation(GqlQueryErr::TooComplex(
+          QueryValidationError::new(
+            format!("Query costs {}, more than the limit of {}", cost, max_cost),
+            query_info.starting_type.clone(),
+          ),
+        )));
+      }
+    }
+
+    Ok(pending_queries)
+  }
+
+  /// Adds what an operation resolved to the response data
+  fn add_query_data(
+    &self,
+    pending_query: &PendingQuery,
+    mut res: BTreeMap<String, GqlValue>,
+    data: &mut JsonMap<String, JsonValue>,
+  ) -> Result<(), ResolutionErr> {
+    for field in &pending_query.fields {
+      let val = res.get_mut(field.response_key()).unwrap();
+      // And extra fields that weren't requested are removed here
+      sparsify_return(val, &field);
+      // convert from GqlValue to JsonValue
+      let jdata = execution::gql_to_json(val.to_owned())
+        .map_err(|_| ResolutionErr::QueryResult(format!("Could not encode result to JSON")))?;
+      data.insert(field.response_key().to_owned(), jdata);
+    }
+    Ok(())
+  }
+}
+
+impl<C: Clone + 'static> GqlSchema<C> {
+  /// Resolves a request like `resolve_partial`, but waits on async resolvers
+  /// by chaining futures instead of blocking, so it can run on an event loop.
+  pub fn resolve_async(
+    schema: Arc<Self>,
+    context: C,
+    req: GqlRequest,
+    root: Option<GqlRoot>,
+  ) -> Box<dyn Future<Item = (JsonValue, Vec<GqlError>), Error = ResolutionErr>> {
+    let queries = match schema.prepare(req) {
+      Ok(queries) => queries,
+      Err(e) => return Box::new(future::err(e)),
+    };
+    let resolved = stream::iter_ok::<_, ResolutionErr>(queries).fold(
+      (context, JsonMap::new(), Vec::new()),
+      move |(context, mut data, mut errors), query| {
+        let schema = schema.clone();
+        Self::resolve_operation_async(schema.clone(), context, query, root.clone()).and_then(
+          move |(context, done)| -> Result<_, ResolutionErr> {
+            for query in done {
+              schema.add_query_data(&query.query, query.data, &mut data)?;
+              errors.extend(query.errors);
+            }
+            Ok((context, data, errors))
+          },
+        )
+      },
+    );
+    Box::new(resolved.map(|(_, data, errors)| (JsonValue::Object(data), errors)))
+  }
+
+  /// Resolves an operation, splitting up its root fields when they may run at the same time.
+  /// Mutations always run one field after another.
+  fn resolve_operation_async(
+    schema: Arc<Self>,
+    context: C,
+    query: PendingQuery,
+    root: Option<GqlRoot>,
+  ) -> Box<dyn Future<Item = (C, Vec<QueryDone>), Error = ResolutionErr>> {
+    if !schema.parallel_root_fields || query.on_type != "Query" {
+      return Box::new(
+        Self::resolve_query_async(schema, context, query, root)
+          .map(|(context, done)| (context, vec![done])),
+      );
+    }
+    let on_type = query.on_type;
+    let fields = query.fields.into_iter().map(|field| {
+      let single = PendingQuery {
+        on_type: on_type.clone(),
+        fields: vec![field],
       };
+      Self::resolve_query_async(schema.clone(), context.clone(), single, root.clone())
+    });
+    // join_all gives the results back in the order the fields were asked for in,
+    // though the response map sorts its keys anyway
+    let all = future::join_all(fields.collect::<Vec<_>>());
+    Box::new(all.map(move |results| {
+      let done = results.into_iter().map(|(_, done)| done).collect();
+      (context, done)
+    }))
+  }
+
+  /// Resolves an operation, waiting on each async resolver it comes across
+  fn resolve_query_async(
+    schema: Arc<Self>,
+    context: C,
+    query: PendingQuery,
+    root: Option<GqlRoot>,
+  ) -> Box<dyn Future<Item = (C, QueryDone), Error = ResolutionErr>> {
+    let state = LoopState::new(&query, root);
+    let run = AsyncRun {
+      schema,
+      context,
+      query,
+      state,
+      errors: Vec::new(),
+    };
+    Box::new(future::loop_fn(run, |mut run| -> AsyncStep<C> {
+      match run
+        .schema
+        .resolve_loop_next(&mut run.context, &mut run.state, &mut run.errors)
+      {
+        Err(e) => Box::new(future::err(e)),
+        Ok(LoopOutcome::Pending(pending)) => Box::new(pending.then(move |ready| {
+          run.state.ready = Some(ready);
+          Ok(Loop::Continue(run))
+        })),
+        Ok(LoopOutcome::Done(data)) => {
+          let done = QueryDone {
+            query: run.query,
+            data,
+            errors: run.errors,
+          };
+          Box::new(future::ok(Loop::Break((run.context, done))))
+        }
+      }
+    }))
+  }
+}
+
+/// Everything `resolve_query_async` keeps between futures
+struct AsyncRun<C> {
+  schema: Arc<GqlSchema<C>>,
+  context: C,
+  query: PendingQuery,
+  state: LoopState,
+  errors: Vec<GqlError>,
+}
+
+/// An operation, or one of its root fields, that has been resolved
+struct QueryDone {
+  query: PendingQuery,
+  data: BTreeMap<String, GqlValue>,
+  errors: Vec<GqlError>,
+}
+
+type AsyncStep<C> =
+  Box<dyn Future<Item = Loop<(C, QueryDone), AsyncRun<C>>, Error = ResolutionErr>>;
+
+/// How far resolving an operation has got, so it can stop to wait on an async resolver
+struct LoopState {
+  stack: Vec<ResolutionContext>,
+  /// The value of the async resolver that was waited on, used for the next field visited
+  ready: Option<ResResult>,
+}
 
-      let mut res = self.resolve_loop_next(context, &pending_query, root.clone())?;
-      for field in &pending_query.fields {
-        let val = res.get_mut(&field.name).unwrap();
-        // And extra fields that weren't requested are removed here
-        sparsify_return(val, &field);
-        // convert from GqlValue to JsonValue
-        let jdata = execution::gql_to_json(val.to_owned())
-          .map_err(|_| ResolutionErr::QueryResult(format!("Could not encode result to JSON")))?;
-        data.insert(field.name.to_owned(), jdata);
+impl LoopState {
+  fn new(query: &PendingQuery, initial_root: Option<GqlRoot>) -> Self {
+    let mut initial_res = ResolutionContext::new(
+      query.on_type.to_owned(),
+      "".to_owned(),
+      query.fields.clone(),
+    );
+    if let Some(root) = initial_root {
+      initial_res.set_data(root);
+    }
+    LoopState {
+      stack: vec![initial_res],
+      ready: None,
+    }
+  }
+}
+
+enum LoopOutcome {
+  Done(BTreeMap<String, GqlValue>),
+  Pending(ResFuture),
+}
+
+/// Nulls the nearest nullable object, list item, or list around a non-null field that failed,
+/// dropping anything still being resolved inside it.
+/// Fails the request when nothing up to the root can be nulled.
+fn propagate_null(
+  mut failed: ResolutionContext,
+  stack: &mut Vec<ResolutionContext>,
+  error: ResolutionErr,
+  errors: &mut Vec<GqlError>,
+) -> Result<(), ResolutionErr> {
+  loop {
+    // everything above the parent on the stack is being resolved inside of it
+    let parent_index = match failed.in_list {
+      Some(index) => index,
+      None if stack.is_empty() => return Err(error),
+      None => stack.len() - 1,
+    };
+    let parent_data = &mut stack[parent_index].data;
+    if failed.nullable {
+      // a list item's spot is null until it is resolved, so it can just be left
+      if failed.in_list.is_none() {
+        parent_data.insert(failed.map_key, GqlValue::Null);
       }
+      errors.push(GqlError::from(error));
+      return Ok(());
     }
+    if failed.in_list.is_some() && failed.nullable_list {
+      parent_data.insert(failed.map_key, GqlValue::Null);
+      stack.truncate(parent_index + 1);
+      errors.push(GqlError::from(error));
+      return Ok(());
+    }
+    stack.truncate(parent_index + 1);
+    failed = stack.pop().unwrap();
+  }
+}
+
+/// The value at `index` in a list of lists
+fn list_slot<'a>(value: &'a mut GqlValue, index: &[usize]) -> Option<&'a mut GqlValue> {
+  match index.split_first() {
+    None => Some(value),
+    Some((i, rest)) => match value {
+      GqlValue::List(l) => list_slot(l.get_mut(*i)?, rest),
+      _ => None,
+    },
+  }
+}
 
-    Ok(JsonValue::Object(data))
+/// Merges selections of the same field that have equal arguments and the same response key,
+/// so that the subfields of every selection get resolved.
+fn merge_fields(fields: Vec<SimpleField>) -> Vec<SimpleField> {
+  let mut merged: Vec<SimpleField> = Vec::with_capacity(fields.len());
+  for field in fields {
+    match merged.iter_mut().find(|m| {
+      m.response_key() == field.response_key()
+        && m.name == field.name
+        && m.arguments == field.arguments
+        && m.type_condition == field.type_condition
+    }) {
+      Some(existing) => existing.fields.extend(field.fields),
+      None => merged.push(field),
+    }
+  }
+  for field in merged.iter_mut() {
+    field.fields = merge_fields(std::mem::take(&mut field.fields));
   }
+  merged
 }
 
+/// Removes the data that wasn't selected, such as extra fields a resolver filled in.
+/// Meta fields like `__typename` stay when selected, since they are matched by response key too.
 fn sparsify_return(val: &mut GqlValue, field: &SimpleField) {
-  if let GqlValue::Object(obj) = val {
+  if let GqlValue::List(items) = val {
+    for item in items.iter_mut() {
+      sparsify_return(item, field);
+    }
+  } else if let GqlValue::Object(obj) = val {
+    // members of a union or interface only keep what was selected on their own type
+    let member = match obj.get("__typename") {
+      Some(GqlValue::String(t)) => Some(t.clone()),
+      _ => None,
+    };
     let mut extra_keys = Vec::new();
     for (key, mut val) in obj.iter_mut() {
-      match field.fields.iter().find(|f| f.name == *key) {
+      match field.fields.iter().find(|f| {
+        f.response_key() == key.as_str()
+          && match (&f.type_condition, &member) {
+            (Some(condition), Some(member)) => condition == member,
+            _ => true,
+          }
+      }) {
         Some(field) => {
           sparsify_return(&mut val, &field);
         }
@@ -478,17 +1801,38 @@ fn sparsify_return(val: &mut GqlValue, field: &SimpleField) {
 #[derive(Clone, Debug)]
 struct SimpleField {
   name: String,
+  /// What the result is called in the response, instead of the field name
+  alias: Option<String>,
+  /// Where the field is in the query text
+  position: graphql_parser::Pos,
   directives: Vec<query::Directive>,
   arguments: BTreeMap<String, GqlValue>,
   fields: Vec<SimpleField>,
+  /// The object type the field was selected for, when the parent is a union or interface
+  type_condition: Option<String>,
+}
+
+impl SimpleField {
+  /// The key the field's result is stored under
+  fn response_key(&self) -> &str {
+    self.alias.as_ref().unwrap_or(&self.name)
+  }
 }
 
 #[derive(Clone, Debug)]
-struct PendingQuery<'a> {
-  on_type: &'a str,
+struct PendingQuery {
+  on_type: String,
   fields: Vec<SimpleField>,
 }
 
+/// An object in a list result that still has to be resolved
+struct ListItem {
+  index: Vec<usize>,
+  gql_type: String,
+  data: GqlObj,
+  nullable: bool,
+}
+
 #[derive(Default)]
 struct ResolutionContext {
   cur_type: String,
@@ -497,6 +1841,16 @@ struct ResolutionContext {
   field_res_progress: usize,
   data: BTreeMap<String, query::Value>,
   in_list: Option<usize>,
+  /// For list items, where in the list (and the lists inside it) the item goes
+  list_index: Vec<usize>,
+  /// Whether the object may be nulled if one of its non-null fields fails
+  nullable: bool,
+  /// For list items, whether the whole list may be nulled instead
+  nullable_list: bool,
+  /// Where the data ends up in the response, for error paths
+  path: Vec<JsonValue>,
+  /// Fields whose data was given up front rather than resolved
+  prefilled: Vec<String>,
 }
 
 impl ResolutionContext {
@@ -509,10 +1863,23 @@ impl ResolutionContext {
     }
   }
 
-  fn set_list(&mut self, index: usize, data: BTreeMap<String, GqlValue>) {
-    self.in_list = Some(index);
+  fn set_data(&mut self, data: BTreeMap<String, GqlValue>) {
+    self.prefilled = data.keys().cloned().collect();
     self.data = data;
   }
+
+  fn set_list(&mut self, index: usize, list_index: Vec<usize>, data: BTreeMap<String, GqlValue>) {
+    self.in_list = Some(index);
+    self.list_index = list_index;
+    self.set_data(data);
+  }
+
+  /// Records that `error` happened while resolving `field` of this type
+  fn locate(&self, error: ResolutionErr, field: &SimpleField) -> ResolutionErr {
+    let mut path = self.path.clone();
+    path.push(JsonValue::String(field.response_key().to_owned()));
+    error.at(path, field.position.into())
+  }
 }
 
 #[cfg(test)]
@@ -520,6 +1887,29 @@ mod tests {
   use super::*;
   use serde_json::{from_str, json, to_string};
 
+  #[test]
+  fn schema_types_are_complete() {
+    let schema: GqlSchema<()> = GqlSchema::new(
+      graphql_parser::parse_schema(
+        "enum Role { ADMIN MEMBER }
+        type Query { role: Role }",
+      )
+      .unwrap(),
+    )
+    .unwrap();
+    let req = GqlRequest {
+      query: "query { __schema { types { name kind } } }".to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    let data = schema.resolve(&mut (), req, None).unwrap();
+    let types = data["__schema"]["types"].as_array().unwrap();
+    assert!(types.contains(&json!({ "name": "Boolean", "kind": "SCALAR" })));
+    assert!(types.contains(&json!({ "name": "Role", "kind": "ENUM" })));
+    assert!(types.contains(&json!({ "name": "Query", "kind": "OBJECT" })));
+  }
+
   #[test]
   fn simple_query() {
     let schema = include_str!("../../tests/simple_schema.graphql");
@@ -528,6 +1918,7 @@ mod tests {
       query: query_str.to_owned(),
       operation_name: None,
       variables: None,
+      extensions: None,
     };
     let doc = graphql_parser::parse_schema(schema).unwrap();
 
@@ -567,6 +1958,7 @@ mod tests {
       query: query_str.to_owned(),
       operation_name: None,
       variables: None,
+      extensions: None,
     };
     let doc = graphql_parser::parse_schema(schema).unwrap();
 
@@ -623,6 +2015,7 @@ mod tests {
     "#;
     let req = GqlRequest {
       variables: None,
+      extensions: None,
       query: query.to_owned(),
       operation_name: None,
     };
@@ -640,4 +2033,2039 @@ mod tests {
     }
     panic!("resolve did not return an object");
   }
+
+  #[test]
+  fn error_codes() {
+    let schema: GqlSchema<i32> = GqlSchema::new(
+      graphql_parser::parse_schema(include_str!("../../tests/simple_schema.graphql")).unwrap(),
+    )
+    .unwrap();
+    let req = GqlRequest {
+      query: "query { message ".to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    let resp = GqlResponse::from(schema.resolve(&mut 0, req, None));
+    let errors = json!(resp)["errors"].clone();
+    assert_eq!(
+      errors[0]["extensions"]["code"],
+      json!("GRAPHQL_PARSE_FAILED")
+    );
+    assert!(errors[0].get("QueryParseIssue").is_some());
+    assert_eq!(
+      ResolutionErr::new_missing_argument("Query", "message", "id").error_code(),
+      "BAD_USER_INPUT"
+    );
+  }
+
+  #[test]
+  fn error_http_status() {
+    assert_eq!(
+      ResolutionErr::NotFound("Channel 5".to_owned()).http_status(),
+      404
+    );
+    assert_eq!(
+      ResolutionErr::Unauthorized("Channel".to_owned()).http_status(),
+      401
+    );
+    assert_eq!(
+      ResolutionErr::QueryParseIssue("".to_owned()).http_status(),
+      400
+    );
+    assert_eq!(
+      ResolutionErr::PersistedQueryNotFound("".to_owned()).http_status(),
+      200
+    );
+  }
+
+  #[test]
+  fn list_argument_coercion() {
+    let mut schema: GqlSchema<i32> = GqlSchema::new(
+      graphql_parser::parse_schema("type Query { count(ids: [String!]): Int }").unwrap(),
+    )
+    .unwrap();
+
+    fn resolve_count(
+      _root: &GqlRoot,
+      args: GqlArgs,
+      _ctx: &mut i32,
+      _r: &GqlSchema<i32>,
+    ) -> ResResult {
+      match args.get("ids") {
+        Some(GqlValue::List(l)) => Ok(ResolutionReturn::Scalar(GqlValue::Int(
+          query::Number::from(l.len() as i32),
+        ))),
+        _ => Err(ResolutionErr::new_missing_argument("Query", "count", "ids")),
+      }
+    }
+
+    schema
+      .add_resolvers(vec![Resolver::new(
+        Box::new(resolve_count),
+        "Query",
+        "count",
+      )])
+      .unwrap();
+    for query in &[
+      r#"query { count(ids: "5") }"#,
+      r#"query { count(ids: ["5"]) }"#,
+    ] {
+      let req = GqlRequest {
+        query: query.to_string(),
+        operation_name: None,
+        variables: None,
+        extensions: None,
+      };
+      assert_eq!(
+        schema.resolve(&mut 0, req, None).unwrap(),
+        json!({ "count": 1 })
+      );
+    }
+  }
+
+  #[test]
+  fn missing_resolver_policy() {
+    let doc =
+      graphql_parser::parse_schema(include_str!("../../tests/simple_schema.graphql")).unwrap();
+    let req = GqlRequest {
+      query: include_str!("../../tests/simple_query.graphql").to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+
+    // the missing resolver is reported, and the nullable field is nulled
+    let strict: GqlSchema<i32> = GqlSchema::new(doc.clone()).unwrap();
+    let (data, errors) = strict.resolve_partial(&mut 0, req.clone(), None).unwrap();
+    assert_eq!(data, json!({ "message": null }));
+    assert_eq!(errors.len(), 1);
+
+    let tolerant: GqlSchema<i32> =
+      GqlSchema::new_with_policy(doc, MissingResolverPolicy::Null).unwrap();
+    assert_eq!(
+      tolerant.resolve(&mut 0, req, None).unwrap(),
+      json!({ "message": null })
+    );
+  }
+
+  #[test]
+  fn validate_subscription() {
+    let mut schema: GqlSchema<i32> = GqlSchema::new(
+      graphql_parser::parse_schema(include_str!("../../tests/subscription_schema.graphql"))
+        .unwrap(),
+    )
+    .unwrap();
+    let req = |query: &str| GqlRequest {
+      query: query.to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    let valid = req("subscription { newMessage { id content } }");
+
+    // no resolver has been registered yet
+    assert!(schema.validate_subscription(&valid).is_err());
+
+    fn resolve_new_message(
+      _root: &GqlRoot,
+      _args: GqlArgs,
+      _ctx: &mut i32,
+      _r: &GqlSchema<i32>,
+    ) -> ResResult {
+      Ok(ResolutionReturn::Type((
+        "Message".to_owned(),
+        BTreeMap::new(),
+      )))
+    }
+    schema
+      .add_resolvers(vec![Resolver::new(
+        Box::new(resolve_new_message),
+        "Subscription",
+        "newMessage",
+      )])
+      .unwrap();
+
+    assert!(schema.validate_subscription(&valid).is_ok());
+    assert!(schema
+      .validate_subscription(&req("subscription { oldMessage { id } }"))
+      .is_err());
+    assert!(schema
+      .validate_subscription(&req("subscription { newMessage { author } }"))
+      .is_err());
+    assert!(schema
+      .validate_subscription(&req("query { newMessage { id } }"))
+      .is_err());
+
+    // a document with several operations names the one to subscribe to
+    let doc = "query Me { __typename } subscription OnMessage { newMessage { id } }";
+    let named = |name: &str| GqlRequest {
+      operation_name: Some(name.to_owned()),
+      ..req(doc)
+    };
+    assert!(schema.validate_subscription(&req(doc)).is_err());
+    assert!(schema.validate_subscription(&named("OnMessage")).is_ok());
+    assert!(schema.validate_subscription(&named("Me")).is_err());
+    assert!(schema.validate_subscription(&named("Missing")).is_err());
+  }
+
+  #[test]
+  fn operation_name_picks_a_query() {
+    let mut schema: GqlSchema<()> =
+      GqlSchema::new(graphql_parser::parse_schema("type Query { a: String b: String }").unwrap())
+        .unwrap();
+    fn resolve_a(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      Ok(ResolutionReturn::Scalar(GqlValue::String("a".to_owned())))
+    }
+    fn resolve_b(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      Ok(ResolutionReturn::Scalar(GqlValue::String("b".to_owned())))
+    }
+    schema
+      .add_resolvers(vec![
+        Resolver::new(Box::new(resolve_a), "Query", "a"),
+        Resolver::new(Box::new(resolve_b), "Query", "b"),
+      ])
+      .unwrap();
+
+    let req = |query: &str, name: Option<&str>| GqlRequest {
+      query: query.to_owned(),
+      operation_name: name.map(str::to_owned),
+      variables: None,
+      extensions: None,
+    };
+    let doc = "query GetA { a } query GetB { b }";
+    assert_eq!(
+      schema
+        .resolve(&mut (), req(doc, Some("GetA")), None)
+        .unwrap(),
+      json!({ "a": "a" })
+    );
+    assert_eq!(
+      schema
+        .resolve(&mut (), req(doc, Some("GetB")), None)
+        .unwrap(),
+      json!({ "b": "b" })
+    );
+    // which one to run can't be guessed
+    assert!(schema.resolve(&mut (), req(doc, None), None).is_err());
+    assert!(schema
+      .resolve(&mut (), req(doc, Some("GetC")), None)
+      .is_err());
+    assert!(schema
+      .resolve(
+        &mut (),
+        req("query Get { a } query Get { b }", Some("Get")),
+        None
+      )
+      .is_err());
+    // a lone operation doesn't need to be named
+    assert_eq!(
+      schema
+        .resolve(&mut (), req("query { a }", None), None)
+        .unwrap(),
+      json!({ "a": "a" })
+    );
+  }
+
+  #[test]
+  fn leaf_and_object_selections() {
+    let mut schema: GqlSchema<()> = GqlSchema::new(
+      graphql_parser::parse_schema(
+        "enum Role { ADMIN MEMBER }
+        type User { id: ID role: Role }
+        type Query { me: String user: User users: [User!]! }",
+      )
+      .unwrap(),
+    )
+    .unwrap();
+    fn resolve_me(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      Ok(ResolutionReturn::Scalar(GqlValue::String("joe".to_owned())))
+    }
+    fn resolve_user(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      let mut bmap = BTreeMap::new();
+      bmap.insert("id".to_owned(), GqlValue::String("joe".to_owned()));
+      bmap.insert("role".to_owned(), GqlValue::Enum("ADMIN".to_owned()));
+      Ok(ResolutionReturn::Type(("User".to_owned(), bmap)))
+    }
+    schema
+      .add_resolvers(vec![
+        Resolver::new(Box::new(resolve_me), "Query", "me"),
+        Resolver::new(Box::new(resolve_user), "Query", "user"),
+      ])
+      .unwrap();
+    let run = |query: &str| {
+      schema.resolve(
+        &mut (),
+        GqlRequest {
+          query: query.to_owned(),
+          operation_name: None,
+          variables: None,
+          extensions: None,
+        },
+        None,
+      )
+    };
+    let is_field_err = |query: &str| match run(query) {
+      Err(ResolutionErr::QueryValidation(GqlQueryErr::Field(_))) => true,
+      _ => false,
+    };
+
+    assert!(run("query { me user { id role } __typename }").is_ok());
+    // scalars and enums have no fields to select
+    assert!(is_field_err("query { me { foo } }"));
+    assert!(is_field_err("query { user { id role { name } } }"));
+    assert!(is_field_err("query { __typename { length } }"));
+    // objects, and lists of them, must say which fields they want
+    assert!(is_field_err("query { user }"));
+    assert!(is_field_err("query { users }"));
+    assert!(is_field_err("query { __schema }"));
+  }
+
+  #[test]
+  fn merge_duplicate_fields() {
+    let mut schema: GqlSchema<i32> = GqlSchema::new(
+      graphql_parser::parse_schema("type User { id: ID! name: String } type Query { me: User }")
+        .unwrap(),
+    )
+    .unwrap();
+
+    fn resolve_me(
+      _root: &GqlRoot,
+      _args: GqlArgs,
+      _ctx: &mut i32,
+      _r: &GqlSchema<i32>,
+    ) -> ResResult {
+      let mut bmap = BTreeMap::new();
+      bmap.insert("id".to_owned(), GqlValue::String("1".to_owned()));
+      bmap.insert("name".to_owned(), GqlValue::String("joe".to_owned()));
+      Ok(ResolutionReturn::Type(("User".to_owned(), bmap)))
+    }
+    schema
+      .add_resolvers(vec![Resolver::new(Box::new(resolve_me), "Query", "me")])
+      .unwrap();
+
+    let req = GqlRequest {
+      query: "query { me { id } me { name } }".to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    assert_eq!(
+      schema.resolve(&mut 0, req, None).unwrap(),
+      json!({ "me": { "id": "1", "name": "joe" } })
+    );
+  }
+
+  #[test]
+  fn field_aliases() {
+    let mut schema: GqlSchema<i32> = GqlSchema::new(
+      graphql_parser::parse_schema("type User { id: ID! name: String } type Query { me: User }")
+        .unwrap(),
+    )
+    .unwrap();
+
+    fn resolve_me(
+      _root: &GqlRoot,
+      _args: GqlArgs,
+      ctx: &mut i32,
+      _r: &GqlSchema<i32>,
+    ) -> ResResult {
+      *ctx += 1;
+      let mut bmap = BTreeMap::new();
+      bmap.insert("id".to_owned(), GqlValue::String(ctx.to_string()));
+      bmap.insert("name".to_owned(), GqlValue::String("joe".to_owned()));
+      Ok(ResolutionReturn::Type(("User".to_owned(), bmap)))
+    }
+    schema
+      .add_resolvers(vec![Resolver::new(Box::new(resolve_me), "Query", "me")])
+      .unwrap();
+
+    let req = GqlRequest {
+      query: "query { a: me { id } b: me { userId: id name } }".to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    let mut calls = 0;
+    assert_eq!(
+      schema.resolve(&mut calls, req, None).unwrap(),
+      json!({ "a": { "id": "1" }, "b": { "userId": "2", "name": "joe" } })
+    );
+    assert_eq!(calls, 2);
+  }
+
+  #[test]
+  fn skip_and_include() {
+    let mut schema: GqlSchema<Vec<String>> =
+      GqlSchema::new(graphql_parser::parse_schema("type Query { a: String b: String }").unwrap())
+        .unwrap();
+
+    fn resolve_a(
+      _: &GqlRoot,
+      _: GqlArgs,
+      called: &mut Vec<String>,
+      _: &GqlSchema<Vec<String>>,
+    ) -> ResResult {
+      called.push("a".to_owned());
+      Ok(ResolutionReturn::Scalar(GqlValue::String("a".to_owned())))
+    }
+    fn resolve_b(
+      _: &GqlRoot,
+      _: GqlArgs,
+      called: &mut Vec<String>,
+      _: &GqlSchema<Vec<String>>,
+    ) -> ResResult {
+      called.push("b".to_owned());
+      Ok(ResolutionReturn::Scalar(GqlValue::String("b".to_owned())))
+    }
+    schema
+      .add_resolvers(vec![
+        Resolver::new(Box::new(resolve_a), "Query", "a"),
+        Resolver::new(Box::new(resolve_b), "Query", "b"),
+      ])
+      .unwrap();
+
+    let run = |query: &str, variables: Option<JsonValue>| {
+      let req = GqlRequest {
+        query: query.to_owned(),
+        operation_name: None,
+        variables,
+        extensions: None,
+      };
+      let mut called = Vec::new();
+      let data = schema.resolve(&mut called, req, None).unwrap();
+      (data, called)
+    };
+
+    let (data, called) = run("query { a @skip(if: true) b @include(if: true) }", None);
+    assert_eq!(data, json!({ "b": "b" }));
+    assert_eq!(called, vec!["b".to_owned()]);
+
+    let (data, called) = run("query { a @skip(if: false) b @include(if: false) }", None);
+    assert_eq!(data, json!({ "a": "a" }));
+    assert_eq!(called, vec!["a".to_owned()]);
+
+    let query = "query Test($hide: Boolean!) { a @skip(if: $hide) ...B @include(if: $hide) } fragment B on Query { b }";
+    let (data, _) = run(query, Some(json!({ "hide": true })));
+    assert_eq!(data, json!({ "b": "b" }));
+    let (data, _) = run(query, Some(json!({ "hide": false })));
+    assert_eq!(data, json!({ "a": "a" }));
+  }
+
+  #[test]
+  fn directive_variables() {
+    let schema: GqlSchema<()> = GqlSchema::new(
+      graphql_parser::parse_schema(
+        "type Query { a: String channel: Channel } type Channel { id: ID name: String }",
+      )
+      .unwrap(),
+    )
+    .unwrap();
+    let query = "query Test($show: Boolean!) {
+      a @include(if: $show)
+      channel { id name @include(if: $show) }
+    }";
+    let prepare = |variables: JsonValue| {
+      schema.prepare(GqlRequest {
+        query: query.to_owned(),
+        operation_name: None,
+        variables: Some(variables),
+        extensions: None,
+      })
+    };
+
+    let prepared = prepare(json!({ "show": true })).unwrap();
+    let fields = &prepared[0].fields;
+    let if_arg = |field: &SimpleField| field.directives[0].arguments[0].clone();
+    let shown = GqlValue::Boolean(true);
+    assert_eq!(if_arg(&fields[0]), ("if".to_owned(), shown.clone()));
+    let name = fields[1].fields.iter().find(|f| f.name == "name").unwrap();
+    assert_eq!(if_arg(name), ("if".to_owned(), shown));
+
+    // the variable toggles the fields
+    let prepared = prepare(json!({ "show": false })).unwrap();
+    let fields = &prepared[0].fields;
+    assert_eq!(fields.len(), 1);
+    assert_eq!(fields[0].name, "channel");
+    assert_eq!(fields[0].fields.len(), 1);
+
+    // and the substituted value has to fit the directive
+    let wrong_type = schema.prepare(GqlRequest {
+      query: "query Test($show: String!) { a @include(if: $show) }".to_owned(),
+      operation_name: None,
+      variables: Some(json!({ "show": "yes" })),
+      extensions: None,
+    });
+    assert!(wrong_type.is_err());
+
+    // a nullable variable that isn't given counts as false, unless it has a default
+    let optional = |query: &str| {
+      schema.prepare(GqlRequest {
+        query: query.to_owned(),
+        operation_name: None,
+        variables: None,
+        extensions: None,
+      })
+    };
+    let prepared = optional("query Test($show: Boolean) { a @include(if: $show) channel { id } }");
+    let fields = &prepared.unwrap()[0].fields;
+    assert_eq!(fields.len(), 1);
+    assert_eq!(fields[0].name, "channel");
+    let prepared = optional("query Test($hide: Boolean) { a @skip(if: $hide) channel { id } }");
+    assert_eq!(prepared.unwrap()[0].fields.len(), 2);
+    let prepared =
+      optional("query Test($show: Boolean = true) { a @include(if: $show) channel { id } }");
+    assert_eq!(prepared.unwrap()[0].fields.len(), 2);
+  }
+
+  #[test]
+  fn absent_and_null_inputs() {
+    let mut schema: GqlSchema<i32> = GqlSchema::new(
+      graphql_parser::parse_schema(
+        r#"
+        type Query { me: String }
+        input EditInput { name: String }
+        type Mutation { edit(input: EditInput!): String }
+        "#,
+      )
+      .unwrap(),
+    )
+    .unwrap();
+
+    fn resolve_edit(
+      _root: &GqlRoot,
+      args: GqlArgs,
+      _ctx: &mut i32,
+      _r: &GqlSchema<i32>,
+    ) -> ResResult {
+      let input = match get_input(&args, "input") {
+        MaybeInput::Value(GqlValue::Object(o)) => o.clone(),
+        _ => {
+          return Err(ResolutionErr::new_missing_argument(
+            "Mutation", "edit", "input",
+          ))
+        }
+      };
+      let outcome = match get_input(&input, "name") {
+        MaybeInput::Absent => "unchanged".to_owned(),
+        MaybeInput::Null => "cleared".to_owned(),
+        MaybeInput::Value(GqlValue::String(name)) => format!("set to {}", name),
+        MaybeInput::Value(_) => "invalid".to_owned(),
+      };
+      Ok(ResolutionReturn::Scalar(GqlValue::String(outcome)))
+    }
+    schema
+      .add_resolvers(vec![Resolver::new(
+        Box::new(resolve_edit),
+        "Mutation",
+        "edit",
+      )])
+      .unwrap();
+
+    let edit = |query: &str| {
+      let req = GqlRequest {
+        query: query.to_owned(),
+        operation_name: None,
+        variables: None,
+        extensions: None,
+      };
+      schema.resolve(&mut 0, req, None).unwrap()
+    };
+    assert_eq!(
+      edit("mutation { edit(input: {}) }"),
+      json!({ "edit": "unchanged" })
+    );
+    assert_eq!(
+      edit("mutation { edit(input: { name: null }) }"),
+      json!({ "edit": "cleared" })
+    );
+    assert_eq!(
+      edit(r#"mutation { edit(input: { name: "joe" }) }"#),
+      json!({ "edit": "set to joe" })
+    );
+  }
+
+  #[test]
+  fn directive_locations() {
+    let schema: GqlSchema<()> = GqlSchema::new(
+      graphql_parser::parse_schema(
+        "directive @auth on FIELD | FRAGMENT_SPREAD\ntype Query { a: String }",
+      )
+      .unwrap(),
+    )
+    .unwrap();
+    let mut root = BTreeMap::new();
+    root.insert("name".to_owned(), GqlValue::String("auth".to_owned()));
+    let locations =
+      introspect::r_directive_locations(&root, BTreeMap::new(), &mut (), &schema).unwrap();
+    match locations {
+      ResolutionReturn::ScalarList(val) => assert_eq!(
+        val,
+        vec![
+          GqlValue::Enum("FIELD".to_owned()),
+          GqlValue::Enum("FRAGMENT_SPREAD".to_owned()),
+        ]
+      ),
+      _ => panic!("Expected a scalar list of locations"),
+    }
+  }
+
+  #[test]
+  fn schema_directives() {
+    let schema: GqlSchema<()> = GqlSchema::new(
+      graphql_parser::parse_schema(
+        "directive @auth(role: String!) on FIELD
+type Query { a: String }",
+      )
+      .unwrap(),
+    )
+    .unwrap();
+    let req = GqlRequest {
+      query: "query { __schema { directives { name locations args { name type { kind ofType { name } } } } } }"
+        .to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    let data = schema.resolve(&mut (), req, None).unwrap();
+    let directives = data["__schema"]["directives"].as_array().unwrap();
+    let find = |name: &str| {
+      directives
+        .iter()
+        .find(|d| d["name"] == json!(name))
+        .unwrap_or_else(|| panic!("{} should be listed", name))
+        .clone()
+    };
+    assert_eq!(
+      find("skip")["args"],
+      json!([{ "name": "if", "type": { "kind": "NON_NULL", "ofType": { "name": "Boolean" } } }])
+    );
+    assert!(find("include")["locations"]
+      .as_array()
+      .unwrap()
+      .contains(&json!("INLINE_FRAGMENT")));
+    assert_eq!(find("auth")["locations"], json!(["FIELD"]));
+    assert_eq!(
+      find("auth")["args"],
+      json!([{ "name": "role", "type": { "kind": "NON_NULL", "ofType": { "name": "String" } } }])
+    );
+  }
+
+  #[test]
+  fn deprecated_fields() {
+    let schema: GqlSchema<()> = GqlSchema::new(
+      graphql_parser::parse_schema(
+        "enum Role { ADMIN MOD @deprecated }
+        type Query {
+          old: String @deprecated(reason: \"Use current\")
+          current: String
+          role: Role
+        }",
+      )
+      .unwrap(),
+    )
+    .unwrap();
+    let req = GqlRequest {
+      query: "query { __schema { queryType { fields { name isDeprecated deprecationReason } } } }"
+        .to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    let data = schema.resolve(&mut (), req, None).unwrap();
+    let fields = data["__schema"]["queryType"]["fields"].as_array().unwrap();
+    assert!(fields.contains(
+      &json!({ "name": "old", "isDeprecated": true, "deprecationReason": "Use current" })
+    ));
+    assert!(fields
+      .contains(&json!({ "name": "current", "isDeprecated": false, "deprecationReason": null })));
+
+    let mut root = BTreeMap::new();
+    root.insert("name".to_owned(), GqlValue::String("Role".to_owned()));
+    root.insert("kind".to_owned(), GqlValue::Enum("ENUM".to_owned()));
+    match introspect::r_type_enumvals(&root, BTreeMap::new(), &mut (), &schema).unwrap() {
+      ResolutionReturn::TypeList((_, values)) => {
+        assert_eq!(
+          values[0].get("isDeprecated"),
+          Some(&GqlValue::Boolean(false))
+        );
+        assert_eq!(
+          values[1].get("isDeprecated"),
+          Some(&GqlValue::Boolean(true))
+        );
+        assert_eq!(
+          values[1].get("deprecationReason"),
+          Some(&GqlValue::String("No longer supported".to_owned()))
+        );
+      }
+      _ => panic!("Expected a list of enum values"),
+    }
+  }
+
+  #[test]
+  fn wrapper_types_have_no_name() {
+    let schema: GqlSchema<()> =
+      GqlSchema::new(graphql_parser::parse_schema("type Query { tags: [String]! }").unwrap())
+        .unwrap();
+    let req = GqlRequest {
+      query: "query { __schema { queryType { fields {
+        type { name kind ofType { name kind ofType { name kind } } }
+      } } } }"
+        .to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    let data = schema.resolve(&mut (), req, None).unwrap();
+    assert_eq!(
+      data["__schema"]["queryType"]["fields"][0]["type"],
+      json!({
+        "name": null,
+        "kind": "NON_NULL",
+        "ofType": {
+          "name": null,
+          "kind": "LIST",
+          "ofType": { "name": "String", "kind": "SCALAR" }
+        }
+      })
+    );
+  }
+
+  #[test]
+  fn input_value_defaults() {
+    let schema: GqlSchema<()> = GqlSchema::new(
+      graphql_parser::parse_schema(
+        "input NewUser { name: String! role: String = \"member\" }
+        type Query { a: String }",
+      )
+      .unwrap(),
+    )
+    .unwrap();
+    let default_of = |field: &str| {
+      let mut root = BTreeMap::new();
+      root.insert("name".to_owned(), GqlValue::String(field.to_owned()));
+      root.insert(
+        "parentTypename".to_owned(),
+        GqlValue::String("NewUser".to_owned()),
+      );
+      match introspect::r_inputvalue_default(&root, BTreeMap::new(), &mut (), &schema).unwrap() {
+        ResolutionReturn::Scalar(value) => value,
+        _ => panic!("Expected a scalar default"),
+      }
+    };
+    assert_eq!(default_of("role"), GqlValue::String("member".to_owned()));
+    assert_eq!(default_of("name"), GqlValue::Null);
+  }
+
+  #[test]
+  fn max_expanded_fields() {
+    let mut schema: GqlSchema<()> = GqlSchema::new_with_policy(
+      graphql_parser::parse_schema("type Query { a: String b: String }").unwrap(),
+      MissingResolverPolicy::Null,
+    )
+    .unwrap();
+    let req = || GqlRequest {
+      query: "query { ...ab ...ab } fragment ab on Query { a b }".to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    assert!(schema.resolve(&mut (), req(), None).is_ok());
+
+    schema.set_max_expanded_fields(Some(3));
+    match schema.resolve(&mut (), req(), None) {
+      Err(ResolutionErr::QueryValidation(GqlQueryErr::Field(_))) => {}
+      other => panic!("Expected the field limit to be hit, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn max_complexity() {
+    let schema: GqlSchema<()> = GqlSchema::new_with_policy(
+      graphql_parser::parse_schema(
+        "type Query { channels: [Channel] }
+        type Channel { id: ID messages: [Message] }
+        type Message { id: ID content: String }",
+      )
+      .unwrap(),
+      MissingResolverPolicy::Null,
+    )
+    .unwrap();
+    let req = |query: &str| GqlRequest {
+      query: query.to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    // 1 + 10 * (1 + 1 + 10 * (1 + 1)) = 221
+    let nested = "query { channels { id messages { id content } } }";
+
+    let schema = schema.with_max_complexity(Some(220), 10);
+    match schema.resolve(&mut (), req(nested), None) {
+      Err(ResolutionErr::QueryValidation(GqlQueryErr::TooComplex(e))) => {
+        assert!(format!("{:?}", e).contains("Query costs 221"));
+      }
+      other => panic!("Expected the query to be too complex, got {:?}", other),
+    }
+    // 1 + 10 * 1 = 11
+    assert!(schema
+      .resolve(&mut (), req("query { channels { id } }"), None)
+      .is_ok());
+  }
+
+  #[test]
+  fn custom_scalars() {
+    use chrono::NaiveDateTime;
+
+    let mut schema: GqlSchema<()> = GqlSchema::new(
+      graphql_parser::parse_schema(
+        "scalar DateTime
+        type Query { echo(at: DateTime!): DateTime }",
+      )
+      .unwrap(),
+    )
+    .unwrap();
+    fn parse_datetime(raw: &str) -> Result<GqlValue, String> {
+      NaiveDateTime::parse_from_str(raw, "%Y-%m-%dT%H:%M:%S")
+        .map(|at| GqlValue::Int((at.timestamp() as i32).into()))
+        .map_err(|e| e.to_string())
+    }
+    fn serialize_datetime(value: &GqlValue) -> JsonValue {
+      match value {
+        GqlValue::Int(secs) => json!(NaiveDateTime::from_timestamp(secs.as_i64().unwrap(), 0)
+          .format("%Y-%m-%dT%H:%M:%S")
+          .to_string()),
+        _ => JsonValue::Null,
+      }
+    }
+    fn resolve_echo(_: &GqlRoot, args: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      let at = args.get("at").unwrap().clone();
+      assert_eq!(at, GqlValue::Int(1_580_472_000.into()));
+      Ok(ResolutionReturn::Scalar(at))
+    }
+    assert!(schema
+      .add_scalar("Date", parse_datetime, serialize_datetime)
+      .is_err());
+    schema
+      .add_scalar("DateTime", parse_datetime, serialize_datetime)
+      .unwrap();
+    schema
+      .add_resolvers(vec![Resolver::new(Box::new(resolve_echo), "Query", "echo")])
+      .unwrap();
+    assert!(schema.is_scalar("DateTime"));
+
+    let req = |at: &str| GqlRequest {
+      query: "query Echo($at: DateTime!) { echo(at: $at) }".to_owned(),
+      operation_name: None,
+      variables: Some(json!({ "at": at })),
+      extensions: None,
+    };
+    assert_eq!(
+      schema
+        .resolve(&mut (), req("2020-01-31T12:00:00"), None)
+        .unwrap(),
+      json!({ "echo": "2020-01-31T12:00:00" })
+    );
+    match schema.resolve(&mut (), req("yesterday"), None) {
+      Err(ResolutionErr::QueryValidation(GqlQueryErr::Type(_))) => {}
+      other => panic!("Expected a bad DateTime to be rejected, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn resolvers_match_schema() {
+    let mut schema: GqlSchema<()> =
+      GqlSchema::new(graphql_parser::parse_schema("type Query { a: String }").unwrap()).unwrap();
+    assert!(schema.check_resolvers().is_ok());
+
+    fn resolve_nothing(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      Ok(ResolutionReturn::Scalar(GqlValue::Null))
+    }
+    schema.resolvers.get_mut("__Type").unwrap().insert(
+      "bogus".to_owned(),
+      Resolver::new(Box::new(resolve_nothing), "__Type", "bogus"),
+    );
+    match schema.check_resolvers() {
+      Err(GqlSchemaErr::UnknownResolverField((on_type, field))) => {
+        assert_eq!((on_type.as_str(), field.as_str()), ("__Type", "bogus"));
+      }
+      other => panic!("Expected drift to be caught, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn partial_list_failure() {
+    let mut schema: GqlSchema<()> = GqlSchema::new(
+      graphql_parser::parse_schema(
+        "type Item { id: Int name: String! }
+        type Query { items: [Item] strict: [Item!] required: [Item!]! }",
+      )
+      .unwrap(),
+    )
+    .unwrap();
+
+    fn resolve_items(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      Ok(ResolutionReturn::TypeList((
+        "Item".to_owned(),
+        (1..4)
+          .map(|id| {
+            let mut bmap = BTreeMap::new();
+            bmap.insert("id".to_owned(), GqlValue::Int(query::Number::from(id)));
+            bmap
+          })
+          .collect(),
+      )))
+    }
+    fn resolve_name(root: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      match root.get("id") {
+        Some(GqlValue::Int(id)) if id.as_i64() == Some(2) => {
+          Err(ResolutionErr::NotFound("Item 2".to_owned()))
+        }
+        _ => Ok(ResolutionReturn::Scalar(GqlValue::String("ok".to_owned()))),
+      }
+    }
+    schema
+      .add_resolvers(vec![
+        Resolver::new(Box::new(resolve_items), "Query", "items"),
+        Resolver::new(Box::new(resolve_items), "Query", "strict"),
+        Resolver::new(Box::new(resolve_items), "Query", "required"),
+        Resolver::new(Box::new(resolve_name), "Item", "name"),
+      ])
+      .unwrap();
+
+    let req = |query: &str| GqlRequest {
+      query: query.to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    let (data, errors) = schema
+      .resolve_partial(&mut (), req("query { items { id name } }"), None)
+      .unwrap();
+    let items = data["items"].as_array().unwrap();
+    assert_eq!(items.len(), 3);
+    assert_eq!(items.iter().filter(|i| i.is_null()).count(), 1);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(json!(errors[0])["path"], json!(["items", 1, "name"]));
+
+    // non-null items can't be nulled, so the list is nulled instead
+    let (data, errors) = schema
+      .resolve_partial(&mut (), req("query { strict { id name } }"), None)
+      .unwrap();
+    assert_eq!(data, json!({ "strict": null }));
+    assert_eq!(errors.len(), 1);
+
+    // and when the list can't be nulled either, the whole request fails
+    assert!(schema
+      .resolve_partial(&mut (), req("query { required { id name } }"), None)
+      .is_err());
+  }
+
+  #[test]
+  fn nested_lists() {
+    let mut schema: GqlSchema<()> = GqlSchema::new(
+      graphql_parser::parse_schema(
+        "type Item { id: Int name: String! }
+        type Query { grid: [[Int]] pages: [[Item!]]! }",
+      )
+      .unwrap(),
+    )
+    .unwrap();
+
+    fn int(i: i32) -> GqlValue {
+      GqlValue::Int(query::Number::from(i))
+    }
+    fn resolve_grid(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      Ok(ResolutionReturn::Scalar(GqlValue::List(vec![
+        GqlValue::List(vec![int(1), int(2)]),
+        GqlValue::List(vec![]),
+        GqlValue::List(vec![int(3)]),
+      ])))
+    }
+    fn resolve_pages(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      let item = |id: i32| {
+        let mut bmap = GqlObj::new();
+        bmap.insert("id".to_owned(), int(id));
+        bmap
+      };
+      Ok(ResolutionReturn::List(vec![
+        ResolutionReturn::TypeList(("Item".to_owned(), vec![item(1), item(2)])),
+        ResolutionReturn::TypeList(("Item".to_owned(), vec![])),
+        ResolutionReturn::TypeList(("Item".to_owned(), vec![item(3)])),
+      ]))
+    }
+    fn resolve_name(root: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      match root.get("id") {
+        Some(GqlValue::Int(id)) => Ok(ResolutionReturn::Scalar(GqlValue::String(format!(
+          "item {}",
+          id.as_i64().unwrap()
+        )))),
+        _ => Err(ResolutionErr::NotFound("Item".to_owned())),
+      }
+    }
+    schema
+      .add_resolvers(vec![
+        Resolver::new(Box::new(resolve_grid), "Query", "grid"),
+        Resolver::new(Box::new(resolve_pages), "Query", "pages"),
+        Resolver::new(Box::new(resolve_name), "Item", "name"),
+      ])
+      .unwrap();
+
+    let req = GqlRequest {
+      query: "query { grid pages { id name } }".to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    let data = schema.resolve(&mut (), req, None).unwrap();
+    assert_eq!(data["grid"], json!([[1, 2], [], [3]]));
+    // objects keep both their depth and their order
+    assert_eq!(
+      data["pages"],
+      json!([
+        [{ "id": 1, "name": "item 1" }, { "id": 2, "name": "item 2" }],
+        [],
+        [{ "id": 3, "name": "item 3" }]
+      ])
+    );
+  }
+
+  #[test]
+  fn scalar_lists() {
+    let mut schema: GqlSchema<()> = GqlSchema::new(
+      graphql_parser::parse_schema("type Query { tags: [String] none: [String!]! }").unwrap(),
+    )
+    .unwrap();
+
+    fn resolve_tags(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      Ok(ResolutionReturn::ScalarList(vec![
+        GqlValue::String("urgent".to_owned()),
+        GqlValue::Null,
+        GqlValue::String("later".to_owned()),
+      ]))
+    }
+    fn resolve_none(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      Ok(ResolutionReturn::ScalarList(vec![]))
+    }
+    schema
+      .add_resolvers(vec![
+        Resolver::new(Box::new(resolve_tags), "Query", "tags"),
+        Resolver::new(Box::new(resolve_none), "Query", "none"),
+      ])
+      .unwrap();
+
+    let req = GqlRequest {
+      query: "query { tags none }".to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    let data = schema.resolve(&mut (), req, None).unwrap();
+    assert_eq!(data["tags"], json!(["urgent", null, "later"]));
+    assert_eq!(data["none"], json!([]));
+  }
+
+  #[test]
+  fn sibling_field_failure() {
+    let mut schema: GqlSchema<()> = GqlSchema::new(
+      graphql_parser::parse_schema("type Query { good: String bad: String required: String! }")
+        .unwrap(),
+    )
+    .unwrap();
+
+    fn resolve_good(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      Ok(ResolutionReturn::Scalar(GqlValue::String(
+        "fine".to_owned(),
+      )))
+    }
+    fn resolve_bad(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      Err(ResolutionErr::NotFound("Nothing here".to_owned()))
+    }
+    schema
+      .add_resolvers(vec![
+        Resolver::new(Box::new(resolve_good), "Query", "good"),
+        Resolver::new(Box::new(resolve_bad), "Query", "bad"),
+        Resolver::new(Box::new(resolve_bad), "Query", "required"),
+      ])
+      .unwrap();
+
+    let req = |query: &str| GqlRequest {
+      query: query.to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    let (data, errors) = schema
+      .resolve_partial(&mut (), req("query { good bad }"), None)
+      .unwrap();
+    assert_eq!(data, json!({ "good": "fine", "bad": null }));
+    assert_eq!(errors.len(), 1);
+    assert_eq!(json!(errors[0])["path"], json!(["bad"]));
+    assert_eq!(
+      json!(GqlResponse::from(Ok((data, errors))))["errors"][0]["extensions"]["code"],
+      json!("NOT_FOUND")
+    );
+
+    // a non-null field has nowhere to put the null, so the request fails
+    assert!(schema
+      .resolve_partial(&mut (), req("query { good required }"), None)
+      .is_err());
+  }
+
+  #[test]
+  fn denied_fields() {
+    let mut schema: GqlSchema<()> = GqlSchema::new(
+      graphql_parser::parse_schema("type Query { search: String me: String }").unwrap(),
+    )
+    .unwrap();
+    fn resolve_str(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      Ok(ResolutionReturn::Scalar(GqlValue::String("ok".to_owned())))
+    }
+    schema
+      .add_resolvers(vec![
+        Resolver::new(Box::new(resolve_str), "Query", "search"),
+        Resolver::new(Box::new(resolve_str), "Query", "me"),
+      ])
+      .unwrap();
+    let denylist = FieldDenylist::default();
+    schema.set_denylist(denylist.clone());
+
+    let req = || GqlRequest {
+      query: "query { search me }".to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    assert_eq!(
+      schema.resolve(&mut (), req(), None).unwrap(),
+      json!({ "search": "ok", "me": "ok" })
+    );
+
+    // changes show up in schemas that were given the list earlier
+    denylist.set(&["Query.search".to_owned()]).unwrap();
+    assert_eq!(denylist.fields(), vec!["Query.search".to_owned()]);
+    let (data, errors) = schema.resolve_partial(&mut (), req(), None).unwrap();
+    assert_eq!(data, json!({ "search": null, "me": "ok" }));
+    assert_eq!(json!(errors[0])["extensions"]["code"], json!("FORBIDDEN"));
+
+    // a bad entry leaves the list as it was
+    assert_eq!(
+      denylist.set(&["search".to_owned()]),
+      Err("search".to_owned())
+    );
+    assert!(denylist.is_denied("Query", "search"));
+  }
+
+  #[test]
+  fn auth_directive() {
+    const SCHEMA: &str = "
+      enum Role { MEMBER ADMIN }
+      directive @auth(requires: Role!) on FIELD_DEFINITION
+      type Query {
+        messages: String @auth(requires: MEMBER)
+        settings: String @auth(requires: ADMIN)
+      }";
+    // the context is the current user's role
+    let mut schema: GqlSchema<String> =
+      GqlSchema::new(graphql_parser::parse_schema(SCHEMA).unwrap()).unwrap();
+    fn resolve_str(_: &GqlRoot, _: GqlArgs, _: &mut String, _: &GqlSchema<String>) -> ResResult {
+      Ok(ResolutionReturn::Scalar(GqlValue::String("ok".to_owned())))
+    }
+    fn role(
+      _: &str,
+      _: &GqlRoot,
+      _: &GqlArgs,
+      role: &mut String,
+    ) -> Result<Option<String>, ResolutionErr> {
+      Ok(Some(role.clone()))
+    }
+    schema
+      .add_resolvers(vec![
+        Resolver::new(Box::new(resolve_str), "Query", "messages"),
+        Resolver::new(Box::new(resolve_str), "Query", "settings"),
+      ])
+      .unwrap();
+    let req = || GqlRequest {
+      query: "query { messages settings }".to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+
+    // nobody gets in before there is a way to look up roles
+    let (data, _) = schema
+      .resolve_partial(&mut "admin".to_owned(), req(), None)
+      .unwrap();
+    assert_eq!(data, json!({ "messages": null, "settings": null }));
+
+    schema.set_role_lookup(role);
+    let (data, errors) = schema
+      .resolve_partial(&mut "member".to_owned(), req(), None)
+      .unwrap();
+    assert_eq!(data, json!({ "messages": "ok", "settings": null }));
+    assert_eq!(errors.len(), 1);
+    assert_eq!(
+      json!(errors[0])["extensions"]["code"],
+      json!("UNAUTHENTICATED")
+    );
+    assert_eq!(
+      schema
+        .resolve(&mut "admin".to_owned(), req(), None)
+        .unwrap(),
+      json!({ "messages": "ok", "settings": "ok" })
+    );
+
+    // the required role has to be one of the enum's values
+    let invalid = SCHEMA.replace("requires: ADMIN", "requires: OWNER");
+    match GqlSchema::<()>::new(graphql_parser::parse_schema(&invalid).unwrap()) {
+      Err(GqlSchemaErr::InvalidDirective(_)) => {}
+      other => panic!("expected an invalid directive, got {:?}", other.map(|_| ())),
+    }
+  }
+
+  #[test]
+  fn typename_survives_sparsify() {
+    let mut schema: GqlSchema<()> = GqlSchema::new(
+      graphql_parser::parse_schema(
+        "type Query { channel: Channel channels: [Channel] }
+        type Channel { id: ID name: String }",
+      )
+      .unwrap(),
+    )
+    .unwrap();
+
+    fn channel() -> GqlObj {
+      let mut bmap = GqlObj::new();
+      bmap.insert("id".to_owned(), GqlValue::String("1".to_owned()));
+      bmap.insert("name".to_owned(), GqlValue::String("general".to_owned()));
+      bmap.insert("secret".to_owned(), GqlValue::String("hidden".to_owned()));
+      bmap
+    }
+    fn resolve_channel(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      Ok(ResolutionReturn::Type(("Channel".to_owned(), channel())))
+    }
+    fn resolve_channels(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      Ok(ResolutionReturn::TypeList((
+        "Channel".to_owned(),
+        vec![channel()],
+      )))
+    }
+    schema
+      .add_resolvers(vec![
+        Resolver::new(Box::new(resolve_channel), "Query", "channel"),
+        Resolver::new(Box::new(resolve_channels), "Query", "channels"),
+      ])
+      .unwrap();
+
+    let req = GqlRequest {
+      query: "query { __typename channel { __typename id } channels { kind: __typename id } }"
+        .to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    assert_eq!(
+      schema.resolve(&mut (), req, None).unwrap(),
+      json!({
+        "__typename": "Query",
+        "channel": { "__typename": "Channel", "id": "1" },
+        "channels": [{ "kind": "Channel", "id": "1" }]
+      })
+    );
+  }
+
+  #[test]
+  fn required_arguments() {
+    let mut schema: GqlSchema<()> = GqlSchema::new(
+      graphql_parser::parse_schema(
+        "input CreateMessageInput { content: String! }
+        type Query { count(n: Int!): Int }
+        type Mutation { createMessage(input: CreateMessageInput!, silent: Boolean! = false): String }",
+      )
+      .unwrap(),
+    )
+    .unwrap();
+
+    fn resolve_create(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      Ok(ResolutionReturn::Scalar(GqlValue::String(
+        "created".to_owned(),
+      )))
+    }
+    fn resolve_count(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      Ok(ResolutionReturn::Scalar(GqlValue::Int(
+        query::Number::from(1),
+      )))
+    }
+    schema
+      .add_resolvers(vec![
+        Resolver::new(Box::new(resolve_create), "Mutation", "createMessage"),
+        Resolver::new(Box::new(resolve_count), "Query", "count"),
+      ])
+      .unwrap();
+
+    let req = |query: &str| GqlRequest {
+      query: query.to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    let err = schema
+      .resolve(&mut (), req("mutation { createMessage }"), None)
+      .unwrap_err();
+    match err.cause() {
+      ResolutionErr::MissingArgument(missing) => {
+        assert_eq!(missing.on_type, "Mutation");
+        assert_eq!(missing.on_field, "createMessage");
+        assert_eq!(missing.name, "input");
+      }
+      other => panic!("Expected a missing argument, got {:?}", other),
+    }
+    assert_eq!(err.error_code(), "BAD_USER_INPUT");
+
+    // arguments with a default may be left out
+    assert_eq!(
+      schema
+        .resolve(
+          &mut (),
+          req(r#"mutation { createMessage(input: { content: "hi" }) }"#),
+          None
+        )
+        .unwrap(),
+      json!({ "createMessage": "created" })
+    );
+
+    assert!(schema
+      .resolve(&mut (), req(r#"query { count(n: "one") }"#), None)
+      .is_err());
+  }
+
+  #[test]
+  fn non_null_propagation() {
+    let mut schema: GqlSchema<()> = GqlSchema::new(
+      graphql_parser::parse_schema(
+        "type Query { channel: Channel required: Channel! name: String! }
+        type Channel { id: ID owner: User }
+        type User { name: String! }",
+      )
+      .unwrap(),
+    )
+    .unwrap();
+
+    fn resolve_channel(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      let mut channel = GqlObj::new();
+      channel.insert("id".to_owned(), GqlValue::String("1".to_owned()));
+      Ok(ResolutionReturn::Type(("Channel".to_owned(), channel)))
+    }
+    fn resolve_owner(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      Ok(ResolutionReturn::Type(("User".to_owned(), GqlObj::new())))
+    }
+    fn resolve_null(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      Ok(ResolutionReturn::Scalar(GqlValue::Null))
+    }
+    schema
+      .add_resolvers(vec![
+        Resolver::new(Box::new(resolve_channel), "Query", "channel"),
+        Resolver::new(Box::new(resolve_channel), "Query", "required"),
+        Resolver::new(Box::new(resolve_null), "Query", "name"),
+        Resolver::new(Box::new(resolve_owner), "Channel", "owner"),
+        Resolver::new(Box::new(resolve_null), "User", "name"),
+      ])
+      .unwrap();
+
+    let req = |query: &str| GqlRequest {
+      query: query.to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    // the null stops at the nearest nullable field, which is the owner
+    let (data, errors) = schema
+      .resolve_partial(
+        &mut (),
+        req("query { channel { id owner { name } } }"),
+        None,
+      )
+      .unwrap();
+    assert_eq!(data, json!({ "channel": { "id": "1", "owner": null } }));
+    assert_eq!(errors.len(), 1);
+    assert_eq!(
+      json!(errors[0])["path"],
+      json!(["channel", "owner", "name"])
+    );
+
+    let (data, errors) = schema
+      .resolve_partial(
+        &mut (),
+        req("query { channel { id } required { owner { name } } }"),
+        None,
+      )
+      .unwrap();
+    assert_eq!(
+      data,
+      json!({ "channel": { "id": "1" }, "required": { "owner": null } })
+    );
+    assert_eq!(errors.len(), 1);
+
+    // a null that reaches the root fails the request
+    assert!(schema
+      .resolve(&mut (), req("query { name }"), None)
+      .is_err());
+  }
+
+  #[test]
+  fn prefilled_object_fields() {
+    let mut schema: GqlSchema<()> = GqlSchema::new(
+      graphql_parser::parse_schema(
+        "type Query { entry: Entry }
+        type Entry { channel: Channel }
+        type Channel { id: ID name: String }",
+      )
+      .unwrap(),
+    )
+    .unwrap();
+
+    fn resolve_entry(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      let mut channel = GqlObj::new();
+      channel.insert("id".to_owned(), GqlValue::String("1".to_owned()));
+      let mut entry = GqlObj::new();
+      entry.insert("channel".to_owned(), GqlValue::Object(channel));
+      Ok(ResolutionReturn::Type(("Entry".to_owned(), entry)))
+    }
+    fn resolve_name(root: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      assert_eq!(root.get("id"), Some(&GqlValue::String("1".to_owned())));
+      Ok(ResolutionReturn::Scalar(GqlValue::String(
+        "general".to_owned(),
+      )))
+    }
+    schema
+      .add_resolvers(vec![
+        Resolver::new(Box::new(resolve_entry), "Query", "entry"),
+        Resolver::new(Box::new(resolve_name), "Channel", "name"),
+      ])
+      .unwrap();
+
+    let req = GqlRequest {
+      query: "query { entry { channel { id name } } }".to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    // the fields left out of the prefilled channel are still resolved
+    assert_eq!(
+      schema.resolve(&mut (), req, None).unwrap(),
+      json!({ "entry": { "channel": { "id": "1", "name": "general" } } })
+    );
+  }
+
+  #[test]
+  fn inline_fragment_conditions() {
+    let mut schema: GqlSchema<()> = GqlSchema::new(
+      graphql_parser::parse_schema(
+        "type Query { message: Message }
+        type Message { id: ID content: String }
+        type Channel { name: String }",
+      )
+      .unwrap(),
+    )
+    .unwrap();
+
+    fn resolve_message(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      let mut message = GqlObj::new();
+      message.insert("id".to_owned(), GqlValue::String("1".to_owned()));
+      message.insert("content".to_owned(), GqlValue::String("hi".to_owned()));
+      Ok(ResolutionReturn::Type(("Message".to_owned(), message)))
+    }
+    schema
+      .add_resolvers(vec![Resolver::new(
+        Box::new(resolve_message),
+        "Query",
+        "message",
+      )])
+      .unwrap();
+
+    let req = GqlRequest {
+      query: "query { message { id ... on Message { content } ... on Channel { name } } }"
+        .to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    assert_eq!(
+      schema.resolve(&mut (), req, None).unwrap(),
+      json!({ "message": { "id": "1", "content": "hi" } })
+    );
+  }
+
+  #[test]
+  fn union_members() {
+    let mut schema: GqlSchema<()> = GqlSchema::new(
+      graphql_parser::parse_schema(
+        "union SearchResult = Message | Channel
+        type Query { search: [SearchResult] top: SearchResult }
+        type Message { id: ID content: String }
+        type Channel { id: ID name: String }",
+      )
+      .unwrap(),
+    )
+    .unwrap();
+
+    fn result(typename: &str, field: &str, value: &str) -> GqlObj {
+      let mut obj = GqlObj::new();
+      obj.insert(
+        "__typename".to_owned(),
+        GqlValue::String(typename.to_owned()),
+      );
+      obj.insert("id".to_owned(), GqlValue::String("1".to_owned()));
+      obj.insert(field.to_owned(), GqlValue::String(value.to_owned()));
+      obj
+    }
+    fn resolve_search(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      Ok(ResolutionReturn::TypeList((
+        "SearchResult".to_owned(),
+        vec![
+          result("Message", "content", "hi"),
+          result("Channel", "name", "general"),
+        ],
+      )))
+    }
+    fn resolve_top(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      Ok(ResolutionReturn::Type((
+        "SearchResult".to_owned(),
+        result("Channel", "name", "general"),
+      )))
+    }
+    schema
+      .add_resolvers(vec![
+        Resolver::new(Box::new(resolve_search), "Query", "search"),
+        Resolver::new(Box::new(resolve_top), "Query", "top"),
+      ])
+      .unwrap();
+
+    let req = GqlRequest {
+      query: "query {
+        search { __typename ... on Message { content } ... on Channel { id name } }
+        top { ... on Message { content } ... on Channel { name } }
+      }"
+      .to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    let data = schema.resolve(&mut (), req, None).unwrap();
+    let search = data["search"].as_array().unwrap();
+    assert_eq!(search.len(), 2);
+    assert!(search.contains(&json!({ "__typename": "Message", "content": "hi" })));
+    assert!(search.contains(&json!({ "__typename": "Channel", "id": "1", "name": "general" })));
+    assert_eq!(data["top"], json!({ "name": "general" }));
+
+    // named fragments only apply to their own type too
+    let req = GqlRequest {
+      query: "query { search { __typename ...MessageContent ...ChannelName } }
+        fragment MessageContent on Message { content }
+        fragment ChannelName on Channel { name }"
+        .to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    let data = schema.resolve(&mut (), req, None).unwrap();
+    let search = data["search"].as_array().unwrap();
+    assert!(search.contains(&json!({ "__typename": "Message", "content": "hi" })));
+    assert!(search.contains(&json!({ "__typename": "Channel", "name": "general" })));
+  }
+
+  #[test]
+  fn interface_fields() {
+    let mut schema: GqlSchema<()> = GqlSchema::new(
+      graphql_parser::parse_schema(
+        "interface Node { id: ID! }
+        type Message implements Node { id: ID! content: String }
+        type Query { node: Node message: Message }",
+      )
+      .unwrap(),
+    )
+    .unwrap();
+
+    fn message() -> GqlObj {
+      let mut obj = GqlObj::new();
+      obj.insert(
+        "__typename".to_owned(),
+        GqlValue::String("Message".to_owned()),
+      );
+      obj.insert("id".to_owned(), GqlValue::String("1".to_owned()));
+      obj.insert("content".to_owned(), GqlValue::String("hi".to_owned()));
+      obj
+    }
+    fn resolve_node(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      Ok(ResolutionReturn::Type(("Node".to_owned(), message())))
+    }
+    fn resolve_message(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      Ok(ResolutionReturn::Type(("Message".to_owned(), message())))
+    }
+    schema
+      .add_resolvers(vec![
+        Resolver::new(Box::new(resolve_node), "Query", "node"),
+        Resolver::new(Box::new(resolve_message), "Query", "message"),
+      ])
+      .unwrap();
+
+    let req = GqlRequest {
+      query: "query {
+        node { id ... on Message { content } }
+        message { ... on Node { id } __type { name interfaces { name kind } } }
+      }"
+      .to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    assert_eq!(
+      schema.resolve(&mut (), req, None).unwrap(),
+      json!({
+        "node": { "id": "1", "content": "hi" },
+        "message": {
+          "id": "1",
+          "__type": { "name": "Message", "interfaces": [{ "name": "Node", "kind": "INTERFACE" }] }
+        }
+      })
+    );
+  }
+
+  #[test]
+  fn nested_error_path() {
+    let mut schema: GqlSchema<()> = GqlSchema::new(
+      graphql_parser::parse_schema(
+        "type Query { channel: Channel! }
+        type Channel { messages: [Message!]! }
+        type Message { sender: String! }",
+      )
+      .unwrap(),
+    )
+    .unwrap();
+
+    fn resolve_channel(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      Ok(ResolutionReturn::Type((
+        "Channel".to_owned(),
+        BTreeMap::new(),
+      )))
+    }
+    fn resolve_messages(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      Ok(ResolutionReturn::TypeList((
+        "Message".to_owned(),
+        vec![BTreeMap::new()],
+      )))
+    }
+    fn resolve_sender(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      Err(ResolutionErr::NotFound("User 3".to_owned()))
+    }
+    schema
+      .add_resolvers(vec![
+        Resolver::new(Box::new(resolve_channel), "Query", "channel"),
+        Resolver::new(Box::new(resolve_messages), "Channel", "messages"),
+        Resolver::new(Box::new(resolve_sender), "Message", "sender"),
+      ])
+      .unwrap();
+
+    let req = GqlRequest {
+      query: "query {\n  channel { messages { sender } }\n}".to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    let err = schema.resolve(&mut (), req, None).unwrap_err();
+    assert_eq!(
+      err.path(),
+      &[
+        json!("channel"),
+        json!("messages"),
+        json!(0),
+        json!("sender")
+      ][..]
+    );
+    let error = json!(GqlError::from(err));
+    assert_eq!(error["path"], json!(["channel", "messages", 0, "sender"]));
+    assert_eq!(error["locations"][0]["line"], json!(2));
+    assert_eq!(error["extensions"]["code"], json!("NOT_FOUND"));
+    assert!(error.get("NotFound").is_some());
+  }
+
+  #[test]
+  fn invalid_enum_value() {
+    let mut schema: GqlSchema<String> = GqlSchema::new(
+      graphql_parser::parse_schema("enum Role { ADMIN MEMBER } type Query { role: Role }").unwrap(),
+    )
+    .unwrap();
+    fn resolve_role(
+      _: &GqlRoot,
+      _: GqlArgs,
+      role: &mut String,
+      _: &GqlSchema<String>,
+    ) -> ResResult {
+      Ok(ResolutionReturn::Scalar(GqlValue::Enum(role.clone())))
+    }
+    schema
+      .add_resolvers(vec![Resolver::new(Box::new(resolve_role), "Query", "role")])
+      .unwrap();
+    let req = || GqlRequest {
+      query: "query { role }".to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+
+    assert_eq!(
+      schema
+        .resolve(&mut "ADMIN".to_owned(), req(), None)
+        .unwrap(),
+      json!({ "role": "ADMIN" })
+    );
+    // `role` is nullable, so it is nulled and the error is reported alongside
+    let (data, errors) = schema
+      .resolve_partial(&mut "ADMN".to_owned(), req(), None)
+      .unwrap();
+    assert_eq!(data, json!({ "role": null }));
+    assert_eq!(errors.len(), 1);
+    match errors[0].error.cause() {
+      ResolutionErr::QueryResult(msg) => assert!(msg.contains("ADMN")),
+      other => panic!("Expected an invalid enum error, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn input_object_variable() {
+    let mut schema: GqlSchema<()> =
+      GqlSchema::new(graphql_parser::parse_schema(include_str!("../../schema.graphql")).unwrap())
+        .unwrap();
+
+    // reads its input the same way `mutation_create_message` does
+    fn resolve_create(_: &GqlRoot, args: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      let input = match args.get("input") {
+        Some(GqlValue::Object(input)) => input,
+        _ => {
+          return Err(ResolutionErr::new_missing_argument(
+            "Mutation",
+            "createMessage",
+            "input",
+          ))
+        }
+      };
+      let mut bmap = BTreeMap::new();
+      bmap.insert("content".to_owned(), input["content"].clone());
+      bmap.insert("id".to_owned(), input["channel"].clone());
+      Ok(ResolutionReturn::Type(("Message".to_owned(), bmap)))
+    }
+    schema
+      .add_resolvers(vec![Resolver::new(
+        Box::new(resolve_create),
+        "Mutation",
+        "createMessage",
+      )])
+      .unwrap();
+
+    let query =
+      "mutation Send($input: CreateMessageInput) { createMessage(input: $input) { id content } }";
+    let req = |variables: JsonValue| GqlRequest {
+      query: query.to_owned(),
+      operation_name: None,
+      variables: Some(variables),
+      extensions: None,
+    };
+    let sent = schema
+      .resolve(
+        &mut (),
+        req(json!({ "input": { "content": "Hello", "channel": "3" } })),
+        None,
+      )
+      .unwrap();
+    assert_eq!(
+      sent,
+      json!({ "createMessage": { "id": "3", "content": "Hello" } })
+    );
+
+    // required input fields are checked, as are unknown ones
+    let missing = req(json!({ "input": { "channel": "3" } }));
+    assert!(schema.resolve(&mut (), missing, None).is_err());
+    let unknown = req(json!({ "input": { "content": "Hi", "channel": "3", "extra": 1 } }));
+    assert!(schema.resolve(&mut (), unknown, None).is_err());
+
+    // the default input is used when the variable is left out
+    let with_default = GqlRequest {
+      query: r#"mutation Send($input: CreateMessageInput = { content: "hi", channel: 1 }) {
+        createMessage(input: $input) { id content }
+      }"#
+        .to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    assert_eq!(
+      schema.resolve(&mut (), with_default, None).unwrap(),
+      json!({ "createMessage": { "id": 1, "content": "hi" } })
+    );
+  }
+
+  #[test]
+  fn variables_in_arguments() {
+    let mut schema: GqlSchema<Vec<GqlArgs>> =
+      GqlSchema::new(graphql_parser::parse_schema(include_str!("../../schema.graphql")).unwrap())
+        .unwrap();
+
+    // keeps the arguments it was given, so the test can see what they were
+    fn resolve_create(
+      _: &GqlRoot,
+      args: GqlArgs,
+      seen: &mut Vec<GqlArgs>,
+      _: &GqlSchema<Vec<GqlArgs>>,
+    ) -> ResResult {
+      seen.push(args);
+      let mut bmap = BTreeMap::new();
+      bmap.insert("id".to_owned(), GqlValue::String("1".to_owned()));
+      Ok(ResolutionReturn::Type(("Message".to_owned(), bmap)))
+    }
+    schema
+      .add_resolvers(vec![Resolver::new(
+        Box::new(resolve_create),
+        "Mutation",
+        "createMessage",
+      )])
+      .unwrap();
+
+    let req = GqlRequest {
+      query: "mutation Send($text: String!, $channel: ID!) {
+        createMessage(input: { content: $text, channel: $channel }) { id }
+      }"
+      .to_owned(),
+      operation_name: None,
+      variables: Some(json!({ "text": "Hello", "channel": "3" })),
+      extensions: None,
+    };
+    let mut seen = Vec::new();
+    schema.resolve(&mut seen, req, None).unwrap();
+
+    let mut input = GqlObj::new();
+    input.insert("content".to_owned(), GqlValue::String("Hello".to_owned()));
+    input.insert("channel".to_owned(), GqlValue::String("3".to_owned()));
+    assert_eq!(seen.len(), 1);
+    assert_eq!(seen[0]["input"], GqlValue::Object(input));
+  }
+
+  #[test]
+  fn async_resolvers() {
+    use futures::sync::oneshot;
+    use std::{thread, time::Duration};
+
+    let mut schema: GqlSchema<()> = GqlSchema::new(
+      graphql_parser::parse_schema(
+        "type Query { greeting: String channel: Channel }
+        type Channel { id: ID title: String }",
+      )
+      .unwrap(),
+    )
+    .unwrap();
+
+    // gives back `value` from another thread after a short wait
+    fn later(value: ResolutionReturn) -> ResFuture {
+      let (sender, receiver) = oneshot::channel();
+      thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        sender.send(value).unwrap();
+      });
+      Box::new(receiver.map_err(|_| ResolutionErr::QueryResult("Sender dropped".to_owned())))
+    }
+    fn resolve_channel(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResFuture {
+      let mut channel = GqlObj::new();
+      channel.insert("id".to_owned(), GqlValue::String("7".to_owned()));
+      later(ResolutionReturn::Type(("Channel".to_owned(), channel)))
+    }
+    fn resolve_title(parent: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResFuture {
+      let title = match &parent["id"] {
+        GqlValue::String(id) => format!("Channel {}", id),
+        _ => "Unknown".to_owned(),
+      };
+      later(ResolutionReturn::Scalar(GqlValue::String(title)))
+    }
+    fn resolve_greeting(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+      Ok(ResolutionReturn::Scalar(GqlValue::String("hi".to_owned())))
+    }
+    schema
+      .add_async_resolvers(vec![
+        AsyncResolver::new(Box::new(resolve_channel), "Query", "channel"),
+        AsyncResolver::new(Box::new(resolve_title), "Channel", "title"),
+      ])
+      .unwrap();
+    schema
+      .add_resolvers(vec![Resolver::new(
+        Box::new(resolve_greeting),
+        "Query",
+        "greeting",
+      )])
+      .unwrap();
+    assert!(schema
+      .add_async_resolvers(vec![AsyncResolver::new(
+        Box::new(resolve_title),
+        "Channel",
+        "name"
+      )])
+      .is_err());
+
+    let req = || GqlRequest {
+      query: "{ greeting channel { id title } }".to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: None,
+    };
+    let expected = json!({ "greeting": "hi", "channel": { "id": "7", "title": "Channel 7" } });
+    let (data, errors) = GqlSchema::resolve_async(Arc::new(schema.clone()), (), req(), None)
+      .wait()
+      .unwrap();
+    assert!(errors.is_empty());
+    assert_eq!(data, expected);
+    // resolving without an event loop blocks on them instead
+    assert_eq!(schema.resolve(&mut (), req(), None).unwrap(), expected);
+  }
+
+  #[test]
+  fn parallel_root_fields() {
+    use futures::sync::oneshot;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Each resolver says when it has started, and only finishes once the other one has,
+    /// which can only happen when both are running at once
+    #[derive(Default)]
+    struct Handshake {
+      started: BTreeMap<&'static str, oneshot::Sender<()>>,
+      other_started: BTreeMap<&'static str, oneshot::Receiver<()>>,
+    }
+    type Ctx = Rc<RefCell<Handshake>>;
+
+    let mut schema: GqlSchema<Ctx> = GqlSchema::new(
+      graphql_parser::parse_schema("type Query { first: String second: String }").unwrap(),
+    )
+    .unwrap();
+
+    fn meet(name: &'static str, handshake: &mut Ctx) -> ResFuture {
+      let mut handshake = handshake.borrow_mut();
+      handshake.started.remove(name).unwrap().send(()).unwrap();
+      let other_started = handshake.other_started.remove(name).unwrap();
+      let value = ResolutionReturn::Scalar(GqlValue::String(name.to_owned()));
+      Box::new(
+        other_started
+          .map(move |_| value)
+          .map_err(|_| ResolutionErr::QueryResult("Sender dropped".to_owned())),
+      )
+    }
+    fn resolve_first(_: &GqlRoot, _: GqlArgs, ctx: &mut Ctx, _: &GqlSchema<Ctx>) -> ResFuture {
+      meet("first", ctx)
+    }
+    fn resolve_second(_: &GqlRoot, _: GqlArgs, ctx: &mut Ctx, _: &GqlSchema<Ctx>) -> ResFuture {
+      meet("second", ctx)
+    }
+    schema
+      .add_async_resolvers(vec![
+        AsyncResolver::new(Box::new(resolve_first), "Query", "first"),
+        AsyncResolver::new(Box::new(resolve_second), "Query", "second"),
+      ])
+      .unwrap();
+    // every oneshot is sent to right away, so a single poll either finishes or never will
+    struct NoWakeups;
+    impl futures::executor::Notify for NoWakeups {
+      fn notify(&self, _: usize) {}
+    }
+    let poll_once = |schema: &GqlSchema<Ctx>| {
+      let mut handshake = Handshake::default();
+      let (first_started, first_seen) = oneshot::channel();
+      let (second_started, second_seen) = oneshot::channel();
+      handshake.started.insert("first", first_started);
+      handshake.started.insert("second", second_started);
+      handshake.other_started.insert("first", second_seen);
+      handshake.other_started.insert("second", first_seen);
+      let req = GqlRequest {
+        query: "{ second first }".to_owned(),
+        operation_name: None,
+        variables: None,
+        extensions: None,
+      };
+      futures::executor::spawn(GqlSchema::resolve_async(
+        Arc::new(schema.clone()),
+        Rc::new(RefCell::new(handshake)),
+        req,
+        None,
+      ))
+      .poll_future_notify(&Arc::new(NoWakeups), 0)
+      .unwrap()
+    };
+
+    // one after the other, `first` waits on `second` forever
+    assert!(poll_once(&schema).is_not_ready());
+
+    schema.set_parallel_root_fields(true);
+    let (data, errors) = match poll_once(&schema) {
+      futures::Async::Ready(resolved) => resolved,
+      futures::Async::NotReady => panic!("Root fields should have run at the same time"),
+    };
+    assert!(errors.is_empty());
+    // the response map sorts its keys, whatever order the fields were asked for or finished in
+    assert_eq!(
+      to_string(&data).unwrap(),
+      r#"{"first":"first","second":"second"}"#
+    );
+  }
+
+  #[test]
+  fn parallel_root_fields_timing() {
+    use futures::sync::oneshot;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const DELAY: Duration = Duration::from_millis(100);
+    let mut schema: GqlSchema<()> = GqlSchema::new(
+      graphql_parser::parse_schema("type Query { first: String second: String }").unwrap(),
+    )
+    .unwrap();
+
+    // like a resolver waiting on the database
+    fn slow(name: &str) -> ResFuture {
+      let (sender, receiver) = oneshot::channel();
+      let value = ResolutionReturn::Scalar(GqlValue::String(name.to_owned()));
+      thread::spawn(move || {
+        thread::sleep(DELAY);
+        sender.send(value).unwrap();
+      });
+      Box::new(receiver.map_err(|_| ResolutionErr::QueryResult("Sender dropped".to_owned())))
+    }
+    fn resolve_first(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResFuture {
+      slow("first")
+    }
+    fn resolve_second(_: &GqlRoot, _: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResFuture {
+      slow("second")
+    }
+    schema
+      .add_async_resolvers(vec![
+        AsyncResolver::new(Box::new(resolve_first), "Query", "first"),
+        AsyncResolver::new(Box::new(resolve_second), "Query", "second"),
+      ])
+      .unwrap();
+    let time = |schema: &GqlSchema<()>| {
+      let req = GqlRequest {
+        query: "{ first second }".to_owned(),
+        operation_name: None,
+        variables: None,
+        extensions: None,
+      };
+      let started = Instant::now();
+      let (data, errors) = GqlSchema::resolve_async(Arc::new(schema.clone()), (), req, None)
+        .wait()
+        .unwrap();
+      assert!(errors.is_empty());
+      assert_eq!(data, json!({ "first": "first", "second": "second" }));
+      started.elapsed()
+    };
+
+    // one after the other, the delays add up
+    assert!(time(&schema) >= DELAY * 2);
+    // at the same time, the request takes about as long as one of them
+    schema.set_parallel_root_fields(true);
+    assert!(time(&schema) < DELAY * 2);
+  }
 }
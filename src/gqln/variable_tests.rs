@@ -0,0 +1,160 @@
+//! How variables of each built-in type are accepted, coerced, or rejected.
+
+use super::*;
+use serde_json::json;
+
+const SCHEMA: &str = r#"
+  enum Role { ADMIN MEMBER }
+  input Filter { role: Role, limit: Int }
+  type Query {
+    int(v: Int): String
+    float(v: Float): String
+    string(v: String): String
+    boolean(v: Boolean): String
+    id(v: ID): String
+    role(v: Role): String
+    ints(v: [Int]): String
+    required(v: Int!): String
+    filter(v: Filter): String
+  }
+"#;
+
+/// Describes the argument it was given, so tests can see what it was coerced into
+fn describe_arg(_: &GqlRoot, args: GqlArgs, _: &mut (), _: &GqlSchema<()>) -> ResResult {
+  let described = match get_input(&args, "v") {
+    MaybeInput::Absent => "absent".to_owned(),
+    MaybeInput::Null => "null".to_owned(),
+    MaybeInput::Value(v) => format!("{:?}", v),
+  };
+  Ok(ResolutionReturn::Scalar(GqlValue::String(described)))
+}
+
+fn test_schema() -> GqlSchema<()> {
+  let mut schema = GqlSchema::new(graphql_parser::parse_schema(SCHEMA).unwrap()).unwrap();
+  let fields = [
+    "int", "float", "string", "boolean", "id", "role", "ints", "required", "filter",
+  ];
+  schema
+    .add_resolvers(
+      fields
+        .iter()
+        .map(|f| Resolver::new(Box::new(describe_arg), "Query", f))
+        .collect(),
+    )
+    .unwrap();
+  schema
+}
+
+/// Runs `field` with `$v` declared as `var_type`, returning what the resolver saw
+fn run(var_type: &str, field: &str, variables: JsonValue) -> Result<String, ResolutionErr> {
+  let req = GqlRequest {
+    query: format!("query Test($v: {}) {{ {}(v: $v) }}", var_type, field),
+    operation_name: None,
+    variables: Some(variables),
+    extensions: None,
+  };
+  let data = test_schema().resolve(&mut (), req, None)?;
+  Ok(data[field].as_str().unwrap().to_owned())
+}
+
+fn accepts(var_type: &str, field: &str, value: JsonValue) -> String {
+  run(var_type, field, json!({ "v": value }))
+    .unwrap_or_else(|e| panic!("{} should accept {}, got {:?}", var_type, value, e))
+}
+
+fn rejects(var_type: &str, field: &str, value: JsonValue) {
+  if let Ok(seen) = run(var_type, field, json!({ "v": value })) {
+    panic!(
+      "{} should reject {}, but the resolver saw {}",
+      var_type, value, seen
+    );
+  }
+}
+
+#[test]
+fn int_variables() {
+  assert_eq!(accepts("Int", "int", json!(5)), "Int(Number(5))");
+  rejects("Int", "int", json!(1.5));
+  rejects("Int", "int", json!("5"));
+  rejects("Int", "int", json!(true));
+}
+
+#[test]
+fn float_variables() {
+  assert_eq!(accepts("Float", "float", json!(1.5)), "Float(1.5)");
+  // Ints are coerced into Floats
+  assert_eq!(accepts("Float", "float", json!(2)), "Float(2.0)");
+  rejects("Float", "float", json!("1.5"));
+}
+
+#[test]
+fn string_and_boolean_variables() {
+  assert_eq!(accepts("String", "string", json!("hi")), r#"String("hi")"#);
+  rejects("String", "string", json!(5));
+  assert_eq!(accepts("Boolean", "boolean", json!(true)), "Boolean(true)");
+  rejects("Boolean", "boolean", json!("true"));
+  rejects("Boolean", "boolean", json!(1));
+}
+
+#[test]
+fn id_variables() {
+  assert_eq!(accepts("ID", "id", json!("abc")), r#"String("abc")"#);
+  assert_eq!(accepts("ID", "id", json!(7)), "Int(Number(7))");
+  rejects("ID", "id", json!(true));
+}
+
+#[test]
+fn enum_variables() {
+  assert_eq!(accepts("Role", "role", json!("ADMIN")), r#"Enum("ADMIN")"#);
+  rejects("Role", "role", json!("OWNER"));
+  rejects("Role", "role", json!(1));
+  assert_eq!(
+    accepts("Filter", "filter", json!({ "role": "MEMBER" })),
+    r#"Object({"role": Enum("MEMBER")})"#
+  );
+  rejects("Filter", "filter", json!({ "role": "OWNER" }));
+}
+
+#[test]
+fn list_variables() {
+  assert_eq!(
+    accepts("[Int]", "ints", json!([1, 2])),
+    "List([Int(Number(1)), Int(Number(2))])"
+  );
+  // a single value becomes a one item list
+  assert_eq!(accepts("[Int]", "ints", json!(3)), "List([Int(Number(3))])");
+  rejects("[Int]", "ints", json!(["a"]));
+}
+
+#[test]
+fn default_variables() {
+  // `var_type` is pasted into the variable definition, default and all
+  assert_eq!(
+    run("[Int] = [1, 2]", "ints", json!({})).unwrap(),
+    "List([Int(Number(1)), Int(Number(2))])"
+  );
+  assert_eq!(
+    run("[Int] = 3", "ints", json!({})).unwrap(),
+    "List([Int(Number(3))])"
+  );
+  assert_eq!(
+    run("Filter = { role: ADMIN, limit: 2 }", "filter", json!({})).unwrap(),
+    r#"Object({"limit": Int(Number(2)), "role": Enum("ADMIN")})"#
+  );
+  // a sent value wins over the default
+  assert_eq!(
+    run("[Int] = [1, 2]", "ints", json!({ "v": [7] })).unwrap(),
+    "List([Int(Number(7))])"
+  );
+  assert!(run("Int = \"five\"", "int", json!({})).is_err());
+}
+
+#[test]
+fn missing_and_null_variables() {
+  // a nullable variable without a value leaves the argument out
+  assert_eq!(run("Int", "int", json!({})).unwrap(), "absent");
+  assert_eq!(accepts("Int", "int", JsonValue::Null), "null");
+  assert_eq!(accepts("Int!", "required", json!(1)), "Int(Number(1))");
+  assert!(run("Int!", "required", json!({})).is_err());
+  rejects("Int!", "required", JsonValue::Null);
+}
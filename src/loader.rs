@@ -0,0 +1,130 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::rc::Rc;
+
+/// Collects the keys resolvers ask for during a request, and fetches all of them
+/// the first time any of their values is needed, so a list of N items costs
+/// one query instead of N.
+pub struct BatchLoader<K, V> {
+  state: Rc<RefCell<LoaderState<K, V>>>,
+}
+
+struct LoaderState<K, V> {
+  queued: BTreeSet<K>,
+  /// Keys that were fetched but not found are kept as None, so they aren't asked for again
+  loaded: BTreeMap<K, Option<V>>,
+}
+
+/// A value that will be loaded with the rest of its batch
+pub struct Deferred<K, V> {
+  key: K,
+  state: Rc<RefCell<LoaderState<K, V>>>,
+}
+
+impl<K, V> Clone for BatchLoader<K, V> {
+  fn clone(&self) -> Self {
+    BatchLoader {
+      state: self.state.clone(),
+    }
+  }
+}
+
+impl<K: Ord + Clone, V: Clone> Default for BatchLoader<K, V> {
+  fn default() -> Self {
+    BatchLoader {
+      state: Rc::new(RefCell::new(LoaderState {
+        queued: BTreeSet::new(),
+        loaded: BTreeMap::new(),
+      })),
+    }
+  }
+}
+
+impl<K: Ord + Clone, V: Clone> BatchLoader<K, V> {
+  pub fn new() -> Self {
+    Default::default()
+  }
+
+  /// Queues `key` to be fetched with the next batch, unless it was already loaded
+  pub fn load(&self, key: K) -> Deferred<K, V> {
+    {
+      let mut state = self.state.borrow_mut();
+      if !state.loaded.contains_key(&key) {
+        state.queued.insert(key.clone());
+      }
+    }
+    Deferred {
+      key,
+      state: self.state.clone(),
+    }
+  }
+}
+
+impl<K: Ord + Clone, V: Clone> Deferred<K, V> {
+  /// The value for the key, if there is one.
+  /// When it hasn't been loaded yet, `fetch` is called once with every key queued so far.
+  pub fn get<E, F>(self, fetch: F) -> Result<Option<V>, E>
+  where
+    F: FnOnce(&[K]) -> Result<Vec<(K, V)>, E>,
+  {
+    let mut state = self.state.borrow_mut();
+    if !state.loaded.contains_key(&self.key) {
+      // anything not loaded yet was queued by `load`
+      let keys: Vec<K> = state.queued.iter().cloned().collect();
+      let found = fetch(&keys)?;
+      state.queued.clear();
+      for key in keys {
+        state.loaded.insert(key, None);
+      }
+      for (key, value) in found {
+        state.loaded.insert(key, Some(value));
+      }
+    }
+    Ok(state.loaded[&self.key].clone())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::cell::Cell;
+
+  #[test]
+  fn fetches_queued_keys_once() {
+    let loader: BatchLoader<i32, String> = BatchLoader::new();
+    let calls = Cell::new(0);
+    let fetch = |keys: &[i32]| -> Result<Vec<(i32, String)>, ()> {
+      calls.set(calls.get() + 1);
+      // 3 is missing from the "database"
+      Ok(
+        keys
+          .iter()
+          .filter(|k| **k != 3)
+          .map(|k| (*k, format!("message {}", k)))
+          .collect(),
+      )
+    };
+
+    // like a list resolver queueing every message it returns
+    let pending: Vec<Deferred<i32, String>> = (1..=4).map(|id| loader.load(id)).collect();
+    let loaded: Vec<Option<String>> = pending.into_iter().map(|d| d.get(fetch).unwrap()).collect();
+    assert_eq!(calls.get(), 1);
+    assert_eq!(loaded[0], Some("message 1".to_owned()));
+    assert_eq!(loaded[2], None);
+
+    // already loaded keys, found or not, don't go back to the database
+    assert_eq!(
+      loader.load(2).get(fetch).unwrap(),
+      Some("message 2".to_owned())
+    );
+    assert_eq!(loader.load(3).get(fetch).unwrap(), None);
+    assert_eq!(calls.get(), 1);
+
+    // but new ones do
+    assert_eq!(
+      loader.load(9).get(fetch).unwrap(),
+      Some("message 9".to_owned())
+    );
+    assert_eq!(calls.get(), 2);
+  }
+}
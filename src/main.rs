@@ -2,7 +2,11 @@ use actix::{Actor, System};
 use actix_web::{guard, middleware, web, App, HttpServer};
 use env_logger;
 use graphql_parser::parse_schema;
-use log::info;
+use log::{info, warn};
+use serde_json::json;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Instant;
 
 #[macro_use]
 extern crate diesel;
@@ -16,7 +20,11 @@ mod auth;
 mod config;
 mod gql_context;
 mod gqln;
+/// Batches database lookups made while resolving a request
+mod loader;
 mod models;
+/// Storage for Apollo style automatic persisted queries
+mod persisted_queries;
 mod resolvers;
 mod routes;
 mod schema;
@@ -29,12 +37,29 @@ use routes::*;
 
 use gqln::*;
 
+fn init_logger(format: config::LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == config::LogFormat::Json {
+        builder.format(|buf, record| {
+            let line = json!({
+                "time": buf.timestamp().to_string(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
+
 fn main() -> std::io::Result<()> {
+    let started = Instant::now();
     // read the .env and populate std::env
     dotenv::dotenv().ok();
 
     // set the env var RUST_LOG to "actix_web" to see access logs
-    env_logger::init();
+    init_logger(config::LogFormat::from_env());
 
     // Load up our graphql schema and set some resolvers
     let schema =
@@ -45,11 +70,34 @@ fn main() -> std::io::Result<()> {
 
     // the DB pool allows connections to the mysql db to be shared across threads
     let manager = ConnectionManager::<MysqlConnection>::new(config.db_url.clone().unwrap());
-    let pool = Pool::builder()
-        .build(manager)
-        .expect("Failed to create pool.");
+    let mut pool_builder = Pool::builder();
+    if let Some(timeout) = config.statement_timeout_ms {
+        pool_builder =
+            pool_builder.connection_customizer(Box::new(models::StatementTimeout(timeout)));
+    }
+    let pool = pool_builder.build(manager).expect("Failed to create pool.");
 
-    let mut gqschema = GqlSchema::new(schema).unwrap();
+    let mut gqschema = GqlSchema::new_with_policy(schema, config.missing_resolver_policy)
+        .unwrap()
+        .with_max_complexity(config.max_query_complexity, config.list_complexity_factor);
+    gqschema.set_max_expanded_fields(config.max_expanded_fields);
+    gqschema.set_parallel_root_fields(config.parallel_root_fields);
+    let denylist = FieldDenylist::default();
+    if let Err(entry) = denylist.set(&config.denied_fields) {
+        warn!(
+            "Ignoring DENIED_FIELDS, {} should look like Type.field",
+            entry
+        );
+    }
+    gqschema.set_denylist(denylist.clone());
+    gqschema.set_role_lookup(resolvers::channel_role);
+    gqschema
+        .add_scalar(
+            "DateTime",
+            resolvers::parse_date_time,
+            resolvers::serialize_date_time,
+        )
+        .unwrap();
     gqschema
         .add_resolvers(vec![
             Resolver::new(
@@ -57,27 +105,132 @@ fn main() -> std::io::Result<()> {
                 "Mutation",
                 "createMessage",
             ),
+            Resolver::new(
+                Box::new(resolvers::mutation_edit_message),
+                "Mutation",
+                "editMessage",
+            ),
             Resolver::new(
                 Box::new(resolvers::subscription_message),
                 "Subscription",
                 "message",
             ),
+            Resolver::new(
+                Box::new(resolvers::subscription_typing),
+                "Subscription",
+                "typing",
+            ),
+            Resolver::new(
+                Box::new(resolvers::mutation_send_typing),
+                "Mutation",
+                "sendTyping",
+            ),
             Resolver::new(Box::new(resolvers::query_me), "Query", "me"),
             Resolver::new(
                 Box::new(resolvers::mutation_read_message),
                 "Mutation",
                 "readMessage",
             ),
-            Resolver::new(Box::new(resolvers::query_unread), "Query", "unreadMessages"),
+            Resolver::new(
+                Box::new(resolvers::mutation_join_channel),
+                "Mutation",
+                "joinChannel",
+            ),
+            Resolver::new(
+                Box::new(resolvers::mutation_leave_channel),
+                "Mutation",
+                "leaveChannel",
+            ),
+            Resolver::new(
+                Box::new(resolvers::query_schema_hash),
+                "Query",
+                "schemaHash",
+            ),
+            Resolver::new(
+                Box::new(resolvers::query_server_info),
+                "Query",
+                "serverInfo",
+            ),
             Resolver::new(Box::new(resolvers::message_sender), "Message", "sender"),
+            Resolver::new(Box::new(resolvers::message_read), "Message", "read"),
+            Resolver::new(
+                Box::new(resolvers::message_reactions),
+                "Message",
+                "reactions",
+            ),
+            Resolver::new(
+                Box::new(resolvers::mutation_add_reaction),
+                "Mutation",
+                "addReaction",
+            ),
+            Resolver::new(
+                Box::new(resolvers::message_content_truncated),
+                "Message",
+                "contentTruncated",
+            ),
+            Resolver::new(Box::new(resolvers::channel_members), "Channel", "members"),
+            Resolver::new(
+                Box::new(resolvers::channel_member_count),
+                "Channel",
+                "memberCount",
+            ),
+            Resolver::new(
+                Box::new(resolvers::channel_message_count),
+                "Channel",
+                "messageCount",
+            ),
+            Resolver::new(
+                Box::new(resolvers::channel_created_at),
+                "Channel",
+                "createdAt",
+            ),
+            Resolver::new(
+                Box::new(resolvers::channel_updated_at),
+                "Channel",
+                "updatedAt",
+            ),
+        ])
+        .unwrap();
+    // root fields that wait on the database, so they can run at the same time
+    gqschema
+        .add_async_resolvers(vec![
+            AsyncResolver::new(Box::new(resolvers::query_unread), "Query", "unreadMessages"),
+            AsyncResolver::new(
+                Box::new(resolvers::query_unread_count),
+                "Query",
+                "unreadCount",
+            ),
+            AsyncResolver::new(Box::new(resolvers::query_my_role), "Query", "myRole"),
+            AsyncResolver::new(Box::new(resolvers::query_inbox), "Query", "inbox"),
+            AsyncResolver::new(
+                Box::new(resolvers::query_my_channels),
+                "Query",
+                "myChannels",
+            ),
+            AsyncResolver::new(
+                Box::new(resolvers::query_channel_messages),
+                "Query",
+                "channelMessages",
+            ),
         ])
         .unwrap();
 
-    let ws_tracker = ws_actors::ConnectionTracker::new(gqschema.clone(), pool.clone());
-    let gql_context = GqlRouteContext::new(gqschema, pool.clone());
+    let ws_tracker = ws_actors::ConnectionTracker::new(gqschema.clone(), pool.clone())
+        .max_broadcast_content(config.max_broadcast_content);
+    let ws_schema = gqschema.clone();
+    let mut gql_context = GqlRouteContext::new(gqschema, pool.clone(), started)
+        .max_persisted_queries(config.max_persisted_queries);
+    if config.reject_blank_messages {
+        gql_context = gql_context.content_filter(Arc::new(gql_context::reject_blank_content));
+    }
+    // read once, since RSA keys would otherwise be loaded for every token
+    let jwt = config.jwt_config().expect(
+        "Could not load the JWT keys. Check `JWT_PRIVATE_KEY_PATH` and `JWT_PUBLIC_KEY_PATH`.",
+    );
     let api_context = ApiContext {
         db: pool.clone(),
         config: config.clone(),
+        jwt: jwt.clone(),
     };
 
     // start the runtime to allow actix actors to handle events
@@ -88,19 +241,27 @@ fn main() -> std::io::Result<()> {
 
     let port = config.graphql_port;
     let man_port = config.management_port;
+    // one per server, since acceptor builders can't be cloned
+    let gql_tls = config.tls_config();
+    let man_tls = config.tls_config();
+    let man_tracker_addr = tracker_addr.clone();
+    let man_cors_enabled = !config.management_cors_origins.is_empty();
+    let man_config = config.clone();
 
     // Starting the server creates more actors
     // graphql clients
-    HttpServer::new(move || {
+    let gql_server = HttpServer::new(move || {
         App::new()
             .data(pool.clone())
             .data(gql_context.clone())
             .data(tracker_addr.clone())
+            .data(ws_schema.clone())
             .data(config.clone())
+            .data(jwt.clone())
             .route(
                 "/graphql",
                 web::post()
-                    .to(r_graphql_post)
+                    .to_async(r_graphql_post)
                     .guard(guard::Header("content-type", "application/json")),
             )
             .route(
@@ -112,38 +273,68 @@ fn main() -> std::io::Result<()> {
             .route(
                 "/graphql",
                 web::get()
-                    .to(r_graphql_get)
+                    .to_async(r_graphql_get)
                     .guard(guard::Header("content-type", "application/json")),
             )
             .wrap(middleware::Logger::default())
-    })
-    .bind(format!("0.0.0.0:{}", port))?
-    .start();
+    });
+    let gql_server = match gql_tls {
+        Some(tls) => gql_server.bind_ssl(format!("0.0.0.0:{}", port), tls)?,
+        None => gql_server.bind(format!("0.0.0.0:{}", port))?,
+    };
+    gql_server.start();
 
     // server management
-    HttpServer::new(move || {
-        App::new().wrap(middleware::Logger::default()).service(
-            web::scope("/api/v1")
-                .data(api_context.clone())
-                .route("/healthz", web::get().to(r_health))
-                .route("/channel", web::get().to(r_get_channels)) // view channels
-                .route("/channel", web::post().to(r_create_channel)) // create channel
-                .route("/channel/{channelId}", web::get().to(r_get_channel_info))
-                .route("/channel/{channelId}", web::delete().to(r_delete_channel))
-                .route(
-                    "/channel/{channelId}/users",
-                    web::get().to(r_get_channel_users),
-                )
-                .route("/channel/{channelId}/users", web::put().to(r_add_user))
-                .route(
-                    "/channel/{channelId}/{uid}",
-                    web::delete().to(r_remove_user),
-                )
-                .route("/jwt/{uid}", web::get().to(r_get_jwt)),
-        )
-    })
-    .bind(format!("0.0.0.0:{}", man_port))?
-    .start();
+    let man_server = HttpServer::new(move || {
+        App::new()
+            .wrap(middleware::Condition::new(
+                man_cors_enabled,
+                man_config.management_cors(),
+            ))
+            .wrap(middleware::Logger::default())
+            .service(
+                web::scope("/api/v1")
+                    .data(api_context.clone())
+                    .data(man_tracker_addr.clone())
+                    .data(denylist.clone())
+                    .route("/healthz", web::get().to(r_health))
+                    .route("/channel", web::get().to(r_get_channels)) // view channels
+                    .route("/channel", web::post().to(r_create_channel)) // create channel
+                    .route("/channel/{channelId}", web::get().to(r_get_channel_info))
+                    .route("/channel/{channelId}", web::delete().to(r_delete_channel))
+                    .route(
+                        "/channel/{channelId}/restore",
+                        web::post().to(r_restore_channel),
+                    )
+                    .route(
+                        "/channel/{channelId}/users",
+                        web::get().to(r_get_channel_users),
+                    )
+                    .route("/channel/{channelId}/users", web::put().to(r_add_user))
+                    .route(
+                        "/channel/{channelId}/messages",
+                        web::delete().to(r_delete_old_messages),
+                    )
+                    .route(
+                        "/channel/{channelId}/{uid}",
+                        web::delete().to(r_remove_user),
+                    )
+                    .route("/jwt/refresh", web::post().to(r_refresh_jwt))
+                    .route("/jwt/{uid}", web::get().to(r_get_jwt))
+                    .route("/denied-fields", web::get().to(r_get_denied_fields))
+                    .route("/denied-fields", web::put().to(r_set_denied_fields))
+                    .route("/subscriptions", web::get().to_async(r_get_subscriptions))
+                    .route(
+                        "/users/{uid}/disconnect",
+                        web::post().to_async(r_disconnect_user),
+                    ),
+            )
+    });
+    let man_server = match man_tls {
+        Some(tls) => man_server.bind_ssl(format!("0.0.0.0:{}", man_port), tls)?,
+        None => man_server.bind(format!("0.0.0.0:{}", man_port))?,
+    };
+    man_server.start();
 
     info!("Time to start server.");
     actix_sys.run()?;
@@ -1,19 +1,65 @@
-use chrono::NaiveDateTime;
-use diesel::mysql::MysqlConnection;
+use chrono::{NaiveDateTime, Utc};
+use diesel::connection::SimpleConnection;
+use diesel::dsl;
+use diesel::mysql::{Mysql, MysqlConnection};
 use diesel::prelude::*;
-use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::query_builder::{BoxedSelectStatement, QueryFragment};
+use diesel::query_dsl::methods::ExecuteDsl;
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Error as DbConnsErr, Pool};
+use diesel::result::DatabaseErrorKind;
+use diesel::sql_types::{BigInt, Integer, Text};
 use log::info;
+use std::collections::HashMap;
 
 use crate::schema::*;
 
 pub type DbPool = Pool<ConnectionManager<MysqlConnection>>;
 
+/// Limits how long (in milliseconds) statements may run on a connection from the pool.
+/// MariaDB bounds every statement, reads and writes alike. MySQL only bounds
+/// read-only SELECTs, so writes like `mark_all_as_read` can still run long there.
+#[derive(Debug, Clone, Copy)]
+pub struct StatementTimeout(pub u64);
+
+impl StatementTimeout {
+  /// The statement that sets the timeout on a server reporting `version`.
+  /// MariaDB takes seconds and doesn't know MySQL's variable.
+  fn set_statement(&self, version: &str) -> String {
+    if version.contains("MariaDB") {
+      format!(
+        "SET SESSION max_statement_time = {}",
+        self.0 as f64 / 1000.0
+      )
+    } else {
+      format!("SET SESSION max_execution_time = {}", self.0)
+    }
+  }
+}
+
+impl CustomizeConnection<MysqlConnection, DbConnsErr> for StatementTimeout {
+  fn on_acquire(&self, conn: &mut MysqlConnection) -> Result<(), DbConnsErr> {
+    let version = diesel::select(dsl::sql::<Text>("VERSION()"))
+      .get_result::<String>(conn)
+      .map_err(DbConnsErr::QueryError)?;
+    conn
+      .batch_execute(&self.set_statement(&version))
+      .map_err(DbConnsErr::QueryError)
+  }
+}
+
 #[derive(Queryable, PartialEq, Debug, Clone)]
 pub struct DbChannel {
   pub id: i32,
   pub display_name: Option<String>,
   pub created_at: NaiveDateTime,
   pub updated_at: NaiveDateTime,
+  /// Whether any user can join the channel themselves
+  pub is_public: bool,
+  /// Set by external systems that provision channels, unique when present
+  pub external_key: Option<String>,
+  /// When the channel was deleted. Deleted channels keep their messages and members,
+  /// but are left out of listings unless asked for.
+  pub deleted_at: Option<NaiveDateTime>,
 }
 
 #[derive(Queryable, PartialEq, Debug)]
@@ -31,8 +77,8 @@ pub struct DbMessage {
   pub updated_at: NaiveDateTime,
   pub created_at: NaiveDateTime,
   edited: Option<bool>,
-  channel_id: i32,
-  content: Option<String>,
+  pub channel_id: i32,
+  pub content: Option<String>,
 }
 
 #[derive(Queryable, PartialEq, Debug)]
@@ -43,10 +89,34 @@ pub struct DbMessageView {
   created_at: NaiveDateTime,
 }
 
+#[derive(Queryable, PartialEq, Debug, Clone)]
+pub struct DbReaction {
+  pub id: i32,
+  pub message_id: i32,
+  pub user: String,
+  pub emoji: String,
+  pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable, PartialEq, Debug, Clone)]
+pub struct DbUser {
+  pub id: String,
+  pub name: Option<String>,
+}
+
 #[derive(Insertable)]
 #[table_name = "channels"]
 pub struct NewChannel<'a> {
   pub display_name: &'a str,
+  pub is_public: bool,
+  pub external_key: Option<&'a str>,
+}
+
+#[derive(Insertable)]
+#[table_name = "users"]
+pub struct NewUser<'a> {
+  pub id: &'a str,
+  pub name: Option<&'a str>,
 }
 
 #[derive(Insertable)]
@@ -72,6 +142,14 @@ pub struct NewMessageRead<'a> {
   user: &'a str,
 }
 
+#[derive(Insertable)]
+#[table_name = "message_reactions"]
+pub struct NewReaction<'a> {
+  pub message_id: i32,
+  pub user: &'a str,
+  pub emoji: &'a str,
+}
+
 pub fn get_message(conn: &MysqlConnection, id: i32) -> QueryResult<Option<DbMessage>> {
   let findings = messages::table.find(id).load::<DbMessage>(conn)?;
   if findings.len() == 1 {
@@ -80,8 +158,23 @@ pub fn get_message(conn: &MysqlConnection, id: i32) -> QueryResult<Option<DbMess
   Ok(None)
 }
 
-pub fn create_channel(conn: &MysqlConnection, display_name: &str) -> QueryResult<DbChannel> {
-  let new_channel = NewChannel { display_name };
+/// Loads several messages in one query, leaving out any that don't exist
+pub fn get_messages(conn: &MysqlConnection, ids: &[i32]) -> QueryResult<Vec<DbMessage>> {
+  messages::table
+    .filter(messages::id.eq_any(ids))
+    .load::<DbMessage>(conn)
+}
+
+pub fn create_channel(
+  conn: &MysqlConnection,
+  display_name: &str,
+  is_public: bool,
+) -> QueryResult<DbChannel> {
+  let new_channel = NewChannel {
+    display_name,
+    is_public,
+    external_key: None,
+  };
 
   diesel::insert_into(channels::table)
     .values(&new_channel)
@@ -90,6 +183,76 @@ pub fn create_channel(conn: &MysqlConnection, display_name: &str) -> QueryResult
   Ok(channels::table.order(channels::id.desc()).first(conn)?)
 }
 
+pub fn get_channel_by_external_key(
+  conn: &MysqlConnection,
+  external_key: &str,
+) -> QueryResult<Option<DbChannel>> {
+  channels::table
+    .filter(channels::external_key.eq(external_key))
+    .first(conn)
+    .optional()
+}
+
+fn create_channel_idempotent_query<'a>(
+  new_channel: NewChannel<'a>,
+) -> impl RunQueryDsl<MysqlConnection> + ExecuteDsl<MysqlConnection> + QueryFragment<Mysql> + 'a {
+  // the unique index on external_key turns a repeat, even a concurrent one, into a no-op
+  diesel::insert_or_ignore_into(channels::table).values(new_channel)
+}
+
+/// Creates a channel unless one with the same external key already exists.
+/// Returns the channel, and whether it was created by this call.
+pub fn create_channel_idempotent(
+  conn: &MysqlConnection,
+  display_name: &str,
+  external_key: &str,
+  is_public: bool,
+) -> QueryResult<(DbChannel, bool)> {
+  let new_channel = NewChannel {
+    display_name,
+    is_public,
+    external_key: Some(external_key),
+  };
+  let created = create_channel_idempotent_query(new_channel).execute(conn)? > 0;
+  let channel = get_channel_by_external_key(conn, external_key)?.ok_or(diesel::NotFound)?;
+  // a deleted channel still holds on to its key, and has to be restored to be used again
+  if channel.deleted_at.is_some() {
+    return Err(diesel::result::Error::DatabaseError(
+      DatabaseErrorKind::UniqueViolation,
+      Box::new(format!(
+        "Channel {} with external key {} was deleted",
+        channel.id, external_key
+      )),
+    ));
+  }
+  Ok((channel, created))
+}
+
+fn store_user_query<'a>(
+  user: NewUser<'a>,
+) -> impl RunQueryDsl<MysqlConnection> + ExecuteDsl<MysqlConnection> + QueryFragment<Mysql> + 'a {
+  // a user who is already stored keeps their name
+  diesel::insert_or_ignore_into(users::table).values(user)
+}
+
+fn rename_user_query<'a>(
+  user: NewUser<'a>,
+) -> impl RunQueryDsl<MysqlConnection> + ExecuteDsl<MysqlConnection> + QueryFragment<Mysql> + 'a {
+  diesel::replace_into(users::table).values(user)
+}
+
+/// Stores a user, so tokens can be issued for them.
+/// Without a `name`, the one already stored is kept.
+pub fn store_user(conn: &MysqlConnection, id: &str, name: Option<&str>) -> QueryResult<()> {
+  let user = NewUser { id, name };
+  match name {
+    Some(_) => rename_user_query(user).execute(conn)?,
+    None => store_user_query(user).execute(conn)?,
+  };
+  Ok(())
+}
+
+/// Makes a user a member of a channel, storing the user if they are new
 pub fn add_user_to_channel(
   conn: &MysqlConnection,
   user: &str,
@@ -102,10 +265,13 @@ pub fn add_user_to_channel(
     user,
   };
 
-  diesel::insert_into(channel_members::table)
-    .values(&new_member)
-    .execute(conn)?;
-  Ok(())
+  conn.transaction(|| {
+    store_user(conn, user, None)?;
+    diesel::insert_into(channel_members::table)
+      .values(&new_member)
+      .execute(conn)?;
+    Ok(())
+  })
 }
 
 pub fn create_message(
@@ -128,42 +294,372 @@ pub fn create_message(
   })
 }
 
-pub fn get_channels(conn: &MysqlConnection) -> QueryResult<Vec<DbChannel>> {
-  channels::table.load::<DbChannel>(conn)
+fn edit_message_query<'a>(
+  id: i32,
+  sender: &'a str,
+  new_content: &'a str,
+) -> impl RunQueryDsl<MysqlConnection> + ExecuteDsl<MysqlConnection> + QueryFragment<Mysql> + 'a {
+  diesel::update(messages::table.find(id).filter(messages::sender.eq(sender))).set((
+    messages::content.eq(Some(new_content)),
+    messages::edited.eq(Some(true)),
+  ))
+}
+
+/// How an attempt to edit a message went
+#[derive(PartialEq, Debug)]
+pub enum MessageEdit {
+  Edited(DbMessage),
+  /// There is no message with that id
+  NotFound,
+  /// Someone else sent the message
+  NotSender,
+}
+
+/// Whether `sender` may edit `message`, which is None when it doesn't exist
+fn check_editor(message: Option<&DbMessage>, sender: &str) -> Result<(), MessageEdit> {
+  match message {
+    None => Err(MessageEdit::NotFound),
+    Some(message) if message.sender != sender => Err(MessageEdit::NotSender),
+    Some(_) => Ok(()),
+  }
+}
+
+/// Replaces the content of a message and marks it as edited.
+/// Changes nothing unless the message exists and `sender` sent it.
+pub fn update_message(
+  conn: &MysqlConnection,
+  id: i32,
+  sender: &str,
+  new_content: &str,
+) -> QueryResult<MessageEdit> {
+  conn.transaction(|| {
+    if let Err(refused) = check_editor(get_message(conn, id)?.as_ref(), sender) {
+      return Ok(refused);
+    }
+    edit_message_query(id, sender, new_content).execute(conn)?;
+    Ok(get_message(conn, id)?.map_or(MessageEdit::NotFound, MessageEdit::Edited))
+  })
+}
+
+/// Channels after the `after` id in id order, with one extra row to tell if there's another page
+fn channel_page_query(
+  after: Option<i32>,
+  limit: i64,
+  include_deleted: bool,
+) -> channels::BoxedQuery<'static, Mysql> {
+  let mut query = channels::table
+    .order(channels::id.asc())
+    .limit(limit.saturating_add(1))
+    .into_boxed();
+  if let Some(after) = after {
+    query = query.filter(channels::id.gt(after));
+  }
+  if !include_deleted {
+    query = query.filter(channels::deleted_at.is_null());
+  }
+  query
+}
+
+/// Splits the extra row `channel_page_query` loads off of a page
+fn take_page<T>(mut rows: Vec<T>, limit: i64) -> (Vec<T>, bool) {
+  let limit = limit.max(0) as usize;
+  let has_next = rows.len() > limit;
+  rows.truncate(limit);
+  (rows, has_next)
+}
+
+/// A page of at most `limit` channels with ids greater than `after`, and whether there are more
+pub fn get_channels_paged(
+  conn: &MysqlConnection,
+  after: Option<i32>,
+  limit: i64,
+  include_deleted: bool,
+) -> QueryResult<(Vec<DbChannel>, bool)> {
+  let rows = channel_page_query(after, limit, include_deleted).load::<DbChannel>(conn)?;
+  Ok(take_page(rows, limit))
+}
+
+/// Turns a search into a `LIKE` pattern matching display names that start with it.
+/// SQL wildcards in the search are matched literally, and `*` matches anything.
+fn channel_search_pattern(needle: &str) -> String {
+  let mut pattern = String::with_capacity(needle.len() + 1);
+  for c in needle.chars() {
+    match c {
+      '\\' | '%' | '_' => {
+        pattern.push('\\');
+        pattern.push(c);
+      }
+      '*' => pattern.push('%'),
+      c => pattern.push(c),
+    }
+  }
+  pattern.push('%');
+  pattern
 }
 
-pub fn get_channel(conn: &MysqlConnection, channel_id: i32) -> QueryResult<Option<DbChannel>> {
-  let ch = channels::table.find(channel_id).load::<DbChannel>(conn)?;
+pub fn search_channels(
+  conn: &MysqlConnection,
+  needle: &str,
+  limit: i64,
+  include_deleted: bool,
+) -> QueryResult<Vec<DbChannel>> {
+  let mut query = channels::table
+    .filter(
+      channels::display_name
+        .like(channel_search_pattern(needle))
+        .escape('\\'),
+    )
+    .order(channels::display_name.asc())
+    .limit(limit)
+    .into_boxed();
+  if !include_deleted {
+    query = query.filter(channels::deleted_at.is_null());
+  }
+  query.load::<DbChannel>(conn)
+}
+
+fn channel_query(channel_id: i32, include_deleted: bool) -> channels::BoxedQuery<'static, Mysql> {
+  let mut query = channels::table.find(channel_id).into_boxed();
+  if !include_deleted {
+    query = query.filter(channels::deleted_at.is_null());
+  }
+  query
+}
+
+/// The channel with this id. Deleted channels are only found with `include_deleted`.
+pub fn get_channel(
+  conn: &MysqlConnection,
+  channel_id: i32,
+  include_deleted: bool,
+) -> QueryResult<Option<DbChannel>> {
+  let ch = channel_query(channel_id, include_deleted).load::<DbChannel>(conn)?;
   if ch.len() == 1 {
     return Ok(Some(ch[0].clone()));
   }
   Ok(None)
 }
 
-pub fn get_users_channels(conn: &MysqlConnection, user: &str) -> QueryResult<Vec<i32>> {
-  let res = channel_members::table
+/// The ids of channels that haven't been deleted, so memberships of deleted ones can be left out
+fn live_channel_ids(
+) -> dsl::Filter<dsl::Select<channels::table, channels::id>, dsl::IsNull<channels::deleted_at>> {
+  channels::table
+    .select(channels::id)
+    .filter(channels::deleted_at.is_null())
+}
+
+/// The user's memberships, leaving out deleted channels
+fn memberships_query(user: &str) -> channel_members::BoxedQuery<'_, Mysql> {
+  channel_members::table
     .filter(channel_members::dsl::user.eq(user))
-    .load::<DbChannelMember>(conn)?;
+    .filter(channel_members::channel_id.eq_any(live_channel_ids()))
+    .into_boxed()
+}
 
-  Ok(res.into_iter().map(|cm| cm.channel_id).collect())
+/// The channels the user is a member of, leaving out deleted ones
+pub fn get_users_channels(conn: &MysqlConnection, user: &str) -> QueryResult<Vec<i32>> {
+  memberships_query(user)
+    .select(channel_members::channel_id)
+    .load(conn)
 }
 
-pub fn get_channel_users(conn: &MysqlConnection, channel: i32) -> QueryResult<Vec<String>> {
-  let res = channel_members::table
+/// Every membership of a channel, with the role each member has in it
+pub fn get_channel_members(
+  conn: &MysqlConnection,
+  channel: i32,
+) -> QueryResult<Vec<DbChannelMember>> {
+  channel_members::table
     .filter(channel_members::dsl::channel_id.eq(channel))
-    .load::<DbChannelMember>(conn)?;
+    .load::<DbChannelMember>(conn)
+}
 
+pub fn get_channel_users(conn: &MysqlConnection, channel: i32) -> QueryResult<Vec<String>> {
+  let res = get_channel_members(conn, channel)?;
   Ok(res.into_iter().map(|member| member.user).collect())
 }
 
-pub fn delete_channel(conn: &MysqlConnection, channel: i32) -> QueryResult<()> {
+/// The role a user has in a channel, or `None` if they are not a member
+pub fn get_user_role(
+  conn: &MysqlConnection,
+  channel: i32,
+  user: &str,
+) -> QueryResult<Option<String>> {
+  let role = memberships_query(user)
+    .filter(channel_members::dsl::channel_id.eq(channel))
+    .select(channel_members::user_role)
+    .first::<Option<String>>(conn)
+    .optional()?;
+  Ok(role.flatten())
+}
+
+/// Whether the user is a member of the channel. Nobody is a member of a deleted channel.
+pub fn is_member(conn: &MysqlConnection, channel: i32, user: &str) -> QueryResult<bool> {
+  diesel::select(diesel::dsl::exists(
+    memberships_query(user).filter(channel_members::dsl::channel_id.eq(channel)),
+  ))
+  .get_result(conn)
+}
+
+pub fn get_user(conn: &MysqlConnection, id: &str) -> QueryResult<Option<DbUser>> {
+  users::table.find(id).first(conn).optional()
+}
+
+/// The stored users out of `ids`
+pub fn get_users(conn: &MysqlConnection, ids: &[String]) -> QueryResult<Vec<DbUser>> {
+  users::table.filter(users::id.eq_any(ids)).load(conn)
+}
+
+/// Memberships of any of `users` in any of `channels`, leaving out deleted channels
+fn member_roles_query<'a>(
+  channels: &'a [i32],
+  users: &'a [&'a str],
+) -> channel_members::BoxedQuery<'a, Mysql> {
+  channel_members::table
+    .filter(channel_members::channel_id.eq_any(channels))
+    .filter(channel_members::dsl::user.eq_any(users))
+    .filter(channel_members::channel_id.eq_any(live_channel_ids()))
+    .into_boxed()
+}
+
+/// The role of each member in each channel, like `get_user_role` for many of them at once.
+/// Members without a role, and users who aren't members, are left out.
+pub fn get_member_roles(
+  conn: &MysqlConnection,
+  members: &[(i32, String)],
+) -> QueryResult<Vec<((i32, String), String)>> {
+  let channels: Vec<i32> = members.iter().map(|(channel, _)| *channel).collect();
+  let users: Vec<&str> = members.iter().map(|(_, user)| user.as_str()).collect();
+  let found = member_roles_query(&channels, &users)
+    .select((
+      channel_members::channel_id,
+      channel_members::user,
+      channel_members::user_role,
+    ))
+    .load::<(i32, String, Option<String>)>(conn)?;
+  // the query also matches other pairings of the channels and users
+  Ok(
+    found
+      .into_iter()
+      .filter_map(|(channel, user, role)| Some(((channel, user), role?)))
+      .filter(|(member, _)| members.contains(member))
+      .collect(),
+  )
+}
+
+/// A page of the members of a channel in the order they joined,
+/// along with any user info we have stored for them
+pub fn get_channel_members_paginated(
+  conn: &MysqlConnection,
+  channel: i32,
+  offset: i64,
+  limit: i64,
+) -> QueryResult<Vec<(DbChannelMember, Option<DbUser>)>> {
+  channel_members::table
+    .left_join(users::table.on(users::id.eq(channel_members::user)))
+    .filter(channel_members::dsl::channel_id.eq(channel))
+    .order(channel_members::id.asc())
+    .limit(limit)
+    .offset(offset)
+    .load(conn)
+}
+
+pub fn count_channel_members(conn: &MysqlConnection, channel: i32) -> QueryResult<i64> {
+  channel_members::table
+    .filter(channel_members::dsl::channel_id.eq(channel))
+    .count()
+    .get_result(conn)
+}
+
+fn soft_delete_query(
+  channel: i32,
+  at: NaiveDateTime,
+) -> impl RunQueryDsl<MysqlConnection> + ExecuteDsl<MysqlConnection> + QueryFragment<Mysql> {
+  diesel::update(
+    channels::table
+      .find(channel)
+      .filter(channels::deleted_at.is_null()),
+  )
+  .set(channels::deleted_at.eq(Some(at)))
+}
+
+/// Marks a channel as deleted, hiding it without removing its messages or members.
+/// Deleting a channel again keeps the time it was first deleted.
+pub fn soft_delete_channel(conn: &MysqlConnection, channel: i32) -> QueryResult<()> {
   info!("Deleted channel {}", channel);
-  diesel::delete(channels::table.filter(channels::dsl::id.eq(channel))).execute(conn)?;
-  diesel::delete(channel_members::table.filter(channel_members::dsl::channel_id.eq(channel)))
-    .execute(conn)?;
+  soft_delete_query(channel, Utc::now().naive_utc()).execute(conn)?;
   Ok(())
 }
 
+fn restore_query(
+  channel: i32,
+) -> impl RunQueryDsl<MysqlConnection> + ExecuteDsl<MysqlConnection> + QueryFragment<Mysql> {
+  diesel::update(
+    channels::table
+      .find(channel)
+      .filter(channels::deleted_at.is_not_null()),
+  )
+  .set(channels::deleted_at.eq(None::<NaiveDateTime>))
+}
+
+/// Brings back a soft deleted channel, along with its messages and members.
+/// Returns whether the channel was deleted.
+pub fn restore_channel(conn: &MysqlConnection, channel: i32) -> QueryResult<bool> {
+  info!("Restored channel {}", channel);
+  Ok(restore_query(channel).execute(conn)? > 0)
+}
+
+/// Deletes messages in a channel sent before `cutoff`, along with their views and reactions.
+/// Returns how many messages were deleted.
+pub fn delete_messages_older_than(
+  conn: &MysqlConnection,
+  channel: i32,
+  cutoff: NaiveDateTime,
+) -> QueryResult<usize> {
+  conn.transaction(|| {
+    let expired = || {
+      messages::table
+        .select(messages::id)
+        .filter(messages::channel_id.eq(channel))
+        .filter(messages::created_at.lt(cutoff))
+    };
+    diesel::delete(message_views::table.filter(message_views::message_id.eq_any(expired())))
+      .execute(conn)?;
+    diesel::delete(
+      message_reactions::table.filter(message_reactions::message_id.eq_any(expired())),
+    )
+    .execute(conn)?;
+    diesel::delete(
+      messages::table
+        .filter(messages::channel_id.eq(channel))
+        .filter(messages::created_at.lt(cutoff)),
+    )
+    .execute(conn)
+  })
+}
+
+/// The newest messages of a channel, newest first
+fn channel_messages_query(channel: i32, limit: i64) -> messages::BoxedQuery<'static, Mysql> {
+  messages::table
+    .filter(messages::channel_id.eq(channel))
+    .order((messages::created_at.desc(), messages::id.desc()))
+    .limit(limit)
+    .into_boxed()
+}
+
+pub fn get_channel_messages(
+  conn: &MysqlConnection,
+  channel: i32,
+  limit: i64,
+) -> QueryResult<Vec<DbMessage>> {
+  channel_messages_query(channel, limit).load::<DbMessage>(conn)
+}
+
+pub fn count_channel_messages(conn: &MysqlConnection, channel: i32) -> QueryResult<i64> {
+  messages::table
+    .filter(messages::channel_id.eq(channel))
+    .count()
+    .get_result(conn)
+}
+
 pub fn remove_user(conn: &MysqlConnection, channel: i32, user: &str) -> QueryResult<()> {
   diesel::delete(
     channel_members::table
@@ -185,14 +681,107 @@ pub fn mark_message_as_read(conn: &MysqlConnection, message: i32, user: &str) ->
   Ok(())
 }
 
+fn add_reaction_query<'a>(
+  reaction: NewReaction<'a>,
+) -> impl RunQueryDsl<MysqlConnection> + ExecuteDsl<MysqlConnection> + QueryFragment<Mysql> + 'a {
+  // the unique index on (message_id, user, emoji) turns repeats into no-ops
+  diesel::insert_or_ignore_into(message_reactions::table).values(reaction)
+}
+
+/// Adds a user's reaction to a message. Reacting with the same emoji again changes nothing.
+pub fn add_reaction(
+  conn: &MysqlConnection,
+  message_id: i32,
+  user: &str,
+  emoji: &str,
+) -> QueryResult<()> {
+  add_reaction_query(NewReaction {
+    message_id,
+    user,
+    emoji,
+  })
+  .execute(conn)?;
+  Ok(())
+}
+
+fn reactions_query(message_ids: &[i32]) -> message_reactions::BoxedQuery<'_, Mysql> {
+  message_reactions::table
+    .filter(message_reactions::message_id.eq_any(message_ids))
+    .order((
+      message_reactions::created_at.asc(),
+      message_reactions::id.asc(),
+    ))
+    .into_boxed()
+}
+
+/// The reactions to each of the messages that have any, oldest first
+pub fn get_reactions(
+  conn: &MysqlConnection,
+  message_ids: &[i32],
+) -> QueryResult<Vec<(i32, Vec<DbReaction>)>> {
+  Ok(group_reactions(reactions_query(message_ids).load(conn)?))
+}
+
+/// Splits reactions up by message, keeping them in order
+fn group_reactions(reactions: Vec<DbReaction>) -> Vec<(i32, Vec<DbReaction>)> {
+  let mut by_message: HashMap<i32, Vec<DbReaction>> = HashMap::new();
+  for reaction in reactions {
+    by_message
+      .entry(reaction.message_id)
+      .or_insert_with(Vec::new)
+      .push(reaction);
+  }
+  by_message.into_iter().collect()
+}
+
+/// Messages in the user's channels that were sent by someone else
+/// and that the user has not viewed yet.
+fn unread_messages(user: &str) -> messages::BoxedQuery<'_, Mysql> {
+  let viewed = message_views::table
+    .select(message_views::message_id)
+    .filter(message_views::dsl::user.eq(user));
+  let joined = channel_members::table
+    .select(channel_members::channel_id)
+    .filter(channel_members::dsl::user.eq(user))
+    .filter(channel_members::channel_id.eq_any(live_channel_ids()));
+
+  messages::table
+    .filter(messages::id.ne_all(viewed))
+    .filter(messages::channel_id.eq_any(joined))
+    .filter(messages::sender.ne(user))
+    .into_boxed()
+}
+
+/// How many unread messages the user has in each channel that has any
+fn unread_by_channel(
+  user: &str,
+) -> BoxedSelectStatement<'_, (Integer, BigInt), messages::table, Mysql> {
+  // diesel 1.4 won't mix `count_star` with plain columns, even when they are grouped by
+  unread_messages(user)
+    .select((messages::channel_id, diesel::dsl::sql::<BigInt>("COUNT(*)")))
+    .group_by(messages::channel_id)
+}
+
 pub fn get_unread(conn: &MysqlConnection, user: &str) -> QueryResult<Vec<i32>> {
-  let messages: Vec<i32> = messages::table
-    .left_join(message_views::table)
-    .filter(message_views::dsl::id.is_null())
-    .select(messages::id)
-    .load(conn)?;
+  unread_messages(user).select(messages::id).load(conn)
+}
+
+pub fn get_total_unread(conn: &MysqlConnection, user: &str) -> QueryResult<i64> {
+  unread_messages(user).count().get_result(conn)
+}
 
-  Ok(messages)
+/// Of the given messages, finds the ones the user has viewed.
+pub fn get_read_messages(
+  conn: &MysqlConnection,
+  user: &str,
+  message_ids: &[i32],
+) -> QueryResult<Vec<i32>> {
+  message_views::table
+    .select(message_views::message_id)
+    .filter(message_views::dsl::user.eq(user))
+    .filter(message_views::message_id.eq_any(message_ids))
+    .distinct()
+    .load(conn)
 }
 
 pub fn mark_all_as_read(conn: &MysqlConnection, user: &str) -> QueryResult<()> {
@@ -209,3 +798,289 @@ pub fn mark_all_as_read(conn: &MysqlConnection, user: &str) -> QueryResult<()> {
     .execute(conn)?;
   Ok(())
 }
+
+/// One of a user's channels, as shown on their home screen
+#[derive(Debug, Clone)]
+pub struct InboxEntry {
+  pub channel: DbChannel,
+  pub unread_count: i64,
+  pub last_message: Option<DbMessage>,
+}
+
+/// The user's channels with their unread counts and latest messages, most recently active first.
+/// Takes four queries however many channels the user is in.
+pub fn get_inbox(conn: &MysqlConnection, user: &str) -> QueryResult<Vec<InboxEntry>> {
+  let channels = channel_members::table
+    .inner_join(channels::table)
+    .filter(channel_members::dsl::user.eq(user))
+    .filter(channels::deleted_at.is_null())
+    .select(channels::all_columns)
+    .load::<DbChannel>(conn)?;
+  if channels.is_empty() {
+    return Ok(Vec::new());
+  }
+  let channel_ids: Vec<i32> = channels.iter().map(|ch| ch.id).collect();
+
+  let unread_counts: HashMap<i32, i64> = unread_by_channel(user)
+    .load::<(i32, i64)>(conn)?
+    .into_iter()
+    .collect();
+
+  let latest_ids: Vec<Option<i32>> = messages::table
+    .filter(messages::channel_id.eq_any(&channel_ids))
+    .group_by(messages::channel_id)
+    .select(diesel::dsl::max(messages::id))
+    .load(conn)?;
+  let latest_ids: Vec<i32> = latest_ids.into_iter().flatten().collect();
+  let mut last_messages: HashMap<i32, DbMessage> = messages::table
+    .filter(messages::id.eq_any(&latest_ids))
+    .load::<DbMessage>(conn)?
+    .into_iter()
+    .map(|msg| (msg.channel_id, msg))
+    .collect();
+
+  let mut inbox: Vec<InboxEntry> = channels
+    .into_iter()
+    .map(|channel| InboxEntry {
+      unread_count: unread_counts.get(&channel.id).cloned().unwrap_or(0),
+      last_message: last_messages.remove(&channel.id),
+      channel,
+    })
+    .collect();
+  // channels without any messages go last
+  inbox.sort_by_key(|entry| std::cmp::Reverse(entry.last_message.as_ref().map(|msg| msg.id)));
+  Ok(inbox)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use diesel::debug_query;
+
+  #[test]
+  fn unread_count_matches_unread_list() {
+    let list_sql =
+      debug_query::<Mysql, _>(&unread_messages("joe").select(messages::id)).to_string();
+    let count_sql = debug_query::<Mysql, _>(&unread_messages("joe").count()).to_string();
+    let list_filter = &list_sql[list_sql.find("WHERE").unwrap()..];
+    let count_filter = &count_sql[count_sql.find("WHERE").unwrap()..];
+    assert_eq!(list_filter, count_filter);
+    assert!(list_filter.contains("`message_views`.`user` = ?"));
+
+    let grouped_sql = debug_query::<Mysql, _>(&unread_by_channel("joe")).to_string();
+    let group_at = grouped_sql.find(" GROUP BY").unwrap();
+    assert_eq!(
+      &grouped_sql[grouped_sql.find("WHERE").unwrap()..group_at],
+      &list_filter[..list_filter.find(" -- binds").unwrap()]
+    );
+    assert!(grouped_sql[group_at..].starts_with(" GROUP BY `messages`.`channel_id`"));
+  }
+
+  #[test]
+  fn statement_timeout_per_server() {
+    let timeout = StatementTimeout(2500);
+    assert_eq!(
+      timeout.set_statement("10.4.12-MariaDB-1:10.4.12+maria~bionic"),
+      "SET SESSION max_statement_time = 2.5"
+    );
+    assert_eq!(
+      timeout.set_statement("8.0.19"),
+      "SET SESSION max_execution_time = 2500"
+    );
+  }
+
+  #[test]
+  fn member_roles() {
+    let sql = debug_query::<Mysql, _>(&member_roles_query(&[5, 6], &["joe", "sue"])).to_string();
+    assert!(sql.contains("`channel_members`.`channel_id` IN (?, ?)"));
+    assert!(sql.contains("`channel_members`.`user` IN (?, ?)"));
+    assert!(sql.contains("`channels`.`deleted_at` IS NULL"));
+    assert!(sql.ends_with(r#"-- binds: [5, 6, "joe", "sue"]"#));
+  }
+
+  #[test]
+  fn channel_search_escapes_wildcards() {
+    assert_eq!(channel_search_pattern("gen"), "gen%");
+    assert_eq!(channel_search_pattern("100%_real"), "100\\%\\_real%");
+    assert_eq!(channel_search_pattern("dev*ops"), "dev%ops%");
+    assert_eq!(channel_search_pattern("a\\b"), "a\\\\b%");
+  }
+
+  #[test]
+  fn channel_pages() {
+    let first = debug_query::<Mysql, _>(&channel_page_query(None, 2, true)).to_string();
+    assert!(!first.contains("WHERE"));
+    assert!(first.contains("ORDER BY `channels`.`id` ASC LIMIT ?"));
+    assert!(first.ends_with("-- binds: [3]"));
+    let next = debug_query::<Mysql, _>(&channel_page_query(Some(7), 2, true)).to_string();
+    assert!(next.contains("WHERE `channels`.`id` > ?"));
+    assert!(next.ends_with("-- binds: [7, 3]"));
+
+    // paging through channels 1 to 5, two at a time
+    let channels: Vec<i32> = (1..=5).collect();
+    let page = |after: i32| {
+      let rows = channels
+        .iter()
+        .cloned()
+        .filter(|id| *id > after)
+        .take(3)
+        .collect();
+      take_page(rows, 2)
+    };
+    assert_eq!(page(0), (vec![1, 2], true));
+    assert_eq!(page(2), (vec![3, 4], true));
+    assert_eq!(page(4), (vec![5], false));
+    assert_eq!(page(5), (vec![], false));
+  }
+
+  #[test]
+  fn soft_deleted_channels() {
+    let at = chrono::NaiveDate::from_ymd(2026, 10, 16).and_hms(9, 0, 0);
+    let sql = debug_query::<Mysql, _>(&soft_delete_query(4, at)).to_string();
+    // the row stays, so it can be recovered
+    assert!(sql.starts_with("UPDATE `channels` SET `deleted_at` = ?"));
+    assert!(sql.contains("WHERE `channels`.`id` = ? AND `channels`.`deleted_at` IS NULL"));
+
+    // deleted channels are gone from listings
+    let hidden = "`channels`.`deleted_at` IS NULL";
+    let page = debug_query::<Mysql, _>(&channel_page_query(None, 2, false)).to_string();
+    assert!(page.contains(hidden));
+    let found = debug_query::<Mysql, _>(&channel_query(4, false)).to_string();
+    assert!(found.contains(&format!("WHERE `channels`.`id` = ? AND {}", hidden)));
+
+    // and from everything that goes through memberships
+    let memberships = debug_query::<Mysql, _>(&memberships_query("joe")).to_string();
+    assert!(memberships.contains(&format!(
+      "`channel_members`.`channel_id` IN (SELECT `channels`.`id` FROM `channels` WHERE {})",
+      hidden
+    )));
+    let unread = debug_query::<Mysql, _>(&unread_messages("joe").count()).to_string();
+    assert!(unread.contains(hidden));
+
+    // unless they're asked for
+    let page = debug_query::<Mysql, _>(&channel_page_query(None, 2, true)).to_string();
+    assert!(!page.contains(hidden));
+    let found = debug_query::<Mysql, _>(&channel_query(4, true)).to_string();
+    assert!(!found.contains(hidden));
+    assert!(found.ends_with("-- binds: [4]"));
+
+    // restoring only touches deleted channels
+    let sql = debug_query::<Mysql, _>(&restore_query(4)).to_string();
+    assert!(sql.starts_with("UPDATE `channels` SET `deleted_at` = ?"));
+    assert!(sql.contains("WHERE `channels`.`id` = ? AND `channels`.`deleted_at` IS NOT NULL"));
+    assert!(sql.ends_with("-- binds: [None, 4]"));
+  }
+
+  #[test]
+  fn channel_messages_are_newest_first() {
+    let sql = debug_query::<Mysql, _>(&channel_messages_query(3, 20)).to_string();
+    assert!(sql.contains("WHERE `messages`.`channel_id` = ?"));
+    assert!(sql.contains("ORDER BY `messages`.`created_at` DESC, `messages`.`id` DESC LIMIT ?"));
+    assert!(sql.ends_with("-- binds: [3, 20]"));
+  }
+
+  #[test]
+  fn editing_marks_message_edited() {
+    let sql = debug_query::<Mysql, _>(&edit_message_query(5, "joe", "Fixed")).to_string();
+    assert!(sql.starts_with("UPDATE `messages` SET `content` = ?, `edited` = ?"));
+    // only the sender's own message is changed
+    assert!(sql.contains("WHERE `messages`.`id` = ? AND `messages`.`sender` = ?"));
+    assert!(sql.ends_with(r#"-- binds: [Some("Fixed"), Some(true), 5, "joe"]"#));
+  }
+
+  #[test]
+  fn edit_after_insert() {
+    let at = chrono::NaiveDate::from_ymd(2026, 10, 16).and_hms(9, 0, 0);
+    let inserted = DbMessage {
+      id: 5,
+      sender: "joe".to_owned(),
+      updated_at: at,
+      created_at: at,
+      edited: None,
+      channel_id: 3,
+      content: Some("Frist".to_owned()),
+    };
+    assert_eq!(check_editor(Some(&inserted), "joe"), Ok(()));
+    assert_eq!(
+      check_editor(Some(&inserted), "ann"),
+      Err(MessageEdit::NotSender)
+    );
+    assert_eq!(check_editor(None, "joe"), Err(MessageEdit::NotFound));
+  }
+
+  #[test]
+  fn idempotent_channels_ignore_repeats() {
+    let new_channel = NewChannel {
+      display_name: "General",
+      is_public: true,
+      external_key: Some("team-1"),
+    };
+    let sql = debug_query::<Mysql, _>(&create_channel_idempotent_query(new_channel)).to_string();
+    assert!(sql.starts_with("INSERT IGNORE INTO `channels`"));
+    assert!(sql.contains("(`display_name`, `is_public`, `external_key`)"));
+    assert!(sql.contains(r#""team-1""#));
+  }
+
+  #[test]
+  fn members_are_stored_as_users() {
+    let member = NewUser {
+      id: "joe",
+      name: None,
+    };
+    let sql = debug_query::<Mysql, _>(&store_user_query(member)).to_string();
+    // joining another channel doesn't wipe the name
+    assert!(sql.starts_with("INSERT IGNORE INTO `users` (`id`, `name`) VALUES (?, DEFAULT)"));
+    assert!(sql.ends_with(r#"-- binds: ["joe"]"#));
+    let named = NewUser {
+      id: "joe",
+      name: Some("Joe"),
+    };
+    let sql = debug_query::<Mysql, _>(&rename_user_query(named)).to_string();
+    assert!(sql.starts_with("REPLACE INTO `users` (`id`, `name`)"));
+    assert!(sql.ends_with(r#"-- binds: ["joe", "Joe"]"#));
+  }
+
+  #[test]
+  fn reactions() {
+    let reaction = NewReaction {
+      message_id: 5,
+      user: "joe",
+      emoji: "👍",
+    };
+    let sql = debug_query::<Mysql, _>(&add_reaction_query(reaction)).to_string();
+    // reacting twice hits the unique index, which is ignored
+    assert!(sql.starts_with("INSERT IGNORE INTO `message_reactions`"));
+    assert!(sql.contains("(`message_id`, `user`, `emoji`)"));
+    assert!(sql.ends_with(r#"-- binds: [5, "joe", "👍"]"#));
+
+    // every message in a list has its reactions loaded at once
+    let sql = debug_query::<Mysql, _>(&reactions_query(&[5, 6])).to_string();
+    assert!(sql.contains("WHERE `message_reactions`.`message_id` IN (?, ?)"));
+    assert!(
+      sql.contains("ORDER BY `message_reactions`.`created_at` ASC, `message_reactions`.`id` ASC")
+    );
+    assert!(sql.ends_with("-- binds: [5, 6]"));
+
+    let at = chrono::NaiveDate::from_ymd(2026, 10, 16).and_hms(9, 0, 0);
+    let reaction = |id, message_id, emoji: &str| DbReaction {
+      id,
+      message_id,
+      user: "joe".to_owned(),
+      emoji: emoji.to_owned(),
+      created_at: at,
+    };
+    let mut grouped = group_reactions(vec![
+      reaction(1, 5, "👍"),
+      reaction(2, 6, "🎉"),
+      reaction(3, 5, "👀"),
+    ]);
+    grouped.sort_by_key(|(message_id, _)| *message_id);
+    assert_eq!(
+      grouped,
+      vec![
+        (5, vec![reaction(1, 5, "👍"), reaction(3, 5, "👀")]),
+        (6, vec![reaction(2, 6, "🎉")]),
+      ]
+    );
+  }
+}
@@ -0,0 +1,148 @@
+use ring::digest;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use crate::gqln::{GqlQueryErr, GqlRequest, QueryValidationError, ResolutionErr};
+
+/// Registered queries by their SHA-256 hash, dropping the least recently used past `capacity`
+#[derive(Clone)]
+pub struct PersistedQueries {
+  store: Arc<Mutex<QueryStore>>,
+  capacity: usize,
+}
+
+#[derive(Default)]
+struct QueryStore {
+  queries: HashMap<String, String>,
+  /// Hashes from least to most recently used
+  used: VecDeque<String>,
+}
+
+impl QueryStore {
+  fn touch(&mut self, hash: &str) {
+    if let Some(pos) = self.used.iter().position(|h| h == hash) {
+      self.used.remove(pos);
+    }
+    self.used.push_back(hash.to_owned());
+  }
+}
+
+pub fn sha256_hex(text: &str) -> String {
+  digest::digest(&digest::SHA256, text.as_bytes())
+    .as_ref()
+    .iter()
+    .map(|b| format!("{:02x}", b))
+    .collect()
+}
+
+impl PersistedQueries {
+  pub fn new(capacity: usize) -> Self {
+    PersistedQueries {
+      store: Default::default(),
+      capacity,
+    }
+  }
+
+  /// Fills in the query of a request that only carries a hash.
+  /// Requests with both a hash and a query register the query for next time.
+  pub fn load(&self, mut req: GqlRequest) -> Result<GqlRequest, ResolutionErr> {
+    let hash = match req
+      .extensions
+      .as_ref()
+      .and_then(|e| e.persisted_query.as_ref())
+    {
+      Some(persisted) => persisted.sha256_hash.to_lowercase(),
+      None => return Ok(req),
+    };
+
+    if req.query.is_empty() {
+      let mut store = self.store.lock().unwrap();
+      req.query = store
+        .queries
+        .get(&hash)
+        .ok_or(ResolutionErr::PersistedQueryNotFound(hash.clone()))?
+        .to_owned();
+      store.touch(&hash);
+      return Ok(req);
+    }
+
+    if sha256_hex(&req.query) != hash {
+      return Err(ResolutionErr::QueryValidation(GqlQueryErr::PersistedQuery(
+        QueryValidationError::new(
+          "The provided sha256Hash does not match the query".to_owned(),
+          hash,
+        ),
+      )));
+    }
+    let mut store = self.store.lock().unwrap();
+    store.touch(&hash);
+    store.queries.insert(hash, req.query.clone());
+    while store.queries.len() > self.capacity {
+      match store.used.pop_front() {
+        Some(oldest) => store.queries.remove(&oldest),
+        None => break,
+      };
+    }
+    Ok(req)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::gqln::{GqlRequestExtensions, PersistedQuery};
+
+  const QUERY: &str = "query { me }";
+  const QUERY_HASH: &str = "8aea24594ba227016a1ff9978a84a4257e7d9edbe6d5b0471481ad7e3f4562b5";
+
+  fn request(query: &str, hash: &str) -> GqlRequest {
+    GqlRequest {
+      query: query.to_owned(),
+      operation_name: None,
+      variables: None,
+      extensions: Some(GqlRequestExtensions {
+        persisted_query: Some(PersistedQuery {
+          version: 1,
+          sha256_hash: hash.to_owned(),
+        }),
+      }),
+    }
+  }
+
+  #[test]
+  fn register_and_load() {
+    let store = PersistedQueries::new(10);
+    match store.load(request("", QUERY_HASH)) {
+      Err(ResolutionErr::PersistedQueryNotFound(hash)) => assert_eq!(hash, QUERY_HASH),
+      _ => panic!("an unknown hash should not be found"),
+    }
+
+    assert!(store.load(request(QUERY, QUERY_HASH)).is_ok());
+    assert_eq!(store.load(request("", QUERY_HASH)).unwrap().query, QUERY);
+  }
+
+  #[test]
+  fn hash_mismatch() {
+    let store = PersistedQueries::new(10);
+    assert!(store.load(request("query { other }", QUERY_HASH)).is_err());
+    assert!(store.load(request("", QUERY_HASH)).is_err());
+  }
+
+  #[test]
+  fn least_recently_used_is_dropped() {
+    let store = PersistedQueries::new(2);
+    let queries = ["query { a }", "query { b }", "query { c }"];
+    for query in &queries[..2] {
+      assert!(store.load(request(query, &sha256_hex(query))).is_ok());
+    }
+    // using the first query makes the second the oldest
+    assert!(store.load(request("", &sha256_hex(queries[0]))).is_ok());
+    assert!(store
+      .load(request(queries[2], &sha256_hex(queries[2])))
+      .is_ok());
+
+    assert!(store.load(request("", &sha256_hex(queries[0]))).is_ok());
+    assert!(store.load(request("", &sha256_hex(queries[1]))).is_err());
+    assert!(store.load(request("", &sha256_hex(queries[2]))).is_ok());
+  }
+}
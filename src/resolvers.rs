@@ -1,5 +1,7 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
 use diesel::prelude::*;
 use diesel::{mysql::MysqlConnection, r2d2::Error as DbConnsErr};
+use futures::{future, Future};
 use graphql_parser::query;
 use log::info;
 use std::collections::BTreeMap;
@@ -7,10 +9,15 @@ use std::convert::TryInto;
 
 use crate::gql_context::GqlContext;
 use crate::gqln::{
-  GqlArgs, GqlObj, GqlRoot, GqlSchema, MissingArgument, ResResult, ResolutionErr, ResolutionReturn,
+  get_input, GqlArgs, GqlObj, GqlQueryErr, GqlRoot, GqlSchema, MissingArgument,
+  QueryValidationError, ResFuture, ResResult, ResolutionErr, ResolutionReturn,
 };
+use crate::loader::Deferred;
 use crate::models::*;
-use crate::ws_actors::MsgMessageCreated;
+use crate::persisted_queries::sha256_hex;
+use crate::ws_actors::{
+  MsgMessageCreated, MsgUserAddedToChannel, MsgUserRemovedFromChannel, MsgUserTyping,
+};
 
 fn assert_arg_is_object<'a>(arg: &'a query::Value) -> Option<&'a GqlObj> {
   match arg {
@@ -40,6 +47,13 @@ fn assert_arg_is_number(arg: &query::Value) -> Option<i32> {
   }
 }
 
+fn assert_arg_is_id(arg: &query::Value) -> Option<i32> {
+  match arg {
+    query::Value::String(s) => s.parse().ok(),
+    _ => assert_arg_is_number(arg),
+  }
+}
+
 fn assert_has_id(root: &GqlRoot) -> Result<String, ResolutionErr> {
   let id = root
     .get("id")
@@ -72,17 +86,26 @@ pub fn mutation_create_message(
   let input_err = ResolutionErr::new_missing_argument("Mutation", "createMessage", "input");
   let input =
     assert_arg_is_object(args.get("input").ok_or(input_err.clone())?).ok_or(input_err.clone())?;
-  let msg_content = assert_arg_is_string(input.get("content").unwrap_or(&query::Value::Null))
+  let msg_content = get_input(input, "content")
+    .value()
+    .and_then(assert_arg_is_string)
     .ok_or(ResolutionErr::MissingArgument(MissingArgument::new(
       "CreateMessageInput",
       "content",
       "",
     )))?
     .to_owned();
-  let msg_channel = assert_arg_is_number(input.get("channel").ok_or(input_err.clone())?)
-    .ok_or(input_err.clone())?;
+  let msg_content = filter_content(context, msg_content)?;
+  // IDs may come in as strings, which is how variables usually send them
+  let msg_channel =
+    assert_arg_is_id(input.get("channel").ok_or(input_err.clone())?).ok_or(input_err.clone())?;
 
-  let conn: &MysqlConnection = &*context.db.get()?;
+  let conn: &MysqlConnection = &*context.conn()?;
+  // deleted channels keep their messages, but don't get new ones
+  get_channel(conn, msg_channel, false)?.ok_or(ResolutionErr::NotFound(format!(
+    "Could not find channel {}",
+    msg_channel
+  )))?;
   let new_msg = create_message(&conn, &context.cur_user, msg_channel, &msg_content)
     .map_err(|_| ResolutionErr::io_err("Database error"))?;
 
@@ -90,15 +113,17 @@ pub fn mutation_create_message(
     msg_channel,
     msg_content.clone(),
     context.cur_user.clone(),
-    100,
+    new_msg.id,
   );
-  context.ws_addr.do_send(actor_message);
+  context.notify(actor_message);
 
+  // the id lets nested fields like `sender` load the rest of the message
   let mut bmap = GqlObj::new();
   bmap.insert(
     "id".to_owned(),
-    query::Value::Int(query::Number::from(new_msg.id.to_owned())),
+    query::Value::String(new_msg.id.to_string()),
   );
+  bmap.insert("content".to_owned(), query::Value::String(msg_content));
   Ok(ResolutionReturn::Type(("Message".to_owned(), bmap)))
 }
 
@@ -108,16 +133,68 @@ pub fn subscription_message(
   context: &mut GqlContext,
   _schema: &GqlSchema<GqlContext>,
 ) -> ResResult {
+  // the event is something other than a new message
+  let id = match root.get("id") {
+    Some(id) => id,
+    None => return Ok(ResolutionReturn::Scalar(query::Value::Null)),
+  };
   let mut bmap = BTreeMap::new();
-  if let Some(id) = root.get("id") {
-    bmap.insert("id".to_owned(), id.to_owned());
-  }
+  bmap.insert("id".to_owned(), id.to_owned());
   if let Some(content) = root.get("content") {
     bmap.insert("content".to_owned(), content.to_owned());
   }
+  if let Some(truncated) = root.get("contentTruncated") {
+    bmap.insert("contentTruncated".to_owned(), truncated.to_owned());
+  }
   Ok(ResolutionReturn::Type(("Message".to_owned(), bmap)))
 }
 
+pub fn subscription_typing(
+  root: &GqlRoot,
+  _: GqlArgs,
+  _context: &mut GqlContext,
+  _schema: &GqlSchema<GqlContext>,
+) -> ResResult {
+  let (channel, user) = match (root.get("typingChannel"), root.get("typingUser")) {
+    (Some(channel), Some(user)) => (channel, user),
+    // the event is something other than someone typing
+    _ => return Ok(ResolutionReturn::Scalar(query::Value::Null)),
+  };
+  let mut bmap = BTreeMap::new();
+  bmap.insert("channelId".to_owned(), channel.to_owned());
+  bmap.insert("userId".to_owned(), user.to_owned());
+  Ok(ResolutionReturn::Type(("TypingEvent".to_owned(), bmap)))
+}
+
+/// Tells the other subscribers of a channel the current user is typing there
+pub fn mutation_send_typing(
+  _root: &GqlRoot,
+  args: GqlArgs,
+  context: &mut GqlContext,
+  _: &GqlSchema<GqlContext>,
+) -> ResResult {
+  let channel_id =
+    args
+      .get("channelId")
+      .and_then(assert_arg_is_id)
+      .ok_or(ResolutionErr::new_missing_argument(
+        "Mutation",
+        "sendTyping",
+        "channelId",
+      ))?;
+  if context.role_in(channel_id)?.is_none() {
+    return Err(ResolutionErr::Unauthorized(format!(
+      "Not a member of channel {}",
+      channel_id
+    )));
+  }
+  context.ws_addr.do_send(MsgUserTyping {
+    channel: channel_id,
+    user: context.cur_user.clone(),
+  });
+  Ok(ResolutionReturn::Scalar(query::Value::Null))
+}
+
 pub fn query_me(
   _root: &GqlRoot,
   _args: GqlArgs,
@@ -129,6 +206,160 @@ pub fn query_me(
   )))
 }
 
+/// Runs message content through the server's content filter, if it has one
+fn filter_content(context: &GqlContext, content: String) -> Result<String, ResolutionErr> {
+  match &context.content_filter {
+    Some(filter) => filter(&content).map_err(|reason| {
+      ResolutionErr::QueryValidation(GqlQueryErr::Field(QueryValidationError::new(
+        reason,
+        "content".to_owned(),
+      )))
+    }),
+    None => Ok(content),
+  }
+}
+
+pub fn mutation_edit_message(
+  _root: &GqlRoot,
+  args: GqlArgs,
+  context: &mut GqlContext,
+  _: &GqlSchema<GqlContext>,
+) -> ResResult {
+  let msg_id =
+    args
+      .get("message")
+      .and_then(assert_arg_is_id)
+      .ok_or(ResolutionErr::new_missing_argument(
+        "Mutation",
+        "editMessage",
+        "message",
+      ))?;
+  let content = args
+    .get("content")
+    .and_then(assert_arg_is_string)
+    .ok_or(ResolutionErr::new_missing_argument(
+      "Mutation",
+      "editMessage",
+      "content",
+    ))?
+    .to_owned();
+  let content = filter_content(context, content)?;
+
+  let conn: &MysqlConnection = &*context.conn()?;
+  let edited = match update_message(conn, msg_id, &context.cur_user, &content)? {
+    MessageEdit::Edited(message) => message,
+    MessageEdit::NotFound => {
+      return Err(ResolutionErr::NotFound(format!("Message {}", msg_id)));
+    }
+    MessageEdit::NotSender => {
+      return Err(ResolutionErr::Forbidden(format!(
+        "Only the sender of message {} can edit it",
+        msg_id
+      )));
+    }
+  };
+  let mut bmap = GqlObj::new();
+  bmap.insert("id".to_owned(), query::Value::String(edited.id.to_string()));
+  bmap.insert("content".to_owned(), query::Value::String(content));
+  Ok(ResolutionReturn::Type(("Message".to_owned(), bmap)))
+}
+
+/// Reactions are an emoji, or a short sequence of them like a flag or a skin tone
+fn check_emoji(emoji: &str) -> Result<(), ResolutionErr> {
+  let length = emoji.chars().count();
+  if length == 0 || length > 16 || emoji.chars().any(|c| c.is_whitespace() || c.is_control()) {
+    return Err(ResolutionErr::QueryResult(format!(
+      "{:?} is not an emoji",
+      emoji
+    )));
+  }
+  Ok(())
+}
+
+/// Reacts to a message in one of the current user's channels.
+/// Reacting with the same emoji twice is the same as reacting once.
+pub fn mutation_add_reaction(
+  _root: &GqlRoot,
+  args: GqlArgs,
+  context: &mut GqlContext,
+  _: &GqlSchema<GqlContext>,
+) -> ResResult {
+  let msg_id =
+    args
+      .get("message")
+      .and_then(assert_arg_is_id)
+      .ok_or(ResolutionErr::new_missing_argument(
+        "Mutation",
+        "addReaction",
+        "message",
+      ))?;
+  let emoji =
+    args
+      .get("emoji")
+      .and_then(assert_arg_is_string)
+      .ok_or(ResolutionErr::new_missing_argument(
+        "Mutation",
+        "addReaction",
+        "emoji",
+      ))?;
+  check_emoji(emoji)?;
+
+  let conn: &MysqlConnection = &*context.conn()?;
+  let message = get_message(conn, msg_id)?.ok_or(ResolutionErr::NotFound(format!(
+    "Could not find message {}",
+    msg_id
+  )))?;
+  if !is_member(conn, message.channel_id, &context.cur_user)? {
+    return Err(ResolutionErr::Unauthorized(format!(
+      "Not a member of channel {}",
+      message.channel_id
+    )));
+  }
+  add_reaction(conn, msg_id, &context.cur_user, emoji)?;
+  // so a `sender` or `reactions` on the result goes through the loaders
+  context.load_message(msg_id);
+  context.load_reactions(msg_id);
+  let mut bmap = GqlObj::new();
+  bmap.insert("id".to_owned(), query::Value::String(msg_id.to_string()));
+  Ok(ResolutionReturn::Type(("Message".to_owned(), bmap)))
+}
+
+fn reaction_obj(reaction: DbReaction) -> GqlObj {
+  let mut bmap = GqlObj::new();
+  bmap.insert("userId".to_owned(), query::Value::String(reaction.user));
+  bmap.insert("emoji".to_owned(), query::Value::String(reaction.emoji));
+  bmap
+}
+
+/// A message's reactions, fetched with `fetch` along with those of every other message queued
+fn reactions_of<F>(
+  pending: Deferred<i32, Vec<DbReaction>>,
+  fetch: F,
+) -> Result<Vec<GqlObj>, ResolutionErr>
+where
+  F: FnOnce(&[i32]) -> Result<Vec<(i32, Vec<DbReaction>)>, ResolutionErr>,
+{
+  // messages nobody reacted to aren't in the batch
+  let reactions = pending.get(fetch)?.unwrap_or_default();
+  Ok(reactions.into_iter().map(reaction_obj).collect())
+}
+
+pub fn message_reactions(
+  root: &GqlRoot,
+  _args: GqlArgs,
+  context: &mut GqlContext,
+  _: &GqlSchema<GqlContext>,
+) -> ResResult {
+  let msg_id: i32 = assert_has_id(root)?
+    .parse()
+    .map_err(|_| ResolutionErr::new_invalid_field("Message", "id"))?;
+  let pending = context.load_reactions(msg_id);
+  Ok(ResolutionReturn::TypeList((
+    "Reaction".to_owned(),
+    reactions_of(pending, |ids| context.fetch_reactions(ids))?,
+  )))
+}
+
 pub fn mutation_read_message(
   _root: &GqlRoot,
   args: GqlArgs,
@@ -148,31 +379,358 @@ pub fn mutation_read_message(
     .parse()
     .map_err(|_| ResolutionErr::new_missing_argument("Mutation", "readMessage", "message"))?;
 
-  let conn: &MysqlConnection = &*context.db.get()?;
+  let conn: &MysqlConnection = &*context.conn()?;
   mark_message_as_read(conn, msg, &context.cur_user)?;
 
   Ok(ResolutionReturn::Scalar(query::Value::Null))
 }
 
+pub fn mutation_join_channel(
+  _root: &GqlRoot,
+  args: GqlArgs,
+  context: &mut GqlContext,
+  _: &GqlSchema<GqlContext>,
+) -> ResResult {
+  let channel_id =
+    args
+      .get("channelId")
+      .and_then(assert_arg_is_id)
+      .ok_or(ResolutionErr::new_missing_argument(
+        "Mutation",
+        "joinChannel",
+        "channelId",
+      ))?;
+
+  let conn: &MysqlConnection = &*context.conn()?;
+  let channel = get_channel(conn, channel_id, false)?.ok_or(ResolutionErr::NotFound(format!(
+    "Could not find channel {}",
+    channel_id
+  )))?;
+  if !channel.is_public {
+    return Err(ResolutionErr::Unauthorized(format!(
+      "Channel {} is private",
+      channel_id
+    )));
+  }
+  if !is_member(conn, channel_id, &context.cur_user)? {
+    add_user_to_channel(conn, &context.cur_user, channel_id, "member")?;
+    context.notify(MsgUserAddedToChannel {
+      user: context.cur_user.clone(),
+      channel: channel_id,
+    });
+  }
+
+  Ok(ResolutionReturn::Scalar(query::Value::Null))
+}
+
+pub fn mutation_leave_channel(
+  _root: &GqlRoot,
+  args: GqlArgs,
+  context: &mut GqlContext,
+  _: &GqlSchema<GqlContext>,
+) -> ResResult {
+  let channel_id =
+    args
+      .get("channelId")
+      .and_then(assert_arg_is_id)
+      .ok_or(ResolutionErr::new_missing_argument(
+        "Mutation",
+        "leaveChannel",
+        "channelId",
+      ))?;
+
+  let conn: &MysqlConnection = &*context.conn()?;
+  if !is_member(conn, channel_id, &context.cur_user)? {
+    return Err(ResolutionErr::NotFound(format!(
+      "Not a member of channel {}",
+      channel_id
+    )));
+  }
+  remove_user(conn, channel_id, &context.cur_user)?;
+  context.notify(MsgUserRemovedFromChannel {
+    user: context.cur_user.clone(),
+    channel: channel_id,
+  });
+
+  Ok(ResolutionReturn::Scalar(query::Value::Null))
+}
+
 pub fn query_unread(
   _root: &GqlRoot,
   _args: GqlArgs,
   context: &mut GqlContext,
   _: &GqlSchema<GqlContext>,
-) -> ResResult {
-  let conn: &MysqlConnection = &*context.db.get()?;
-  let messages = get_unread(conn, &context.cur_user)?;
-  Ok(ResolutionReturn::TypeList((
-    "Message".to_owned(),
-    messages
+) -> ResFuture {
+  let user = context.cur_user.clone();
+  let context = context.clone();
+  let found = context.blocking_query(move |conn| Ok(get_unread(conn, &user)?));
+  Box::new(found.map(move |messages| {
+    ResolutionReturn::TypeList((
+      "Message".to_owned(),
+      messages
+        .into_iter()
+        .map(|id| {
+          // so a `sender` or `reactions` on each of them is loaded in one query
+          context.load_message(id);
+          context.load_reactions(id);
+          let mut bmap = BTreeMap::new();
+          bmap.insert("id".to_owned(), query::Value::String(format!("{}", id)));
+          // Saves a lookup per message in `message_read`
+          bmap.insert("read".to_owned(), query::Value::Boolean(false));
+          bmap
+        })
+        .collect(),
+    ))
+  }))
+}
+
+/// The current user's channels, with everything a home screen shows for them.
+/// Loaded up front in a few queries, rather than by a resolver per channel.
+pub fn query_inbox(
+  _root: &GqlRoot,
+  _args: GqlArgs,
+  context: &mut GqlContext,
+  _: &GqlSchema<GqlContext>,
+) -> ResFuture {
+  let user = context.cur_user.clone();
+  let context = context.clone();
+  let found = context.blocking_query(move |conn| Ok(get_inbox(conn, &user)?));
+  Box::new(found.and_then(move |inbox| {
+    let entries = inbox
       .into_iter()
-      .map(|id| {
-        let mut bmap = BTreeMap::new();
-        bmap.insert("id".to_owned(), query::Value::String(format!("{}", id)));
-        bmap
-      })
-      .collect(),
-  )))
+      .map(|entry| inbox_entry(entry, &context))
+      .collect::<Result<Vec<GqlObj>, ResolutionErr>>()?;
+    Ok(ResolutionReturn::TypeList((
+      "InboxEntry".to_owned(),
+      entries,
+    )))
+  }))
+}
+
+/// An `InboxEntry` for one of the user's channels
+fn inbox_entry(entry: InboxEntry, context: &GqlContext) -> Result<GqlObj, ResolutionErr> {
+  let unread: i32 = entry
+    .unread_count
+    .try_into()
+    .map_err(|_| ResolutionErr::QueryResult("Unread count is too large".to_owned()))?;
+  let mut channel = GqlObj::new();
+  channel.insert(
+    "id".to_owned(),
+    query::Value::String(entry.channel.id.to_string()),
+  );
+  channel.insert(
+    "display_name".to_owned(),
+    entry
+      .channel
+      .display_name
+      .map(query::Value::String)
+      .unwrap_or(query::Value::Null),
+  );
+  let last_message = match entry.last_message {
+    Some(msg) => {
+      // so a `sender`, `read` or `reactions` on every last message is loaded in one query
+      context.load_message(msg.id);
+      context.load_read(msg.id);
+      context.load_reactions(msg.id);
+      let mut bmap = GqlObj::new();
+      bmap.insert("id".to_owned(), query::Value::String(msg.id.to_string()));
+      bmap.insert(
+        "content".to_owned(),
+        msg
+          .content
+          .map(query::Value::String)
+          .unwrap_or(query::Value::Null),
+      );
+      query::Value::Object(bmap)
+    }
+    None => query::Value::Null,
+  };
+  let mut bmap = GqlObj::new();
+  bmap.insert("channel".to_owned(), query::Value::Object(channel));
+  bmap.insert(
+    "unreadCount".to_owned(),
+    query::Value::Int(query::Number::from(unread)),
+  );
+  bmap.insert("lastMessage".to_owned(), last_message);
+  Ok(bmap)
+}
+
+fn channel_obj(channel: DbChannel) -> GqlObj {
+  let mut bmap = GqlObj::new();
+  bmap.insert(
+    "id".to_owned(),
+    query::Value::String(channel.id.to_string()),
+  );
+  bmap.insert(
+    "display_name".to_owned(),
+    channel
+      .display_name
+      .map(query::Value::String)
+      .unwrap_or(query::Value::Null),
+  );
+  bmap
+}
+
+/// Loads each of `channel_ids` with `load`, leaving out any that no longer exist
+fn my_channels<F>(channel_ids: Vec<i32>, mut load: F) -> Result<Vec<GqlObj>, ResolutionErr>
+where
+  F: FnMut(i32) -> QueryResult<Option<DbChannel>>,
+{
+  let mut channels = Vec::with_capacity(channel_ids.len());
+  for id in channel_ids {
+    if let Some(channel) = load(id)? {
+      channels.push(channel_obj(channel));
+    }
+  }
+  Ok(channels)
+}
+
+/// Every channel the current user is a member of
+pub fn query_my_channels(
+  _root: &GqlRoot,
+  _args: GqlArgs,
+  context: &mut GqlContext,
+  _: &GqlSchema<GqlContext>,
+) -> ResFuture {
+  let user = context.cur_user.clone();
+  let found = context.blocking_query(move |conn| {
+    let channel_ids = get_users_channels(conn, &user)?;
+    my_channels(channel_ids, |id| get_channel(conn, id, false))
+  });
+  Box::new(found.map(|channels| ResolutionReturn::TypeList(("Channel".to_owned(), channels))))
+}
+
+/// Only broadcasts of long messages carry a shortened `content`,
+/// so messages loaded any other way are always complete
+pub fn message_content_truncated(
+  _root: &GqlRoot,
+  _args: GqlArgs,
+  _context: &mut GqlContext,
+  _: &GqlSchema<GqlContext>,
+) -> ResResult {
+  Ok(ResolutionReturn::Scalar(query::Value::Boolean(false)))
+}
+
+pub fn message_read(
+  root: &GqlRoot,
+  _args: GqlArgs,
+  context: &mut GqlContext,
+  _: &GqlSchema<GqlContext>,
+) -> ResResult {
+  let msg_id = root
+    .get("id")
+    .and_then(assert_arg_is_id)
+    .ok_or(ResolutionErr::new_invalid_field("Message", "id"))?;
+  let read = context.is_read(context.load_read(msg_id))?;
+  Ok(ResolutionReturn::Scalar(query::Value::Boolean(read)))
+}
+
+/// A hash of the schema the server was built with, so clients can tell when
+/// their cached introspection is out of date
+pub fn query_schema_hash(
+  _root: &GqlRoot,
+  _args: GqlArgs,
+  _context: &mut GqlContext,
+  _: &GqlSchema<GqlContext>,
+) -> ResResult {
+  Ok(ResolutionReturn::Scalar(query::Value::String(schema_hash(
+    include_str!("../schema.graphql"),
+  ))))
+}
+
+/// Hashes the schema as printed back from its parsed form,
+/// so comments and formatting don't change the hash
+fn schema_hash(sdl: &str) -> String {
+  match graphql_parser::parse_schema(sdl) {
+    Ok(doc) => sha256_hex(&doc.to_string()),
+    Err(_) => sha256_hex(sdl),
+  }
+}
+
+pub fn query_server_info(
+  _root: &GqlRoot,
+  _args: GqlArgs,
+  context: &mut GqlContext,
+  _: &GqlSchema<GqlContext>,
+) -> ResResult {
+  let uptime = context
+    .server_started
+    .map(|started| started.elapsed().as_secs())
+    .unwrap_or(0);
+  let mut bmap = GqlObj::new();
+  bmap.insert(
+    "uptimeSeconds".to_owned(),
+    query::Value::Int(query::Number::from(
+      uptime.try_into().unwrap_or(i32::max_value()),
+    )),
+  );
+  bmap.insert(
+    "version".to_owned(),
+    query::Value::String(env!("CARGO_PKG_VERSION").to_owned()),
+  );
+  Ok(ResolutionReturn::Type(("ServerInfo".to_owned(), bmap)))
+}
+
+pub fn query_unread_count(
+  _root: &GqlRoot,
+  _args: GqlArgs,
+  context: &mut GqlContext,
+  _: &GqlSchema<GqlContext>,
+) -> ResFuture {
+  let user = context.cur_user.clone();
+  Box::new(context.blocking_query(move |conn| {
+    let count: i32 = get_total_unread(conn, &user)?
+      .try_into()
+      .map_err(|_| ResolutionErr::QueryResult("Unread count is too large".to_owned()))?;
+    Ok(ResolutionReturn::Scalar(query::Value::Int(
+      query::Number::from(count),
+    )))
+  }))
+}
+
+pub fn query_my_role(
+  _root: &GqlRoot,
+  args: GqlArgs,
+  context: &mut GqlContext,
+  _: &GqlSchema<GqlContext>,
+) -> ResFuture {
+  let channel_id = match args.get("channelId").and_then(assert_arg_is_id) {
+    Some(id) => id,
+    None => {
+      return Box::new(future::err(ResolutionErr::new_missing_argument(
+        "Query",
+        "myRole",
+        "channelId",
+      )))
+    }
+  };
+  let user = context.cur_user.clone();
+  Box::new(context.blocking_query(move |conn| {
+    Ok(ResolutionReturn::Scalar(
+      get_user_role(conn, channel_id, &user)?
+        .map(query::Value::String)
+        .unwrap_or(query::Value::Null),
+    ))
+  }))
+}
+
+/// The current user's role for fields guarded with `@auth`: in the channel the field
+/// asks about with a `channelId` argument, or the channel it is a field of
+pub fn channel_role(
+  on_type: &str,
+  root: &GqlRoot,
+  args: &GqlArgs,
+  context: &mut GqlContext,
+) -> Result<Option<String>, ResolutionErr> {
+  let channel_id = match args.get("channelId").and_then(assert_arg_is_id) {
+    Some(id) => Some(id),
+    None if on_type == "Channel" => root.get("id").and_then(assert_arg_is_id),
+    None => None,
+  };
+  match channel_id {
+    Some(id) => context.role_in(id),
+    None => Ok(None),
+  }
 }
 
 pub fn message_sender(
@@ -181,13 +739,534 @@ pub fn message_sender(
   context: &mut GqlContext,
   _: &GqlSchema<GqlContext>,
 ) -> ResResult {
-  let msg_id: i32 = assert_has_id(root)?.parse().unwrap();
-  let conn: &MysqlConnection = &*context.db.get()?;
-  let message = get_message(conn, msg_id)?.ok_or(ResolutionErr::QueryResult(format!(
-    "Could not find message {}",
-    msg_id
-  )))?;
-  Ok(ResolutionReturn::Scalar(query::Value::String(
-    message.sender,
+  let id = assert_has_id(root)?;
+  let msg_id: i32 = id
+    .parse()
+    .map_err(|_| ResolutionErr::QueryResult(format!("{:?} is not a message id", id)))?;
+  let message =
+    context
+      .get_message(context.load_message(msg_id))?
+      .ok_or(ResolutionErr::NotFound(format!(
+        "Could not find message {}",
+        msg_id
+      )))?;
+  let (user, role) = context.load_sender(&message);
+  Ok(ResolutionReturn::Type((
+    "User".to_owned(),
+    sender_of(
+      message.sender,
+      user,
+      role,
+      |ids| context.fetch_users(ids),
+      |members| context.fetch_roles(members),
+    )?,
+  )))
+}
+
+/// A message's sender as a `User`, with their role in the message's channel.
+/// Each is fetched along with those of every other message queued.
+fn sender_of<U, R>(
+  sender: String,
+  user: Deferred<String, DbUser>,
+  role: Deferred<(i32, String), String>,
+  fetch_users: U,
+  fetch_roles: R,
+) -> Result<GqlObj, ResolutionErr>
+where
+  U: FnOnce(&[String]) -> Result<Vec<(String, DbUser)>, ResolutionErr>,
+  R: FnOnce(&[(i32, String)]) -> Result<Vec<((i32, String), String)>, ResolutionErr>,
+{
+  // senders we have no details for, and members without a role, aren't in the batches
+  let name = user.get(fetch_users)?.and_then(|u| u.name);
+  let role = role.get(fetch_roles)?;
+  let mut bmap = GqlObj::new();
+  bmap.insert("id".to_owned(), query::Value::String(sender));
+  bmap.insert(
+    "name".to_owned(),
+    name.map(query::Value::String).unwrap_or(query::Value::Null),
+  );
+  bmap.insert(
+    "role".to_owned(),
+    role.map(query::Value::String).unwrap_or(query::Value::Null),
+  );
+  Ok(bmap)
+}
+
+fn load_channel(root: &GqlRoot, context: &mut GqlContext) -> Result<DbChannel, ResolutionErr> {
+  let channel_id: i32 = assert_has_id(root)?
+    .parse()
+    .map_err(|_| ResolutionErr::new_invalid_field("Channel", "id"))?;
+  let conn: &MysqlConnection = &*context.conn()?;
+  get_channel(conn, channel_id, false)?.ok_or(ResolutionErr::NotFound(format!(
+    "Could not find channel {}",
+    channel_id
   )))
 }
+
+fn date_time(time: NaiveDateTime) -> query::Value {
+  query::Value::String(DateTime::<Utc>::from_utc(time, Utc).to_rfc3339())
+}
+
+/// Reads `DateTime` arguments, which are RFC 3339 strings like the ones `date_time` makes
+pub fn parse_date_time(raw: &str) -> Result<query::Value, String> {
+  DateTime::parse_from_rfc3339(raw)
+    .map(|at| query::Value::String(at.with_timezone(&Utc).to_rfc3339()))
+    .map_err(|e| e.to_string())
+}
+
+/// `DateTime` results are already RFC 3339 strings
+pub fn serialize_date_time(value: &query::Value) -> serde_json::Value {
+  match value {
+    query::Value::String(at) => serde_json::Value::String(at.clone()),
+    _ => serde_json::Value::Null,
+  }
+}
+
+pub fn channel_created_at(
+  root: &GqlRoot,
+  _args: GqlArgs,
+  context: &mut GqlContext,
+  _: &GqlSchema<GqlContext>,
+) -> ResResult {
+  let channel = load_channel(root, context)?;
+  Ok(ResolutionReturn::Scalar(date_time(channel.created_at)))
+}
+
+pub fn channel_updated_at(
+  root: &GqlRoot,
+  _args: GqlArgs,
+  context: &mut GqlContext,
+  _: &GqlSchema<GqlContext>,
+) -> ResResult {
+  let channel = load_channel(root, context)?;
+  Ok(ResolutionReturn::Scalar(date_time(channel.updated_at)))
+}
+
+pub fn channel_message_count(
+  root: &GqlRoot,
+  _args: GqlArgs,
+  context: &mut GqlContext,
+  _: &GqlSchema<GqlContext>,
+) -> ResResult {
+  let channel_id: i32 = assert_has_id(root)?
+    .parse()
+    .map_err(|_| ResolutionErr::new_invalid_field("Channel", "id"))?;
+  let conn: &MysqlConnection = &*context.conn()?;
+  let count: i32 = count_channel_messages(conn, channel_id)?
+    .try_into()
+    .map_err(|_| ResolutionErr::QueryResult("Message count is too large".to_owned()))?;
+  Ok(ResolutionReturn::Scalar(query::Value::Int(
+    query::Number::from(count),
+  )))
+}
+
+pub fn channel_member_count(
+  root: &GqlRoot,
+  _args: GqlArgs,
+  context: &mut GqlContext,
+  _: &GqlSchema<GqlContext>,
+) -> ResResult {
+  let channel_id: i32 = assert_has_id(root)?
+    .parse()
+    .map_err(|_| ResolutionErr::new_invalid_field("Channel", "id"))?;
+  let conn: &MysqlConnection = &*context.conn()?;
+  let count: i32 = count_channel_members(conn, channel_id)?
+    .try_into()
+    .map_err(|_| ResolutionErr::QueryResult("Member count is too large".to_owned()))?;
+  Ok(ResolutionReturn::Scalar(query::Value::Int(
+    query::Number::from(count),
+  )))
+}
+
+/// The most messages a single page of `Query.channelMessages` can hold
+const MAX_MESSAGES_PAGE: i64 = 100;
+
+/// Loads a page of at most `limit` messages with `load`, as long as the user is a member
+fn channel_messages_page<T, F>(
+  channel_id: i32,
+  limit: Option<i64>,
+  is_member: bool,
+  load: F,
+) -> Result<Vec<T>, ResolutionErr>
+where
+  F: FnOnce(i64) -> QueryResult<Vec<T>>,
+{
+  if !is_member {
+    return Err(ResolutionErr::Unauthorized(format!(
+      "Not a member of channel {}",
+      channel_id
+    )));
+  }
+  let limit = limit
+    .unwrap_or(MAX_MESSAGES_PAGE)
+    .max(0)
+    .min(MAX_MESSAGES_PAGE);
+  Ok(load(limit)?)
+}
+
+/// The newest messages of a channel the current user is in
+pub fn query_channel_messages(
+  _root: &GqlRoot,
+  args: GqlArgs,
+  context: &mut GqlContext,
+  _: &GqlSchema<GqlContext>,
+) -> ResFuture {
+  let channel_id = match args.get("channelId").and_then(assert_arg_is_id) {
+    Some(id) => id,
+    None => {
+      return Box::new(future::err(ResolutionErr::new_missing_argument(
+        "Query",
+        "channelMessages",
+        "channelId",
+      )))
+    }
+  };
+  let limit = args
+    .get("limit")
+    .and_then(assert_arg_is_number)
+    .map(i64::from);
+  let user = context.cur_user.clone();
+  let context = context.clone();
+  let found = context.blocking_query(move |conn| {
+    let member = is_member(conn, channel_id, &user)?;
+    channel_messages_page(channel_id, limit, member, |limit| {
+      get_channel_messages(conn, channel_id, limit)
+    })
+  });
+  Box::new(found.map(move |messages| {
+    ResolutionReturn::TypeList((
+      "Message".to_owned(),
+      messages
+        .into_iter()
+        .map(|msg| {
+          // so a `sender`, `read` or `reactions` on each of them is loaded in one query
+          context.load_message(msg.id);
+          context.load_read(msg.id);
+          context.load_reactions(msg.id);
+          let mut bmap = GqlObj::new();
+          bmap.insert("id".to_owned(), query::Value::String(msg.id.to_string()));
+          bmap.insert(
+            "content".to_owned(),
+            msg
+              .content
+              .map(query::Value::String)
+              .unwrap_or(query::Value::Null),
+          );
+          bmap
+        })
+        .collect(),
+    ))
+  }))
+}
+
+/// The most members a single page of `Channel.members` can hold
+const MAX_MEMBERS_PAGE: i64 = 100;
+
+pub fn channel_members(
+  root: &GqlRoot,
+  args: GqlArgs,
+  context: &mut GqlContext,
+  _: &GqlSchema<GqlContext>,
+) -> ResResult {
+  let channel_id: i32 = assert_has_id(root)?
+    .parse()
+    .map_err(|_| ResolutionErr::new_invalid_field("Channel", "id"))?;
+  let offset = args
+    .get("offset")
+    .and_then(assert_arg_is_number)
+    .map_or(0, i64::from)
+    .max(0);
+  let limit = args
+    .get("limit")
+    .and_then(assert_arg_is_number)
+    .map_or(MAX_MEMBERS_PAGE, i64::from)
+    .max(0)
+    .min(MAX_MEMBERS_PAGE);
+  let conn: &MysqlConnection = &*context.conn()?;
+  let members = get_channel_members_paginated(conn, channel_id, offset, limit)?;
+  Ok(ResolutionReturn::TypeList((
+    "User".to_owned(),
+    members
+      .into_iter()
+      .map(|(member, user)| member_obj(member, user))
+      .collect(),
+  )))
+}
+
+/// A channel member as a `User`, with their role in that channel
+fn member_obj(member: DbChannelMember, user: Option<DbUser>) -> GqlObj {
+  let mut bmap = BTreeMap::new();
+  bmap.insert("id".to_owned(), query::Value::String(member.user));
+  bmap.insert(
+    "name".to_owned(),
+    user
+      .and_then(|u| u.name)
+      .map(query::Value::String)
+      .unwrap_or(query::Value::Null),
+  );
+  bmap.insert(
+    "role".to_owned(),
+    member
+      .user_role
+      .map(query::Value::String)
+      .unwrap_or(query::Value::Null),
+  );
+  bmap
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::loader::BatchLoader;
+  use chrono::NaiveDate;
+  use std::cell::Cell;
+
+  #[test]
+  fn reactions() {
+    assert!(check_emoji("👍").is_ok());
+    // flags and skin tones are several characters long
+    assert!(check_emoji("🇳🇿").is_ok());
+    assert!(check_emoji("👋🏽").is_ok());
+    assert!(check_emoji("").is_err());
+    assert!(check_emoji("👍 👍").is_err());
+    assert!(check_emoji(&"👍".repeat(17)).is_err());
+
+    let reaction = DbReaction {
+      id: 1,
+      message_id: 5,
+      user: "joe".to_owned(),
+      emoji: "🎉".to_owned(),
+      created_at: NaiveDate::from_ymd(2026, 10, 16).and_hms(9, 0, 0),
+    };
+    let obj = reaction_obj(reaction);
+    assert_eq!(obj["userId"], query::Value::String("joe".to_owned()));
+    assert_eq!(obj["emoji"], query::Value::String("🎉".to_owned()));
+  }
+
+  #[test]
+  fn reactions_load_in_one_batch() {
+    let at = NaiveDate::from_ymd(2026, 10, 16).and_hms(9, 0, 0);
+    let seeded: Vec<DbReaction> = vec![(5, "joe", "👍"), (6, "sue", "🎉"), (5, "sue", "👀")]
+      .into_iter()
+      .enumerate()
+      .map(|(i, (message_id, user, emoji))| DbReaction {
+        id: i as i32 + 1,
+        message_id,
+        user: user.to_owned(),
+        emoji: emoji.to_owned(),
+        created_at: at,
+      })
+      .collect();
+    let fetches = Cell::new(0);
+    let fetch = |ids: &[i32]| -> Result<Vec<(i32, Vec<DbReaction>)>, ResolutionErr> {
+      fetches.set(fetches.get() + 1);
+      assert_eq!(ids, &[5, 6, 7]);
+      // what get_reactions gives back for the ids
+      let mut found: BTreeMap<i32, Vec<DbReaction>> = BTreeMap::new();
+      for reaction in seeded.iter().filter(|r| ids.contains(&r.message_id)) {
+        let list = found.entry(reaction.message_id).or_insert_with(Vec::new);
+        list.push(reaction.clone());
+      }
+      Ok(found.into_iter().collect())
+    };
+
+    // like channelMessages queueing each message it returns
+    let loader: BatchLoader<i32, Vec<DbReaction>> = BatchLoader::new();
+    let pending: Vec<_> = (5..=7).map(|id| loader.load(id)).collect();
+    let emojis: Vec<Vec<query::Value>> = pending
+      .into_iter()
+      .map(|p| {
+        let objs = reactions_of(p, fetch).unwrap();
+        objs.into_iter().map(|obj| obj["emoji"].clone()).collect()
+      })
+      .collect();
+    assert_eq!(fetches.get(), 1);
+    let emoji = |e: &str| query::Value::String(e.to_owned());
+    assert_eq!(emojis[0], vec![emoji("👍"), emoji("👀")]);
+    assert_eq!(emojis[1], vec![emoji("🎉")]);
+    // nobody reacted to 7
+    assert!(emojis[2].is_empty());
+  }
+
+  #[test]
+  fn senders_load_in_one_batch() {
+    let user_fetches = Cell::new(0);
+    let fetch_users = |ids: &[String]| -> Result<Vec<(String, DbUser)>, ResolutionErr> {
+      user_fetches.set(user_fetches.get() + 1);
+      assert_eq!(ids, &["joe".to_owned(), "sue".to_owned()]);
+      // sue has no stored details
+      let joe = DbUser {
+        id: "joe".to_owned(),
+        name: Some("Joe".to_owned()),
+      };
+      Ok(vec![("joe".to_owned(), joe)])
+    };
+    let role_fetches = Cell::new(0);
+    let fetch_roles =
+      |members: &[(i32, String)]| -> Result<Vec<((i32, String), String)>, ResolutionErr> {
+        role_fetches.set(role_fetches.get() + 1);
+        assert_eq!(members, &[(5, "joe".to_owned()), (5, "sue".to_owned())]);
+        Ok(vec![((5, "joe".to_owned()), "admin".to_owned())])
+      };
+
+    // like loading a page of messages queueing the sender of each
+    let users: BatchLoader<String, DbUser> = BatchLoader::new();
+    let roles: BatchLoader<(i32, String), String> = BatchLoader::new();
+    let pending: Vec<_> = vec!["joe", "sue", "joe"]
+      .into_iter()
+      .map(|sender| {
+        (
+          sender.to_owned(),
+          users.load(sender.to_owned()),
+          roles.load((5, sender.to_owned())),
+        )
+      })
+      .collect();
+    let senders: Vec<GqlObj> = pending
+      .into_iter()
+      .map(|(sender, user, role)| sender_of(sender, user, role, fetch_users, fetch_roles).unwrap())
+      .collect();
+    assert_eq!(user_fetches.get(), 1);
+    assert_eq!(role_fetches.get(), 1);
+    let string = |s: &str| query::Value::String(s.to_owned());
+    assert_eq!(senders[0]["name"], string("Joe"));
+    assert_eq!(senders[0]["role"], string("admin"));
+    assert_eq!(senders[1]["id"], string("sue"));
+    assert_eq!(senders[1]["name"], query::Value::Null);
+    assert_eq!(senders[1]["role"], query::Value::Null);
+    assert_eq!(senders[2], senders[0]);
+  }
+
+  #[test]
+  fn date_times_round_trip() {
+    let at = date_time(NaiveDate::from_ymd(2026, 10, 16).and_hms(9, 30, 0));
+    let raw = match &at {
+      query::Value::String(raw) => raw.clone(),
+      _ => panic!("DateTimes are strings"),
+    };
+    assert_eq!(parse_date_time(&raw), Ok(at.clone()));
+    // other offsets are moved to UTC
+    assert_eq!(parse_date_time("2026-10-16T11:30:00+02:00"), Ok(at.clone()));
+    assert_eq!(serialize_date_time(&at), serde_json::Value::String(raw));
+    assert!(parse_date_time("yesterday").is_err());
+  }
+
+  #[test]
+  fn channel_messages_pages() {
+    // message ids in channel 3, newest first
+    let seeded: Vec<i32> = (1..=150).rev().collect();
+    let load = |limit: i64| -> QueryResult<Vec<i32>> {
+      Ok(seeded.iter().cloned().take(limit as usize).collect())
+    };
+
+    let page = channel_messages_page(3, Some(2), true, load).unwrap();
+    assert_eq!(page, vec![150, 149]);
+    // pages are never bigger than MAX_MESSAGES_PAGE
+    let page = channel_messages_page(3, None, true, load).unwrap();
+    assert_eq!(page.len(), MAX_MESSAGES_PAGE as usize);
+    let page = channel_messages_page(3, Some(500), true, load).unwrap();
+    assert_eq!(page.len(), MAX_MESSAGES_PAGE as usize);
+    let page = channel_messages_page(3, Some(-4), true, load).unwrap();
+    assert!(page.is_empty());
+
+    // others don't get to read the channel, so nothing is loaded for them
+    let refused = channel_messages_page(3, Some(2), false, |_| -> QueryResult<Vec<i32>> {
+      panic!("loaded messages for someone outside the channel")
+    });
+    match refused {
+      Err(ResolutionErr::Unauthorized(msg)) => assert_eq!(msg, "Not a member of channel 3"),
+      other => panic!("expected Unauthorized, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn schema_hash_ignores_formatting() {
+    let sdl = "type Query {\n  me: String\n}\n";
+    let messy = "# the root\ntype   Query{ me :String }";
+    assert_eq!(schema_hash(sdl), schema_hash(messy));
+    assert_ne!(schema_hash(sdl), schema_hash("type Query { me: Int }"));
+  }
+
+  #[test]
+  fn member_roles() {
+    let admin = DbChannelMember {
+      id: 1,
+      channel_id: 1,
+      user: "joe".to_owned(),
+      user_role: Some("admin".to_owned()),
+    };
+    let user = DbUser {
+      id: "joe".to_owned(),
+      name: Some("Joe".to_owned()),
+    };
+    let obj = member_obj(admin, Some(user));
+    assert_eq!(obj["id"], query::Value::String("joe".to_owned()));
+    assert_eq!(obj["name"], query::Value::String("Joe".to_owned()));
+    assert_eq!(obj["role"], query::Value::String("admin".to_owned()));
+
+    // members without a role or a users row still come through
+    let member = DbChannelMember {
+      id: 2,
+      channel_id: 1,
+      user: "sue".to_owned(),
+      user_role: None,
+    };
+    let obj = member_obj(member, None);
+    assert_eq!(obj["name"], query::Value::Null);
+    assert_eq!(obj["role"], query::Value::Null);
+  }
+
+  #[test]
+  fn my_channels_only_has_joined_channels() {
+    let at = NaiveDate::from_ymd(2026, 10, 16).and_hms(9, 0, 0);
+    let channels: BTreeMap<i32, DbChannel> = (1..=3)
+      .map(|id| {
+        let channel = DbChannel {
+          id,
+          display_name: Some(format!("channel {}", id)),
+          created_at: at,
+          updated_at: at,
+          is_public: false,
+          external_key: None,
+          deleted_at: None,
+        };
+        (id, channel)
+      })
+      .collect();
+    let member = |id, channel_id, user: &str| DbChannelMember {
+      id,
+      channel_id,
+      user: user.to_owned(),
+      user_role: None,
+    };
+    let memberships = vec![
+      member(1, 1, "joe"),
+      member(2, 2, "joe"),
+      member(3, 2, "sue"),
+      member(4, 3, "sue"),
+    ];
+
+    let names_for = |user: &str| -> Vec<query::Value> {
+      // what get_users_channels returns for the user
+      let ids = memberships
+        .iter()
+        .filter(|m| m.user == user)
+        .map(|m| m.channel_id)
+        .collect();
+      my_channels(ids, |id| Ok(channels.get(&id).cloned()))
+        .unwrap()
+        .into_iter()
+        .map(|c| c["display_name"].clone())
+        .collect()
+    };
+    let names = |ids: &[i32]| -> Vec<query::Value> {
+      ids
+        .iter()
+        .map(|id| query::Value::String(format!("channel {}", id)))
+        .collect()
+    };
+    assert_eq!(names_for("joe"), names(&[1, 2]));
+    assert_eq!(names_for("sue"), names(&[2, 3]));
+    assert_eq!(names_for("bob"), names(&[]));
+  }
+}
@@ -1,19 +1,27 @@
-use crate::auth;
+use crate::auth::{self, AuthError};
 use crate::config;
-use crate::gql_context::GqlContext;
-use crate::gqln::{GqlRequest, GqlResponse, GqlSchema};
+use crate::gql_context::{ContentFilter, GqlContext, Schema};
+use crate::gqln::{FieldDenylist, GqlError, GqlRequest, GqlResponse, GqlSchema, ResolutionErr};
 use crate::models::*;
+use crate::persisted_queries::PersistedQueries;
 use crate::ws_actors::*;
+use crate::ws_messages::WsProtocol;
 use actix::Addr;
-use actix_web::{http::StatusCode, web, Error, HttpRequest, HttpResponse, Responder};
+use actix_web::{error, http::StatusCode, web, Error, HttpRequest, HttpResponse, Responder};
 use actix_web_actors::ws;
+use chrono::{DateTime, NaiveDateTime, Utc};
 use diesel::mysql::MysqlConnection;
-use log::info;
+use diesel::Connection;
+use futures::{future, Future};
+use log::{info, warn};
 use serde;
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 // TODO: Make this into impl REsponder
-use diesel::result::Error as DBError;
+use diesel::result::{DatabaseErrorKind, Error as DBError};
 
 #[derive(Debug)]
 pub struct DbQueryErr(DBError);
@@ -30,12 +38,37 @@ impl std::fmt::Display for DbQueryErr {
   }
 }
 
-impl actix_web::ResponseError for DbQueryErr {}
+/// The body of REST errors
+#[derive(Serialize)]
+struct ApiError {
+  error: String,
+}
+
+impl actix_web::ResponseError for DbQueryErr {
+  fn error_response(&self) -> HttpResponse {
+    let (status, error) = match &self.0 {
+      DBError::NotFound => (StatusCode::NOT_FOUND, "Not found".to_owned()),
+      DBError::DatabaseError(DatabaseErrorKind::UniqueViolation, info) => {
+        (StatusCode::CONFLICT, info.message().to_owned())
+      }
+      // the details of anything else stay in the logs
+      e => {
+        warn!("Database error: {:?}", e);
+        (
+          StatusCode::INTERNAL_SERVER_ERROR,
+          "Database error".to_owned(),
+        )
+      }
+    };
+    HttpResponse::build(status).json(ApiError { error })
+  }
+}
 
 #[derive(Clone)]
 pub struct ApiContext {
   pub db: DbPool,
   pub config: config::AppConfig,
+  pub jwt: auth::JwtConfig,
 }
 
 #[derive(Serialize, Debug)]
@@ -45,23 +78,65 @@ pub struct ApiChannel {
   display_name: Option<String>,
 }
 
-pub struct Channels(Vec<ApiChannel>);
+/// Some channels, and the cursor for the page after them if there is one
+pub struct Channels(Vec<ApiChannel>, Option<i32>);
 
 impl Responder for Channels {
   type Error = DbQueryErr;
   type Future = Result<HttpResponse, DbQueryErr>;
 
   fn respond_to(self, _req: &HttpRequest) -> Self::Future {
-    Ok(
-      HttpResponse::Ok()
-        .content_type("application/json")
-        .json(&self.0),
-    )
+    let mut resp = HttpResponse::Ok();
+    if let Some(next) = self.1 {
+      resp.header("X-Next-Cursor", next.to_string());
+    }
+    Ok(resp.content_type("application/json").json(&self.0))
   }
 }
 
-pub fn r_get_channels(context: web::Data<ApiContext>) -> Result<Channels, DbQueryErr> {
-  let channels = get_channels(&context.db.get().unwrap())?;
+#[derive(Deserialize, Debug)]
+pub struct ChannelSearch {
+  search: Option<String>,
+  limit: Option<i64>,
+  /// Only list channels after this one. Anything but a channel id is ignored.
+  after: Option<String>,
+  /// List deleted channels too
+  include_deleted: Option<bool>,
+}
+
+const MAX_SEARCH_RESULTS: i64 = 100;
+const MAX_CHANNEL_PAGE: i64 = 100;
+
+pub fn r_get_channels(
+  query: web::Query<ChannelSearch>,
+  context: web::Data<ApiContext>,
+) -> Result<Channels, DbQueryErr> {
+  let conn: &MysqlConnection = &context.db.get().unwrap();
+  let include_deleted = query.include_deleted.unwrap_or(false);
+  let (channels, next_cursor) = match &query.search {
+    Some(needle) => {
+      let limit = query
+        .limit
+        .unwrap_or(MAX_SEARCH_RESULTS)
+        .max(0)
+        .min(MAX_SEARCH_RESULTS);
+      (search_channels(conn, needle, limit, include_deleted)?, None)
+    }
+    None => {
+      let limit = query
+        .limit
+        .unwrap_or(MAX_CHANNEL_PAGE)
+        .max(0)
+        .min(MAX_CHANNEL_PAGE);
+      let after = query.after.as_ref().and_then(|after| after.parse().ok());
+      let (channels, has_next) = get_channels_paged(conn, after, limit, include_deleted)?;
+      let next_cursor = match channels.last() {
+        Some(last) if has_next => Some(last.id),
+        _ => None,
+      };
+      (channels, next_cursor)
+    }
+  };
   Ok(Channels(
     channels
       .into_iter()
@@ -70,12 +145,53 @@ pub fn r_get_channels(context: web::Data<ApiContext>) -> Result<Channels, DbQuer
         display_name: ch.display_name,
       })
       .collect(),
+    next_cursor,
   ))
 }
 
-pub fn r_get_jwt(path: web::Path<(String,)>, context: web::Data<ApiContext>) -> String {
-  let name = "bob";
-  auth::encode_jwt(&path.0, name, &context.config.jwt_secret.as_ref().unwrap())
+/// Mints a token for any user, so only admins may use it.
+/// Otherwise anyone who can reach this port could get an admin's token.
+pub fn r_get_jwt(
+  req: HttpRequest,
+  path: web::Path<(String,)>,
+  context: web::Data<ApiContext>,
+) -> Result<HttpResponse, DbQueryErr> {
+  if let Err(resp) = require_admin(&req, &context) {
+    return Ok(resp);
+  }
+  let conn: &MysqlConnection = &context.db.get().unwrap();
+  let token = user_jwt(get_user(conn, &path.0)?, &context.jwt)?;
+  Ok(HttpResponse::Ok().body(token))
+}
+
+/// A token for a stored user, carrying their name
+fn user_jwt(user: Option<DbUser>, config: &auth::JwtConfig) -> Result<String, DbQueryErr> {
+  let user = user.ok_or(DBError::NotFound)?;
+  let name = user.name.unwrap_or_default();
+  Ok(auth::encode_jwt(&user.id, &name, config).unwrap())
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct RefreshJwtInput {
+  token: String,
+}
+
+#[derive(Serialize)]
+struct ApiToken {
+  token: String,
+}
+
+/// Swaps a token that is still valid, or only expired recently, for a new one
+pub fn r_refresh_jwt(
+  input: web::Json<RefreshJwtInput>,
+  context: web::Data<ApiContext>,
+) -> HttpResponse {
+  match auth::refresh_jwt(&input.token, &context.jwt) {
+    Ok(token) => HttpResponse::Ok().json(ApiToken { token }),
+    Err(e) => HttpResponse::Unauthorized().json(ApiError {
+      error: e.to_string(),
+    }),
+  }
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -83,6 +199,10 @@ pub fn r_get_jwt(path: web::Path<(String,)>, context: web::Data<ApiContext>) ->
 pub struct CreateChannelInput {
   display_name: String,
   initial_users: Vec<String>,
+  #[serde(default)]
+  is_public: bool,
+  /// Makes creation idempotent: retries with the same key return the first channel
+  external_key: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -102,11 +222,25 @@ impl Responder for CreateChannelOutput {
 pub fn r_create_channel(
   channel: web::Json<CreateChannelInput>,
   context: web::Data<ApiContext>,
+  tracker: web::Data<Addr<ConnectionTracker>>,
 ) -> Result<CreateChannelOutput, DbQueryErr> {
   let conn: &MysqlConnection = &context.db.get().unwrap();
-  let new_channel = create_channel(conn, &channel.display_name)?;
-  for user in &channel.initial_users {
-    add_user_to_channel(conn, user, new_channel.id, "member")?;
+  let (new_channel, created) = match &channel.external_key {
+    Some(key) => create_channel_idempotent(conn, &channel.display_name, key, channel.is_public)?,
+    None => (
+      create_channel(conn, &channel.display_name, channel.is_public)?,
+      true,
+    ),
+  };
+  // a retried request already added the initial users
+  if created {
+    for user in &channel.initial_users {
+      add_user_to_channel(conn, user, new_channel.id, "member")?;
+      tracker.do_send(MsgUserAddedToChannel {
+        user: user.to_owned(),
+        channel: new_channel.id,
+      });
+    }
   }
   Ok(CreateChannelOutput { id: new_channel.id })
 }
@@ -114,9 +248,15 @@ pub fn r_create_channel(
 pub fn r_remove_user(
   path: web::Path<(i32, String)>,
   context: web::Data<ApiContext>,
+  tracker: web::Data<Addr<ConnectionTracker>>,
 ) -> Result<HttpResponse, DbQueryErr> {
   let conn: &MysqlConnection = &context.db.get().unwrap();
   remove_user(conn, path.0, &path.1)?;
+  // live subscriptions stop hearing about the channel right away
+  tracker.do_send(MsgUserRemovedFromChannel {
+    user: path.1.clone(),
+    channel: path.0,
+  });
   Ok(HttpResponse::Ok().finish())
 }
 
@@ -125,6 +265,8 @@ pub fn r_remove_user(
 struct ChannelInfo {
   display_name: String,
   users: Vec<String>,
+  created_at: String,
+  updated_at: String,
 }
 
 pub fn r_get_channel_info(
@@ -132,12 +274,14 @@ pub fn r_get_channel_info(
   context: web::Data<ApiContext>,
 ) -> Result<HttpResponse, DbQueryErr> {
   let conn: &MysqlConnection = &context.db.get().unwrap();
-  match get_channel(conn, path.0)? {
+  match get_channel(conn, path.0, false)? {
     Some(ch) => {
       let users = get_channel_users(conn, path.0)?;
       Ok(HttpResponse::Ok().json(ChannelInfo {
         display_name: ch.display_name.unwrap(),
         users,
+        created_at: DateTime::<Utc>::from_utc(ch.created_at, Utc).to_rfc3339(),
+        updated_at: DateTime::<Utc>::from_utc(ch.updated_at, Utc).to_rfc3339(),
       }))
     }
     None => Ok(HttpResponse::build(StatusCode::NOT_FOUND).finish()),
@@ -182,27 +326,163 @@ pub fn r_get_channel_users(
 #[derive(Deserialize, Debug)]
 pub struct ApiAddUser {
   uid: String,
+  /// Stored for the user, and put in the tokens issued for them
+  name: Option<String>,
 }
 
 pub fn r_add_user(
   path: web::Path<(i32,)>,
   data: web::Json<ApiAddUser>,
   context: web::Data<ApiContext>,
+  tracker: web::Data<Addr<ConnectionTracker>>,
 ) -> Result<HttpResponse, DbQueryErr> {
   let conn: &MysqlConnection = &context.db.get().unwrap();
+  if let Some(name) = &data.name {
+    store_user(conn, &data.uid, Some(name.as_str()))?;
+  }
   add_user_to_channel(conn, &data.uid, path.0, "temp")?;
+  // so the user's live subscriptions hear about the channel without reconnecting
+  tracker.do_send(MsgUserAddedToChannel {
+    user: data.uid.clone(),
+    channel: path.0,
+  });
   Ok(HttpResponse::Ok().finish())
 }
 
+#[derive(Deserialize, Debug)]
+pub struct RetentionQuery {
+  before: String,
+}
+
+#[derive(Serialize, Debug)]
+struct DeletedMessages {
+  deleted: usize,
+}
+
+pub fn r_delete_old_messages(
+  req: HttpRequest,
+  path: web::Path<(i32,)>,
+  query: web::Query<RetentionQuery>,
+  context: web::Data<ApiContext>,
+) -> Result<HttpResponse, DbQueryErr> {
+  if let Err(resp) = require_admin(&req, &context) {
+    return Ok(resp);
+  }
+  let cutoff = match NaiveDateTime::parse_from_str(&query.before, "%Y-%m-%dT%H:%M:%S") {
+    Ok(cutoff) => cutoff,
+    Err(_) => {
+      return Ok(HttpResponse::BadRequest().body("`before` should look like 2020-01-31T12:00:00"))
+    }
+  };
+  let conn: &MysqlConnection = &context.db.get().unwrap();
+  let deleted = delete_messages_older_than(conn, path.0, cutoff)?;
+  Ok(HttpResponse::Ok().json(DeletedMessages { deleted }))
+}
+
 pub fn r_delete_channel(
   path: web::Path<(i32,)>,
   context: web::Data<ApiContext>,
 ) -> Result<HttpResponse, DbQueryErr> {
   let conn: &MysqlConnection = &context.db.get().unwrap();
-  delete_channel(conn, path.0)?;
+  soft_delete_channel(conn, path.0)?;
   Ok(HttpResponse::Ok().finish())
 }
 
+/// Undoes deleting a channel
+pub fn r_restore_channel(
+  path: web::Path<(i32,)>,
+  context: web::Data<ApiContext>,
+) -> Result<HttpResponse, DbQueryErr> {
+  let conn: &MysqlConnection = &context.db.get().unwrap();
+  get_channel(conn, path.0, true)?.ok_or(DBError::NotFound)?;
+  restore_channel(conn, path.0)?;
+  Ok(HttpResponse::Ok().finish())
+}
+
+/// The id of the user whose JWT is in the Authorization header, if it is valid
+fn authorized_user(req: &HttpRequest, config: &auth::JwtConfig) -> Option<String> {
+  let jwt = req.headers().get("Authorization")?.to_str().ok()?;
+  auth::decode_jwt(jwt, config).ok().map(|claims| claims.id)
+}
+
+/// Checks that the request comes from an admin user,
+/// otherwise gives back the response to reject it with.
+fn require_admin(req: &HttpRequest, context: &ApiContext) -> Result<String, HttpResponse> {
+  match authorized_user(req, &context.jwt) {
+    None => Err(HttpResponse::Unauthorized().finish()),
+    Some(user) if !context.config.admin_users.contains(&user) => {
+      Err(HttpResponse::Forbidden().finish())
+    }
+    Some(user) => Ok(user),
+  }
+}
+
+pub fn r_get_subscriptions(
+  req: HttpRequest,
+  context: web::Data<ApiContext>,
+  tracker: web::Data<Addr<ConnectionTracker>>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+  if let Err(resp) = require_admin(&req, &context) {
+    return Box::new(future::ok(resp));
+  }
+  Box::new(
+    tracker
+      .send(MsgGetAllSubscriptions)
+      .map_err(|_| error::ErrorInternalServerError("Could not reach the connection tracker"))
+      .map(|subs| HttpResponse::Ok().json(subs)),
+  )
+}
+
+pub fn r_get_denied_fields(
+  req: HttpRequest,
+  context: web::Data<ApiContext>,
+  denylist: web::Data<FieldDenylist>,
+) -> HttpResponse {
+  if let Err(resp) = require_admin(&req, &context) {
+    return resp;
+  }
+  HttpResponse::Ok().json(denylist.fields())
+}
+
+pub fn r_set_denied_fields(
+  req: HttpRequest,
+  fields: web::Json<Vec<String>>,
+  context: web::Data<ApiContext>,
+  denylist: web::Data<FieldDenylist>,
+) -> HttpResponse {
+  if let Err(resp) = require_admin(&req, &context) {
+    return resp;
+  }
+  match denylist.set(&fields) {
+    Ok(()) => HttpResponse::Ok().json(denylist.fields()),
+    Err(entry) => {
+      HttpResponse::BadRequest().body(format!("`{}` should look like Type.field", entry))
+    }
+  }
+}
+
+#[derive(Serialize, Debug)]
+struct DisconnectedSessions {
+  disconnected: usize,
+}
+
+pub fn r_disconnect_user(
+  req: HttpRequest,
+  path: web::Path<(String,)>,
+  context: web::Data<ApiContext>,
+  tracker: web::Data<Addr<ConnectionTracker>>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+  if let Err(resp) = require_admin(&req, &context) {
+    return Box::new(future::ok(resp));
+  }
+  Box::new(
+    tracker
+      .send(MsgForceDisconnect(path.0.clone()))
+      .map_err(|_| error::ErrorInternalServerError("Could not reach the connection tracker"))
+      .map(|disconnected| HttpResponse::Ok().json(DisconnectedSessions { disconnected })),
+  )
+}
+
 // For standard health checks
 pub fn r_health() -> impl Responder {
   HttpResponse::Ok()
@@ -215,35 +495,117 @@ pub fn r_wsspawn(
   req: HttpRequest,
   stream: web::Payload,
   recip: web::Data<Addr<ConnectionTracker>>,
+  schema: web::Data<Schema>,
   config: web::Data<config::AppConfig>,
+  jwt: web::Data<auth::JwtConfig>,
 ) -> Result<HttpResponse, Error> {
   info!("New websocket request. Some subscriptions will be next.");
   let id = match req.headers().get("Authorization").map(|i| i.to_str()) {
-    Some(Ok(s)) => match auth::decode_jwt(s, &config.jwt_secret.as_ref().unwrap()) {
+    Some(Ok(s)) => match auth::decode_jwt(s, &jwt) {
       Ok(claims) => Some(claims.id),
       _ => None,
     },
     _ => None,
   };
 
+  let protocol = WsProtocol::negotiate(
+    req
+      .headers()
+      .get("Sec-WebSocket-Protocol")
+      .and_then(|h| h.to_str().ok()),
+  );
   let handler = WsHandler::new(
     recip.get_ref().to_owned(),
+    schema,
     id,
-    config.jwt_secret.clone().unwrap(),
-  );
-  ws::start_with_protocols(handler, &["graphql-ws"], &req, stream)
+    jwt.get_ref().clone(),
+  )
+  .keep_alive(config.ws_keep_alive_ms.map(Duration::from_millis))
+  .ack_payload(config.ws_ack_payload)
+  .max_subscriptions(config.max_subscriptions_per_connection)
+  .init_timeout(config.ws_init_timeout_ms.map(Duration::from_millis))
+  .protocol(protocol);
+  ws::start_with_protocols(handler, &WsProtocol::SUPPORTED, &req, stream)
 }
 
 #[derive(Clone)]
 pub struct GqlRouteContext {
   db: DbPool,
-  schema: GqlSchema<GqlContext>,
+  schema: Arc<Schema>,
+  persisted_queries: PersistedQueries,
+  content_filter: Option<ContentFilter>,
+  started: Instant,
 }
 
 impl GqlRouteContext {
-  pub fn new(schema: GqlSchema<GqlContext>, db: DbPool) -> Self {
-    GqlRouteContext { db, schema }
+  pub fn new(schema: GqlSchema<GqlContext>, db: DbPool, started: Instant) -> Self {
+    GqlRouteContext {
+      db,
+      schema: Arc::new(schema),
+      persisted_queries: PersistedQueries::new(1000),
+      content_filter: None,
+      started,
+    }
+  }
+
+  /// Checks the content of every message created through this route
+  pub fn content_filter(mut self, filter: ContentFilter) -> Self {
+    self.content_filter = Some(filter);
+    self
   }
+
+  /// How many persisted queries are kept before the least recently used are dropped
+  pub fn max_persisted_queries(mut self, max: usize) -> Self {
+    self.persisted_queries = PersistedQueries::new(max);
+    self
+  }
+}
+
+/// Answers with 200 unless `http_error_status` is set,
+/// in which case a failed request gets the status matching its error.
+/// Queries that can't be parsed always get a 400.
+fn gql_http_response(
+  gql_resp: Result<(serde_json::Value, Vec<GqlError>), ResolutionErr>,
+  config: &config::AppConfig,
+) -> HttpResponse {
+  let status = match &gql_resp {
+    Err(ResolutionErr::QueryParseIssue(_)) => StatusCode::BAD_REQUEST,
+    Err(e) if config.http_error_status => {
+      StatusCode::from_u16(e.http_status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+    _ => StatusCode::OK,
+  };
+  HttpResponse::build(status).json(GqlResponse::from(gql_resp))
+}
+
+/// Runs a whole request on one connection inside a transaction,
+/// so its mutations are either all committed or all rolled back.
+/// Any error rolls back the whole request, and the response has no data.
+/// Subscribers are only told about the changes once they are committed.
+fn resolve_in_transaction(
+  schema: &Schema,
+  context: GqlContext,
+  payload: GqlRequest,
+) -> Result<(serde_json::Value, Vec<GqlError>), ResolutionErr> {
+  let conn = Rc::new(context.db.get()?);
+  let mut context = context.transaction(conn.clone());
+  let mut failed = Vec::new();
+  let committed = conn.transaction(|| {
+    let (data, errors) = schema.resolve_partial(&mut context, payload, None)?;
+    if errors.is_empty() {
+      return Ok(data);
+    }
+    failed = errors;
+    Err(ResolutionErr::from(DBError::RollbackTransaction))
+  });
+  if !failed.is_empty() {
+    return Ok((serde_json::Value::Null, failed));
+  }
+  let data = committed?;
+  for notice in context.take_notices() {
+    notice(&context.ws_addr);
+  }
+  Ok((data, Vec::new()))
 }
 
 pub fn handle_graphql_req(
@@ -252,17 +614,47 @@ pub fn handle_graphql_req(
   ctx: &web::Data<GqlRouteContext>,
   tracker: &Addr<ConnectionTracker>,
   config: &config::AppConfig,
-) -> HttpResponse {
+  jwt_config: &auth::JwtConfig,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
   if let Some(auth_header) = req.headers().get("Authorization") {
     if let Ok(jwt) = auth_header.to_str() {
-      if let Ok(user_info) = auth::decode_jwt(jwt, &config.jwt_secret.as_ref().unwrap()) {
-        let mut context = GqlContext::new(ctx.db.clone(), user_info.id, tracker.to_owned());
-        let gql_resp = ctx.schema.resolve(&mut context, payload, None);
-        return HttpResponse::Ok().json(GqlResponse::from(gql_resp));
+      let user_info = match auth::decode_jwt(jwt, jwt_config) {
+        Ok(user_info) => Some(user_info),
+        // tells clients they can get a new token and try again
+        Err(AuthError::Expired) => {
+          return Box::new(future::ok(
+            HttpResponse::Unauthorized()
+              .header(
+                "WWW-Authenticate",
+                r#"Bearer error="invalid_token", error_description="The token has expired""#,
+              )
+              .finish(),
+          ));
+        }
+        Err(_) => None,
+      };
+      if let Some(user_info) = user_info {
+        let payload = match ctx.persisted_queries.load(payload) {
+          Ok(p) => p,
+          Err(e) => return Box::new(future::ok(gql_http_response(Err(e), config))),
+        };
+        let context = GqlContext::new(ctx.db.clone(), user_info.id, tracker.to_owned())
+          .content_filter(ctx.content_filter.clone())
+          .server_started(ctx.started);
+        if config.request_transactions {
+          let gql_resp = resolve_in_transaction(&ctx.schema, context, payload);
+          return Box::new(future::ok(gql_http_response(gql_resp, config)));
+        }
+        // async resolvers are waited on without blocking the worker
+        let config = config.clone();
+        return Box::new(
+          Schema::resolve_async(ctx.schema.clone(), context, payload, None)
+            .then(move |gql_resp| Ok(gql_http_response(gql_resp, &config))),
+        );
       }
     }
   }
-  HttpResponse::Unauthorized().finish()
+  Box::new(future::ok(HttpResponse::Unauthorized().finish()))
 }
 
 // The main POST endpoint for graphql queries
@@ -273,13 +665,15 @@ pub fn r_graphql_post(
   gql_ctx: web::Data<GqlRouteContext>,
   tracker: web::Data<Addr<ConnectionTracker>>,
   config: web::Data<config::AppConfig>,
-) -> impl Responder {
+  jwt: web::Data<auth::JwtConfig>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
   handle_graphql_req(
     &req,
     payload.0,
     &gql_ctx,
     tracker.get_ref(),
     config.get_ref(),
+    jwt.get_ref(),
   )
 }
 
@@ -290,12 +684,82 @@ pub fn r_graphql_get(
   gql_ctx: web::Data<GqlRouteContext>,
   tracker: web::Data<Addr<ConnectionTracker>>,
   config: web::Data<config::AppConfig>,
-) -> impl Responder {
+  jwt: web::Data<auth::JwtConfig>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
   handle_graphql_req(
     &req,
     payload.0,
     &gql_ctx,
     tracker.get_ref(),
     config.get_ref(),
+    jwt.get_ref(),
   )
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use actix_web::ResponseError;
+
+  #[test]
+  fn db_error_statuses() {
+    let status = |e: DBError| DbQueryErr::from(e).error_response().status();
+    assert_eq!(status(DBError::NotFound), StatusCode::NOT_FOUND);
+    let duplicate = DBError::DatabaseError(
+      DatabaseErrorKind::UniqueViolation,
+      Box::new("Duplicate entry 'general' for key 'display_name'".to_owned()),
+    );
+    assert_eq!(status(duplicate), StatusCode::CONFLICT);
+    let foreign_key = DBError::DatabaseError(
+      DatabaseErrorKind::ForeignKeyViolation,
+      Box::new("Cannot add or update a child row".to_owned()),
+    );
+    assert_eq!(status(foreign_key), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(
+      status(DBError::RollbackTransaction),
+      StatusCode::INTERNAL_SERVER_ERROR
+    );
+  }
+
+  #[test]
+  fn jwt_has_the_users_name() {
+    let config = auth::JwtConfig::hmac("123456");
+    let alice = DbUser {
+      id: "7".to_owned(),
+      name: Some("alice".to_owned()),
+    };
+    let token = user_jwt(Some(alice), &config).unwrap();
+    assert_eq!(
+      auth::decode_jwt(&token, &config).unwrap(),
+      auth::UserClaims {
+        name: "alice".to_owned(),
+        id: "7".to_owned(),
+      }
+    );
+    let unknown = user_jwt(None, &config).unwrap_err();
+    assert_eq!(unknown.error_response().status(), StatusCode::NOT_FOUND);
+  }
+
+  #[test]
+  fn jwt_for_added_users() {
+    let config = auth::JwtConfig::hmac("123456");
+    // the users row r_add_user stores, and r_get_jwt then finds
+    let stored = |body: &str| {
+      let added: ApiAddUser = serde_json::from_str(body).unwrap();
+      DbUser {
+        id: added.uid,
+        name: added.name,
+      }
+    };
+    let claims = |user| auth::decode_jwt(&user_jwt(Some(user), &config).unwrap(), &config).unwrap();
+    assert_eq!(
+      claims(stored(r#"{"uid": "7", "name": "alice"}"#)),
+      auth::UserClaims {
+        name: "alice".to_owned(),
+        id: "7".to_owned(),
+      }
+    );
+    // members added without a name still get a token
+    assert_eq!(claims(stored(r#"{"uid": "8"}"#)).id, "8");
+  }
+}
@@ -6,6 +6,9 @@ table! {
         display_name -> Nullable<Varchar>,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        is_public -> Bool,
+        external_key -> Nullable<Varchar>,
+        deleted_at -> Nullable<Timestamp>,
     }
 }
 
@@ -30,6 +33,16 @@ table! {
     }
 }
 
+table! {
+    message_reactions (id) {
+        id -> Integer,
+        message_id -> Integer,
+        user -> Varchar,
+        emoji -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
 table! {
     message_views (id) {
         id -> Integer,
@@ -39,8 +52,23 @@ table! {
     }
 }
 
+table! {
+    users (id) {
+        id -> Varchar,
+        name -> Nullable<Varchar>,
+    }
+}
+
 joinable!(channel_members -> channels (channel_id));
+joinable!(message_reactions -> messages (message_id));
 joinable!(message_views -> messages (message_id));
 joinable!(messages -> channels (channel_id));
 
-allow_tables_to_appear_in_same_query!(channels, channel_members, messages, message_views,);
+allow_tables_to_appear_in_same_query!(
+    channels,
+    channel_members,
+    messages,
+    message_reactions,
+    message_views,
+    users,
+);
@@ -1,19 +1,33 @@
-use crate::gqln::{GqlRequest, ResolutionErr};
-use crate::ws_actors::WsHandler;
-use actix::{Addr, Message};
+use crate::gqln::{GqlError, GqlRequest, ResolutionErr};
+use crate::ws_messages::WsError;
+use actix::{Message, Recipient};
+use serde::Serialize;
 use serde_json::{json, Value as JsonValue};
 
+/// Registers a subscription, unless its user already has one with the same id
 #[derive(Message)]
+#[rtype(result = "Result<(), WsError>")]
 pub struct MsgNewSubscription {
   pub user_id: String,
   pub sub_id: String,
   pub sub: GqlRequest,
-  pub addr: Addr<WsHandler>,
+  pub addr: Recipient<MsgSubscriptionData>,
+  /// Used to close the connection the subscription came from
+  pub stop_addr: Recipient<MsgStopHandler>,
+}
+
+/// A websocket was authenticated as `user`, so it can be closed by `MsgForceDisconnect`
+/// even before it starts any subscriptions
+#[derive(Message)]
+pub struct MsgWsConnected {
+  pub user: String,
+  pub addr: Recipient<MsgStopHandler>,
 }
 
 #[derive(Message)]
 pub struct MsgWsDisconnected {
   pub id: String,
+  pub addr: Recipient<MsgStopHandler>,
 }
 
 #[derive(Message, Clone, Debug)]
@@ -41,6 +55,54 @@ impl MsgMessageCreated {
   }
 }
 
+/// A user is typing in a channel. Only sent to subscribers, never stored.
+#[derive(Message, Clone, Debug)]
+pub struct MsgUserTyping {
+  pub channel: i32,
+  pub user: String,
+}
+
+/// A user joined a channel, so their subscriptions
+/// should start receiving its messages
+#[derive(Message, Clone, Debug)]
+pub struct MsgUserAddedToChannel {
+  pub user: String,
+  pub channel: i32,
+}
+
+/// A user left (or was removed from) a channel,
+/// so their subscriptions should stop receiving its messages
+#[derive(Message, Clone, Debug)]
+pub struct MsgUserRemovedFromChannel {
+  pub user: String,
+  pub channel: i32,
+}
+
+/// Closes every live connection of a user and drops their subscriptions.
+/// Returns how many connections were told to close.
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct MsgForceDisconnect(pub String);
+
+/// Tells a websocket handler to close its connection
+#[derive(Message, Clone, Debug)]
+pub struct MsgStopHandler {
+  pub reason: String,
+}
+
+/// An active subscription, and the channels it receives messages from
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct SubscriptionSummary {
+  pub user: String,
+  pub id: String,
+  pub channels: Vec<i32>,
+}
+
+/// Lists every active subscription across all users
+#[derive(Message)]
+#[rtype(result = "Vec<SubscriptionSummary>")]
+pub struct MsgGetAllSubscriptions;
+
 #[derive(Message, Clone, Debug)]
 pub struct MsgSubscriptionData {
   pub errors: Vec<JsonValue>,
@@ -49,15 +111,15 @@ pub struct MsgSubscriptionData {
 }
 
 impl MsgSubscriptionData {
-  pub fn new(id: String, result: Result<JsonValue, ResolutionErr>) -> Self {
+  pub fn new(id: String, result: Result<(JsonValue, Vec<GqlError>), ResolutionErr>) -> Self {
     match result {
-      Ok(data) => MsgSubscriptionData {
-        errors: Vec::new(),
+      Ok((data, errors)) => MsgSubscriptionData {
+        errors: errors.into_iter().map(|e| json!(e)).collect(),
         data: Some(data),
         id,
       },
       Err(err) => MsgSubscriptionData {
-        errors: vec![json!(err)],
+        errors: vec![json!(GqlError::from(err))],
         data: None,
         id,
       },
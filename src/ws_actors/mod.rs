@@ -1,15 +1,21 @@
-use actix::{Actor, ActorContext, Addr, AsyncContext, Context, Handler, StreamHandler};
+use actix::fut::{ActorFuture, WrapFuture};
+use actix::{
+  Actor, ActorContext, Addr, AsyncContext, Context, Handler, MessageResult, Recipient, SpawnHandle,
+  StreamHandler,
+};
+use actix_web::web;
 use actix_web_actors::ws;
 use graphql_parser::query::Value as GqlValue;
 use log::{info, warn};
 use serde_json::Value as JsonValue;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
 
-use crate::auth;
+use crate::auth::{self, JwtConfig};
 use crate::gql_context::{GqlContext, Schema};
-use crate::gqln::{GqlRequest, GqlRoot};
+use crate::gqln::{GqlArgs, GqlRequest, GqlRoot};
 use crate::models::{get_users_channels, DbPool};
-use crate::ws_messages::{ClientWsMessage, ServerWsMessage, WsError};
+use crate::ws_messages::{AckPayload, ClientWsMessage, ServerWsMessage, WsError, WsProtocol};
 
 // --------------- Messages -----------------------
 mod messages;
@@ -25,41 +31,88 @@ struct SubscriptionInstance {
 
 struct ActiveSubscription {
   channels: Vec<i32>,
-  addr: Addr<WsHandler>,
+  addr: Recipient<MsgSubscriptionData>,
+  stop_addr: Recipient<MsgStopHandler>,
   req: GqlRequest,
+  /// The root fields the subscription selects, like `message`, with their arguments
+  fields: BTreeMap<String, GqlArgs>,
+}
+
+impl ActiveSubscription {
+  /// Whether the subscription selects `field` for events in `channel`.
+  /// Fields without a `channel` argument hear about every channel the user is in.
+  fn wants(&self, field: &str, channel: i32) -> bool {
+    let arguments = match self.fields.get(field) {
+      Some(arguments) => arguments,
+      None => return false,
+    };
+    match arguments.get("channel") {
+      Some(GqlValue::String(id)) => id.parse::<i32>().ok() == Some(channel),
+      Some(GqlValue::Int(id)) => id.as_i64() == Some(i64::from(channel)),
+      _ => true,
+    }
+  }
+}
+
+/// Finds the channels a user should receive messages from
+pub type ChannelLookup = fn(&DbPool, &str) -> Vec<i32>;
+
+fn db_channel_lookup(pool: &DbPool, user: &str) -> Vec<i32> {
+  match pool.get() {
+    Ok(conn) => get_users_channels(&conn, user).unwrap_or(Vec::new()),
+    Err(e) => {
+      warn!("Could not look up channels for {}: {:?}", user, e);
+      Vec::new()
+    }
+  }
 }
 
 pub struct ConnectionTracker {
   pub connections: usize,
   subscriptions: HashMap<SubscriptionInstance, ActiveSubscription>,
   channels: HashMap<i32, Vec<SubscriptionInstance>>,
+  /// Every authenticated websocket, by user
+  handlers: HashMap<String, Vec<Recipient<MsgStopHandler>>>,
   schema: Schema,
   pool: DbPool,
+  channel_lookup: ChannelLookup,
+  /// Messages longer than this many bytes are broadcast as a preview,
+  /// which clients can replace by fetching the message
+  max_broadcast_content: Option<usize>,
 }
 
 impl ConnectionTracker {
   pub fn new(schema: Schema, pool: DbPool) -> Self {
+    Self::with_channel_lookup(schema, pool, db_channel_lookup)
+  }
+
+  pub fn with_channel_lookup(schema: Schema, pool: DbPool, channel_lookup: ChannelLookup) -> Self {
     ConnectionTracker {
       connections: 0,
       subscriptions: HashMap::new(),
       channels: HashMap::new(),
+      handlers: HashMap::new(),
       schema,
       pool,
+      channel_lookup,
+      max_broadcast_content: None,
     }
   }
 
+  pub fn max_broadcast_content(mut self, max: Option<usize>) -> Self {
+    self.max_broadcast_content = max;
+    self
+  }
+
   fn remove_sub(&mut self, user: &String, sub_id: &String) {
     let instance = SubscriptionInstance {
       user: user.to_owned(),
       id: sub_id.to_owned(),
     };
-    if let Some(sub) = self.subscriptions.get(&instance) {
+    if let Some(sub) = self.subscriptions.remove(&instance) {
       for channel in &sub.channels {
-        let chsub = self.channels.get_mut(channel).unwrap();
-        for i in 0..chsub.len() {
-          if chsub[i] == instance {
-            chsub.swap_remove(i);
-          }
+        if let Some(chsub) = self.channels.get_mut(channel) {
+          chsub.retain(|s| *s != instance);
         }
       }
     }
@@ -84,23 +137,39 @@ impl Actor for ConnectionTracker {
 }
 
 impl Handler<MsgNewSubscription> for ConnectionTracker {
-  type Result = ();
+  type Result = Result<(), WsError>;
 
-  fn handle(&mut self, msg: MsgNewSubscription, ctx: &mut Self::Context) {
-    self.connections += 1;
+  fn handle(&mut self, msg: MsgNewSubscription, ctx: &mut Self::Context) -> Self::Result {
     let instance = SubscriptionInstance {
       user: msg.user_id.clone(),
       id: msg.sub_id.clone(),
     };
-    let channels =
-      get_users_channels(&self.pool.get().unwrap(), &msg.user_id).unwrap_or(Vec::new());
+    // replacing it would leave the first one behind in `channels`
+    if self.subscriptions.contains_key(&instance) {
+      return Err(WsError::DuplicateSubscription(msg.sub_id));
+    }
+    // without its arguments the subscription can't tell which events it wants
+    let fields = match self.schema.root_arguments(&msg.sub) {
+      Ok(fields) => fields,
+      Err(e) => {
+        warn!(
+          "Could not read the arguments of subscription {}: {:?}",
+          msg.sub_id, e
+        );
+        return Err(WsError::from(e));
+      }
+    };
+    self.connections += 1;
+    let channels = (self.channel_lookup)(&self.pool, &msg.user_id);
     info!("new user connected, listening on channels {:?}", &channels);
     self.subscriptions.insert(
       instance.clone(),
       ActiveSubscription {
         channels: channels.clone(),
         addr: msg.addr.clone(),
+        stop_addr: msg.stop_addr.clone(),
         req: msg.sub.clone(),
+        fields,
       },
     );
 
@@ -115,6 +184,18 @@ impl Handler<MsgNewSubscription> for ConnectionTracker {
       }
     }
     println!("{} clients are connected", self.connections);
+    Ok(())
+  }
+}
+
+impl Handler<MsgWsConnected> for ConnectionTracker {
+  type Result = ();
+
+  fn handle(&mut self, msg: MsgWsConnected, _ctx: &mut Self::Context) {
+    let handlers = self.handlers.entry(msg.user).or_insert_with(Vec::new);
+    if !handlers.contains(&msg.addr) {
+      handlers.push(msg.addr);
+    }
   }
 }
 
@@ -123,39 +204,95 @@ impl Handler<MsgWsDisconnected> for ConnectionTracker {
 
   fn handle(&mut self, msg: MsgWsDisconnected, ctx: &mut Self::Context) {
     self.connections = self.connections.saturating_sub(1);
+    if let Some(handlers) = self.handlers.get_mut(&msg.id) {
+      handlers.retain(|h| *h != msg.addr);
+      if handlers.is_empty() {
+        self.handlers.remove(&msg.id);
+      }
+    }
     self.remove_user(&msg.id);
     println!("{} clients are connected", self.connections);
   }
 }
 
-impl Handler<MsgMessageCreated> for ConnectionTracker {
-  type Result = ();
+/// The start of `content`, at most `max` bytes long, without splitting a character
+fn content_preview(content: &str, max: usize) -> &str {
+  let mut end = max.min(content.len());
+  while !content.is_char_boundary(end) {
+    end -= 1;
+  }
+  &content[..end]
+}
 
-  fn handle(&mut self, msg: MsgMessageCreated, ctx: &mut Self::Context) {
-    if let Some(subs) = self.channels.get(&msg.channel) {
-      let mut root = GqlRoot::new();
-      root.insert("id".to_owned(), GqlValue::String(format!("{}", msg.msg_id)));
-      root.insert(
-        "content".to_owned(),
-        GqlValue::String(msg.content.to_owned()),
-      );
-      for sub in subs {
-        // No need to tell a user about the message they just sent
-        if sub.user != msg.sender {
-          let mut context = GqlContext::new(self.pool.clone(), sub.user.clone(), ctx.address());
-          let sub_data = self.subscriptions.get(sub).unwrap();
-          let res = self
+impl ConnectionTracker {
+  /// Resolves the subscriptions selecting `field` in `channel` with `root`, and sends
+  /// each one its data. The user the event came from isn't told about it.
+  fn broadcast(
+    &self,
+    field: &str,
+    channel: i32,
+    from: &str,
+    root: GqlRoot,
+    tracker: Addr<ConnectionTracker>,
+  ) {
+    let subs = match self.channels.get(&channel) {
+      Some(subs) => subs,
+      None => return,
+    };
+    for sub in subs {
+      let sub_data = self.subscriptions.get(sub).unwrap();
+      // No need to tell a user about what they just did,
+      // or about channels they didn't subscribe to
+      if sub.user != from && sub_data.wants(field, channel) {
+        let mut context = GqlContext::new(self.pool.clone(), sub.user.clone(), tracker.clone());
+        let res =
+          self
             .schema
-            .resolve(&mut context, sub_data.req.clone(), Some(root.clone()));
-          sub_data
-            .addr
-            .do_send(MsgSubscriptionData::new(sub.id.clone(), res));
+            .resolve_partial(&mut context, sub_data.req.clone(), Some(root.clone()));
+        if let Err(e) = sub_data
+          .addr
+          .do_send(MsgSubscriptionData::new(sub.id.clone(), res))
+        {
+          warn!(
+            "Could not deliver subscription data to {}: {:?}",
+            sub.user, e
+          );
         }
       }
     }
   }
 }
 
+impl Handler<MsgMessageCreated> for ConnectionTracker {
+  type Result = ();
+
+  fn handle(&mut self, msg: MsgMessageCreated, ctx: &mut Self::Context) {
+    let mut root = GqlRoot::new();
+    root.insert("id".to_owned(), GqlValue::String(format!("{}", msg.msg_id)));
+    let (content, truncated) = match self.max_broadcast_content {
+      Some(max) if msg.content.len() > max => (content_preview(&msg.content, max), true),
+      _ => (msg.content.as_str(), false),
+    };
+    root.insert("content".to_owned(), GqlValue::String(content.to_owned()));
+    root.insert("contentTruncated".to_owned(), GqlValue::Boolean(truncated));
+    self.broadcast("message", msg.channel, &msg.sender, root, ctx.address());
+  }
+}
+
+impl Handler<MsgUserTyping> for ConnectionTracker {
+  type Result = ();
+
+  fn handle(&mut self, msg: MsgUserTyping, ctx: &mut Self::Context) {
+    let mut root = GqlRoot::new();
+    root.insert(
+      "typingChannel".to_owned(),
+      GqlValue::String(msg.channel.to_string()),
+    );
+    root.insert("typingUser".to_owned(), GqlValue::String(msg.user.clone()));
+    self.broadcast("typing", msg.channel, &msg.user, root, ctx.address());
+  }
+}
+
 impl Handler<MsgSubscriptionStop> for ConnectionTracker {
   type Result = ();
 
@@ -164,30 +301,257 @@ impl Handler<MsgSubscriptionStop> for ConnectionTracker {
   }
 }
 
+impl Handler<MsgUserAddedToChannel> for ConnectionTracker {
+  type Result = ();
+
+  fn handle(&mut self, msg: MsgUserAddedToChannel, _ctx: &mut Self::Context) {
+    for (instance, sub) in self.subscriptions.iter_mut() {
+      if instance.user == msg.user && !sub.channels.contains(&msg.channel) {
+        sub.channels.push(msg.channel);
+        self
+          .channels
+          .entry(msg.channel)
+          .or_insert_with(Vec::new)
+          .push(instance.clone());
+      }
+    }
+  }
+}
+
+impl Handler<MsgUserRemovedFromChannel> for ConnectionTracker {
+  type Result = ();
+
+  fn handle(&mut self, msg: MsgUserRemovedFromChannel, _ctx: &mut Self::Context) {
+    for (instance, sub) in self.subscriptions.iter_mut() {
+      if instance.user == msg.user {
+        sub.channels.retain(|c| *c != msg.channel);
+      }
+    }
+    if let Some(chsub) = self.channels.get_mut(&msg.channel) {
+      chsub.retain(|s| s.user != msg.user);
+    }
+  }
+}
+
+impl Handler<MsgForceDisconnect> for ConnectionTracker {
+  type Result = usize;
+
+  fn handle(&mut self, msg: MsgForceDisconnect, _ctx: &mut Self::Context) -> usize {
+    // every connection of the user, including those without subscriptions,
+    // and a connection with several subscriptions only needs to be told once
+    let mut handlers: Vec<Recipient<MsgStopHandler>> =
+      self.handlers.remove(&msg.0).unwrap_or_default();
+    for (instance, sub) in &self.subscriptions {
+      if instance.user == msg.0 && !handlers.contains(&sub.stop_addr) {
+        handlers.push(sub.stop_addr.clone());
+      }
+    }
+    let stop = MsgStopHandler {
+      reason: "Disconnected by an administrator".to_owned(),
+    };
+    for handler in &handlers {
+      if let Err(e) = handler.do_send(stop.clone()) {
+        warn!("Could not disconnect a session of {}: {:?}", msg.0, e);
+      }
+    }
+    let disconnected = handlers.len();
+    self.remove_user(&msg.0);
+    disconnected
+  }
+}
+
+impl Handler<MsgGetAllSubscriptions> for ConnectionTracker {
+  type Result = MessageResult<MsgGetAllSubscriptions>;
+
+  fn handle(&mut self, _msg: MsgGetAllSubscriptions, _ctx: &mut Self::Context) -> Self::Result {
+    MessageResult(
+      self
+        .subscriptions
+        .iter()
+        .map(|(instance, sub)| SubscriptionSummary {
+          user: instance.user.clone(),
+          id: instance.id.clone(),
+          channels: sub.channels.clone(),
+        })
+        .collect(),
+    )
+  }
+}
+
 pub struct WsHandler {
   conn_id: Option<String>,
-  secret: String,
+  jwt: JwtConfig,
   tracker: Addr<ConnectionTracker>,
+  schema: web::Data<Schema>,
+  ack_payload: Option<AckPayload>,
+  keep_alive: Option<Duration>,
+  /// Ids of the subscriptions started on this connection and not stopped yet
+  subscriptions: HashSet<String>,
+  max_subscriptions: Option<usize>,
+  init_timeout: Option<Duration>,
+  /// Closes the connection unless it is cancelled by an authenticated `connection_init`
+  init_deadline: Option<SpawnHandle>,
+  protocol: WsProtocol,
 }
 
 impl WsHandler {
-  pub fn new(tracker: Addr<ConnectionTracker>, id: Option<String>, secret: String) -> Self {
+  pub fn new(
+    tracker: Addr<ConnectionTracker>,
+    schema: web::Data<Schema>,
+    id: Option<String>,
+    jwt: JwtConfig,
+  ) -> Self {
     WsHandler {
       conn_id: id,
       tracker,
-      secret,
+      schema,
+      jwt,
+      ack_payload: None,
+      keep_alive: None,
+      subscriptions: HashSet::new(),
+      max_subscriptions: None,
+      init_timeout: None,
+      init_deadline: None,
+      protocol: WsProtocol::GraphqlWs,
+    }
+  }
+
+  /// The subprotocol the client and server agreed on
+  pub fn protocol(mut self, protocol: WsProtocol) -> Self {
+    self.protocol = protocol;
+    self
+  }
+
+  /// Closes the connection with a 4408 if it isn't initialized with auth in time
+  pub fn init_timeout(mut self, timeout: Option<Duration>) -> Self {
+    self.init_timeout = timeout;
+    self
+  }
+
+  /// Refuses to start more than `max` subscriptions at once
+  pub fn max_subscriptions(mut self, max: Option<usize>) -> Self {
+    self.max_subscriptions = max;
+    self
+  }
+
+  /// Keeps track of a new subscription, unless the connection is already at its limit
+  fn add_subscription(&mut self, id: &str) -> Result<(), WsError> {
+    match self.max_subscriptions {
+      // restarting a subscription that is already running doesn't add another
+      Some(max) if self.subscriptions.len() >= max && !self.subscriptions.contains(id) => {
+        Err(WsError::TooManySubscriptions(max))
+      }
+      _ => {
+        self.subscriptions.insert(id.to_owned());
+        Ok(())
+      }
     }
   }
 
-  fn disconnected(&self) {
+  /// Sends a `ka` message on this interval, and tells clients about it in the ack
+  pub fn keep_alive(mut self, interval: Option<Duration>) -> Self {
+    self.keep_alive = interval;
+    if let Some(payload) = &mut self.ack_payload {
+      payload.keep_alive_ms = interval.map(|i| i.as_millis() as u64);
+    }
+    self
+  }
+
+  /// Includes server details in `connection_ack`
+  pub fn ack_payload(mut self, enabled: bool) -> Self {
+    self.ack_payload = match enabled {
+      true => Some(AckPayload {
+        server_version: env!("CARGO_PKG_VERSION").to_owned(),
+        keep_alive_ms: self.keep_alive.map(|i| i.as_millis() as u64),
+      }),
+      false => None,
+    };
+    self
+  }
+
+  fn disconnected(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+    self.subscriptions.clear();
     if let Some(id) = &self.conn_id {
-      self.tracker.do_send(MsgWsDisconnected { id: id.clone() });
+      self.tracker.do_send(MsgWsDisconnected {
+        id: id.clone(),
+        addr: ctx.address().recipient(),
+      });
+    }
+  }
+
+  /// Closes the connection with one of the protocol's close codes
+  fn close_with(&mut self, code: u16, reason: &str, ctx: &mut ws::WebsocketContext<Self>) {
+    ctx.close(Some(ws::CloseReason {
+      code: ws::CloseCode::Other(code),
+      description: Some(reason.to_owned()),
+    }));
+    self.disconnected(ctx);
+    ctx.stop();
+  }
+
+  /// Turns down a connection_init. The reason lets clients tell an expired
+  /// token, which they can refresh, from a bad one.
+  fn refuse_init(&mut self, reason: String, ctx: &mut ws::WebsocketContext<Self>) {
+    match self.protocol {
+      WsProtocol::GraphqlTransportWs => self.close_with(4403, &reason, ctx),
+      WsProtocol::GraphqlWs => {
+        ctx.close(Some(ws::CloseReason {
+          code: ws::CloseCode::Policy,
+          description: Some(reason),
+        }));
+        self.disconnected(ctx);
+        ctx.stop();
+      }
+    }
+  }
+
+  /// Tells the client a subscription could not be started. graphql-transport-ws
+  /// has its own error message, and closes the connection when an id is reused.
+  fn subscription_error(&mut self, id: &str, err: WsError, ctx: &mut ws::WebsocketContext<Self>) {
+    match (self.protocol, err) {
+      (WsProtocol::GraphqlTransportWs, err @ WsError::DuplicateSubscription(_)) => {
+        self.close_with(4409, &err.message(), ctx)
+      }
+      (WsProtocol::GraphqlTransportWs, err) => {
+        ctx.text(&ServerWsMessage::subscription_error(id.to_owned(), &err))
+      }
+      (WsProtocol::GraphqlWs, err) => ctx.text(&ServerWsMessage::from_err(err)),
+    }
+  }
+
+  /// Lets the tracker close this connection when its user is disconnected
+  fn connected(&self, ctx: &mut ws::WebsocketContext<Self>) {
+    if let Some(id) = &self.conn_id {
+      self.tracker.do_send(MsgWsConnected {
+        user: id.clone(),
+        addr: ctx.address().recipient(),
+      });
     }
   }
 }
 
 impl Actor for WsHandler {
   type Context = ws::WebsocketContext<Self>;
+
+  fn started(&mut self, ctx: &mut Self::Context) {
+    self.connected(ctx);
+    if let Some(interval) = self.keep_alive {
+      ctx.run_interval(interval, |act, ctx| {
+        ctx.text(&ServerWsMessage::keep_alive(act.protocol))
+      });
+    }
+    if let Some(timeout) = self.init_timeout {
+      self.init_deadline = Some(ctx.run_later(timeout, |act, ctx| {
+        info!("Closing a websocket that was never initialized");
+        ctx.close(Some(ws::CloseReason {
+          code: ws::CloseCode::Other(4408),
+          description: Some("Connection initialisation timeout".to_owned()),
+        }));
+        act.disconnected(ctx);
+        ctx.stop();
+      }));
+    }
+  }
 }
 
 impl StreamHandler<ws::Message, ws::ProtocolError> for WsHandler {
@@ -195,68 +559,132 @@ impl StreamHandler<ws::Message, ws::ProtocolError> for WsHandler {
     info!("recieved a websocket message {:?}", msg);
     match msg {
       ws::Message::Ping(msg) => ctx.pong(&msg),
-      ws::Message::Text(text) => match ClientWsMessage::from_str(&text) {
+      ws::Message::Text(text) => match ClientWsMessage::parse(&text, self.protocol) {
         Err(e) => {
           warn!("{:?}", e);
-          ctx.text(&ServerWsMessage::from_err(e));
+          match self.protocol {
+            WsProtocol::GraphqlTransportWs => self.close_with(4400, &e.message(), ctx),
+            WsProtocol::GraphqlWs => ctx.text(&ServerWsMessage::from_err(e)),
+          }
         }
         Ok(ClientWsMessage::ConnectionInit(init)) => {
-          if let Some(JsonValue::String(jwt)) = init.payload.get("Authorization") {
-            match auth::decode_jwt(jwt, &self.secret) {
-              Ok(user_info) => {
-                info!(
-                  "A user has sent auth over websocket. They are: {}",
-                  user_info.id
-                );
-                self.conn_id = Some(user_info.id);
-              }
-              Err(e) => {
-                info!("JWT Error in websocket {:?}", e);
-                self.disconnected();
-                ctx.close(None);
-                ctx.stop();
-              }
+          let jwt = match init.payload.get("Authorization") {
+            Some(JsonValue::String(jwt)) => jwt,
+            _ => {
+              warn!("No authentication for client. Closing socket.");
+              self.refuse_init("Forbidden".to_owned(), ctx);
+              return;
+            }
+          };
+          match auth::decode_jwt(jwt, &self.jwt) {
+            Ok(user_info) => {
+              info!(
+                "A user has sent auth over websocket. They are: {}",
+                user_info.id
+              );
+              self.conn_id = Some(user_info.id);
+              self.connected(ctx);
+            }
+            Err(e) => {
+              info!("JWT Error in websocket: {}", e);
+              self.refuse_init(e.to_string(), ctx);
+              return;
             }
           }
-          if self.conn_id == None {
-            warn!("No authentication for client. Closing socket.");
-            ctx.close(None);
-            self.disconnected();
-            ctx.stop();
+          if let Some(deadline) = self.init_deadline.take() {
+            ctx.cancel_future(deadline);
           }
-          ctx.text(&ServerWsMessage::ack());
+          ctx.text(&ServerWsMessage::ack(self.ack_payload.clone()));
         }
         Ok(ClientWsMessage::ConnectionTerminate) => {
           ctx.close(None);
-          self.disconnected();
+          self.disconnected(ctx);
           ctx.stop();
         }
-        Ok(ClientWsMessage::Start(new_sub)) => {
-          if let Some(id) = &self.conn_id {
+        Ok(ClientWsMessage::Start(new_sub)) | Ok(ClientWsMessage::Subscribe(new_sub)) => {
+          if let Some(id) = self.conn_id.clone() {
+            // graphql-transport-ws doesn't let a running subscription be restarted
+            if self.protocol == WsProtocol::GraphqlTransportWs
+              && self.subscriptions.contains(&new_sub.id)
+            {
+              let err = WsError::DuplicateSubscription(new_sub.id.clone());
+              self.subscription_error(&new_sub.id, err, ctx);
+              return;
+            }
+            // parse errors and unresolvable fields are reported right away,
+            // since the subscription would never produce any data
+            if let Err(e) = self.schema.validate_subscription(&new_sub.payload) {
+              warn!("Rejected subscription {}: {:?}", new_sub.id, e);
+              self.subscription_error(&new_sub.id, WsError::from(e), ctx);
+              return;
+            }
+            if let Err(e) = self.add_subscription(&new_sub.id) {
+              warn!("Refused subscription {} for {}: {:?}", new_sub.id, id, e);
+              self.subscription_error(&new_sub.id, e, ctx);
+              return;
+            }
+            let sub_id = new_sub.id.clone();
             dbg!("REgistering a new subscription for user {}", &id);
-            self.tracker.do_send(MsgNewSubscription {
-              user_id: id.clone(),
+            let registered = self.tracker.send(MsgNewSubscription {
+              user_id: id,
               sub_id: new_sub.id,
-              addr: ctx.address(),
+              addr: ctx.address().recipient(),
+              stop_addr: ctx.address().recipient(),
               sub: new_sub.payload,
             });
-            info!("New subscription");
+            ctx.spawn(
+              registered
+                .into_actor(self)
+                .map(move |registered, act, ctx| match registered {
+                  Ok(()) => info!("New subscription"),
+                  Err(e) => {
+                    warn!("Refused subscription: {:?}", e);
+                    // a duplicate id still belongs to the subscription that is already running
+                    if e != WsError::DuplicateSubscription(sub_id.clone()) {
+                      act.subscriptions.remove(&sub_id);
+                    }
+                    act.subscription_error(&sub_id, e, ctx);
+                  }
+                })
+                .map_err(|e, _, _| warn!("Could not register subscription: {:?}", e)),
+            );
           } else {
             warn!("Client attempted to subscribe without authorization");
-            ctx.close(None);
+            match self.protocol {
+              WsProtocol::GraphqlTransportWs => self.close_with(4401, "Unauthorized", ctx),
+              WsProtocol::GraphqlWs => ctx.close(None),
+            }
           }
         }
-        Ok(ClientWsMessage::Stop(end_sub)) => {
-          let msg = MsgSubscriptionStop {
-            sub_id: end_sub.id,
-            user_id: self.conn_id.as_ref().unwrap().to_owned(),
+        Ok(ClientWsMessage::Stop(end_sub)) | Ok(ClientWsMessage::Complete(end_sub)) => {
+          let user_id = match self.conn_id.clone() {
+            Some(id) => id,
+            None => {
+              warn!("Client attempted to stop a subscription without authorization");
+              match self.protocol {
+                WsProtocol::GraphqlTransportWs => self.close_with(4401, "Unauthorized", ctx),
+                WsProtocol::GraphqlWs => ctx.close(None),
+              }
+              return;
+            }
           };
-          self.tracker.do_send(msg);
+          self.tracker.do_send(MsgSubscriptionStop {
+            sub_id: end_sub.id.clone(),
+            user_id,
+          });
+          self.subscriptions.remove(&end_sub.id);
+          // the tracker won't send anything else for it, so that was the last data frame.
+          // graphql-transport-ws clients already know, since they sent the complete.
+          if self.protocol == WsProtocol::GraphqlWs {
+            ctx.text(&ServerWsMessage::complete(end_sub.id));
+          }
         }
+        Ok(ClientWsMessage::Ping) => ctx.text(&ServerWsMessage::Pong),
+        Ok(ClientWsMessage::Pong) => (),
       },
       ws::Message::Close(_) => {
         info!("client has disconnected");
-        self.disconnected();
+        self.disconnected(ctx);
         // End the actor
         ctx.stop();
       }
@@ -265,15 +693,667 @@ impl StreamHandler<ws::Message, ws::ProtocolError> for WsHandler {
   }
 }
 
+impl Handler<MsgStopHandler> for WsHandler {
+  type Result = ();
+
+  fn handle(&mut self, msg: MsgStopHandler, ctx: &mut Self::Context) {
+    info!("Closing websocket for {:?}: {}", self.conn_id, msg.reason);
+    ctx.close(Some(ws::CloseReason {
+      code: ws::CloseCode::Policy,
+      description: Some(msg.reason),
+    }));
+    self.disconnected(ctx);
+    ctx.stop();
+  }
+}
+
+/// The frame telling a client about new subscription data.
+/// When resolving failed the data is null, and the errors say why.
+fn subscription_frame(data: MsgSubscriptionData, protocol: WsProtocol) -> ServerWsMessage {
+  ServerWsMessage::data_for(
+    protocol,
+    data.id,
+    data.data.unwrap_or(JsonValue::Null),
+    data.errors,
+  )
+}
+
 impl Handler<MsgSubscriptionData> for WsHandler {
   type Result = ();
   fn handle(&mut self, data: MsgSubscriptionData, ctx: &mut Self::Context) {
-    if let Some(jdata) = data.data {
-      if data.errors.len() == 0 {
-        let resp = ServerWsMessage::data(data.id, jdata);
-        ctx.text(&resp);
-      }
+    if !data.errors.is_empty() {
+      warn!(
+        "Subscription {} resolved with errors: {:?}",
+        data.id, data.errors
+      );
+    }
+    ctx.text(&subscription_frame(data, self.protocol));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::gqln::{ResolutionErr, Resolver};
+  use crate::resolvers;
+  use actix::System;
+  use diesel::mysql::MysqlConnection;
+  use diesel::r2d2::{ConnectionManager, Pool};
+  use serde_json::json;
+
+  /// Stands in for a `WsHandler`, keeping whatever the tracker sends it
+  #[derive(Default)]
+  struct MockClient {
+    received: Vec<MsgSubscriptionData>,
+    stopped: usize,
+  }
+
+  impl Actor for MockClient {
+    type Context = Context<Self>;
+  }
+
+  impl Handler<MsgSubscriptionData> for MockClient {
+    type Result = ();
+
+    fn handle(&mut self, msg: MsgSubscriptionData, _ctx: &mut Self::Context) {
+      self.received.push(msg);
+    }
+  }
+
+  impl Handler<MsgStopHandler> for MockClient {
+    type Result = ();
+
+    fn handle(&mut self, _msg: MsgStopHandler, _ctx: &mut Self::Context) {
+      self.stopped += 1;
+    }
+  }
+
+  struct TakeReceived;
+
+  impl actix::Message for TakeReceived {
+    type Result = Vec<MsgSubscriptionData>;
+  }
+
+  impl Handler<TakeReceived> for MockClient {
+    type Result = MessageResult<TakeReceived>;
+
+    fn handle(&mut self, _msg: TakeReceived, _ctx: &mut Self::Context) -> Self::Result {
+      MessageResult(std::mem::take(&mut self.received))
+    }
+  }
+
+  // everyone is in channel 1
+  fn test_channels(_pool: &DbPool, _user: &str) -> Vec<i32> {
+    vec![1]
+  }
+
+  // nobody is in any channel yet
+  fn no_channels(_pool: &DbPool, _user: &str) -> Vec<i32> {
+    Vec::new()
+  }
+
+  // everyone is in channels 1 and 2
+  fn both_channels(_pool: &DbPool, _user: &str) -> Vec<i32> {
+    vec![1, 2]
+  }
+
+  fn test_tracker() -> ConnectionTracker {
+    test_tracker_with(test_channels)
+  }
+
+  fn test_tracker_with(channel_lookup: ChannelLookup) -> ConnectionTracker {
+    let mut schema =
+      Schema::new(graphql_parser::parse_schema(include_str!("../../schema.graphql")).unwrap())
+        .unwrap();
+    schema
+      .add_resolvers(vec![
+        Resolver::new(
+          Box::new(resolvers::subscription_message),
+          "Subscription",
+          "message",
+        ),
+        Resolver::new(
+          Box::new(resolvers::subscription_typing),
+          "Subscription",
+          "typing",
+        ),
+      ])
+      .unwrap();
+    // never connects, since nothing in these tests touches the database
+    let pool = Pool::builder()
+      .min_idle(Some(0))
+      .build_unchecked(ConnectionManager::<MysqlConnection>::new(
+        "mysql://localhost/clacks_test",
+      ));
+    ConnectionTracker::with_channel_lookup(schema, pool, channel_lookup)
+  }
+
+  fn subscribe(user: &str, client: &Addr<MockClient>) -> MsgNewSubscription {
+    MsgNewSubscription {
+      user_id: user.to_owned(),
+      sub_id: "1".to_owned(),
+      sub: GqlRequest {
+        query: "subscription { message { id content } }".to_owned(),
+        operation_name: None,
+        variables: None,
+        extensions: None,
+      },
+      addr: client.clone().recipient(),
+      stop_addr: client.clone().recipient(),
+    }
+  }
+
+  #[test]
+  fn subscription_lifecycle() {
+    let mut sys = System::new("subscription_lifecycle");
+    let tracker = test_tracker().start();
+    let alice = MockClient::default().start();
+    let bob = MockClient::default().start();
+
+    sys
+      .block_on(tracker.send(subscribe("alice", &alice)))
+      .unwrap()
+      .unwrap();
+    sys
+      .block_on(tracker.send(subscribe("bob", &bob)))
+      .unwrap()
+      .unwrap();
+
+    // bob sends a message, only alice hears about it
+    let created = MsgMessageCreated::new(1, "Hello world!".to_owned(), "bob".to_owned(), 5);
+    sys.block_on(tracker.send(created)).unwrap();
+    let alice_got = sys.block_on(alice.send(TakeReceived)).unwrap();
+    assert_eq!(alice_got.len(), 1);
+    assert_eq!(alice_got[0].id, "1");
+    assert!(alice_got[0].errors.is_empty());
+    assert_eq!(
+      alice_got[0].data,
+      Some(json!({ "message": { "id": "5", "content": "Hello world!" } }))
+    );
+    assert!(sys.block_on(bob.send(TakeReceived)).unwrap().is_empty());
+
+    // messages in channels nobody is subscribed to go nowhere
+    let elsewhere = MsgMessageCreated::new(2, "Anyone?".to_owned(), "bob".to_owned(), 6);
+    sys.block_on(tracker.send(elsewhere)).unwrap();
+    assert!(sys.block_on(alice.send(TakeReceived)).unwrap().is_empty());
+
+    // once alice stops her subscription she no longer receives data
+    let stop = MsgSubscriptionStop {
+      sub_id: "1".to_owned(),
+      user_id: "alice".to_owned(),
+    };
+    sys.block_on(tracker.send(stop)).unwrap();
+    let created = MsgMessageCreated::new(1, "Still there?".to_owned(), "bob".to_owned(), 7);
+    sys.block_on(tracker.send(created)).unwrap();
+    assert!(sys.block_on(alice.send(TakeReceived)).unwrap().is_empty());
+
+    // bob is still subscribed until he disconnects
+    let created = MsgMessageCreated::new(1, "Hi bob".to_owned(), "alice".to_owned(), 8);
+    sys.block_on(tracker.send(created)).unwrap();
+    assert_eq!(sys.block_on(bob.send(TakeReceived)).unwrap().len(), 1);
+
+    let disconnect = MsgWsDisconnected {
+      id: "bob".to_owned(),
+      addr: bob.clone().recipient(),
+    };
+    sys.block_on(tracker.send(disconnect)).unwrap();
+    let created = MsgMessageCreated::new(1, "Bye bob".to_owned(), "alice".to_owned(), 9);
+    sys.block_on(tracker.send(created)).unwrap();
+    assert!(sys.block_on(bob.send(TakeReceived)).unwrap().is_empty());
+  }
+
+  #[test]
+  fn subscriptions_filter_by_channel() {
+    let mut sys = System::new("subscriptions_filter_by_channel");
+    let tracker = test_tracker_with(both_channels).start();
+    let alice = MockClient::default().start();
+    let bob = MockClient::default().start();
+    let carol = MockClient::default().start();
+
+    let mut sub = subscribe("alice", &alice);
+    sub.sub.query = "subscription { message(channel: 1) { id } }".to_owned();
+    sys.block_on(tracker.send(sub)).unwrap().unwrap();
+    let mut sub = subscribe("bob", &bob);
+    sub.sub.query = "subscription($channel: ID) { message(channel: $channel) { id } }".to_owned();
+    sub.sub.variables = Some(json!({ "channel": "2" }));
+    sys.block_on(tracker.send(sub)).unwrap().unwrap();
+    // without a channel, every channel the user is in
+    sys
+      .block_on(tracker.send(subscribe("carol", &carol)))
+      .unwrap()
+      .unwrap();
+
+    let created = MsgMessageCreated::new(2, "Hi".to_owned(), "dave".to_owned(), 5);
+    sys.block_on(tracker.send(created)).unwrap();
+    assert!(sys.block_on(alice.send(TakeReceived)).unwrap().is_empty());
+    assert_eq!(sys.block_on(bob.send(TakeReceived)).unwrap().len(), 1);
+    assert_eq!(sys.block_on(carol.send(TakeReceived)).unwrap().len(), 1);
+
+    let created = MsgMessageCreated::new(1, "Hello".to_owned(), "dave".to_owned(), 6);
+    sys.block_on(tracker.send(created)).unwrap();
+    let alice_got = sys.block_on(alice.send(TakeReceived)).unwrap();
+    assert_eq!(alice_got.len(), 1);
+    assert_eq!(alice_got[0].data, Some(json!({ "message": { "id": "6" } })));
+    assert!(sys.block_on(bob.send(TakeReceived)).unwrap().is_empty());
+    assert_eq!(sys.block_on(carol.send(TakeReceived)).unwrap().len(), 1);
+  }
+
+  #[test]
+  fn typing_reaches_other_subscribers() {
+    let mut sys = System::new("typing_reaches_other_subscribers");
+    let tracker = test_tracker().start();
+    let alice = MockClient::default().start();
+    let bob = MockClient::default().start();
+    let carol = MockClient::default().start();
+
+    for (user, client) in &[("alice", &alice), ("bob", &bob)] {
+      let mut sub = subscribe(user, client);
+      sub.sub.query = "subscription { typing { channelId userId } }".to_owned();
+      sys.block_on(tracker.send(sub)).unwrap().unwrap();
+    }
+    // carol only wants messages
+    sys
+      .block_on(tracker.send(subscribe("carol", &carol)))
+      .unwrap()
+      .unwrap();
+
+    let typing = MsgUserTyping {
+      channel: 1,
+      user: "bob".to_owned(),
+    };
+    sys.block_on(tracker.send(typing)).unwrap();
+    let alice_got = sys.block_on(alice.send(TakeReceived)).unwrap();
+    assert_eq!(alice_got.len(), 1);
+    assert_eq!(
+      alice_got[0].data,
+      Some(json!({ "typing": { "channelId": "1", "userId": "bob" } }))
+    );
+    assert!(sys.block_on(bob.send(TakeReceived)).unwrap().is_empty());
+    assert!(sys.block_on(carol.send(TakeReceived)).unwrap().is_empty());
+
+    // and messages don't go to typing subscriptions
+    let created = MsgMessageCreated::new(1, "Hello".to_owned(), "bob".to_owned(), 5);
+    sys.block_on(tracker.send(created)).unwrap();
+    assert!(sys.block_on(alice.send(TakeReceived)).unwrap().is_empty());
+    assert_eq!(sys.block_on(carol.send(TakeReceived)).unwrap().len(), 1);
+  }
+
+  #[test]
+  fn duplicate_subscription_ids() {
+    let mut sys = System::new("duplicate_subscription_ids");
+    let tracker = test_tracker().start();
+    let alice = MockClient::default().start();
+    let other = MockClient::default().start();
+
+    sys
+      .block_on(tracker.send(subscribe("alice", &alice)))
+      .unwrap()
+      .unwrap();
+    let again = sys
+      .block_on(tracker.send(subscribe("alice", &other)))
+      .unwrap();
+    assert_eq!(again, Err(WsError::DuplicateSubscription("1".to_owned())));
+
+    // the first subscription is untouched
+    let created = MsgMessageCreated::new(1, "Hello".to_owned(), "bob".to_owned(), 5);
+    sys.block_on(tracker.send(created)).unwrap();
+    assert_eq!(sys.block_on(alice.send(TakeReceived)).unwrap().len(), 1);
+    assert!(sys.block_on(other.send(TakeReceived)).unwrap().is_empty());
+    let subs = sys.block_on(tracker.send(MsgGetAllSubscriptions)).unwrap();
+    assert_eq!(subs.len(), 1);
+  }
+
+  #[test]
+  fn unreadable_arguments_are_refused() {
+    let mut sys = System::new("unreadable_arguments_are_refused");
+    let tracker = test_tracker().start();
+    let alice = MockClient::default().start();
+
+    let mut bad = subscribe("alice", &alice);
+    bad.sub.query = "subscription { nope }".to_owned();
+    let refused = sys.block_on(tracker.send(bad)).unwrap();
+    match refused {
+      Err(WsError::InvalidSubscription(_)) => (),
+      other => panic!("expected the subscription to be refused, got {:?}", other),
     }
-    // TODO: handle error condition
+    // nothing is left behind to match events against
+    let subs = sys.block_on(tracker.send(MsgGetAllSubscriptions)).unwrap();
+    assert!(subs.is_empty());
+  }
+
+  #[test]
+  fn large_messages_are_previewed() {
+    let mut sys = System::new("large_messages_are_previewed");
+    let tracker = test_tracker().max_broadcast_content(Some(5)).start();
+    let alice = MockClient::default().start();
+
+    let mut sub = subscribe("alice", &alice);
+    sub.sub.query = "subscription { message { id content contentTruncated } }".to_owned();
+    sys.block_on(tracker.send(sub)).unwrap().unwrap();
+
+    let created = MsgMessageCreated::new(1, "Hi".to_owned(), "bob".to_owned(), 5);
+    sys.block_on(tracker.send(created)).unwrap();
+    let created = MsgMessageCreated::new(1, "Hellé world".to_owned(), "bob".to_owned(), 6);
+    sys.block_on(tracker.send(created)).unwrap();
+    let alice_got = sys.block_on(alice.send(TakeReceived)).unwrap();
+    assert_eq!(
+      alice_got[0].data,
+      Some(json!({ "message": { "id": "5", "content": "Hi", "contentTruncated": false } }))
+    );
+    // the preview stops short of splitting the é
+    assert_eq!(
+      alice_got[1].data,
+      Some(json!({ "message": { "id": "6", "content": "Hell", "contentTruncated": true } }))
+    );
+  }
+
+  #[test]
+  fn list_all_subscriptions() {
+    let mut sys = System::new("list_all_subscriptions");
+    let tracker = test_tracker().start();
+    let alice = MockClient::default().start();
+
+    assert!(sys
+      .block_on(tracker.send(MsgGetAllSubscriptions))
+      .unwrap()
+      .is_empty());
+    sys
+      .block_on(tracker.send(subscribe("alice", &alice)))
+      .unwrap()
+      .unwrap();
+    assert_eq!(
+      sys.block_on(tracker.send(MsgGetAllSubscriptions)).unwrap(),
+      vec![SubscriptionSummary {
+        user: "alice".to_owned(),
+        id: "1".to_owned(),
+        channels: vec![1],
+      }]
+    );
+  }
+
+  #[test]
+  fn user_removed_from_channel() {
+    let mut sys = System::new("user_removed_from_channel");
+    let tracker = test_tracker().start();
+    let alice = MockClient::default().start();
+
+    sys
+      .block_on(tracker.send(subscribe("alice", &alice)))
+      .unwrap()
+      .unwrap();
+    let removed = MsgUserRemovedFromChannel {
+      user: "alice".to_owned(),
+      channel: 1,
+    };
+    sys.block_on(tracker.send(removed)).unwrap();
+
+    let created = MsgMessageCreated::new(1, "Gone?".to_owned(), "bob".to_owned(), 5);
+    sys.block_on(tracker.send(created)).unwrap();
+    assert!(sys.block_on(alice.send(TakeReceived)).unwrap().is_empty());
+    assert_eq!(
+      sys.block_on(tracker.send(MsgGetAllSubscriptions)).unwrap()[0].channels,
+      Vec::<i32>::new()
+    );
+  }
+
+  #[test]
+  fn user_added_to_channel() {
+    let mut sys = System::new("user_added_to_channel");
+    let tracker = test_tracker().start();
+    let alice = MockClient::default().start();
+
+    sys
+      .block_on(tracker.send(subscribe("alice", &alice)))
+      .unwrap()
+      .unwrap();
+    let added = MsgUserAddedToChannel {
+      user: "alice".to_owned(),
+      channel: 2,
+    };
+    sys.block_on(tracker.send(added.clone())).unwrap();
+    // joining twice should not deliver messages twice
+    sys.block_on(tracker.send(added)).unwrap();
+
+    let created = MsgMessageCreated::new(2, "Welcome".to_owned(), "bob".to_owned(), 5);
+    sys.block_on(tracker.send(created)).unwrap();
+    assert_eq!(sys.block_on(alice.send(TakeReceived)).unwrap().len(), 1);
+  }
+
+  #[test]
+  fn added_user_starts_receiving() {
+    let mut sys = System::new("added_user_starts_receiving");
+    let tracker = test_tracker_with(no_channels).start();
+    let alice = MockClient::default().start();
+
+    sys
+      .block_on(tracker.send(subscribe("alice", &alice)))
+      .unwrap()
+      .unwrap();
+    let created = MsgMessageCreated::new(3, "Before".to_owned(), "bob".to_owned(), 5);
+    sys.block_on(tracker.send(created)).unwrap();
+    assert!(sys.block_on(alice.send(TakeReceived)).unwrap().is_empty());
+
+    // added while subscribed, without reconnecting
+    let added = MsgUserAddedToChannel {
+      user: "alice".to_owned(),
+      channel: 3,
+    };
+    sys.block_on(tracker.send(added)).unwrap();
+    let created = MsgMessageCreated::new(3, "After".to_owned(), "bob".to_owned(), 6);
+    sys.block_on(tracker.send(created)).unwrap();
+    let alice_got = sys.block_on(alice.send(TakeReceived)).unwrap();
+    assert_eq!(alice_got.len(), 1);
+    assert_eq!(
+      alice_got[0].data,
+      Some(json!({ "message": { "id": "6", "content": "After" } }))
+    );
+  }
+
+  struct TimesStopped;
+
+  impl actix::Message for TimesStopped {
+    type Result = usize;
+  }
+
+  impl Handler<TimesStopped> for MockClient {
+    type Result = usize;
+
+    fn handle(&mut self, _msg: TimesStopped, _ctx: &mut Self::Context) -> usize {
+      self.stopped
+    }
+  }
+
+  #[test]
+  fn force_disconnect() {
+    let mut sys = System::new("force_disconnect");
+    let tracker = test_tracker().start();
+    let alice = MockClient::default().start();
+    let bob = MockClient::default().start();
+
+    sys
+      .block_on(tracker.send(subscribe("alice", &alice)))
+      .unwrap()
+      .unwrap();
+    let mut second = subscribe("alice", &alice);
+    second.sub_id = "2".to_owned();
+    sys.block_on(tracker.send(second)).unwrap().unwrap();
+    sys
+      .block_on(tracker.send(subscribe("bob", &bob)))
+      .unwrap()
+      .unwrap();
+
+    let disconnected = sys
+      .block_on(tracker.send(MsgForceDisconnect("alice".to_owned())))
+      .unwrap();
+    assert_eq!(disconnected, 1);
+    assert_eq!(sys.block_on(alice.send(TimesStopped)).unwrap(), 1);
+    assert_eq!(sys.block_on(bob.send(TimesStopped)).unwrap(), 0);
+    let remaining = sys.block_on(tracker.send(MsgGetAllSubscriptions)).unwrap();
+    assert_eq!(remaining.len(), 1);
+    assert_eq!(remaining[0].user, "bob");
+  }
+
+  #[test]
+  fn force_disconnect_without_subscriptions() {
+    let mut sys = System::new("force_disconnect_without_subscriptions");
+    let tracker = test_tracker().start();
+    let idle = MockClient::default().start();
+    let subscribed = MockClient::default().start();
+
+    // alice has one connection that never subscribed, and one that did
+    for client in &[&idle, &subscribed] {
+      sys
+        .block_on(tracker.send(MsgWsConnected {
+          user: "alice".to_owned(),
+          addr: (*client).clone().recipient(),
+        }))
+        .unwrap();
+    }
+    sys
+      .block_on(tracker.send(subscribe("alice", &subscribed)))
+      .unwrap()
+      .unwrap();
+
+    let disconnected = sys
+      .block_on(tracker.send(MsgForceDisconnect("alice".to_owned())))
+      .unwrap();
+    assert_eq!(disconnected, 2);
+    assert_eq!(sys.block_on(idle.send(TimesStopped)).unwrap(), 1);
+    assert_eq!(sys.block_on(subscribed.send(TimesStopped)).unwrap(), 1);
+  }
+
+  #[test]
+  fn keep_alive_interval() {
+    use actix_web::error::PayloadError;
+    use futures::{stream, Async, Poll, Stream};
+
+    let mut sys = System::new("keep_alive_interval");
+    let schema =
+      Schema::new(graphql_parser::parse_schema(include_str!("../../schema.graphql")).unwrap())
+        .unwrap();
+    let handler = WsHandler::new(
+      test_tracker().start(),
+      web::Data::new(schema),
+      None,
+      JwtConfig::hmac("secret"),
+    )
+    .keep_alive(Some(Duration::from_millis(10)));
+    // a client that never sends anything, so every frame out is a keep-alive
+    let silent =
+      stream::poll_fn(|| -> Poll<Option<web::Bytes>, PayloadError> { Ok(Async::NotReady) });
+    let frames = ws::WebsocketContext::create(handler, silent);
+
+    let (first, frames) = sys
+      .block_on(frames.into_future())
+      .map_err(|(e, _)| e)
+      .unwrap();
+    assert!(first.unwrap().ends_with(br#"{"type":"ka"}"#));
+    let (second, _) = sys
+      .block_on(frames.into_future())
+      .map_err(|(e, _)| e)
+      .unwrap();
+    assert!(second.unwrap().ends_with(br#"{"type":"ka"}"#));
+  }
+
+  #[test]
+  fn subscription_errors_are_sent() {
+    let failed = MsgSubscriptionData::new(
+      "1".to_owned(),
+      Err(ResolutionErr::NotFound(
+        "Could not find message 5".to_owned(),
+      )),
+    );
+    let frame: JsonValue = serde_json::from_str(&String::from(&subscription_frame(
+      failed,
+      WsProtocol::GraphqlWs,
+    )))
+    .unwrap();
+    assert_eq!(frame["type"], json!("data"));
+    assert_eq!(frame["id"], json!("1"));
+    assert_eq!(frame["payload"]["data"], JsonValue::Null);
+    let errors = frame["payload"]["errors"].as_array().unwrap();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0]["NotFound"], json!("Could not find message 5"));
+    assert_eq!(errors[0]["extensions"]["code"], json!("NOT_FOUND"));
+
+    let ok = MsgSubscriptionData::new("2".to_owned(), Ok((json!({ "message": null }), vec![])));
+    let frame: JsonValue = serde_json::from_str(&String::from(&subscription_frame(
+      ok,
+      WsProtocol::GraphqlWs,
+    )))
+    .unwrap();
+    assert_eq!(
+      frame,
+      json!({ "type": "data", "id": "2", "payload": { "data": { "message": null }, "errors": [] } })
+    );
+  }
+
+  #[test]
+  fn subscription_limit() {
+    let _sys = System::new("subscription_limit");
+    let schema =
+      Schema::new(graphql_parser::parse_schema(include_str!("../../schema.graphql")).unwrap())
+        .unwrap();
+    let mut handler = WsHandler::new(
+      test_tracker().start(),
+      web::Data::new(schema),
+      Some("alice".to_owned()),
+      JwtConfig::hmac("secret"),
+    )
+    .max_subscriptions(Some(2));
+
+    assert_eq!(handler.add_subscription("1"), Ok(()));
+    assert_eq!(handler.add_subscription("2"), Ok(()));
+    assert_eq!(
+      handler.add_subscription("3"),
+      Err(WsError::TooManySubscriptions(2))
+    );
+    // starting one that is already running again is fine
+    assert_eq!(handler.add_subscription("2"), Ok(()));
+
+    // stopping one makes room for another
+    handler.subscriptions.remove("1");
+    assert_eq!(handler.add_subscription("3"), Ok(()));
+    handler.disconnected();
+    assert!(handler.subscriptions.is_empty());
+  }
+
+  #[test]
+  fn init_timeout() {
+    use actix_web::error::PayloadError;
+    use futures::{stream, Async, Poll, Stream};
+
+    let mut sys = System::new("init_timeout");
+    let schema =
+      Schema::new(graphql_parser::parse_schema(include_str!("../../schema.graphql")).unwrap())
+        .unwrap();
+    let handler = WsHandler::new(
+      test_tracker().start(),
+      web::Data::new(schema),
+      None,
+      JwtConfig::hmac("secret"),
+    )
+    .init_timeout(Some(Duration::from_millis(10)));
+    // never sends connection_init
+    let silent =
+      stream::poll_fn(|| -> Poll<Option<web::Bytes>, PayloadError> { Ok(Async::NotReady) });
+    let frames = ws::WebsocketContext::create(handler, silent);
+
+    let started = std::time::Instant::now();
+    let (close, frames) = sys
+      .block_on(frames.into_future())
+      .map_err(|(e, _)| e)
+      .unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(10));
+    let close = close.unwrap();
+    // a close frame, with the code right after the two byte header
+    assert_eq!(close[0], 0x88);
+    assert_eq!(u16::from_be_bytes([close[2], close[3]]), 4408);
+    // and nothing after it
+    let (rest, _) = sys
+      .block_on(frames.into_future())
+      .map_err(|(e, _)| e)
+      .unwrap();
+    assert!(rest.is_none());
   }
 }
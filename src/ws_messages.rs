@@ -1,13 +1,85 @@
 use serde::{Deserialize, Serialize};
-use serde_json::{Map, Value};
+use serde_json::{json, Map, Value};
 
-use crate::gqln::GqlRequest;
+use crate::gqln::{GqlError, GqlRequest, ResolutionErr};
+
+/// The websocket subprotocols subscriptions can be made over
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum WsProtocol {
+  /// The original protocol of subscriptions-transport-ws
+  GraphqlWs,
+  /// The protocol of the graphql-ws library, from version 5 on
+  GraphqlTransportWs,
+}
+
+impl WsProtocol {
+  pub const SUPPORTED: [&str; 2] = ["graphql-ws", "graphql-transport-ws"];
+
+  pub fn name(self) -> &'static str {
+    match self {
+      WsProtocol::GraphqlWs => "graphql-ws",
+      WsProtocol::GraphqlTransportWs => "graphql-transport-ws",
+    }
+  }
+
+  /// Picks the first protocol in a `Sec-WebSocket-Protocol` header that is supported,
+  /// the same way the handshake does. Clients that don't ask for one get graphql-ws.
+  pub fn negotiate(requested: Option<&str>) -> Self {
+    requested
+      .into_iter()
+      .flat_map(|protocols| protocols.split(','))
+      .map(str::trim)
+      .filter_map(|name| match name {
+        "graphql-ws" => Some(WsProtocol::GraphqlWs),
+        "graphql-transport-ws" => Some(WsProtocol::GraphqlTransportWs),
+        _ => None,
+      })
+      .next()
+      .unwrap_or(WsProtocol::GraphqlWs)
+  }
+}
 
 #[derive(Serialize, Debug, PartialEq, Clone)]
 pub enum WsError {
   MessageParse(String),
   MessageEncode(String),
   Unauthorized,
+  /// The query of a subscription could not be parsed
+  QueryParse(String),
+  /// A subscription that can never be resolved, with the reason why
+  InvalidSubscription(Value),
+  /// The connection already has as many subscriptions as it may, which is given
+  TooManySubscriptions(usize),
+  /// A subscription with this id is already running on the connection
+  DuplicateSubscription(String),
+  /// A message that belongs to the other subprotocol
+  WrongProtocol(String),
+}
+
+impl WsError {
+  /// What went wrong, for protocols that send errors as `{ message }`
+  pub fn message(&self) -> String {
+    match self {
+      WsError::MessageParse(msg) | WsError::MessageEncode(msg) => msg.clone(),
+      WsError::Unauthorized => "Unauthorized".to_owned(),
+      WsError::QueryParse(msg) => format!("Could not parse the subscription: {}", msg),
+      WsError::InvalidSubscription(error) => format!("Invalid subscription: {}", error),
+      WsError::TooManySubscriptions(max) => {
+        format!("No more than {} subscriptions may run at once", max)
+      }
+      WsError::DuplicateSubscription(id) => format!("Subscriber for {} already exists", id),
+      WsError::WrongProtocol(msg) => msg.clone(),
+    }
+  }
+}
+
+impl std::convert::From<ResolutionErr> for WsError {
+  fn from(err: ResolutionErr) -> Self {
+    match err {
+      ResolutionErr::QueryParseIssue(msg) => WsError::QueryParse(msg),
+      e => WsError::InvalidSubscription(json!(GqlError::from(e))),
+    }
+  }
 }
 
 #[derive(Serialize, Debug, Clone, PartialEq)]
@@ -16,41 +88,101 @@ pub struct SubDataPayload {
   pub errors: Vec<Value>,
 }
 
+/// An error as graphql-transport-ws sends it
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ErrorMessage {
+  pub message: String,
+}
+
 #[derive(Serialize, Debug, Clone, PartialEq)]
 pub struct SubData {
   pub payload: SubDataPayload,
   pub id: String,
 }
 
+/// Server details sent along with `connection_ack`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AckPayload {
+  pub server_version: String,
+  /// How often the server sends keep-alive messages, if it does
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub keep_alive_ms: Option<u64>,
+}
+
 #[derive(Serialize, Debug, PartialEq, Clone)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 pub enum ServerWsMessage {
-  ConnectionAck,
+  ConnectionAck {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload: Option<AckPayload>,
+  },
   ConnectionError,
+  #[serde(rename = "ka")]
   KA,
   Data(SubData),
+  /// graphql-transport-ws's `data`
+  Next(SubData),
+  Ping,
+  Pong,
   #[serde(rename = "error")]
   GqlError(WsError),
-  Complete,
+  /// graphql-transport-ws's `error`, which ends one subscription
+  #[serde(rename = "error")]
+  SubscriptionError {
+    id: String,
+    payload: Vec<ErrorMessage>,
+  },
+  /// No more data will be sent for the subscription
+  Complete {
+    id: String,
+  },
 }
 
 impl ServerWsMessage {
   pub fn from_err(err: WsError) -> Self {
     Self::GqlError(err)
   }
-  pub fn ack() -> Self {
-    Self::ConnectionAck
+  /// A failed subscription, as graphql-transport-ws reports it
+  pub fn subscription_error(id: String, err: &WsError) -> Self {
+    Self::SubscriptionError {
+      id,
+      payload: vec![ErrorMessage {
+        message: err.message(),
+      }],
+    }
+  }
+  pub fn ack(payload: Option<AckPayload>) -> Self {
+    Self::ConnectionAck { payload }
+  }
+  /// The keep-alive message of a protocol
+  pub fn keep_alive(protocol: WsProtocol) -> Self {
+    match protocol {
+      WsProtocol::GraphqlWs => Self::KA,
+      WsProtocol::GraphqlTransportWs => Self::Ping,
+    }
+  }
+  pub fn complete(id: String) -> Self {
+    Self::Complete { id }
   }
   pub fn data(id: String, data: Value) -> Self {
+    Self::data_with_errors(id, data, Vec::new())
+  }
+  /// Data for a subscription that was only partly, or not at all, resolved
+  pub fn data_with_errors(id: String, data: Value, errors: Vec<Value>) -> Self {
     Self::Data(SubData {
       id,
-      payload: SubDataPayload {
-        data,
-        errors: Vec::new(),
-      },
+      payload: SubDataPayload { data, errors },
     })
   }
+  /// Subscription data, as a `data` or `next` message depending on the protocol
+  pub fn data_for(protocol: WsProtocol, id: String, data: Value, errors: Vec<Value>) -> Self {
+    match (protocol, Self::data_with_errors(id, data, errors)) {
+      (WsProtocol::GraphqlTransportWs, Self::Data(data)) => Self::Next(data),
+      (_, msg) => msg,
+    }
+  }
 }
 
 impl std::convert::From<WsError> for ServerWsMessage {
@@ -75,6 +207,8 @@ impl std::convert::From<&ServerWsMessage> for String {
 
 #[derive(Deserialize, Debug, PartialEq, Clone)]
 pub struct ClientInit {
+  /// graphql-transport-ws clients may leave this out
+  #[serde(default)]
   pub payload: Map<String, Value>,
 }
 
@@ -97,9 +231,35 @@ pub enum ClientWsMessage {
   Start(ClientStart),
   Stop(ClientStop),
   ConnectionTerminate,
+  /// graphql-transport-ws's `start`
+  Subscribe(ClientStart),
+  /// graphql-transport-ws's `stop`
+  Complete(ClientStop),
+  Ping,
+  Pong,
 }
 
 impl ClientWsMessage {
+  /// Parses a message, making sure it belongs to the protocol the connection uses
+  pub fn parse(msg: &str, protocol: WsProtocol) -> Result<Self, WsError> {
+    let parsed = Self::from_str(msg)?;
+    let belongs_to = match &parsed {
+      ClientWsMessage::ConnectionInit(_) => None,
+      ClientWsMessage::Start(_)
+      | ClientWsMessage::Stop(_)
+      | ClientWsMessage::ConnectionTerminate => Some(WsProtocol::GraphqlWs),
+      _ => Some(WsProtocol::GraphqlTransportWs),
+    };
+    match belongs_to {
+      Some(other) if other != protocol => Err(WsError::WrongProtocol(format!(
+        "That message is part of {}, but this connection uses {}",
+        other.name(),
+        protocol.name()
+      ))),
+      _ => Ok(parsed),
+    }
+  }
+
   pub fn from_str(msg: &str) -> Result<Self, WsError> {
     serde_json::from_str(msg).map_err(|e| {
       WsError::MessageParse(format!(
@@ -148,4 +308,105 @@ mod tests {
       panic!()
     };
   }
+
+  #[test]
+  fn subscription_parse_error() {
+    let err = WsError::from(ResolutionErr::QueryParseIssue("unexpected end".to_owned()));
+    assert_eq!(err, WsError::QueryParse("unexpected end".to_owned()));
+    let msg = String::from(&ServerWsMessage::from_err(err));
+    let parsed: Value = serde_json::from_str(&msg).unwrap();
+    assert_eq!(parsed["type"], json!("error"));
+    assert_eq!(parsed["QueryParse"], json!("unexpected end"));
+  }
+
+  #[test]
+  fn ack_payload() {
+    let bare: Value = serde_json::from_str(&String::from(&ServerWsMessage::ack(None))).unwrap();
+    assert_eq!(bare, json!({ "type": "connection_ack" }));
+
+    let ack = ServerWsMessage::ack(Some(AckPayload {
+      server_version: "0.1.0".to_owned(),
+      keep_alive_ms: Some(10000),
+    }));
+    let parsed: Value = serde_json::from_str(&String::from(&ack)).unwrap();
+    assert_eq!(
+      parsed,
+      json!({
+        "type": "connection_ack",
+        "payload": { "serverVersion": "0.1.0", "keepAliveMs": 10000 }
+      })
+    );
+    assert_eq!(String::from(&ServerWsMessage::KA), r#"{"type":"ka"}"#);
+  }
+
+  #[test]
+  fn complete_has_id() {
+    let complete = String::from(&ServerWsMessage::complete("1".to_owned()));
+    let parsed: Value = serde_json::from_str(&complete).unwrap();
+    assert_eq!(parsed, json!({ "type": "complete", "id": "1" }));
+  }
+
+  #[test]
+  fn transport_ws_messages() {
+    let subscribe = r#"{
+      "id": "1",
+      "type": "subscribe",
+      "payload": { "query": "subscription { message { id } }" }
+    }"#;
+    match ClientWsMessage::parse(subscribe, WsProtocol::GraphqlTransportWs).unwrap() {
+      ClientWsMessage::Subscribe(start) => assert_eq!(start.id, "1"),
+      other => panic!("Expected a subscribe, got {:?}", other),
+    }
+    // the other protocol's messages aren't accepted
+    assert!(ClientWsMessage::parse(subscribe, WsProtocol::GraphqlWs).is_err());
+    let ping = r#"{ "type": "ping", "payload": {} }"#;
+    assert_eq!(
+      ClientWsMessage::parse(ping, WsProtocol::GraphqlTransportWs),
+      Ok(ClientWsMessage::Ping)
+    );
+
+    let next = ServerWsMessage::data_for(
+      WsProtocol::GraphqlTransportWs,
+      "1".to_owned(),
+      json!({ "message": { "id": "5" } }),
+      Vec::new(),
+    );
+    let parsed: Value = serde_json::from_str(&String::from(&next)).unwrap();
+    assert_eq!(
+      parsed,
+      json!({
+        "type": "next",
+        "id": "1",
+        "payload": { "data": { "message": { "id": "5" } }, "errors": [] }
+      })
+    );
+    let data = ServerWsMessage::data_for(WsProtocol::GraphqlWs, "1".to_owned(), json!({}), vec![]);
+    assert!(String::from(&data).contains(r#""type":"data""#));
+    assert_eq!(String::from(&ServerWsMessage::Pong), r#"{"type":"pong"}"#);
+
+    let error =
+      ServerWsMessage::subscription_error("1".to_owned(), &WsError::TooManySubscriptions(20));
+    let parsed: Value = serde_json::from_str(&String::from(&error)).unwrap();
+    assert_eq!(
+      parsed,
+      json!({
+        "type": "error",
+        "id": "1",
+        "payload": [{ "message": "No more than 20 subscriptions may run at once" }]
+      })
+    );
+  }
+
+  #[test]
+  fn negotiate_protocol() {
+    assert_eq!(WsProtocol::negotiate(None), WsProtocol::GraphqlWs);
+    assert_eq!(
+      WsProtocol::negotiate(Some("graphql-transport-ws")),
+      WsProtocol::GraphqlTransportWs
+    );
+    assert_eq!(
+      WsProtocol::negotiate(Some("unknown, graphql-ws, graphql-transport-ws")),
+      WsProtocol::GraphqlWs
+    );
+  }
 }